@@ -0,0 +1,161 @@
+//! systemd socket activation and idle exit for the local listeners.
+//!
+//! When started by a `.socket` unit, systemd passes the already-bound
+//! listening sockets as file descriptors starting at 3 and announces them via
+//! `LISTEN_PID`/`LISTEN_FDS`. Each passed TCP listener is matched to the
+//! SOCKS5 server or a TCP forward by its local address.
+
+use std::{
+	net::{SocketAddr, TcpListener as StdTcpListener},
+	sync::{
+		Arc,
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+	},
+	time::{Duration, Instant},
+};
+
+use tokio::time;
+
+/// Take the TCP listeners handed over by systemd socket activation. Returns an
+/// empty list when the process was not socket-activated.
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<StdTcpListener> {
+	use std::os::fd::{FromRawFd, RawFd};
+
+	use socket2::{Socket, Type};
+	use tracing::warn;
+
+	const SD_LISTEN_FDS_START: RawFd = 3;
+
+	let for_us = std::env::var("LISTEN_PID")
+		.ok()
+		.and_then(|pid| pid.parse::<u32>().ok())
+		.is_some_and(|pid| pid == std::process::id());
+	let count = std::env::var("LISTEN_FDS")
+		.ok()
+		.and_then(|n| n.parse::<RawFd>().ok())
+		.unwrap_or(0);
+
+	if !for_us || count <= 0 {
+		return Vec::new();
+	}
+
+	(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+		.filter_map(|fd| {
+			// SAFETY: systemd guarantees these descriptors are open and owned by
+			// this process, and `LISTEN_PID` ensures they were meant for us.
+			let socket = unsafe { Socket::from_raw_fd(fd) };
+			match socket.r#type() {
+				Ok(Type::STREAM) => Some(StdTcpListener::from(socket)),
+				_ => {
+					warn!("[activation] ignoring non-TCP socket passed as fd {fd}");
+					None
+				}
+			}
+		})
+		.collect()
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> Vec<StdTcpListener> {
+	Vec::new()
+}
+
+/// Remove and return the activated listener bound to `addr`.
+pub fn take(listeners: &mut Vec<StdTcpListener>, addr: SocketAddr) -> Option<StdTcpListener> {
+	let idx = listeners
+		.iter()
+		.position(|listener| listener.local_addr().is_ok_and(|local| local == addr))?;
+	Some(listeners.swap_remove(idx))
+}
+
+/// Tracks in-flight local connections and when the last one finished.
+#[derive(Debug)]
+pub struct IdleTracker {
+	start: Instant,
+	active: AtomicUsize,
+	last_active_ms: AtomicU64,
+}
+
+impl Default for IdleTracker {
+	fn default() -> Self {
+		Self {
+			start: Instant::now(),
+			active: AtomicUsize::new(0),
+			last_active_ms: AtomicU64::new(0),
+		}
+	}
+}
+
+impl IdleTracker {
+	/// Mark a local connection as active until the returned guard is dropped.
+	pub fn enter(self: &Arc<Self>) -> ActiveGuard {
+		self.active.fetch_add(1, Ordering::AcqRel);
+		ActiveGuard(self.clone())
+	}
+
+	fn idle_for(&self) -> Option<Duration> {
+		if self.active.load(Ordering::Acquire) > 0 {
+			return None;
+		}
+		let last = Duration::from_millis(self.last_active_ms.load(Ordering::Acquire));
+		Some(self.start.elapsed().saturating_sub(last))
+	}
+
+	/// Resolve once no local connection has been active for `timeout`. Never
+	/// resolves when `timeout` is zero.
+	pub async fn wait_idle(&self, timeout: Duration) {
+		if timeout.is_zero() {
+			return std::future::pending().await;
+		}
+
+		loop {
+			match self.idle_for() {
+				Some(idle) if idle >= timeout => return,
+				Some(idle) => time::sleep(timeout - idle).await,
+				None => time::sleep(timeout).await,
+			}
+		}
+	}
+}
+
+pub struct ActiveGuard(Arc<IdleTracker>);
+
+impl Drop for ActiveGuard {
+	fn drop(&mut self) {
+		let now = self.0.start.elapsed().as_millis() as u64;
+		self.0.last_active_ms.store(now, Ordering::Release);
+		self.0.active.fetch_sub(1, Ordering::AcqRel);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_idle_tracker_waits_for_active_connections() {
+		let tracker = Arc::new(IdleTracker::default());
+		let guard = tracker.enter();
+		assert_eq!(tracker.idle_for(), None);
+
+		let timeout = Duration::from_millis(50);
+		assert!(time::timeout(timeout * 2, tracker.wait_idle(timeout)).await.is_err());
+
+		drop(guard);
+		assert!(time::timeout(timeout * 4, tracker.wait_idle(timeout)).await.is_ok());
+	}
+
+	#[test]
+	fn test_take_matches_local_addr() {
+		let a = StdTcpListener::bind("127.0.0.1:0").unwrap();
+		let b = StdTcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = b.local_addr().unwrap();
+		let mut listeners = vec![a, b];
+
+		let taken = take(&mut listeners, addr).unwrap();
+		assert_eq!(taken.local_addr().unwrap(), addr);
+		assert_eq!(listeners.len(), 1);
+		assert!(take(&mut listeners, addr).is_none());
+	}
+}
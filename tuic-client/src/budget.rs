@@ -0,0 +1,233 @@
+//! Monthly data budget.
+//!
+//! With `[budget]`, the client adds up the bytes its relay connections send and
+//! receive, QUIC overhead included as metered links count it, and keeps the
+//! total of the current calendar month (UTC) in `budget.state`. A warning is
+//! logged as usage crosses each of `warn_at` percent of `limit`. Once the limit
+//! is reached, `exhausted` decides what happens to new SOCKS5 requests and port
+//! forwards until the month ends: `warn` keeps tunneling, `direct` connects
+//! TCP without the tunnel, and `block` refuses them. UDP has no direct path,
+//! so UDP packets are dropped in both cases.
+
+use std::{
+	collections::HashMap,
+	fs, io,
+	path::Path,
+	sync::{
+		Arc, Mutex, PoisonError,
+		atomic::{AtomicBool, Ordering},
+	},
+	time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+	AppContext,
+	config::{BudgetConfig, Exhausted},
+	connection::Traffic,
+};
+
+/// How often the traffic of the relay connections is read
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Usage of one month, as kept in `budget.state`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct Usage {
+	/// `YYYY-MM` in UTC
+	month: String,
+	bytes: u64,
+}
+
+pub struct Budget {
+	cfg: BudgetConfig,
+	usage: Mutex<Usage>,
+	exhausted: AtomicBool,
+}
+
+impl Budget {
+	/// The budget with the usage of the current month from `budget.state`
+	pub fn load(cfg: BudgetConfig) -> Self {
+		let usage = match fs::read_to_string(&cfg.state) {
+			Ok(content) => toml::from_str(&content).unwrap_or_else(|err| {
+				warn!("[budget] ignoring malformed {}: {err}", cfg.state.display());
+				Usage::default()
+			}),
+			Err(err) => {
+				if err.kind() != io::ErrorKind::NotFound {
+					warn!("[budget] failed to read {}: {err}", cfg.state.display());
+				}
+				Usage::default()
+			}
+		};
+		let budget = Self {
+			cfg,
+			usage: Mutex::new(usage),
+			exhausted: AtomicBool::new(false),
+		};
+		budget.add(0, &current_month());
+		budget
+	}
+
+	/// What to do with new requests, `None` while they go through the tunnel
+	pub fn policy(&self) -> Option<Exhausted> {
+		(self.cfg.exhausted != Exhausted::Warn && self.exhausted.load(Ordering::Relaxed)).then_some(self.cfg.exhausted)
+	}
+
+	/// Count `bytes` moved during `month`, starting over in a new month
+	fn add(&self, bytes: u64, month: &str) {
+		let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+		if usage.month != month {
+			if !usage.month.is_empty() {
+				info!("[budget] new month {month}, usage starts over");
+			}
+			*usage = Usage {
+				month: month.to_owned(),
+				bytes: 0,
+			};
+		}
+
+		let before = usage.bytes;
+		usage.bytes = usage.bytes.saturating_add(bytes);
+		let limit = self.cfg.limit;
+		for percent in &self.cfg.warn_at {
+			let threshold = limit / 100 * u64::from(*percent);
+			if before < threshold && usage.bytes >= threshold {
+				warn!(
+					"[budget] {percent}% of the monthly budget used ({} of {limit} bytes)",
+					usage.bytes
+				);
+			}
+		}
+		let exhausted = usage.bytes >= limit;
+		if exhausted && !self.exhausted.swap(true, Ordering::Relaxed) {
+			let action = match self.cfg.exhausted {
+				Exhausted::Warn => "still tunneling",
+				Exhausted::Direct => "connecting directly",
+				Exhausted::Block => "refusing new requests",
+			};
+			warn!("[budget] monthly budget of {limit} bytes exhausted, {action} until the month ends");
+		} else if !exhausted {
+			self.exhausted.store(false, Ordering::Relaxed);
+		}
+	}
+
+	fn save(&self) {
+		let usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner).clone();
+		if let Err(err) = write(&self.cfg.state, &usage) {
+			warn!("[budget] failed to write {}: {err}", self.cfg.state.display());
+		}
+	}
+}
+
+/// Replace `path` only once the new content is complete
+fn write(path: &Path, usage: &Usage) -> io::Result<()> {
+	let content = toml::to_string(usage).map_err(io::Error::other)?;
+	let mut tmp = path.to_owned().into_os_string();
+	tmp.push(".tmp");
+	fs::write(&tmp, content)?;
+	fs::rename(tmp, path)
+}
+
+fn current_month() -> String {
+	chrono::Utc::now().format("%Y-%m").to_string()
+}
+
+/// Count the traffic of the relay connections into `budget` until the client
+/// exits.
+pub async fn track(ctx: Arc<AppContext>, budget: Arc<Budget>) {
+	// Bytes counted so far of each connection, by stable ID. A connection that
+	// couldn't be read keeps its count until the next poll, and a replaced one
+	// is forgotten once its last bytes are counted.
+	let mut counted: HashMap<usize, u64> = HashMap::new();
+	let mut ticker = tokio::time::interval(POLL_INTERVAL);
+	loop {
+		ticker.tick().await;
+
+		let mut delta = 0;
+		for conn_mgr in std::iter::once(&ctx.conn_mgr).chain(ctx.servers.values().map(|upstream| &upstream.conn_mgr)) {
+			for traffic in conn_mgr.traffic() {
+				delta += count(&mut counted, &traffic);
+			}
+		}
+
+		if delta > 0 {
+			budget.add(delta, &current_month());
+			budget.save();
+		}
+	}
+}
+
+/// Bytes of `traffic` not in `counted` yet
+fn count(counted: &mut HashMap<usize, u64>, traffic: &Traffic) -> u64 {
+	let before = if traffic.retired {
+		counted.remove(&traffic.id)
+	} else {
+		counted.insert(traffic.id, traffic.bytes)
+	};
+	traffic.bytes.saturating_sub(before.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn budget(exhausted: Exhausted, state: &Path) -> Budget {
+		Budget::load(BudgetConfig {
+			limit: 1000,
+			warn_at: vec![80],
+			exhausted,
+			state: state.to_owned(),
+		})
+	}
+
+	#[test]
+	fn test_budget_exhausts_and_resets() {
+		let dir = tempfile::tempdir().unwrap();
+		let state = dir.path().join("budget.toml");
+		let budget = budget(Exhausted::Block, &state);
+
+		budget.add(999, "2026-01");
+		assert_eq!(budget.policy(), None);
+		budget.add(1, "2026-01");
+		assert_eq!(budget.policy(), Some(Exhausted::Block));
+
+		budget.add(0, "2026-02");
+		assert_eq!(budget.policy(), None);
+	}
+
+	#[test]
+	fn test_warn_only_keeps_tunneling() {
+		let dir = tempfile::tempdir().unwrap();
+		let budget = budget(Exhausted::Warn, &dir.path().join("budget.toml"));
+		budget.add(5000, &current_month());
+		assert_eq!(budget.policy(), None);
+	}
+
+	#[test]
+	fn test_usage_survives_restart() {
+		let dir = tempfile::tempdir().unwrap();
+		let state = dir.path().join("budget.toml");
+		let first = budget(Exhausted::Direct, &state);
+		first.add(600, &current_month());
+		first.save();
+
+		let second = budget(Exhausted::Direct, &state);
+		second.add(400, &current_month());
+		assert_eq!(second.policy(), Some(Exhausted::Direct));
+	}
+
+	#[test]
+	fn test_count() {
+		let traffic = |id, bytes, retired| Traffic { id, bytes, retired };
+		let mut counted = HashMap::new();
+		assert_eq!(count(&mut counted, &traffic(1, 100, false)), 100);
+		// A poll that couldn't read the connection leaves its count alone
+		assert_eq!(count(&mut counted, &traffic(1, 150, false)), 50);
+		// The last bytes of a replaced connection are counted, then forgotten
+		assert_eq!(count(&mut counted, &traffic(1, 180, true)), 30);
+		assert!(counted.is_empty());
+		assert_eq!(count(&mut counted, &traffic(2, 40, false)), 40);
+	}
+}
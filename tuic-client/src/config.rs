@@ -1,4 +1,5 @@
 use std::{
+	collections::HashMap,
 	fmt::Display,
 	io::Error as IoError,
 	net::{IpAddr, SocketAddr},
@@ -19,9 +20,13 @@ use humantime::Duration as HumanDuration;
 use json5::Error as Json5Error;
 use serde::{Deserialize, Deserializer, de::Error as DeError};
 use thiserror::Error;
+use tuic_core::timestamp_auth::{self, AuthMode};
 use uuid::Uuid;
 
-use crate::utils::{CongestionControl, StackPrefer, UdpRelayMode};
+use crate::{
+	route::{self, RouteRule},
+	utils::{CongestionControl, QuicVersion, StackPrefer, UdpRelayMode},
+};
 
 /// Environment state for configuration parsing
 #[derive(Debug, Clone, Default)]
@@ -60,6 +65,84 @@ pub struct Cli {
 	/// Path to the config file
 	#[arg(short, long, value_name = "PATH")]
 	pub config: Option<PathBuf>,
+
+	/// Apply a transport tuning preset before loading the config file. Values
+	/// set explicitly in the config file still take precedence.
+	#[arg(short, long, value_enum, value_name = "PROFILE")]
+	pub profile: Option<Profile>,
+
+	/// Measure the round-trip time through the tunnel to `[relay]` with COUNT
+	/// probes, like `ping`, then exit
+	#[arg(long, value_name = "COUNT", num_args = 0..=1, default_missing_value = "4")]
+	pub ping: Option<u32>,
+
+	/// Fail on unknown options in the config instead of ignoring them with a
+	/// warning
+	#[arg(long)]
+	pub strict_config: bool,
+
+	#[command(subcommand)]
+	pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+	/// Relay stdin and stdout to TARGET through `[relay]`, e.g. as an OpenSSH
+	/// `ProxyCommand`
+	Stdio {
+		/// Destination as HOST:PORT
+		#[arg(value_parser = parse_server)]
+		target: (String, u16),
+	},
+}
+
+/// Transport tuning presets selectable with `--profile`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+	/// Moderate windows and no segmentation offload, for interactive traffic
+	Latency,
+	/// Large flow-control windows, for bulk transfers
+	Throughput,
+	/// Conservative MTU and frequent heartbeats for unstable links
+	LossyLink,
+	/// Small windows and fewer concurrent streams for memory constrained hosts
+	LowMemory,
+}
+
+impl Profile {
+	/// Apply the preset on top of the built-in relay defaults
+	pub fn apply(self, relay: &mut Relay) {
+		match self {
+			Profile::Latency => {
+				relay.congestion_control = CongestionControl::Bbr;
+				relay.send_window = 8 * 1024 * 1024;
+				relay.receive_window = 4 * 1024 * 1024;
+				relay.gso = false;
+			}
+			Profile::Throughput => {
+				relay.congestion_control = CongestionControl::Bbr;
+				relay.send_window = 64 * 1024 * 1024;
+				relay.receive_window = 32 * 1024 * 1024;
+				relay.initial_mtu = 1400;
+				relay.gso = true;
+				relay.pmtu = true;
+			}
+			Profile::LossyLink => {
+				relay.congestion_control = CongestionControl::Bbr;
+				relay.initial_mtu = 1200;
+				relay.min_mtu = 1200;
+				relay.pmtu = false;
+				relay.heartbeat = Duration::from_secs(2);
+				relay.timeout = Duration::from_secs(15);
+			}
+			Profile::LowMemory => {
+				relay.congestion_control = CongestionControl::Cubic;
+				relay.send_window = 2 * 1024 * 1024;
+				relay.receive_window = 1024 * 1024;
+				relay.max_concurrent_streams = 128;
+			}
+		}
+	}
 }
 
 #[derive(Debug, Deserialize, serde::Serialize, Educe)]
@@ -70,11 +153,34 @@ pub struct Config {
 
 	pub local: Local,
 
+	/// Additional servers that `rules` can route to, keyed by name
+	pub relays: HashMap<String, Relay>,
+
+	/// Routing rules for SOCKS5 requests, matched in order
+	pub rules: Vec<RouteRule>,
+
+	/// Monthly limit on the data moved through the tunnel
+	#[educe(Default = None)]
+	pub budget: Option<BudgetConfig>,
+
 	#[educe(Default = "info")]
 	pub log_level: String,
 
 	#[educe(Default(expression = TokioRuntime::Auto))]
 	pub tokio_runtime: TokioRuntime,
+
+	/// Probe count of `--ping`, which replaces the SOCKS5 server
+	#[serde(skip)]
+	pub ping: Option<u32>,
+
+	/// Destination of the `stdio` command, which replaces the SOCKS5 server
+	#[serde(skip)]
+	pub stdio: Option<(String, u16)>,
+
+	/// Options that were ignored as unknown, e.g. `relay.foo`, to be warned
+	/// about. Always empty with `--strict-config`.
+	#[serde(skip)]
+	pub unknown_options: Vec<String>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, serde::Serialize)]
@@ -122,6 +228,11 @@ pub struct Relay {
 	#[educe(Default(expression = Arc::from([])))]
 	pub password: Arc<[u8]>,
 
+	/// File holding the password in place of `password`, relative to the
+	/// config file. A trailing newline is ignored.
+	#[educe(Default = None)]
+	pub password_file: Option<PathBuf>,
+
 	#[educe(Default = None)]
 	pub ip: Option<IpAddr>,
 
@@ -131,12 +242,33 @@ pub struct Relay {
 	#[educe(Default(expression = Vec::new()))]
 	pub certificates: Vec<PathBuf>,
 
+	/// `timestamp` signs the password with the current time step, for servers
+	/// with `auth_mode = "timestamp"`
+	#[educe(Default(expression = AuthMode::Token))]
+	pub auth_mode: AuthMode,
+
+	/// Length of the time steps of `timestamp`, the same as the server's
+	#[serde(with = "humantime_serde")]
+	#[educe(Default(expression = timestamp_auth::DEFAULT_STEP))]
+	pub auth_time_step: Duration,
+
 	#[educe(Default(expression = UdpRelayMode::Native))]
 	pub udp_relay_mode: UdpRelayMode,
 
+	/// Carry UDP packets over QUIC streams when `udp_relay_mode` is `native`
+	/// but the connection cannot send datagrams (e.g. the path drops them or
+	/// the peer disabled them)
+	#[educe(Default = true)]
+	pub udp_stream_fallback: bool,
+
 	#[educe(Default(expression = CongestionControl::Bbr))]
 	pub congestion_control: CongestionControl,
 
+	/// Congestion controller to ask the server to send with, honoured by
+	/// servers that list it in `client_choices`
+	#[educe(Default = None)]
+	pub server_congestion_control: Option<CongestionControl>,
+
 	#[educe(Default(expression = Vec::new()))]
 	#[serde(deserialize_with = "deserialize_alpn")]
 	pub alpn: Vec<Vec<u8>>,
@@ -144,6 +276,12 @@ pub struct Relay {
 	#[educe(Default = false)]
 	pub zero_rtt_handshake: bool,
 
+	/// Wait for the server to confirm each TCP connect before answering the
+	/// SOCKS5 request, so policy denials and unreachable targets get a matching
+	/// SOCKS5 error. Costs a round trip per connection.
+	#[educe(Default = false)]
+	pub connect_replies: bool,
+
 	#[educe(Default = false)]
 	pub disable_sni: bool,
 
@@ -164,9 +302,11 @@ pub struct Relay {
 	#[educe(Default = false)]
 	pub disable_native_certs: bool,
 
+	#[serde(with = "tuic_core::size")]
 	#[educe(Default = 16777216)]
 	pub send_window: u64,
 
+	#[serde(with = "tuic_core::size")]
 	#[educe(Default = 8388608)]
 	pub receive_window: u32,
 
@@ -196,8 +336,143 @@ pub struct Relay {
 	#[educe(Default = false)]
 	pub skip_cert_verify: bool,
 
+	/// Certificate verification policy; overrides the relay-level `sni`,
+	/// `alpn` and `skip_cert_verify` options where set
+	pub tls: TlsConfig,
+
+	/// QUIC version to connect with instead of the stack default (v1)
+	#[educe(Default = None)]
+	pub quic_version: Option<QuicVersion>,
+
 	#[educe(Default = None)]
 	pub proxy: Option<ProxyConfig>,
+
+	/// Obfuscation of every QUIC datagram, must match the server
+	#[educe(Default = None)]
+	pub obfs: Option<ObfsConfig>,
+
+	/// UDP port of the server's `[knock]` gate, knocked at before connecting
+	#[educe(Default = None)]
+	pub knock_port: Option<u16>,
+
+	/// Region of a `[relays]` server, for `auto` rules with a `region`
+	#[educe(Default = None)]
+	pub region: Option<String>,
+
+	/// Relative preference of a `[relays]` server for `auto`; its measured RTT
+	/// is divided by this
+	#[educe(Default = 1)]
+	pub weight: u32,
+
+	/// `auto` only picks among the `[relays]` servers with the lowest priority
+	#[educe(Default = 0)]
+	pub priority: u32,
+}
+
+#[derive(Debug, Deserialize, serde::Serialize, Educe, Clone, PartialEq, Eq)]
+#[educe(Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct TlsConfig {
+	/// Server name sent in the ClientHello, takes precedence over `relay.sni`
+	#[educe(Default = None)]
+	pub sni: Option<String>,
+
+	/// ALPN protocols, take precedence over `relay.alpn`
+	#[educe(Default = None)]
+	pub alpn: Option<Vec<String>>,
+
+	/// Skip all certificate checks, takes precedence over
+	/// `relay.skip_cert_verify`
+	#[educe(Default = None)]
+	pub insecure: Option<bool>,
+
+	/// Additional CA certificates (PEM or DER) trusted for the server
+	#[educe(Default(expression = Vec::new()))]
+	pub ca_file: Vec<PathBuf>,
+
+	/// SHA-256 fingerprints of accepted server certificates. When set, only
+	/// these certificates are accepted and CAs and hostnames are not checked.
+	#[educe(Default(expression = Vec::new()))]
+	pub pinned_sha256: Vec<String>,
+
+	/// Check that the certificate is valid for the server name. Disable to
+	/// verify the chain but ignore the name, e.g. for IP-only servers.
+	#[educe(Default = true)]
+	pub verify_hostname: bool,
+
+	/// Certificate chain (PEM) presented to servers that require client
+	/// certificates, set together with `client_private_key`
+	#[educe(Default = None)]
+	pub client_certificate: Option<PathBuf>,
+
+	/// Private key (PEM) of `client_certificate`
+	#[educe(Default = None)]
+	pub client_private_key: Option<PathBuf>,
+
+	/// TLS 1.3 cipher suites offered, in order of preference, like
+	/// `TLS13_CHACHA20_POLY1305_SHA256`. Empty keeps those of rustls.
+	#[educe(Default(expression = Vec::new()))]
+	pub cipher_suites: Vec<String>,
+
+	/// Key exchange groups offered, in order of preference, like `X25519`.
+	/// Empty keeps those of rustls.
+	#[educe(Default(expression = Vec::new()))]
+	pub kx_groups: Vec<String>,
+
+	/// Write the TLS secrets to the file named by `SSLKEYLOGFILE`, for
+	/// Wireshark to decrypt captured traffic. For debugging only.
+	#[educe(Default = false)]
+	pub key_log: bool,
+}
+
+#[derive(Debug, Deserialize, serde::Serialize, Educe, Clone, PartialEq, Eq)]
+#[educe(Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct ObfsConfig {
+	#[serde(rename = "type")]
+	pub kind: ObfsKind,
+
+	pub password: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, serde::Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ObfsKind {
+	#[default]
+	Salamander,
+}
+
+#[derive(Debug, Deserialize, serde::Serialize, Educe, Clone, PartialEq, Eq)]
+#[educe(Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct BudgetConfig {
+	/// Bytes sent and received per calendar month (UTC), QUIC overhead
+	/// included
+	#[serde(with = "tuic_core::size")]
+	pub limit: u64,
+
+	/// Percentages of `limit` at which a warning is logged
+	#[educe(Default(expression = vec![80, 90]))]
+	pub warn_at: Vec<u8>,
+
+	/// What happens to new requests once `limit` is reached
+	pub exhausted: Exhausted,
+
+	/// File keeping the usage across restarts, relative to the config file
+	#[educe(Default(expression = PathBuf::from("tuic-budget.toml")))]
+	pub state: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, serde::Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Exhausted {
+	/// Only log, keep tunneling
+	#[default]
+	Warn,
+	/// Connect TCP directly, drop UDP
+	Direct,
+	/// Refuse TCP, drop UDP
+	Block,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, serde::Serialize, Default)]
@@ -223,6 +498,7 @@ pub struct ProxyConfig {
 	#[educe(Default = None)]
 	pub password: Option<String>,
 
+	#[serde(with = "tuic_core::size")]
 	#[educe(Default = 2048)]
 	pub udp_buffer_size: usize,
 }
@@ -245,6 +521,7 @@ pub struct Local {
 	#[educe(Default = None)]
 	pub dual_stack: Option<bool>,
 
+	#[serde(with = "tuic_core::size")]
 	#[educe(Default = 1500)]
 	pub max_packet_size: usize,
 
@@ -257,6 +534,13 @@ pub struct Local {
 
 	#[educe(Default(expression = Vec::new()))]
 	pub udp_forward: Vec<UdpForward>,
+
+	/// Exit once no local TCP connection has been active for this long, so a
+	/// socket-activated client can be started again on demand. Zero keeps the
+	/// client running.
+	#[educe(Default(expression = Duration::ZERO))]
+	#[serde(with = "humantime_serde")]
+	pub idle_exit: Duration,
 }
 
 #[derive(Debug, Clone, Deserialize, serde::Serialize)]
@@ -275,6 +559,18 @@ pub struct UdpForward {
 	pub remote: (String, u16),
 	#[serde(default = "default_udp_timeout", deserialize_with = "deserialize_duration")]
 	pub timeout: Duration,
+	/// Answer repeated DNS queries from a local cache. Only for a `remote`
+	/// that is a DNS server.
+	#[serde(default)]
+	pub dns_cache: bool,
+	/// Refresh cached answers of popular names before they expire
+	#[serde(default)]
+	pub dns_prefetch: bool,
+	/// Local DNS server for names that `rules` route to `direct`. Queries for
+	/// them are sent there without the tunnel, so CDNs answer with nearby
+	/// addresses; all other queries still go to `remote`.
+	#[serde(default)]
+	pub direct_resolver: Option<SocketAddr>,
 }
 
 fn default_udp_timeout() -> Duration {
@@ -291,7 +587,14 @@ impl Config {
 			Err(ConfigError::ConfigNotFound(path.clone()))?;
 		}
 
-		let figmet = Figment::from(Serialized::defaults(Config::default()));
+		// Profile presets only replace built-in defaults, so anything set in the
+		// config file still wins
+		let mut defaults = Config::default();
+		if let Some(profile) = cli.profile {
+			profile.apply(&mut defaults.relay);
+		}
+
+		let figmet = Figment::from(Serialized::defaults(defaults));
 		let format;
 
 		// Priority: TUIC_FORCE_TOML > TUIC_CONFIG_FORMAT > file extension > content
@@ -342,7 +645,95 @@ impl Config {
 			}
 		};
 
-		let config: Config = figmet.extract().map_err(ConfigError::Figment)?;
+		let (mut config, unknown_options) =
+			tuic_core::config::extract::<Config>(figmet, cli.strict_config).map_err(ConfigError::Figment)?;
+		config.unknown_options = unknown_options;
+		config.ping = cli.ping;
+		config.stdio = cli.command.map(|Command::Stdio { target }| target);
+
+		for relay in std::iter::once(&mut config.relay).chain(config.relays.values_mut()) {
+			if let Some(password_file) = &relay.password_file {
+				if !relay.password.is_empty() {
+					return Err(ConfigError::PasswordConflict)?;
+				}
+				let password_file = path
+					.parent()
+					.map_or_else(|| password_file.clone(), |dir| dir.join(password_file));
+				let password = std::fs::read_to_string(&password_file)
+					.map_err(|err| ConfigError::PasswordFile(password_file.clone(), err))?;
+				relay.password = Arc::from(password.trim_end_matches(['\r', '\n']).as_bytes());
+			}
+
+			if let Some(version) = relay.quic_version
+				&& !version.is_supported()
+			{
+				return Err(ConfigError::UnsupportedQuicVersion(version))?;
+			}
+
+			if let Some(pin) = relay
+				.tls
+				.pinned_sha256
+				.iter()
+				.find(|pin| crate::tls::parse_pin(pin).is_none())
+			{
+				return Err(ConfigError::InvalidPin(pin.clone()))?;
+			}
+
+			if let Some(alpn) = relay
+				.alpn
+				.iter()
+				.map(Vec::as_slice)
+				.chain(relay.tls.alpn.iter().flatten().map(String::as_bytes))
+				.find(|alpn| !(1..=255).contains(&alpn.len()))
+			{
+				return Err(ConfigError::InvalidAlpn(String::from_utf8_lossy(alpn).into_owned()))?;
+			}
+
+			if relay.tls.client_certificate.is_some() != relay.tls.client_private_key.is_some() {
+				return Err(ConfigError::IncompleteClientCertificate)?;
+			}
+
+			if !timestamp_auth::is_valid_step(relay.auth_time_step) {
+				return Err(ConfigError::InvalidAuthTimeStep(relay.auth_time_step))?;
+			}
+		}
+
+		if let Some(name) = config.relays.keys().find(|name| route::RESERVED.contains(&name.as_str())) {
+			return Err(ConfigError::ReservedServerName(name.clone()))?;
+		}
+
+		if let Some((name, _)) = config.relays.iter().find(|(_, relay)| relay.weight == 0) {
+			return Err(ConfigError::ZeroWeight(name.clone()))?;
+		}
+
+		for rule in &config.rules {
+			if rule.server == route::AUTO {
+				let region = rule.region.as_deref();
+				if !config.relays.values().any(|relay| {
+					region.is_none_or(|region| relay.region.as_deref().is_some_and(|r| r.eq_ignore_ascii_case(region)))
+				}) {
+					return Err(ConfigError::NoAutoCandidate(region.unwrap_or("any").to_owned()))?;
+				}
+			} else if rule.region.is_some() {
+				return Err(ConfigError::RegionWithoutAuto(rule.server.clone()))?;
+			} else if !route::RESERVED.contains(&rule.server.as_str()) && !config.relays.contains_key(&rule.server) {
+				return Err(ConfigError::UnknownServer(rule.server.clone()))?;
+			}
+		}
+
+		if let Some(budget) = &mut config.budget {
+			if budget.limit == 0 {
+				return Err(ConfigError::ZeroBudget)?;
+			}
+			if let Some(percent) = budget.warn_at.iter().find(|percent| !(1..=100).contains(*percent)) {
+				return Err(ConfigError::InvalidBudgetWarning(*percent))?;
+			}
+			if budget.state.is_relative()
+				&& let Some(dir) = path.parent()
+			{
+				budget.state = dir.join(&budget.state);
+			}
+		}
 
 		Ok(config)
 	}
@@ -405,19 +796,18 @@ pub fn deserialize_server<'de, D>(deserializer: D) -> Result<(String, u16), D::E
 where
 	D: Deserializer<'de>,
 {
-	let mut s = String::deserialize(deserializer)?;
-
-	let (domain, port) = s.rsplit_once(':').ok_or(DeError::custom("invalid server address"))?;
+	let s = String::deserialize(deserializer)?;
+	parse_server(&s).map_err(DeError::custom)
+}
 
-	let port = port.parse().map_err(DeError::custom)?;
-	s.truncate(domain.len());
+/// Split `host:port`, stripping the brackets of an IPv6 host
+fn parse_server(s: &str) -> Result<(String, u16), String> {
+	let (host, port) = s.rsplit_once(':').ok_or("invalid server address")?;
+	let port = port.parse().map_err(|err| format!("invalid port: {err}"))?;
 
 	// Strip brackets from IPv6 addresses (e.g., "[::1]" -> "::1")
-	if s.starts_with('[') && s.ends_with(']') {
-		s = s[1..s.len() - 1].to_string();
-	}
-
-	Ok((s, port))
+	let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+	Ok((host.to_owned(), port))
 }
 
 pub fn deserialize_password<'de, D>(deserializer: D) -> Result<Arc<[u8]>, D::Error>
@@ -478,6 +868,39 @@ pub enum ConfigError {
 	Toml(String),
 	#[error("configuration error: {0}")]
 	Figment(#[from] figment::Error),
+	#[error("unsupported QUIC version: {0}")]
+	UnsupportedQuicVersion(QuicVersion),
+	#[error("invalid `tls.pinned_sha256` fingerprint: {0}")]
+	InvalidPin(String),
+	#[error("ALPN protocol must be 1 to 255 bytes long: {0:?}")]
+	InvalidAlpn(String),
+	#[error("`tls.client_certificate` and `tls.client_private_key` must be set together")]
+	IncompleteClientCertificate,
+	#[error(
+		"`auth_time_step` must be whole seconds, from {} to {}, not {}",
+		humantime::format_duration(timestamp_auth::MIN_STEP),
+		humantime::format_duration(timestamp_auth::MAX_STEP),
+		humantime::format_duration(.0.to_owned())
+	)]
+	InvalidAuthTimeStep(Duration),
+	#[error("`rules` refers to unknown server: {0}")]
+	UnknownServer(String),
+	#[error("`relays.{0}` uses a reserved server name")]
+	ReservedServerName(String),
+	#[error("`relays.{0}.weight` must be greater than zero")]
+	ZeroWeight(String),
+	#[error("`auto` rule has no server in `relays` for region {0}")]
+	NoAutoCandidate(String),
+	#[error("`region` is only valid for `auto` rules, not `{0}`")]
+	RegionWithoutAuto(String),
+	#[error("`budget.limit` must be greater than zero")]
+	ZeroBudget,
+	#[error("`password` and `password_file` are mutually exclusive")]
+	PasswordConflict,
+	#[error("failed to read `password_file` {}: {1}", .0.display())]
+	PasswordFile(PathBuf, IoError),
+	#[error("`budget.warn_at` must be between 1 and 100, not {0}")]
+	InvalidBudgetWarning(u8),
 }
 
 impl From<toml::de::Error> for ConfigError {
@@ -534,6 +957,145 @@ mod tests {
 		assert_eq!(config.relay.server.1, 8443);
 	}
 
+	#[test]
+	fn test_profile_preset_is_overridden_by_config() {
+		use std::fs;
+
+		use tempfile::tempdir;
+
+		let temp_dir = tempdir().unwrap();
+		let config_path = temp_dir.path().join("config.toml");
+		let config_content = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "test"
+send_window = 1234
+
+[local]
+server = "127.0.0.1:1080"
+"#;
+		fs::write(&config_path, config_content).unwrap();
+
+		let cli = Cli::try_parse_from([
+			"test_binary",
+			"--config",
+			config_path.to_str().unwrap(),
+			"--profile",
+			"throughput",
+		])
+		.unwrap();
+		assert_eq!(cli.profile, Some(Profile::Throughput));
+
+		let config = Config::parse(cli, EnvState::default()).unwrap();
+		assert_eq!(config.relay.receive_window, 32 * 1024 * 1024);
+		assert_eq!(config.relay.initial_mtu, 1400);
+		assert_eq!(config.relay.send_window, 1234);
+	}
+
+	#[test]
+	fn test_password_file() {
+		use std::fs;
+
+		use tempfile::tempdir;
+
+		let temp_dir = tempdir().unwrap();
+		let config_path = temp_dir.path().join("config.toml");
+		fs::write(temp_dir.path().join("password"), "secret\n").unwrap();
+		let config_content = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password_file = "password"
+
+[local]
+server = "127.0.0.1:1080"
+"#;
+		fs::write(&config_path, config_content).unwrap();
+		let cli = Cli::try_parse_from(["test_binary", "--config", config_path.to_str().unwrap()]).unwrap();
+		let config = Config::parse(cli, EnvState::default()).unwrap();
+		assert_eq!(&*config.relay.password, b"secret");
+
+		fs::write(
+			&config_path,
+			config_content.replace("password_file", "password = \"test\"\npassword_file"),
+		)
+		.unwrap();
+		let cli = Cli::try_parse_from(["test_binary", "--config", config_path.to_str().unwrap()]).unwrap();
+		let err = Config::parse(cli, EnvState::default()).unwrap_err();
+		assert!(matches!(err.downcast_ref(), Some(ConfigError::PasswordConflict)));
+	}
+
+	#[test]
+	fn test_auth_time_step() {
+		let config = |step: &str| {
+			format!(
+				r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "test"
+auth_time_step = "{step}"
+
+[local]
+server = "127.0.0.1:1080"
+"#
+			)
+		};
+		let parsed = test_parse_config(&config("30s"), ".toml").unwrap();
+		assert_eq!(parsed.relay.auth_time_step, Duration::from_secs(30));
+		for step in ["0s", "5s", "10s 500ms", "1year"] {
+			let err = test_parse_config(&config(step), ".toml").unwrap_err();
+			assert!(
+				matches!(err.downcast_ref(), Some(ConfigError::InvalidAuthTimeStep(_))),
+				"{step}: {err}"
+			);
+		}
+	}
+
+	#[test]
+	fn test_human_sizes() {
+		let config = r#"{
+			relay: {
+				server: "example.com:443",
+				uuid: "00000000-0000-0000-0000-000000000000",
+				password: "test",
+				send_window: "32MiB",
+				receive_window: "8 MB",
+				proxy: {server: "127.0.0.1:1081", udp_buffer_size: "4KiB"},
+			},
+			local: {server: "127.0.0.1:1080", max_packet_size: "1.5KiB"},
+		}"#;
+		let config = test_parse_config(config, ".json5").unwrap();
+		assert_eq!(config.relay.send_window, 32 << 20);
+		assert_eq!(config.relay.receive_window, 8_000_000);
+		assert_eq!(config.relay.proxy.unwrap().udp_buffer_size, 4096);
+		assert_eq!(config.local.max_packet_size, 1536);
+	}
+
+	#[test]
+	fn test_ping_flag() {
+		let cli = Cli::try_parse_from(["test_binary", "--ping"]).unwrap();
+		assert_eq!(cli.ping, Some(4));
+		let cli = Cli::try_parse_from(["test_binary", "--ping", "10"]).unwrap();
+		assert_eq!(cli.ping, Some(10));
+		let cli = Cli::try_parse_from(["test_binary"]).unwrap();
+		assert_eq!(cli.ping, None);
+	}
+
+	#[test]
+	fn test_stdio_command() {
+		let cli = Cli::try_parse_from(["test_binary", "-c", "client.toml", "stdio", "[2001:db8::1]:22"]).unwrap();
+		assert_eq!(
+			cli.command,
+			Some(Command::Stdio {
+				target: ("2001:db8::1".to_owned(), 22)
+			})
+		);
+		assert!(Cli::try_parse_from(["test_binary", "stdio", "example.com"]).is_err());
+		assert_eq!(Cli::try_parse_from(["test_binary"]).unwrap().command, None);
+	}
+
 	#[test]
 	fn test_json5_comments() {
 		// Test JSON5 comment support (single-line and multi-line)
@@ -614,8 +1176,14 @@ mod tests {
 		assert_eq!(config.log_level, "info");
 		assert_eq!(config.relay.ipstack_prefer, StackPrefer::V4first);
 		assert_eq!(config.relay.udp_relay_mode, UdpRelayMode::Native);
+		assert!(config.relay.udp_stream_fallback);
+		assert_eq!(config.relay.auth_mode, AuthMode::Token);
+		assert_eq!(config.relay.auth_time_step, Duration::from_secs(60));
 		assert_eq!(config.relay.congestion_control, CongestionControl::Bbr);
+		assert_eq!(config.relay.server_congestion_control, None);
+		assert_eq!(config.relay.knock_port, None);
 		assert!(!config.relay.zero_rtt_handshake);
+		assert!(!config.relay.connect_replies);
 		assert!(!config.relay.disable_sni);
 		assert_eq!(config.relay.timeout, Duration::from_secs(8));
 		assert_eq!(config.relay.startup_mode, StartupMode::Lazy);
@@ -630,8 +1198,11 @@ mod tests {
 		assert_eq!(config.relay.gc_interval, Duration::from_secs(3));
 		assert_eq!(config.relay.gc_lifetime, Duration::from_secs(15));
 		assert!(!config.relay.skip_cert_verify);
+		assert_eq!(config.relay.tls, TlsConfig::default());
+		assert!(config.relay.tls.verify_hostname);
 		assert_eq!(config.local.max_packet_size, 1500);
 		assert_eq!(config.local.server, Some("127.0.0.1:1080".parse().unwrap()));
+		assert_eq!(config.local.idle_exit, Duration::ZERO);
 	}
 
 	#[test]
@@ -684,6 +1255,56 @@ mod tests {
 		assert_eq!(config.local.udp_forward[0].remote.0, "8.8.8.8");
 		assert_eq!(config.local.udp_forward[0].remote.1, 53);
 		assert_eq!(config.local.udp_forward[0].timeout, Duration::from_secs(10));
+		assert!(!config.local.udp_forward[0].dns_cache);
+		assert_eq!(config.local.udp_forward[0].direct_resolver, None);
+	}
+
+	#[test]
+	fn test_unknown_options() {
+		use std::fs;
+
+		use tempfile::tempdir;
+
+		let temp_dir = tempdir().unwrap();
+		let config_path = temp_dir.path().join("config.toml");
+		fs::write(
+			&config_path,
+			r#"
+from_the_future = true
+
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "test"
+new_option = 1
+
+[local]
+server = "127.0.0.1:1080"
+
+[[local.tcp_forward]]
+listen = "127.0.0.1:2222"
+remote = "10.0.0.1:22"
+keepalive = true
+"#,
+		)
+		.unwrap();
+		let parse = |extra: &[&str]| {
+			let args = ["test_binary", "--config", config_path.to_str().unwrap()];
+			let cli = Cli::try_parse_from(args.iter().chain(extra)).unwrap();
+			Config::parse(cli, EnvState::default())
+		};
+
+		let mut config = parse(&[]).unwrap();
+		config.unknown_options.sort();
+		assert_eq!(
+			config.unknown_options,
+			["from_the_future", "local.tcp_forward.keepalive", "relay.new_option"]
+		);
+		assert_eq!(config.relay.server.0, "example.com");
+		assert_eq!(config.local.tcp_forward[0].remote.1, 22);
+
+		let err = parse(&["--strict-config"]).unwrap_err();
+		assert!(err.to_string().contains("unknown field"), "{err}");
 	}
 
 	#[test]
@@ -711,6 +1332,22 @@ mod tests {
 		assert_eq!(config.relay.alpn[0], b"h3".to_vec());
 		assert_eq!(config.relay.alpn[1], b"h2".to_vec());
 		assert_eq!(config.relay.alpn[2], b"http/1.1".to_vec());
+
+		for alpn in [r#"alpn = [""]"#, "[relay.tls]\nalpn = [\"h3\", \"\"]"] {
+			let toml_config = format!(
+				r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+{alpn}
+
+[local]
+server = "127.0.0.1:1081"
+"#
+			);
+			assert!(test_parse_config(&toml_config, ".toml").is_err(), "{alpn}");
+		}
 	}
 
 	#[test]
@@ -736,6 +1373,275 @@ server = "127.0.0.1:1081"
 		assert_eq!(proxy.password.unwrap(), "pwd");
 	}
 
+	#[test]
+	fn test_obfs_config() {
+		let toml_config = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+[relay.obfs]
+type = "salamander"
+password = "obfs_secret"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		let config = test_parse_config(toml_config, ".toml").unwrap();
+		let obfs = config.relay.obfs.unwrap();
+		assert_eq!(obfs.kind, ObfsKind::Salamander);
+		assert_eq!(obfs.password, "obfs_secret");
+	}
+
+	#[test]
+	fn test_tls_config() {
+		let toml_config = r#"
+[relay]
+server = "192.0.2.1:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+sni = "legacy.example.com"
+
+[relay.tls]
+sni = "example.com"
+alpn = ["h3"]
+ca_file = ["/etc/tuic/ca.pem"]
+verify_hostname = false
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		let config = test_parse_config(toml_config, ".toml").unwrap();
+		let tls = &config.relay.tls;
+		assert_eq!(tls.sni.as_deref(), Some("example.com"));
+		assert_eq!(tls.alpn, Some(vec!["h3".to_string()]));
+		assert_eq!(tls.insecure, None);
+		assert_eq!(tls.ca_file, vec![PathBuf::from("/etc/tuic/ca.pem")]);
+		assert!(tls.pinned_sha256.is_empty());
+		assert!(!tls.verify_hostname);
+
+		let toml_config = r#"
+[relay]
+server = "192.0.2.1:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+
+[relay.tls]
+pinned_sha256 = ["not-a-fingerprint"]
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		assert!(test_parse_config(toml_config, ".toml").is_err());
+
+		let toml_config = r#"
+[relay]
+server = "192.0.2.1:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+
+[relay.tls]
+client_certificate = "/etc/tuic/client.pem"
+client_private_key = "/etc/tuic/client.key"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		let config = test_parse_config(toml_config, ".toml").unwrap();
+		assert_eq!(
+			config.relay.tls.client_certificate,
+			Some(PathBuf::from("/etc/tuic/client.pem"))
+		);
+		let toml_config = toml_config.replace("client_private_key = \"/etc/tuic/client.key\"\n", "");
+		assert!(test_parse_config(&toml_config, ".toml").is_err());
+	}
+
+	#[test]
+	fn test_quic_version() {
+		let toml_config = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+quic_version = "draft-29"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		let config = test_parse_config(toml_config, ".toml").unwrap();
+		assert_eq!(config.relay.quic_version, Some(QuicVersion::DRAFT_29));
+
+		let toml_config = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+quic_version = "v2"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		assert!(test_parse_config(toml_config, ".toml").is_err());
+	}
+
+	#[test]
+	fn test_route_rules() {
+		let toml_config = r#"
+[relay]
+server = "nearest.example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+
+[relays.us]
+server = "us.example.com:443"
+uuid = "00000000-0000-0000-0000-000000000001"
+password = "pass"
+congestion_control = "cubic"
+
+[[rules]]
+domain = ["netflix.com", "hulu.com"]
+server = "us"
+
+[[rules]]
+ip = ["192.168.0.0/16", "fc00::/7"]
+server = "direct"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		let config = test_parse_config(toml_config, ".toml").unwrap();
+		let us = &config.relays["us"];
+		assert_eq!(us.server.0, "us.example.com");
+		assert_eq!(us.congestion_control, CongestionControl::Cubic);
+		assert_eq!(config.rules.len(), 2);
+		assert_eq!(config.rules[0].domain, ["netflix.com", "hulu.com"]);
+		assert_eq!(config.rules[1].ip.len(), 2);
+		assert_eq!(config.rules[1].server, route::DIRECT);
+
+		let toml_config = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+
+[[rules]]
+domain = ["netflix.com"]
+server = "us"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		let err = test_parse_config(toml_config, ".toml").unwrap_err();
+		assert!(matches!(
+			err.downcast_ref::<ConfigError>(),
+			Some(ConfigError::UnknownServer(_))
+		));
+
+		let toml_config = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+
+[[rules]]
+ip = ["10.0.0.0/40"]
+server = "direct"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		assert!(test_parse_config(toml_config, ".toml").is_err());
+	}
+
+	#[test]
+	fn test_split_dns() {
+		let toml_config = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+
+[[rules]]
+domain = ["cn"]
+server = "direct"
+
+[local]
+server = "127.0.0.1:1081"
+
+[[local.udp_forward]]
+listen = "127.0.0.1:5353"
+remote = "8.8.8.8:53"
+dns_cache = true
+direct_resolver = "223.5.5.5:53"
+"#;
+		let config = test_parse_config(toml_config, ".toml").unwrap();
+		let forward = &config.local.udp_forward[0];
+		assert_eq!(forward.direct_resolver, Some("223.5.5.5:53".parse().unwrap()));
+		assert!(forward.dns_cache);
+	}
+
+	#[test]
+	fn test_auto_rule() {
+		let toml_config = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+
+[relays.us-east]
+server = "us-east.example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+region = "us"
+weight = 3
+
+[relays.us-backup]
+server = "us-backup.example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+region = "us"
+priority = 1
+
+[[rules]]
+domain = ["netflix.com"]
+server = "auto"
+region = "US"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		let config = test_parse_config(toml_config, ".toml").unwrap();
+		assert_eq!(config.relays["us-east"].weight, 3);
+		assert_eq!(config.relays["us-backup"].weight, 1);
+		assert_eq!(config.relays["us-backup"].priority, 1);
+		assert_eq!(config.rules[0].region.as_deref(), Some("US"));
+
+		let toml_config = r#"
+[relay]
+server = "example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+
+[relays.eu]
+server = "eu.example.com:443"
+uuid = "00000000-0000-0000-0000-000000000000"
+password = "pass"
+region = "eu"
+
+[[rules]]
+server = "auto"
+region = "us"
+
+[local]
+server = "127.0.0.1:1081"
+"#;
+		let err = test_parse_config(toml_config, ".toml").unwrap_err();
+		assert!(matches!(
+			err.downcast_ref::<ConfigError>(),
+			Some(ConfigError::NoAutoCandidate(_))
+		));
+	}
+
 	#[test]
 	fn test_proxy_config_json5() {
 		let json5_config = include_str!("../tests/config/proxy_json5.json5");
@@ -995,6 +1901,10 @@ server = "127.0.0.1:1081"
 	fn test_config_not_found() {
 		let cli = Cli {
 			config: Some(PathBuf::from("/nonexistent/path/config.json")),
+			profile: None,
+			ping: None,
+			strict_config: false,
+			command: None,
 		};
 
 		let result = Config::parse(cli, EnvState::default());
@@ -1006,7 +1916,13 @@ server = "127.0.0.1:1081"
 
 	#[test]
 	fn test_no_config_specified() {
-		let cli = Cli { config: None };
+		let cli = Cli {
+			config: None,
+			profile: None,
+			ping: None,
+			strict_config: false,
+			command: None,
+		};
 
 		let result = Config::parse(cli, EnvState::default());
 		assert!(result.is_err());
@@ -1110,4 +2026,38 @@ server = "127.0.0.1:1081"
 		assert_eq!(config.local.udp_forward[0].remote.0, "8.8.8.8");
 		assert_eq!(config.local.udp_forward[0].timeout, Duration::from_secs(30));
 	}
+
+	#[test]
+	fn test_budget() {
+		let toml_config = r#"
+		[relay]
+		server = "example.com:443"
+		uuid = "00000000-0000-0000-0000-000000000000"
+		password = "test"
+
+		[budget]
+		limit = 100000000000
+		exhausted = "direct"
+		"#;
+
+		let config = test_parse_config(toml_config, ".toml").unwrap();
+		let budget = config.budget.unwrap();
+		assert_eq!(budget.limit, 100_000_000_000);
+		assert_eq!(budget.warn_at, [80, 90]);
+		assert_eq!(budget.exhausted, Exhausted::Direct);
+		assert!(budget.state.is_absolute());
+		assert!(budget.state.ends_with("tuic-budget.toml"));
+
+		let toml_config = r#"
+		[relay]
+		server = "example.com:443"
+		uuid = "00000000-0000-0000-0000-000000000000"
+		password = "test"
+
+		[budget]
+		limit = 1000
+		warn_at = [50, 120]
+		"#;
+		assert!(test_parse_config(toml_config, ".toml").is_err());
+	}
 }
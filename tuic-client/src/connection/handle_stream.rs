@@ -1,5 +1,5 @@
 use bytes::Bytes;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 use tuic_core::quinn::{RecvStream, SendStream, Task};
 
 use super::Connection;
@@ -23,13 +23,18 @@ impl Connection {
 
 		let res = match self.model.accept_uni_stream(recv).await {
 			Err(err) => Err(Error::Model(err)),
-			Ok(Task::Packet(pkt)) => match self.udp_relay_mode {
+			Ok(Task::Packet(pkt)) => match self.effective_udp_relay_mode() {
 				UdpRelayMode::Quic => {
 					self.handle_packet(pkt).await;
 					Ok(())
 				}
 				UdpRelayMode::Native => Err(Error::WrongPacketSource),
 			},
+			Ok(Task::Message(text)) => {
+				// Quoted, so control characters in it can't forge or garble log lines
+				info!("[relay] message from server: {text:?}");
+				Ok(())
+			}
 			_ => unreachable!(),
 		};
 
@@ -56,7 +61,7 @@ impl Connection {
 
 		let res = match self.model.accept_datagram(dg) {
 			Err(err) => Err(Error::Model(err)),
-			Ok(Task::Packet(pkt)) => match self.udp_relay_mode {
+			Ok(Task::Packet(pkt)) => match self.effective_udp_relay_mode() {
 				UdpRelayMode::Native => {
 					self.handle_packet(pkt).await;
 					Ok(())
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use bytes::Bytes;
 use socks5_proto::Address as Socks5Address;
@@ -6,10 +6,13 @@ use tokio::time;
 use tracing::{debug, info, warn};
 use tuic_core::{
 	Address,
-	quinn::{Connect, Packet},
+	auth_options::AuthOptions,
+	quinn::{Packet, ReadError},
+	reject::Reject,
+	timestamp_auth::{self, AuthMode},
 };
 
-use super::Connection;
+use super::{Connection, Relay};
 use crate::{error::Error, utils::UdpRelayMode};
 
 impl Connection {
@@ -22,18 +25,38 @@ impl Connection {
 
 		debug!("[relay] [authenticate] sending authentication");
 
-		match self.model.authenticate(self.uuid, self.password.clone()).await {
+		let options = AuthOptions {
+			congestion_control: self.server_congestion_control,
+			accepts_messages: true,
+			connect_replies: self.connect_replies,
+			resume_token: Some(self.resume_token),
+		};
+		let res = match self.auth_mode {
+			AuthMode::Token => self.model.authenticate_with(self.uuid, self.password.clone(), &options).await,
+			AuthMode::Timestamp => {
+				let counter = timestamp_auth::step_at(SystemTime::now(), self.auth_time_step);
+				let password = timestamp_auth::password_at(&self.password, counter);
+				self.model.authenticate_with(self.uuid, password, &options).await
+			}
+		};
+		match res {
 			Ok(()) => info!("[relay] [authenticate] {uuid}", uuid = self.uuid),
 			Err(err) => warn!("[relay] [authenticate] authentication sending error: {err}"),
 		}
 	}
 
-	pub async fn connect(&self, addr: Address) -> Result<Connect, Error> {
+	pub async fn connect(&self, addr: Address) -> Result<Relay, Error> {
 		let addr_display = addr.to_string();
 		info!("[relay] [connect] {addr_display}");
 
 		match self.model.connect(addr).await {
-			Ok(conn) => Ok(conn),
+			Ok(conn) => {
+				let mut relay = Relay::new(conn, self.connect_replies);
+				if self.connect_replies && self.replies_seen.load() != Some(false) {
+					self.await_reply(&mut relay).await?;
+				}
+				Ok(relay)
+			}
 			Err(err) => {
 				warn!("[relay] [connect] failed initializing relay to {addr_display}: {err}");
 				Err(Error::Model(err))
@@ -44,7 +67,7 @@ impl Connection {
 	pub async fn packet(&self, pkt: Bytes, addr: Address, assoc_id: u16) -> eyre::Result<()> {
 		let addr_display = addr.to_string();
 
-		match self.udp_relay_mode {
+		match self.effective_udp_relay_mode() {
 			UdpRelayMode::Native => {
 				info!("[relay] [packet] [{assoc_id:#06x}] [to-native] to {addr_display}");
 				match self.model.packet_native(pkt, addr, assoc_id) {
@@ -156,4 +179,39 @@ impl Connection {
 			Err(err) => warn!("[relay] [packet] [{assoc_id:#06x}] [from-native] [{pkt_id:#06x}] packet receiving error: {err}"),
 		}
 	}
+
+	/// Wait up to [`REPLY_TIMEOUT`] for the server's reply to a `Connect`, see
+	/// [`tuic_core::reject`]. A stream starting with the target's bytes shows
+	/// the server sends no replies, and so does running out of time before any
+	/// stream got one, so later streams don't wait. A reply arriving late is
+	/// still left out of the stream.
+	async fn await_reply(&self, relay: &mut Relay) -> Result<(), Error> {
+		match time::timeout(REPLY_TIMEOUT, relay.reply()).await {
+			Ok(Ok(true)) => self.replies_seen.store(Some(true)),
+			Ok(Ok(false)) => {
+				if self.replies_seen.swap(Some(false)) != Some(false) {
+					info!("[relay] [connect] the server sends no connect replies, no longer waiting for them");
+				}
+			}
+			Ok(Err(err)) => return Err(rejection(&err).map_or(Error::Unreachable(err), Error::Rejected)),
+			Err(_) => {
+				if self.replies_seen.compare_exchange(None, Some(false)).is_ok() {
+					info!("[relay] [connect] no connect reply from the server, no longer waiting for them");
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// How long a stream waits for its connect reply before it is taken as
+/// connected. Covers the server's default DNS and connect timeouts.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The policy denial a relay stream was reset with, if any
+pub fn rejection(err: &std::io::Error) -> Option<Reject> {
+	match err.get_ref()?.downcast_ref::<ReadError>()? {
+		ReadError::Reset(code) => Reject::from_code(code.into_inner()),
+		_ => None,
+	}
 }
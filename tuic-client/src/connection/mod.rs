@@ -2,38 +2,41 @@ use std::{
 	collections::HashMap,
 	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
 	sync::{Arc, Mutex},
-	time::Duration,
+	time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::Context;
 use crossbeam_utils::atomic::AtomicCell;
-use rustls::{
-	ClientConfig as RustlsClientConfig,
-	pki_types::{CertificateDer, ServerName, UnixTime},
-};
 use tokio::{sync::RwLock as AsyncRwLock, time};
 use tracing::{debug, info, warn};
-use tuic_core::quinn::{
-	ClientConfig, Connection as Model, Endpoint as QuinnEndpoint, EndpointConfig, QuinnConnection, TokioRuntime,
-	TransportConfig, VarInt,
-	bbr::BbrConfig,
-	congestion::{Bbr3Config, CubicConfig, NewRenoConfig},
-	crypto::rustls::QuicClientConfig,
-	side,
+use tuic_core::{
+	knock,
+	obfs::{ObfsUdpSocket, Salamander},
+	quinn::{
+		ClientConfig, Connection as Model, Endpoint as QuinnEndpoint, EndpointConfig, QuinnConnection, TokioRuntime,
+		TransportConfig, VarInt,
+		bbr::BbrConfig,
+		congestion::{Bbr3Config, CubicConfig, NewRenoConfig},
+		side,
+	},
+	timestamp_auth::AuthMode,
 };
 use uuid::Uuid;
 
 use crate::{
 	config::{ProxyConfig, Relay},
 	error::Error,
+	tls::{self, Verification},
 	utils::{self, CongestionControl, ServerAddr, UdpRelayMode},
 };
 
 mod handle_stream;
 mod handle_task;
+mod relay;
 mod socks5;
 
 use self::socks5::Socks5UdpSocket;
+pub use self::{handle_task::rejection, relay::Relay};
 
 /// Convenience type aliases for the two UDP session maps
 type Socks5Sessions = Arc<AsyncRwLock<HashMap<u16, crate::socks5::UdpSession>>>;
@@ -42,19 +45,52 @@ type FwdSessions = Arc<AsyncRwLock<HashMap<u16, crate::forward::ForwardUdpSessio
 /// Default error code for QUIC connection
 pub const ERROR_CODE: VarInt = VarInt::from_u32(0);
 
+/// UDP session dissociated by [`Connection::probe`]. Servers ignore a
+/// `Dissociate` of a session they don't know, and the dedicated connection of
+/// `--ping` relays no UDP, so it's never in use.
+const PROBE_ASSOC_ID: u16 = u16::MAX;
+
+/// Head start of a knock over the QUIC Initial. The knock and the connection
+/// leave from different sockets, and an Initial the gate sees first is
+/// ignored until it is sent again a second or so later.
+const KNOCK_HEAD_START: Duration = Duration::from_millis(100);
+
 pub struct ConnectionManager {
 	endpoint: Arc<AsyncRwLock<Endpoint>>,
 	connection: Arc<Mutex<Option<Arc<AsyncRwLock<Connection>>>>>,
+	/// Connections replaced since [`ConnectionManager::traffic`] last ran,
+	/// kept so their last bytes are counted too
+	retired: Arc<Mutex<Vec<QuinnConnection>>>,
 	timeout: AtomicCell<Duration>,
 }
 
+/// Bytes sent and received on the wire by a relay connection
+pub struct Traffic {
+	/// The connection's stable ID
+	pub id: usize,
+	pub bytes: u64,
+	/// Whether the connection was replaced, and so won't move more bytes
+	pub retired: bool,
+}
+
 #[derive(Clone)]
 pub struct Connection {
 	conn: QuinnConnection,
 	model: Model<side::Client>,
 	uuid: Uuid,
 	password: Arc<[u8]>,
+	auth_mode: AuthMode,
+	auth_time_step: Duration,
+	server_congestion_control: Option<CongestionControl>,
+	connect_replies: bool,
+	/// Whether the server was seen to send connect replies, `None` until a
+	/// stream tells
+	replies_seen: Arc<AtomicCell<Option<bool>>>,
+	/// Sent with every authentication, see
+	/// [`tuic_core::auth_options::AuthOptions::resume_token`]
+	resume_token: Uuid,
 	udp_relay_mode: UdpRelayMode,
+	udp_stream_fallback: bool,
 	pub(crate) socks5_udp_sessions: Socks5Sessions,
 	pub(crate) fwd_udp_sessions: FwdSessions,
 }
@@ -63,79 +99,43 @@ impl ConnectionManager {
 	/// Build a `ConnectionManager` from relay config, constructing the QUIC
 	/// endpoint.
 	pub async fn build(cfg: Relay) -> Result<Self, Error> {
-		// Load certificates for TLS
-		let certs = utils::load_certs(cfg.certificates, cfg.disable_native_certs)?;
-
-		// Build TLS client config, optionally skipping certificate verification (for
-		// development/testing)
-		let mut crypto = if cfg.skip_cert_verify {
-			#[derive(Debug)]
-			struct SkipServerVerification(Arc<rustls::crypto::CryptoProvider>);
-
-			impl SkipServerVerification {
-				fn new() -> Arc<Self> {
-					Arc::new(Self(
-						rustls::crypto::CryptoProvider::get_default()
-							.expect("Crypto not found")
-							.clone(),
-					))
-				}
+		// `[relay.tls]` settings take precedence over the legacy relay-level flags
+		let tls_cfg = cfg.tls;
+		let verification = if tls_cfg.insecure.unwrap_or(cfg.skip_cert_verify) {
+			Verification::Insecure
+		} else if !tls_cfg.pinned_sha256.is_empty() {
+			Verification::Pinned(tls_cfg.pinned_sha256.iter().filter_map(|pin| tls::parse_pin(pin)).collect())
+		} else {
+			let mut paths = cfg.certificates;
+			paths.extend(tls_cfg.ca_file);
+			Verification::WebPki {
+				roots: utils::load_certs(paths, cfg.disable_native_certs)?,
+				verify_hostname: tls_cfg.verify_hostname,
 			}
+		};
 
-			// Custom certificate verifier that skips all checks (dangerous, use only for
-			// testing)
-			impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
-				fn verify_server_cert(
-					&self,
-					_end_entity: &CertificateDer<'_>,
-					_intermediates: &[CertificateDer<'_>],
-					_server_name: &ServerName<'_>,
-					_ocsp: &[u8],
-					_now: UnixTime,
-				) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-					Ok(rustls::client::danger::ServerCertVerified::assertion())
-				}
-
-				fn verify_tls12_signature(
-					&self,
-					message: &[u8],
-					cert: &CertificateDer<'_>,
-					dss: &rustls::DigitallySignedStruct,
-				) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-					rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
-				}
-
-				fn verify_tls13_signature(
-					&self,
-					message: &[u8],
-					cert: &CertificateDer<'_>,
-					dss: &rustls::DigitallySignedStruct,
-				) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-					rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
-				}
-
-				fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-					self.0.signature_verification_algorithms.supported_schemes()
-				}
-			}
-			RustlsClientConfig::builder()
-				.dangerous()
-				.with_custom_certificate_verifier(SkipServerVerification::new())
-				.with_no_client_auth()
-		} else {
-			RustlsClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-				.with_root_certificates(certs)
-				.with_no_client_auth()
+		let client_auth = match (&tls_cfg.client_certificate, &tls_cfg.client_private_key) {
+			(Some(cert), Some(key)) => Some(tls::load_client_auth(cert, key)?),
+			_ => None,
 		};
+		let provider = tls::crypto_provider(&tls_cfg.cipher_suites, &tls_cfg.kx_groups)?;
+		let mut crypto = tls::client_config(verification, client_auth, provider)?;
 
-		crypto.alpn_protocols = cfg.alpn;
+		crypto.alpn_protocols = match tls_cfg.alpn {
+			Some(alpn) => alpn.into_iter().map(String::into_bytes).collect(),
+			None => cfg.alpn,
+		};
 		crypto.enable_early_data = true;
+		if tls_cfg.key_log {
+			if std::env::var_os("SSLKEYLOGFILE").is_none() {
+				warn!("[relay] `tls.key_log` is set but SSLKEYLOGFILE is not, no TLS secrets are written");
+			}
+			crypto.key_log = Arc::new(rustls::KeyLogFile::new());
+		}
 		crypto.enable_sni = !cfg.disable_sni;
 
 		// Build QUIC client and transport configuration
-		let mut config = ClientConfig::new(Arc::new(
-			QuicClientConfig::try_from(crypto).context("no initial cipher suite found")?,
-		));
+		let mut config = ClientConfig::new(Arc::new(tls::quic_client_config(crypto)?));
 		let mut tp_cfg = TransportConfig::default();
 
 		tp_cfg
@@ -164,11 +164,34 @@ impl ConnectionManager {
 		};
 
 		config.transport_config(Arc::new(tp_cfg));
+		if let Some(version) = cfg.quic_version {
+			config.version(version.get());
+		}
 
 		// Prepare server address and create the primary endpoint with IPv4 binding
-		let server = ServerAddr::with_sni(cfg.server.0, cfg.server.1, cfg.ip, cfg.ipstack_prefer, cfg.sni);
+		let server = ServerAddr::with_sni(
+			cfg.server.0,
+			cfg.server.1,
+			cfg.ip,
+			cfg.ipstack_prefer,
+			tls_cfg.sni.or(cfg.sni),
+		);
+
+		let obfs = cfg.obfs.map(|obfs| Salamander::new(obfs.password));
 
 		let (ep, socks5_ctrl) = if let Some(proxy_cfg) = cfg.proxy {
+			if obfs.is_some() {
+				return Err(Error::Other(anyhow::anyhow!(
+					"relay.obfs cannot be combined with relay.proxy"
+				)));
+			}
+			// The server would see the knock and the connection from different addresses
+			if cfg.knock_port.is_some() {
+				return Err(Error::Other(anyhow::anyhow!(
+					"relay.knock_port cannot be combined with relay.proxy"
+				)));
+			}
+
 			debug!(
 				"[relay] outgoing traffic is using socks5 proxy {}:{}",
 				proxy_cfg.server.0.as_str(),
@@ -193,7 +216,16 @@ impl ConnectionManager {
 			(ep, Some(ctrl))
 		} else {
 			let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
-			let ep = QuinnEndpoint::new(EndpointConfig::default(), None, socket, Arc::new(TokioRuntime))?;
+			let ep = if let Some(obfs) = &obfs {
+				QuinnEndpoint::new_with_abstract_socket(
+					EndpointConfig::default(),
+					None,
+					Box::new(ObfsUdpSocket::new(socket, obfs.clone())?),
+					Arc::new(TokioRuntime),
+				)?
+			} else {
+				QuinnEndpoint::new(EndpointConfig::default(), None, socket, Arc::new(TokioRuntime))?
+			};
 			(ep, None)
 		};
 
@@ -204,21 +236,62 @@ impl ConnectionManager {
 			server,
 			uuid: cfg.uuid,
 			password: cfg.password,
+			auth_mode: cfg.auth_mode,
+			auth_time_step: cfg.auth_time_step,
+			server_congestion_control: cfg.server_congestion_control,
+			connect_replies: cfg.connect_replies,
+			resume_token: Uuid::new_v4(),
 			udp_relay_mode: cfg.udp_relay_mode,
+			udp_stream_fallback: cfg.udp_stream_fallback,
 			zero_rtt_handshake: cfg.zero_rtt_handshake,
+			knock_port: cfg.knock_port,
 			heartbeat: cfg.heartbeat,
 			gc_interval: cfg.gc_interval,
 			gc_lifetime: cfg.gc_lifetime,
 			socks5_ctrl,
+			obfs,
 		};
 
 		Ok(Self {
 			endpoint: Arc::new(AsyncRwLock::new(ep)),
 			connection: Arc::new(Mutex::new(None)),
+			retired: Arc::new(Mutex::new(Vec::new())),
 			timeout: AtomicCell::new(cfg.timeout),
 		})
 	}
 
+	/// RTT estimate of the current connection, `None` while there is no live
+	/// connection
+	pub fn rtt(&self) -> Option<Duration> {
+		let conn = self.connection.lock().unwrap().clone()?;
+		let conn = conn.try_read().ok()?;
+		(!conn.is_closed()).then(|| conn.conn.rtt())
+	}
+
+	/// Traffic of the connections replaced since the last call, and of the
+	/// current one unless it is being replaced right now
+	pub fn traffic(&self) -> Vec<Traffic> {
+		let sample = |conn: &QuinnConnection, retired| {
+			let stats = conn.stats();
+			Traffic {
+				id: conn.stable_id(),
+				bytes: stats.udp_tx.bytes + stats.udp_rx.bytes,
+				retired,
+			}
+		};
+		let mut traffic: Vec<_> = std::mem::take(&mut *self.retired.lock().unwrap())
+			.iter()
+			.map(|conn| sample(conn, true))
+			.collect();
+		let current = self.connection.lock().unwrap().clone();
+		if let Some(conn) = current
+			&& let Ok(conn) = conn.try_read()
+		{
+			traffic.push(sample(&conn.conn, false));
+		}
+		traffic
+	}
+
 	pub async fn get_conn(
 		&self,
 		socks5_udp_sessions: Socks5Sessions,
@@ -226,6 +299,7 @@ impl ConnectionManager {
 	) -> Result<Connection, Error> {
 		let endpoint = self.endpoint.clone();
 		let connection = self.connection.clone();
+		let retired = self.retired.clone();
 		let timeout_duration = self.timeout.load();
 
 		let try_get_conn = async move {
@@ -252,7 +326,8 @@ impl ConnectionManager {
 					.await
 					.connect(socks5_udp_sessions.clone(), fwd_udp_sessions.clone())
 					.await?;
-				*conn = new_conn;
+				let old = std::mem::replace(&mut *conn, new_conn);
+				retired.lock().unwrap().push(old.conn);
 			}
 
 			Ok::<_, Error>(conn.clone())
@@ -271,8 +346,14 @@ impl Connection {
 	fn new(
 		conn: QuinnConnection,
 		udp_relay_mode: UdpRelayMode,
+		udp_stream_fallback: bool,
 		uuid: Uuid,
 		password: Arc<[u8]>,
+		auth_mode: AuthMode,
+		auth_time_step: Duration,
+		server_congestion_control: Option<CongestionControl>,
+		connect_replies: bool,
+		resume_token: Uuid,
 		heartbeat: Duration,
 		gc_interval: Duration,
 		gc_lifetime: Duration,
@@ -284,7 +365,14 @@ impl Connection {
 			model: Model::<side::Client>::new(conn),
 			uuid,
 			password,
+			auth_mode,
+			auth_time_step,
+			server_congestion_control,
+			connect_replies,
+			replies_seen: Arc::new(AtomicCell::new(None)),
+			resume_token,
 			udp_relay_mode,
+			udp_stream_fallback,
 
 			socks5_udp_sessions,
 			fwd_udp_sessions,
@@ -300,6 +388,10 @@ impl Connection {
 	async fn init(self, heartbeat: Duration, gc_interval: Duration, gc_lifetime: Duration) {
 		info!("[relay] connection established");
 
+		if self.udp_relay_mode != self.effective_udp_relay_mode() {
+			warn!("[relay] QUIC datagrams are unavailable on this connection, relaying UDP packets over QUIC streams");
+		}
+
 		tokio::spawn(self.clone().authenticate());
 		tokio::spawn(self.clone().heartbeat(heartbeat));
 		tokio::spawn(self.clone().collect_garbage(gc_interval, gc_lifetime));
@@ -324,11 +416,32 @@ impl Connection {
 		warn!("[relay] connection error: {err}");
 	}
 
+	/// Send a `Dissociate` of `PROBE_ASSOC_ID` and return the time until the
+	/// server acknowledged it, failing if that takes longer than `wait`
+	pub async fn probe(&self, wait: Duration) -> eyre::Result<Duration> {
+		let sent = Instant::now();
+		match time::timeout(wait, self.model.dissociate(PROBE_ASSOC_ID)).await {
+			Ok(Ok(())) => Ok(sent.elapsed()),
+			Ok(Err(err)) => Err(err),
+			Err(_) => eyre::bail!("no acknowledgement within {}", humantime::format_duration(wait)),
+		}
+	}
+
 	/// Check if the connection is closed
 	fn is_closed(&self) -> bool {
 		self.conn.close_reason().is_some()
 	}
 
+	/// The UDP relay mode actually used on this connection. Native relay falls
+	/// back to QUIC streams when datagrams are unavailable and the fallback is
+	/// enabled.
+	fn effective_udp_relay_mode(&self) -> UdpRelayMode {
+		match self.udp_relay_mode {
+			UdpRelayMode::Native if self.udp_stream_fallback && self.conn.max_datagram_size().is_none() => UdpRelayMode::Quic,
+			mode => mode,
+		}
+	}
+
 	/// Periodically collect garbage fragments from the model
 	async fn collect_garbage(self, gc_interval: Duration, gc_lifetime: Duration) {
 		loop {
@@ -350,17 +463,54 @@ struct Endpoint {
 	server: ServerAddr,
 	uuid: Uuid,
 	password: Arc<[u8]>,
+	auth_mode: AuthMode,
+	auth_time_step: Duration,
+	server_congestion_control: Option<CongestionControl>,
+	connect_replies: bool,
+	/// The same for all connections of the endpoint, so each reconnect
+	/// resumes the UDP sessions of the one before
+	resume_token: Uuid,
 	udp_relay_mode: UdpRelayMode,
+	udp_stream_fallback: bool,
 	zero_rtt_handshake: bool,
+	knock_port: Option<u16>,
 	heartbeat: Duration,
 	gc_interval: Duration,
 	gc_lifetime: Duration,
 	// SOCKS5 control TCP stream for UDP ASSOCIATE: this must be kept alive to
 	// maintain the UDP relay session, since closing it invalidates the relay address.
 	socks5_ctrl: Option<tokio::net::TcpStream>,
+	obfs: Option<Salamander>,
 }
 
 impl Endpoint {
+	/// Move the endpoint to a new socket, keeping datagram obfuscation
+	fn rebind(&self, socket: UdpSocket) -> Result<(), Error> {
+		if let Some(obfs) = &self.obfs {
+			self.ep.rebind_abstract(Box::new(ObfsUdpSocket::new(socket, obfs.clone())?))?;
+		} else {
+			self.ep.rebind(socket)?;
+		}
+		Ok(())
+	}
+
+	/// Send the server's `[knock]` gate a knock at `addr`, so it lets the
+	/// connection through, and give it [`KNOCK_HEAD_START`] to get there
+	async fn knock(&self, addr: SocketAddr) -> Result<(), Error> {
+		let bind = if addr.is_ipv4() {
+			SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+		} else {
+			SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+		};
+		let socket = tokio::net::UdpSocket::bind(bind).await?;
+		socket
+			.send_to(&knock::encode(self.uuid, &self.password, SystemTime::now()), addr)
+			.await?;
+		debug!("[relay] knocked at {addr}");
+		time::sleep(KNOCK_HEAD_START).await;
+		Ok(())
+	}
+
 	/// Establish a new QUIC connection to the server, rebinding if necessary
 	/// for IP family
 	async fn connect(&self, socks5_udp_sessions: Socks5Sessions, fwd_udp_sessions: FwdSessions) -> Result<Connection, Error> {
@@ -381,14 +531,14 @@ impl Endpoint {
 					warn!("[relay] Rebinding endpoint: Detected IPv4 server address, binding to 0.0.0.0:0");
 					let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
 					warn!("[relay] Successfully bound to IPv4 socket: {:?}", socket.local_addr().ok());
-					self.ep.rebind(socket)?;
+					self.rebind(socket)?;
 					warn!("[relay] Endpoint successfully rebound to IPv4 socket");
 				}
 				std::net::IpAddr::V6(_) => {
 					warn!("[relay] Rebinding endpoint: Detected IPv6 server address, binding to [::]:0");
 					let socket = UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))?;
 					warn!("[relay] Successfully bound to IPv6 socket: {:?}", socket.local_addr().ok());
-					self.ep.rebind(socket)?;
+					self.rebind(socket)?;
 					warn!("[relay] Endpoint successfully rebound to IPv6 socket");
 				}
 			}
@@ -399,6 +549,10 @@ impl Endpoint {
 			self.ep.local_addr().ok()
 		);
 
+		if let Some(port) = self.knock_port {
+			self.knock(SocketAddr::new(server_addr.ip(), port)).await?;
+		}
+
 		let connect_to = async {
 			let conn = self.ep.connect(server_addr, self.server.server_name())?;
 			let conn = if self.zero_rtt_handshake {
@@ -417,8 +571,14 @@ impl Endpoint {
 			Ok(conn) => Ok(Connection::new(
 				conn,
 				self.udp_relay_mode,
+				self.udp_stream_fallback,
 				self.uuid,
 				self.password.clone(),
+				self.auth_mode,
+				self.auth_time_step,
+				self.server_congestion_control,
+				self.connect_replies,
+				self.resume_token,
 				self.heartbeat,
 				self.gc_interval,
 				self.gc_lifetime,
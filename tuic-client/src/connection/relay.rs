@@ -0,0 +1,136 @@
+//! TCP streams relayed through the server, see [`tuic_core::reject`].
+
+use std::{
+	future::poll_fn,
+	io,
+	pin::Pin,
+	task::{Context, Poll, ready},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tuic_core::{
+	quinn::{Connect, VarInt},
+	reject::CONNECT_OK,
+};
+
+/// A relayed TCP stream. While connect replies are asked for, the start of the
+/// stream is matched against [`CONNECT_OK`]: the reply is left out of what is
+/// read, and bytes of the target that turn out not to be one are read first.
+pub struct Relay<C = Connect> {
+	conn: C,
+	/// Start of the stream while it may still be the reply, `None` once the
+	/// reply was read or ruled out
+	head: Option<Vec<u8>>,
+	/// Bytes of the target read while matching the reply
+	prefix: Vec<u8>,
+	replied: bool,
+}
+
+impl Relay {
+	pub fn reset(&mut self, error_code: VarInt) -> eyre::Result<()> {
+		self.conn.reset(error_code)
+	}
+}
+
+impl<C: AsyncRead + Unpin> Relay<C> {
+	pub fn new(conn: C, connect_replies: bool) -> Self {
+		Self {
+			conn,
+			head: connect_replies.then(Vec::new),
+			prefix: Vec::new(),
+			replied: false,
+		}
+	}
+
+	/// Wait until the start of the stream was matched. `true` when it was the
+	/// reply, `false` when the server sent the target's bytes or closed the
+	/// stream without one, as servers without support for replies do.
+	pub async fn reply(&mut self) -> io::Result<bool> {
+		poll_fn(|cx| self.poll_head(cx)).await
+	}
+
+	fn poll_head(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+		let Some(head) = &mut self.head else {
+			return Poll::Ready(Ok(self.replied));
+		};
+		while head.len() < CONNECT_OK.len() {
+			let mut byte = [0; 1];
+			let mut buf = ReadBuf::new(&mut byte);
+			ready!(Pin::new(&mut self.conn).poll_read(cx, &mut buf))?;
+			match buf.filled() {
+				[byte] if *byte == CONNECT_OK[head.len()] => head.push(*byte),
+				filled => {
+					head.extend_from_slice(filled);
+					self.prefix = std::mem::take(head);
+					self.head = None;
+					return Poll::Ready(Ok(false));
+				}
+			}
+		}
+		self.head = None;
+		self.replied = true;
+		Poll::Ready(Ok(true))
+	}
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Relay<C> {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		ready!(this.poll_head(cx))?;
+		if this.prefix.is_empty() {
+			return Pin::new(&mut this.conn).poll_read(cx, buf);
+		}
+		let len = this.prefix.len().min(buf.remaining());
+		buf.put_slice(&this.prefix[..len]);
+		this.prefix.drain(..len);
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Relay<C> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.get_mut().conn).poll_write(cx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().conn).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().conn).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+	use super::*;
+
+	async fn relay(sent: &[u8], connect_replies: bool) -> (bool, Vec<u8>) {
+		let (mut server, client) = duplex(64);
+		server.write_all(sent).await.unwrap();
+		drop(server);
+		let mut relay = Relay::new(client, connect_replies);
+		let replied = relay.reply().await.unwrap();
+		let mut read = Vec::new();
+		relay.read_to_end(&mut read).await.unwrap();
+		(replied, read)
+	}
+
+	#[tokio::test]
+	async fn test_reply_is_left_out() {
+		assert_eq!(relay(b"\0TOKhello", true).await, (true, b"hello".to_vec()));
+		assert_eq!(relay(b"\0TOK", true).await, (true, Vec::new()));
+	}
+
+	#[tokio::test]
+	async fn test_target_bytes_are_kept_without_reply() {
+		// Servers without connect replies relay the target's bytes right away
+		assert_eq!(relay(b"SSH-2.0-OpenSSH", true).await, (false, b"SSH-2.0-OpenSSH".to_vec()));
+		assert_eq!(relay(b"\0TOX", true).await, (false, b"\0TOX".to_vec()));
+		assert_eq!(relay(b"\0T", true).await, (false, b"\0T".to_vec()));
+		assert_eq!(relay(b"", true).await, (false, Vec::new()));
+		assert_eq!(relay(b"\0TOKhello", false).await, (false, b"\0TOKhello".to_vec()));
+	}
+}
@@ -0,0 +1,347 @@
+//! Local cache of DNS answers for UDP forwards to a DNS server.
+//!
+//! A `[[local.udp_forward]]` to a DNS server resolves names remotely, and
+//! every query then costs a round trip through the tunnel. With `dns_cache`
+//! set, queries answered before are answered locally until the answer's TTL
+//! runs out, with the TTLs counted down. With `dns_prefetch` also set, a name
+//! asked for repeatedly is queried again over the tunnel shortly before its
+//! answer expires, so popular names stay cached.
+
+use std::{
+	collections::HashMap,
+	sync::{
+		Mutex, PoisonError,
+		atomic::{AtomicU16, Ordering},
+	},
+	time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Answers kept at most, expired ones are dropped to make room
+const MAX_ENTRIES: usize = 4096;
+/// Hits within one TTL that make a name worth prefetching
+const PREFETCH_HITS: u32 = 3;
+const HEADER_LEN: usize = 12;
+/// The OPT pseudo-record, whose TTL field holds EDNS flags
+const TYPE_OPT: u16 = 41;
+
+struct Entry {
+	response: Vec<u8>,
+	stored: Instant,
+	ttl: Duration,
+	hits: u32,
+	/// ID of the prefetch query in flight
+	prefetch_id: Option<u16>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup {
+	/// Not cached, forward the query
+	Miss,
+	/// Answer the query with this response
+	Hit(Vec<u8>),
+	/// Answer the query with `response`, and forward `query` to refresh the
+	/// answer
+	Prefetch { response: Vec<u8>, query: Vec<u8> },
+}
+
+pub struct DnsCache {
+	prefetch: bool,
+	next_prefetch_id: AtomicU16,
+	/// Keyed by the question section with the name in lowercase
+	entries: Mutex<HashMap<Vec<u8>, Entry>>,
+}
+
+impl DnsCache {
+	pub fn new(prefetch: bool) -> Self {
+		Self {
+			prefetch,
+			next_prefetch_id: AtomicU16::new(0),
+			entries: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn lookup(&self, query: &[u8]) -> Lookup {
+		let Some((id, key)) = parse_query(query) else {
+			return Lookup::Miss;
+		};
+		let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+		let Some(entry) = entries.get_mut(&key) else {
+			return Lookup::Miss;
+		};
+
+		let age = entry.stored.elapsed();
+		let mut response = entry.response.clone();
+		if age >= entry.ttl || rewrite_response(&mut response, id, age).is_none() {
+			entries.remove(&key);
+			return Lookup::Miss;
+		}
+		entry.hits += 1;
+
+		// Prefetch within the last tenth of the TTL
+		if self.prefetch && entry.prefetch_id.is_none() && entry.hits >= PREFETCH_HITS && (entry.ttl - age) * 10 < entry.ttl {
+			let prefetch_id = self.next_prefetch_id.fetch_add(1, Ordering::Relaxed);
+			entry.prefetch_id = Some(prefetch_id);
+			let mut query = query.to_vec();
+			query[..2].copy_from_slice(&prefetch_id.to_be_bytes());
+			return Lookup::Prefetch { response, query };
+		}
+		Lookup::Hit(response)
+	}
+
+	/// Cache `response` if it is a cacheable answer. Returns `true` if it
+	/// answers a prefetch query, which no client is waiting for.
+	pub fn store(&self, response: &[u8]) -> bool {
+		let Some((id, key, ttl)) = parse_response(response) else {
+			return false;
+		};
+		let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+		let prefetched = entries.get(&key).is_some_and(|entry| entry.prefetch_id == Some(id));
+
+		match ttl {
+			Some(ttl) => {
+				if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+					entries.retain(|_, entry| entry.stored.elapsed() < entry.ttl);
+				}
+				if entries.len() < MAX_ENTRIES || entries.contains_key(&key) {
+					entries.insert(
+						key,
+						Entry {
+							response: response.to_vec(),
+							stored: Instant::now(),
+							ttl,
+							hits: 0,
+							prefetch_id: None,
+						},
+					);
+				}
+			}
+			None if prefetched => {
+				// Let a later hit retry the prefetch
+				if let Some(entry) = entries.get_mut(&key) {
+					entry.prefetch_id = None;
+				}
+			}
+			None => {}
+		}
+		prefetched
+	}
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+	Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+/// Position after the domain name at `pos`
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+	loop {
+		let len = *msg.get(pos)?;
+		match len & 0xc0 {
+			0 if len == 0 => return Some(pos + 1),
+			0 => pos += 1 + usize::from(len),
+			// A compression pointer ends the name
+			0xc0 => return Some(pos + 2),
+			_ => return None,
+		}
+	}
+}
+
+/// ID and cache key of a message with a single question, and the position
+/// after the question
+fn parse_question(msg: &[u8]) -> Option<(u16, Vec<u8>, usize)> {
+	if read_u16(msg, 4)? != 1 {
+		return None;
+	}
+	let name_end = skip_name(msg, HEADER_LEN)?;
+	let end = name_end + 4;
+	let mut key = msg.get(HEADER_LEN..end)?.to_vec();
+	key[..name_end - HEADER_LEN].make_ascii_lowercase();
+	Some((read_u16(msg, 0)?, key, end))
+}
+
+/// ID and cache key of a standard query
+fn parse_query(msg: &[u8]) -> Option<(u16, Vec<u8>)> {
+	// QR unset and opcode QUERY
+	if read_u16(msg, 2)? & 0xf800 != 0 {
+		return None;
+	}
+	let (id, key, _) = parse_question(msg)?;
+	Some((id, key))
+}
+
+/// Name asked for by a standard query, without the trailing dot
+pub fn query_name(msg: &[u8]) -> Option<String> {
+	parse_query(msg)?;
+	let mut labels = Vec::new();
+	let mut pos = HEADER_LEN;
+	loop {
+		let len = usize::from(*msg.get(pos)?);
+		if len == 0 {
+			return Some(labels.join("."));
+		}
+		// The question is the first name of a query, so it can't be compressed
+		if len & 0xc0 != 0 {
+			return None;
+		}
+		labels.push(std::str::from_utf8(msg.get(pos + 1..pos + 1 + len)?).ok()?);
+		pos += 1 + len;
+	}
+}
+
+/// Type and TTL position of every resource record after the question
+fn records(msg: &[u8], mut pos: usize) -> Option<Vec<(u16, usize)>> {
+	let count = (6..12)
+		.step_by(2)
+		.map(|at| read_u16(msg, at).map(usize::from))
+		.sum::<Option<usize>>()?;
+	let mut records = Vec::with_capacity(count);
+	for _ in 0..count {
+		pos = skip_name(msg, pos)?;
+		let kind = read_u16(msg, pos)?;
+		let rdlen = read_u16(msg, pos + 8)?;
+		records.push((kind, pos + 4));
+		pos += 10 + usize::from(rdlen);
+	}
+	(pos <= msg.len()).then_some(records)
+}
+
+fn read_ttl(msg: &[u8], pos: usize) -> u32 {
+	u32::from_be_bytes([msg[pos], msg[pos + 1], msg[pos + 2], msg[pos + 3]])
+}
+
+/// ID, cache key and, for answers worth caching, the lowest TTL of the
+/// records of a response
+fn parse_response(msg: &[u8]) -> Option<(u16, Vec<u8>, Option<Duration>)> {
+	let flags = read_u16(msg, 2)?;
+	// QR set and opcode QUERY
+	if flags & 0xf800 != 0x8000 {
+		return None;
+	}
+	let (id, key, end) = parse_question(msg)?;
+
+	// Only complete, successful answers are cached
+	let truncated = flags & 0x0200 != 0;
+	let rcode = flags & 0x000f;
+	if truncated || rcode != 0 || read_u16(msg, 6)? == 0 {
+		return Some((id, key, None));
+	}
+	let ttl = records(msg, end)?
+		.into_iter()
+		.filter(|(kind, _)| *kind != TYPE_OPT)
+		.map(|(_, pos)| read_ttl(msg, pos))
+		.min()
+		.filter(|ttl| *ttl > 0)
+		.map(|ttl| Duration::from_secs(u64::from(ttl)));
+	Some((id, key, ttl))
+}
+
+/// Set the ID of a cached response and count its TTLs down by `age`
+fn rewrite_response(msg: &mut [u8], id: u16, age: Duration) -> Option<()> {
+	let (_, _, end) = parse_question(msg)?;
+	let elapsed = u32::try_from(age.as_secs()).unwrap_or(u32::MAX);
+	for (kind, pos) in records(msg, end)? {
+		if kind != TYPE_OPT {
+			let ttl = read_ttl(msg, pos).saturating_sub(elapsed);
+			msg[pos..pos + 4].copy_from_slice(&ttl.to_be_bytes());
+		}
+	}
+	msg[..2].copy_from_slice(&id.to_be_bytes());
+	Some(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn query(id: u16, name: &str) -> Vec<u8> {
+		let mut msg = id.to_be_bytes().to_vec();
+		msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+		for label in name.split('.') {
+			msg.push(label.len() as u8);
+			msg.extend_from_slice(label.as_bytes());
+		}
+		msg.extend_from_slice(&[0, 0, 1, 0, 1]);
+		msg
+	}
+
+	/// An A record answer to `query(id, name)` with `ttl`
+	fn response(id: u16, name: &str, ttl: u32) -> Vec<u8> {
+		let mut msg = query(id, name);
+		msg[2..4].copy_from_slice(&[0x81, 0x80]);
+		msg[6..8].copy_from_slice(&[0, 1]);
+		// Pointer to the question's name
+		msg.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+		msg.extend_from_slice(&ttl.to_be_bytes());
+		msg.extend_from_slice(&[0, 4, 192, 0, 2, 1]);
+		msg
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_cache_counts_ttl_down() {
+		let cache = DnsCache::new(false);
+		assert_eq!(cache.lookup(&query(1, "example.com")), Lookup::Miss);
+		assert!(!cache.store(&response(1, "example.com", 300)));
+
+		tokio::time::advance(Duration::from_secs(100)).await;
+		assert_eq!(
+			cache.lookup(&query(2, "Example.COM")),
+			Lookup::Hit(response(2, "example.com", 200))
+		);
+
+		tokio::time::advance(Duration::from_secs(200)).await;
+		assert_eq!(cache.lookup(&query(3, "example.com")), Lookup::Miss);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_prefetch_popular_names() {
+		let cache = DnsCache::new(true);
+		cache.store(&response(1, "example.com", 100));
+		for id in 2..5 {
+			assert!(matches!(cache.lookup(&query(id, "example.com")), Lookup::Hit(_)));
+		}
+
+		tokio::time::advance(Duration::from_secs(95)).await;
+		let Lookup::Prefetch {
+			response: answer,
+			query: prefetch,
+		} = cache.lookup(&query(5, "example.com"))
+		else {
+			panic!("expected a prefetch");
+		};
+		assert_eq!(answer, response(5, "example.com", 5));
+		assert!(matches!(cache.lookup(&query(6, "example.com")), Lookup::Hit(_)));
+
+		// The prefetch answer refreshes the entry and isn't passed on
+		let prefetch_id = read_u16(&prefetch, 0).unwrap();
+		assert!(cache.store(&response(prefetch_id, "example.com", 100)));
+		tokio::time::advance(Duration::from_secs(50)).await;
+		assert_eq!(
+			cache.lookup(&query(7, "example.com")),
+			Lookup::Hit(response(7, "example.com", 50))
+		);
+	}
+
+	#[test]
+	fn test_query_name() {
+		assert_eq!(query_name(&query(1, "WWW.example.com")).as_deref(), Some("WWW.example.com"));
+		assert_eq!(query_name(&response(1, "example.com", 60)), None);
+
+		let mut compressed = query(1, "example.com");
+		compressed.splice(12..25, [0xc0, 0x0c]);
+		assert_eq!(query_name(&compressed), None);
+	}
+
+	#[test]
+	fn test_uncacheable_responses() {
+		let cache = DnsCache::new(false);
+		let mut nxdomain = response(1, "example.com", 300);
+		nxdomain[3] = 0x83;
+		cache.store(&nxdomain);
+		cache.store(&response(2, "zero.example.com", 0));
+		cache.store(&[0x12, 0x34, 0x81]);
+
+		assert_eq!(cache.lookup(&query(3, "example.com")), Lookup::Miss);
+		assert_eq!(cache.lookup(&query(4, "zero.example.com")), Lookup::Miss);
+	}
+}
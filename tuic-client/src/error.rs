@@ -2,7 +2,10 @@ use std::io::Error as IoError;
 
 use rustls::Error as RustlsError;
 use thiserror::Error;
-use tuic_core::quinn::{ConnectError, ConnectionError, Error as ModelError};
+use tuic_core::{
+	quinn::{ConnectError, ConnectionError, Error as ModelError},
+	reject::Reject,
+};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -24,6 +27,10 @@ pub enum Error {
 	InvalidSocks5Auth,
 	#[error("socks5 error: {0}")]
 	Socks5(String),
+	#[error("refused by the server: {0}")]
+	Rejected(Reject),
+	#[error("the server could not reach the target: {0}")]
+	Unreachable(IoError),
 	#[error(transparent)]
 	Other(#[from] anyhow::Error),
 }
@@ -1,6 +1,6 @@
 use std::{
 	collections::HashMap,
-	net::{SocketAddr, TcpListener as StdTcpListener},
+	net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener as StdTcpListener},
 	sync::{Arc, atomic::Ordering},
 	time::Duration,
 };
@@ -10,20 +10,31 @@ use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::{
 	io,
 	io::AsyncWriteExt,
-	net::{TcpListener, UdpSocket},
+	net::{TcpListener, TcpStream, UdpSocket},
 };
 use tracing::{debug, info, warn};
 use tuic_core::Address as TuicAddress;
 
 use crate::{
-	config::{TcpForward, UdpForward},
+	config::{Exhausted, TcpForward, UdpForward},
+	dns_cache::{self, DnsCache, Lookup},
 	error::Error,
+	route,
 };
 
+/// Deadline of a query sent to `direct_resolver`
+const DIRECT_DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
 // Global UDP forward session registry
-pub async fn start(ctx: Arc<crate::AppContext>, tcp: Vec<TcpForward>, udp: Vec<UdpForward>) {
+pub async fn start(
+	ctx: Arc<crate::AppContext>,
+	tcp: Vec<TcpForward>,
+	udp: Vec<UdpForward>,
+	activated: &mut Vec<StdTcpListener>,
+) {
 	for entry in tcp {
-		tokio::spawn(run_tcp_forwarder(entry, ctx.clone()));
+		let listener = crate::activation::take(activated, entry.listen);
+		tokio::spawn(run_tcp_forwarder(entry, listener, ctx.clone()));
 	}
 	for entry in udp {
 		tokio::spawn(run_udp_forwarder(entry, ctx.clone()));
@@ -35,18 +46,29 @@ pub struct ForwardUdpSession {
 	socket: Arc<UdpSocket>,
 	src_addr: SocketAddr,
 	assoc_id: u16,
+	dns_cache: Option<Arc<DnsCache>>,
 }
 
 impl ForwardUdpSession {
-	pub fn new(socket: Arc<UdpSocket>, src_addr: SocketAddr, assoc_id: u16) -> Self {
+	pub fn new(socket: Arc<UdpSocket>, src_addr: SocketAddr, assoc_id: u16, dns_cache: Option<Arc<DnsCache>>) -> Self {
 		Self {
 			socket,
 			src_addr,
 			assoc_id,
+			dns_cache,
 		}
 	}
 
 	pub async fn send(&self, pkt: Bytes) -> Result<(), Error> {
+		if let Some(cache) = &self.dns_cache
+			&& cache.store(&pkt)
+		{
+			debug!(
+				"[forward-udp] [{assoc:#06x}] refreshed cached DNS answer",
+				assoc = self.assoc_id
+			);
+			return Ok(());
+		}
 		if let Err(err) = self.socket.send_to(&pkt, self.src_addr).await {
 			warn!(
 				"[forward-udp] [{assoc:#06x}] failed sending packet to {dst}: {err}",
@@ -59,8 +81,12 @@ impl ForwardUdpSession {
 	}
 }
 
-async fn run_tcp_forwarder(entry: TcpForward, ctx: Arc<crate::AppContext>) {
-	match create_tcp_listener(entry.listen) {
+async fn run_tcp_forwarder(entry: TcpForward, activated: Option<StdTcpListener>, ctx: Arc<crate::AppContext>) {
+	let listener = match activated {
+		Some(listener) => from_std_listener(listener),
+		None => create_tcp_listener(entry.listen),
+	};
+	match listener {
 		Ok(listener) => {
 			warn!(
 				"[forward-tcp] listening on {listen} -> {remote:?}",
@@ -72,9 +98,23 @@ async fn run_tcp_forwarder(entry: TcpForward, ctx: Arc<crate::AppContext>) {
 					Ok((mut inbound, peer)) => {
 						let remote = entry.remote.clone();
 						let ctx = ctx.clone();
+						let active = ctx.idle.enter();
 						tokio::spawn(async move {
+							let _active = active;
 							info!("[forward-tcp] [{peer}] connected", peer = peer);
 							let fut = async {
+								match ctx.budget_policy() {
+									Some(Exhausted::Block) => {
+										warn!("[forward-tcp] [{peer}] refused, the data budget is exhausted");
+										return Ok(());
+									}
+									Some(Exhausted::Direct) => {
+										let mut stream = TcpStream::connect((remote.0.as_str(), remote.1)).await?;
+										io::copy_bidirectional(&mut inbound, &mut stream).await?;
+										return Ok(());
+									}
+									Some(Exhausted::Warn) | None => {}
+								}
 								let conn = ctx.get_conn().await?;
 								let remote_addr = TuicAddress::DomainAddress(remote.0, remote.1);
 								let mut relay = conn.connect(remote_addr).await?;
@@ -112,16 +152,20 @@ fn create_tcp_listener(addr: SocketAddr) -> Result<TcpListener, Error> {
 	socket
 		.set_reuse_address(true)
 		.map_err(|err| Error::Socket("failed to set tcp forward socket reuse_address", err))?;
-	socket
-		.set_nonblocking(true)
-		.map_err(|err| Error::Socket("failed setting tcp forward socket as non-blocking", err))?;
 	socket
 		.bind(&SockAddr::from(addr))
 		.map_err(|err| Error::Socket("failed to bind tcp forward socket", err))?;
 	socket
 		.listen(i32::MAX)
 		.map_err(|err| Error::Socket("failed to listen on tcp forward socket", err))?;
-	TcpListener::from_std(StdTcpListener::from(socket)).map_err(|err| Error::Socket("failed to create tcp forward socket", err))
+	from_std_listener(StdTcpListener::from(socket))
+}
+
+fn from_std_listener(listener: StdTcpListener) -> Result<TcpListener, Error> {
+	listener
+		.set_nonblocking(true)
+		.map_err(|err| Error::Socket("failed setting tcp forward socket as non-blocking", err))?;
+	TcpListener::from_std(listener).map_err(|err| Error::Socket("failed to create tcp forward socket", err))
 }
 
 async fn run_udp_forwarder(entry: UdpForward, ctx: Arc<crate::AppContext>) {
@@ -140,6 +184,8 @@ async fn run_udp_forwarder(entry: UdpForward, ctx: Arc<crate::AppContext>) {
 		timeout = entry.timeout
 	);
 
+	let dns_cache = entry.dns_cache.then(|| Arc::new(DnsCache::new(entry.dns_prefetch)));
+
 	let mut buf = vec![0u8; 65535];
 	// Map from client src addr to assoc_id for this forwarder instance
 	let mut src_map: HashMap<SocketAddr, u16> = HashMap::new();
@@ -147,13 +193,27 @@ async fn run_udp_forwarder(entry: UdpForward, ctx: Arc<crate::AppContext>) {
 	loop {
 		match socket.recv_from(&mut buf).await {
 			Ok((n, src_addr)) => {
-				let pkt = Bytes::copy_from_slice(&buf[..n]);
+				let pkt = match dns_cache.as_ref().map(|cache| cache.lookup(&buf[..n])) {
+					None | Some(Lookup::Miss) => Bytes::copy_from_slice(&buf[..n]),
+					Some(Lookup::Hit(response)) => {
+						if let Err(err) = socket.send_to(&response, src_addr).await {
+							warn!("[forward-udp] failed sending cached DNS answer to {src_addr}: {err}");
+						}
+						continue;
+					}
+					Some(Lookup::Prefetch { response, query }) => {
+						if let Err(err) = socket.send_to(&response, src_addr).await {
+							warn!("[forward-udp] failed sending cached DNS answer to {src_addr}: {err}");
+						}
+						Bytes::from(query)
+					}
+				};
 				let assoc_id = match src_map.get(&src_addr).cloned() {
 					Some(id) => id,
 					None => {
 						let id = 0x8000 | (ctx.next_fwd_assoc_id.fetch_add(1, Ordering::Relaxed) & 0x7fff);
 						// register session
-						let session = ForwardUdpSession::new(socket.clone(), src_addr, id);
+						let session = ForwardUdpSession::new(socket.clone(), src_addr, id, dns_cache.clone());
 						ctx.fwd_udp_sessions.write().await.insert(id, session);
 						src_map.insert(src_addr, id);
 						// Spawn timeout watcher
@@ -162,6 +222,25 @@ async fn run_udp_forwarder(entry: UdpForward, ctx: Arc<crate::AppContext>) {
 					}
 				};
 
+				if ctx.budget_policy().is_some() {
+					debug!("[forward-udp] dropped packet from {src_addr}, the data budget is exhausted");
+					continue;
+				}
+
+				if let Some(resolver) = entry.direct_resolver
+					&& let Some(name) = dns_cache::query_name(&pkt)
+					// Rules see the name at the port the query was sent to
+					&& ctx.route(&TuicAddress::DomainAddress(name, entry.remote.1)) == route::DIRECT
+				{
+					let session = ForwardUdpSession::new(socket.clone(), src_addr, assoc_id, dns_cache.clone());
+					tokio::spawn(async move {
+						if let Err(err) = resolve_direct(pkt, resolver, session).await {
+							warn!("[forward-udp] [{assoc_id:#06x}] direct DNS query to {resolver} failed: {err}");
+						}
+					});
+					continue;
+				}
+
 				let remote = entry.remote.clone();
 				let ctx = ctx.clone();
 				tokio::spawn(async move {
@@ -181,6 +260,25 @@ async fn run_udp_forwarder(entry: UdpForward, ctx: Arc<crate::AppContext>) {
 	}
 }
 
+/// Send `query` to `resolver` without the tunnel and pass the answer on like
+/// one relayed from `remote`
+async fn resolve_direct(query: Bytes, resolver: SocketAddr, session: ForwardUdpSession) -> Result<(), Error> {
+	let local: SocketAddr = match resolver {
+		SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+		SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+	};
+	let socket = UdpSocket::bind(local).await?;
+	socket.connect(resolver).await?;
+	socket.send(&query).await?;
+
+	let mut buf = vec![0u8; 65535];
+	let n = tokio::time::timeout(DIRECT_DNS_TIMEOUT, socket.recv(&mut buf))
+		.await
+		.map_err(|_| Error::Timeout)??;
+	buf.truncate(n);
+	session.send(Bytes::from(buf)).await
+}
+
 async fn expire_after(assoc_id: u16, timeout: Duration, ctx: Arc<crate::AppContext>) {
 	tokio::time::sleep(timeout).await;
 	let mut w = ctx.fwd_udp_sessions.write().await;
@@ -13,13 +13,20 @@ use tokio::{
 	sync::{Mutex as AsyncMutex, RwLock as AsyncRwLock},
 	time::{Duration, sleep},
 };
-use tracing::{error, warn};
+use tracing::{error, info, warn};
 
+pub mod activation;
+pub mod budget;
 pub mod config;
 pub mod connection;
+pub mod dns_cache;
 pub mod error;
 pub mod forward;
+pub mod ping;
+pub mod route;
 pub mod socks5;
+pub mod stdio;
+pub mod tls;
 pub mod utils;
 
 pub use config::Config;
@@ -30,6 +37,10 @@ pub use config::Config;
 pub struct AppContext {
 	/// Manages the QUIC endpoint and current connection
 	pub conn_mgr: Arc<connection::ConnectionManager>,
+	/// Servers in `[relays]`, keyed by name
+	pub servers: HashMap<String, route::Upstream>,
+	/// Rules selecting the server for each SOCKS5 request
+	pub rules: Vec<route::RouteRule>,
 	/// SOCKS5 proxy server
 	pub socks5: Arc<socks5::Server>,
 	/// UDP session registry for SOCKS5 UDP associate
@@ -45,6 +56,10 @@ pub struct AppContext {
 	pub first_connected: AtomicBool,
 	/// Serializes first-connection logic under non-eager modes.
 	pub first_connect_lock: AsyncMutex<()>,
+	/// In-flight local connections, for `local.idle_exit`
+	pub idle: Arc<activation::IdleTracker>,
+	/// Monthly data usage, with `[budget]`
+	pub budget: Option<Arc<budget::Budget>>,
 }
 
 impl AppContext {
@@ -96,23 +111,123 @@ impl AppContext {
 			},
 		}
 	}
+
+	/// What to do with new requests because `[budget]` is exhausted, `None`
+	/// while they go through the tunnel
+	pub fn budget_policy(&self) -> Option<config::Exhausted> {
+		self.budget.as_ref()?.policy()
+	}
+
+	/// Name of the server that `rules` select for `addr`.
+	pub fn route(&self, addr: &tuic_core::Address) -> &str {
+		match route::select(&self.rules, addr) {
+			Some(rule) if rule.server == route::AUTO => self.pick_auto(rule.region.as_deref()),
+			Some(rule) => &rule.server,
+			None => route::DEFAULT,
+		}
+	}
+
+	/// Pick one of `[relays]` in `region` for an `auto` rule.
+	fn pick_auto(&self, region: Option<&str>) -> &str {
+		let candidates: Vec<_> = self
+			.servers
+			.iter()
+			.filter(|(_, upstream)| {
+				region.is_none_or(|region| upstream.region.as_deref().is_some_and(|r| r.eq_ignore_ascii_case(region)))
+			})
+			.map(|(name, upstream)| route::Candidate {
+				name,
+				weight: upstream.weight,
+				priority: upstream.priority,
+				rtt: upstream.conn_mgr.rtt(),
+				retry_in: upstream.health.retry_in(),
+			})
+			.collect();
+		route::pick(&candidates).unwrap_or(route::DEFAULT)
+	}
+
+	/// Get or re-establish the connection to a server selected by `route`.
+	/// `direct` has no connection and is only valid for TCP.
+	pub async fn get_conn_to(&self, server: &str) -> Result<connection::Connection, error::Error> {
+		if server == route::DEFAULT {
+			return self.get_conn().await;
+		}
+
+		match self.servers.get(server) {
+			Some(upstream) => {
+				let conn = upstream
+					.conn_mgr
+					.get_conn(self.socks5_udp_sessions.clone(), self.fwd_udp_sessions.clone())
+					.await;
+				upstream.health.record(conn.is_ok());
+				conn
+			}
+			None => Err(error::Error::Other(anyhow::anyhow!(
+				"no relay connection for server `{server}`"
+			))),
+		}
+	}
 }
 
 /// Run the TUIC client with the given configuration.
 pub async fn run(cfg: Config) -> eyre::Result<()> {
+	if let Some(count) = cfg.ping {
+		return ping::run(cfg.relay, count).await;
+	}
+	if let Some(target) = cfg.stdio {
+		return stdio::run(cfg.relay, target).await;
+	}
+
 	let startup_mode = cfg.relay.startup_mode;
 	let conn_mgr = Arc::new(connection::ConnectionManager::build(cfg.relay).await?);
-	let socks5 = Arc::new(socks5::Server::new(
-		cfg.local
-			.server
-			.ok_or_else(|| eyre::eyre!("`local.server` (SOCKS5 listen address) is required"))?,
-		cfg.local.dual_stack,
-		cfg.local.max_packet_size,
-		cfg.local.username,
-		cfg.local.password,
-	)?);
+	let mut servers = HashMap::new();
+	for (name, relay) in cfg.relays {
+		let (region, weight, priority) = (relay.region.clone(), relay.weight, relay.priority);
+		let conn_mgr = Arc::new(connection::ConnectionManager::build(relay).await?);
+		servers.insert(
+			name,
+			route::Upstream {
+				conn_mgr,
+				region,
+				weight,
+				priority,
+				health: route::Health::default(),
+			},
+		);
+	}
+
+	// Sockets passed by systemd socket activation replace binding our own
+	let mut activated = activation::listen_fds();
+	if !activated.is_empty() {
+		info!("[activation] received {} listening socket(s) from systemd", activated.len());
+	}
+
+	let socks5_addr = cfg
+		.local
+		.server
+		.ok_or_else(|| eyre::eyre!("`local.server` (SOCKS5 listen address) is required"))?;
+	let socks5 = match activation::take(&mut activated, socks5_addr) {
+		Some(listener) => socks5::Server::from_listener(
+			listener,
+			cfg.local.dual_stack,
+			cfg.local.max_packet_size,
+			cfg.local.username,
+			cfg.local.password,
+		)?,
+		None => socks5::Server::new(
+			socks5_addr,
+			cfg.local.dual_stack,
+			cfg.local.max_packet_size,
+			cfg.local.username,
+			cfg.local.password,
+		)?,
+	};
+	let socks5 = Arc::new(socks5);
+	let budget = cfg.budget.map(|cfg| Arc::new(budget::Budget::load(cfg)));
 	let ctx = Arc::new(AppContext {
 		conn_mgr,
+		servers,
+		rules: cfg.rules,
 		socks5,
 		socks5_udp_sessions: Arc::new(AsyncRwLock::new(HashMap::new())),
 		fwd_udp_sessions: Arc::new(AsyncRwLock::new(HashMap::new())),
@@ -120,7 +235,12 @@ pub async fn run(cfg: Config) -> eyre::Result<()> {
 		startup_mode,
 		first_connected: AtomicBool::new(false),
 		first_connect_lock: AsyncMutex::new(()),
+		idle: Arc::new(activation::IdleTracker::default()),
+		budget: budget.clone(),
 	});
+	if let Some(budget) = budget {
+		tokio::spawn(budget::track(ctx.clone(), budget));
+	}
 
 	// Eager mode keeps the original behavior: connect at startup and exit on
 	// failure.
@@ -128,7 +248,19 @@ pub async fn run(cfg: Config) -> eyre::Result<()> {
 		ctx.get_conn().await?;
 	}
 
-	forward::start(ctx.clone(), cfg.local.tcp_forward, cfg.local.udp_forward).await;
-	socks5::Server::start(ctx.clone()).await;
+	forward::start(ctx.clone(), cfg.local.tcp_forward, cfg.local.udp_forward, &mut activated).await;
+	for listener in activated {
+		warn!(
+			"[activation] no local listener is configured for passed socket {:?}",
+			listener.local_addr()
+		);
+	}
+
+	tokio::select! {
+		() = socks5::Server::start(ctx.clone()) => {}
+		() = ctx.idle.wait_idle(cfg.local.idle_exit) => {
+			info!("[local] no connections for {}, exiting", humantime::format_duration(cfg.local.idle_exit));
+		}
+	}
 	Ok(())
 }
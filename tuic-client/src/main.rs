@@ -5,7 +5,7 @@ use clap::Parser;
 #[cfg(all(feature = "jemallocator", not(feature = "dhat-heap")))]
 use tikv_jemallocator::Jemalloc;
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt};
 use tuic_client::config::{Cli, Config, EnvState, ResolvedRuntime};
 // dhat takes over the global allocator to trace every heap allocation, so it
 // must be the sole `#[global_allocator]`; jemalloc is disabled whenever
@@ -48,12 +48,19 @@ fn main() -> eyre::Result<()> {
 	let filter = tracing_subscriber::filter::Targets::new()
 		.with_targets(vec![("tuic", level), ("tuic_quinn", level), ("tuic_client", level)])
 		.with_default(LevelFilter::INFO);
+	// In `stdio` mode stdout carries the relayed stream
+	let writer = if cfg.stdio.is_some() {
+		BoxMakeWriter::new(std::io::stderr)
+	} else {
+		BoxMakeWriter::new(std::io::stdout)
+	};
 	let registry = tracing_subscriber::registry();
 	registry
 		.with(filter)
 		.with(
 			tracing_subscriber::fmt::layer()
 				.with_target(true)
+				.with_writer(writer)
 				.with_timer(tracing_subscriber::fmt::time::OffsetTime::new(
 					time::UtcOffset::from_whole_seconds(
 						chrono::Local.timestamp_opt(0, 0).unwrap().offset().fix().local_minus_utc(),
@@ -63,6 +70,9 @@ fn main() -> eyre::Result<()> {
 				)),
 		)
 		.try_init()?;
+	for option in &cfg.unknown_options {
+		tracing::warn!("ignoring unknown option `{option}`, pass --strict-config to fail on it instead");
+	}
 
 	let mut builder = match cfg.tokio_runtime.resolve() {
 		ResolvedRuntime::MultiThread => tokio::runtime::Builder::new_multi_thread(),
@@ -0,0 +1,59 @@
+//! `--ping`: measure the tunnel round-trip time to the relay.
+//!
+//! SOCKS5 carries no ICMP, so pinging a host "through" the client would never
+//! reach the tunnel. Instead each probe sends a TUIC command the server
+//! ignores to `[relay]` on a stream of its own, and reports the time until
+//! the server's QUIC acknowledgement of that stream, giving users testing
+//! their setup an answer in the familiar `ping` form. A probe not acknowledged
+//! within [`INTERVAL`] is counted as lost.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::{sync::RwLock as AsyncRwLock, time};
+
+use crate::{config::Relay, connection::ConnectionManager};
+
+/// Time between probes, and how long each waits for its acknowledgement
+const INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn run(relay: Relay, count: u32) -> eyre::Result<()> {
+	let server = format!("{}:{}", relay.server.0, relay.server.1);
+	let conn_mgr = ConnectionManager::build(relay).await?;
+	let conn = conn_mgr
+		.get_conn(
+			Arc::new(AsyncRwLock::new(HashMap::new())),
+			Arc::new(AsyncRwLock::new(HashMap::new())),
+		)
+		.await?;
+
+	println!("PING {server} through the TUIC tunnel");
+	let mut rtts = Vec::new();
+	let mut ticker = time::interval(INTERVAL);
+	for seq in 1..=count {
+		ticker.tick().await;
+		match conn.probe(INTERVAL).await {
+			Ok(rtt) => {
+				println!("probe from {server}: seq={seq} time={:.1} ms", ms(rtt));
+				rtts.push(rtt);
+			}
+			Err(err) => println!("probe from {server}: seq={seq} failed: {err}"),
+		}
+	}
+
+	println!("--- {server} tunnel ping statistics ---");
+	let loss = f64::from(count - rtts.len() as u32) / f64::from(count.max(1)) * 100.0;
+	println!("{count} probes sent, {} answered, {loss:.0}% lost", rtts.len());
+	if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+		let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+		println!("rtt min/avg/max = {:.1}/{:.1}/{:.1} ms", ms(*min), ms(avg), ms(*max));
+	}
+
+	if rtts.is_empty() {
+		eyre::bail!("no probe was answered");
+	}
+	Ok(())
+}
+
+fn ms(rtt: Duration) -> f64 {
+	rtt.as_secs_f64() * 1000.0
+}
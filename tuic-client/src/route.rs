@@ -0,0 +1,357 @@
+//! Selection of the server that handles a SOCKS5 request.
+//!
+//! `rules` are matched in order against the request's target. The first match
+//! names the server: `default` is `[relay]`, `direct` bypasses the tunnel,
+//! `auto` picks one of `[relays]` by their metadata and measured RTT, and
+//! any other name is a key of `[relays]`. Unmatched traffic goes through
+//! `[relay]`.
+
+use std::{
+	cmp::Reverse,
+	fmt,
+	net::IpAddr,
+	str::FromStr,
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tuic_core::Address;
+
+use crate::connection::ConnectionManager;
+
+/// Server name of `[relay]`
+pub const DEFAULT: &str = "default";
+/// Server name that bypasses the tunnel
+pub const DIRECT: &str = "direct";
+/// Server name that picks one of `[relays]`, see [`pick`]
+pub const AUTO: &str = "auto";
+/// Names that `[relays]` cannot use
+pub const RESERVED: [&str; 3] = [DEFAULT, DIRECT, AUTO];
+/// How long `auto` avoids a server after it failed to connect, doubled with
+/// each further failure in a row up to [`MAX_BACKOFF`]
+pub const BACKOFF: Duration = Duration::from_secs(2);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8`. A bare address matches
+/// only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork {
+	addr: IpAddr,
+	prefix: u8,
+}
+
+impl IpNetwork {
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		let ip = match ip {
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+			ip => ip,
+		};
+
+		match (self.addr, ip) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			}
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+impl FromStr for IpNetwork {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr, Some(prefix)),
+			None => (s, None),
+		};
+		let addr: IpAddr = addr.parse().map_err(|err| format!("invalid IP network '{s}': {err}"))?;
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => prefix
+				.parse::<u8>()
+				.ok()
+				.filter(|prefix| *prefix <= max)
+				.ok_or_else(|| format!("invalid prefix length in IP network '{s}'"))?,
+			None => max,
+		};
+
+		Ok(Self { addr, prefix })
+	}
+}
+
+impl TryFrom<String> for IpNetwork {
+	type Error = String;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl From<IpNetwork> for String {
+	fn from(net: IpNetwork) -> Self {
+		net.to_string()
+	}
+}
+
+impl fmt::Display for IpNetwork {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+	/// Domains to match, including their subdomains
+	#[serde(default)]
+	pub domain: Vec<String>,
+	/// IP networks to match. Domain targets are not resolved for this.
+	#[serde(default)]
+	pub ip: Vec<IpNetwork>,
+	/// `default`, `direct`, `auto` or a key of `[relays]`
+	pub server: String,
+	/// Restrict `auto` to the servers in this region
+	pub region: Option<String>,
+}
+
+impl RouteRule {
+	/// A rule without matchers matches everything
+	fn matches(&self, addr: &Address) -> bool {
+		if self.domain.is_empty() && self.ip.is_empty() {
+			return true;
+		}
+
+		match addr {
+			Address::DomainAddress(domain, _) => {
+				let domain = domain.trim_end_matches('.').as_bytes();
+				self.domain.iter().any(|suffix| {
+					let suffix = suffix.trim_start_matches('.').as_bytes();
+					domain.len() >= suffix.len()
+						&& domain[domain.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+						&& (domain.len() == suffix.len() || domain[domain.len() - suffix.len() - 1] == b'.')
+				})
+			}
+			Address::SocketAddress(addr) => self.ip.iter().any(|net| net.contains(addr.ip())),
+			Address::None => false,
+		}
+	}
+}
+
+/// The first rule matching `addr`, if any.
+pub fn select<'a>(rules: &'a [RouteRule], addr: &Address) -> Option<&'a RouteRule> {
+	rules.iter().find(|rule| rule.matches(addr))
+}
+
+/// A server in `[relays]`
+pub struct Upstream {
+	pub conn_mgr: Arc<ConnectionManager>,
+	pub region: Option<String>,
+	pub weight: u32,
+	pub priority: u32,
+	pub health: Health,
+}
+
+/// Whether connecting to a server has been failing, to keep `auto` away from
+/// it for a [`BACKOFF`]
+#[derive(Debug, Default)]
+pub struct Health {
+	/// Failures in a row, and until when the server is avoided
+	state: Mutex<(u32, Option<Instant>)>,
+}
+
+impl Health {
+	/// Remember whether connecting to the server worked
+	pub fn record(&self, connected: bool) {
+		self.record_at(connected, Instant::now());
+	}
+
+	/// Time left until `auto` tries the server again, `None` unless it failed
+	/// recently
+	pub fn retry_in(&self) -> Option<Duration> {
+		self.retry_in_at(Instant::now())
+	}
+
+	fn record_at(&self, connected: bool, now: Instant) {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		if connected {
+			*state = (0, None);
+			return;
+		}
+		let failures = state.0.saturating_add(1);
+		let backoff = BACKOFF.saturating_mul(1 << (failures - 1).min(16)).min(MAX_BACKOFF);
+		*state = (failures, Some(now + backoff));
+	}
+
+	fn retry_in_at(&self, now: Instant) -> Option<Duration> {
+		let (_, retry_at) = *self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		retry_at
+			.map(|retry_at| retry_at.saturating_duration_since(now))
+			.filter(|left| !left.is_zero())
+	}
+}
+
+/// A server `auto` can pick
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+	pub name: &'a str,
+	pub weight: u32,
+	pub priority: u32,
+	/// RTT of the server's live connection, `None` until it has one
+	pub rtt: Option<Duration>,
+	/// Time left of the server's backoff after failing to connect, see
+	/// [`Health`]
+	pub retry_in: Option<Duration>,
+}
+
+/// Pick among the candidates with the lowest `priority`, leaving out those
+/// that failed to connect recently. Servers without a measured RTT are tried
+/// first, by descending weight, so every one gets measured once traffic
+/// reaches it. After that the lowest RTT divided by weight wins. When every
+/// server failed recently, the one to be retried soonest is picked.
+pub fn pick<'a>(candidates: &[Candidate<'a>]) -> Option<&'a str> {
+	let healthy: Vec<_> = candidates.iter().filter(|c| c.retry_in.is_none()).copied().collect();
+	if healthy.is_empty() {
+		return candidates
+			.iter()
+			.min_by_key(|c| (c.retry_in, c.priority, c.name))
+			.map(|c| c.name);
+	}
+	let priority = healthy.iter().map(|c| c.priority).min()?;
+	let candidates = healthy.iter().filter(|c| c.priority == priority);
+
+	if let Some(unmeasured) = candidates
+		.clone()
+		.filter(|c| c.rtt.is_none())
+		.min_by_key(|c| (Reverse(c.weight), c.name))
+	{
+		return Some(unmeasured.name);
+	}
+
+	candidates
+		.filter_map(|c| Some((c.rtt?.as_secs_f64() / f64::from(c.weight.max(1)), c.name)))
+		.min_by(|(a, a_name), (b, b_name)| a.total_cmp(b).then_with(|| a_name.cmp(b_name)))
+		.map(|(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rule(domain: &[&str], ip: &[&str], server: &str) -> RouteRule {
+		RouteRule {
+			domain: domain.iter().map(|d| d.to_string()).collect(),
+			ip: ip.iter().map(|net| net.parse().unwrap()).collect(),
+			server: server.to_string(),
+			region: None,
+		}
+	}
+
+	fn server<'a>(rules: &'a [RouteRule], addr: &Address) -> &'a str {
+		select(rules, addr).map_or(DEFAULT, |rule| rule.server.as_str())
+	}
+
+	#[test]
+	fn test_select() {
+		let rules = vec![
+			rule(&["netflix.com"], &[], "us"),
+			rule(&[], &["10.0.0.0/8", "fd00::/8"], DIRECT),
+		];
+		let domain = |d: &str| Address::DomainAddress(d.to_string(), 443);
+		let ip = |ip: &str| Address::SocketAddress((ip.parse::<IpAddr>().unwrap(), 443).into());
+
+		assert_eq!(server(&rules, &domain("netflix.com")), "us");
+		assert_eq!(server(&rules, &domain("www.NETFLIX.com.")), "us");
+		assert_eq!(server(&rules, &domain("notnetflix.com")), DEFAULT);
+		assert_eq!(server(&rules, &ip("10.1.2.3")), DIRECT);
+		assert_eq!(server(&rules, &ip("::ffff:10.1.2.3")), DIRECT);
+		assert_eq!(server(&rules, &ip("fd12::1")), DIRECT);
+		assert_eq!(server(&rules, &ip("192.0.2.1")), DEFAULT);
+
+		let rules = vec![rule(&[], &[], "eu")];
+		assert_eq!(server(&rules, &domain("example.com")), "eu");
+	}
+
+	#[test]
+	fn test_pick() {
+		let candidate = |name, weight, priority, rtt: Option<u64>| Candidate {
+			name,
+			weight,
+			priority,
+			rtt: rtt.map(Duration::from_millis),
+			retry_in: None,
+		};
+
+		assert_eq!(pick(&[]), None);
+
+		// The backup is never picked while a lower priority exists
+		let candidates = [
+			candidate("backup", 10, 1, None),
+			candidate("a", 1, 0, Some(80)),
+			candidate("b", 2, 0, Some(120)),
+		];
+		assert_eq!(pick(&candidates), Some("b"));
+
+		// Unmeasured servers go first, heaviest first
+		let candidates = [
+			candidate("a", 1, 0, Some(10)),
+			candidate("b", 1, 0, None),
+			candidate("c", 3, 0, None),
+		];
+		assert_eq!(pick(&candidates), Some("c"));
+	}
+
+	#[test]
+	fn test_pick_skips_failing_servers() {
+		let health: Vec<_> = ["a", "b", "backup"]
+			.into_iter()
+			.map(|name| (name, Health::default()))
+			.collect();
+		let now = Instant::now();
+		let pick_at = |now| {
+			let candidates: Vec<_> = health
+				.iter()
+				.map(|(name, health)| Candidate {
+					name: *name,
+					weight: 1,
+					priority: u32::from(*name == "backup"),
+					rtt: None,
+					retry_in: health.retry_in_at(now),
+				})
+				.collect();
+			pick(&candidates)
+		};
+
+		// "a" can't be reached, so it never gets an RTT
+		assert_eq!(pick_at(now), Some("a"));
+		health[0].1.record_at(false, now);
+		assert_eq!(pick_at(now), Some("b"));
+		// Then the lower priority tier
+		health[1].1.record_at(false, now);
+		assert_eq!(pick_at(now), Some("backup"));
+		// And with everything down, the server retried soonest
+		health[2].1.record_at(false, now);
+		health[0].1.record_at(false, now);
+		assert_eq!(pick_at(now), Some("b"));
+
+		// "b" gets another try once its backoff passed, "a" twice as late
+		assert_eq!(health[0].1.retry_in_at(now), Some(BACKOFF * 2));
+		assert_eq!(pick_at(now + BACKOFF), Some("b"));
+		assert_eq!(pick_at(now + BACKOFF * 2), Some("a"));
+		health[0].1.record_at(true, now);
+		assert_eq!(health[0].1.retry_in_at(now), None);
+	}
+
+	#[test]
+	fn test_ip_network_parse() {
+		assert_eq!("192.0.2.1".parse::<IpNetwork>().unwrap().to_string(), "192.0.2.1/32");
+		assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+		assert!("0.0.0.0/0".parse::<IpNetwork>().unwrap().contains("8.8.8.8".parse().unwrap()));
+	}
+}
@@ -1,16 +1,27 @@
-use std::sync::Arc;
+use std::{
+	collections::HashSet,
+	sync::{Arc, Mutex, PoisonError},
+};
 
 use socks5_proto::{Address, Reply};
 use socks5_server::{
 	Associate, Bind, Connect,
 	connection::{associate, bind, connect},
 };
-use tokio::io::{self, AsyncWriteExt};
+use tokio::{
+	io::{self, AsyncWriteExt},
+	net::TcpStream,
+};
 use tracing::{debug, info, warn};
 use tuic_core::Address as TuicAddress;
 
 use super::{Server, udp_session::UdpSession};
-use crate::connection::ERROR_CODE;
+use crate::{
+	config::Exhausted,
+	connection::{ERROR_CODE, rejection},
+	error::Error,
+	route,
+};
 
 impl Server {
 	pub async fn handle_associate(
@@ -56,7 +67,11 @@ impl Server {
 					ctx.socks5_udp_sessions.write().await.insert(assoc_id, session.clone());
 				}
 
+				// Servers this association has relayed packets through
+				let servers = Arc::new(Mutex::new(HashSet::new()));
+
 				let ctx_loop = ctx.clone();
+				let servers_loop = servers.clone();
 				let handle_local_incoming_pkt = async move {
 					loop {
 						let (pkt, target_addr) = match session.recv().await {
@@ -70,13 +85,35 @@ impl Server {
 						};
 
 						let ctx_fwd = ctx_loop.clone();
+						let servers_fwd = servers_loop.clone();
 						let forward = async move {
 							let target_addr = match target_addr {
 								Address::DomainAddress(domain, port) => TuicAddress::DomainAddress(domain, port),
 								Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
 							};
 
-							match ctx_fwd.get_conn().await {
+							if ctx_fwd.budget_policy().is_some() {
+								debug!(
+									"[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] dropped UDP packet to {target_addr}, \
+									 the data budget is exhausted"
+								);
+								return Ok(());
+							}
+
+							// There is no direct path for UDP, so packets routed to `direct` go
+							// through `[relay]`
+							let server = match ctx_fwd.route(&target_addr) {
+								route::DIRECT => route::DEFAULT,
+								server => server,
+							};
+							{
+								let mut servers = servers_fwd.lock().unwrap_or_else(PoisonError::into_inner);
+								if !servers.contains(server) {
+									servers.insert(server.to_owned());
+								}
+							}
+
+							match ctx_fwd.get_conn_to(server).await {
 								Ok(conn) => conn.packet(pkt, target_addr, assoc_id).await,
 								Err(err) => Err(err)?,
 							}
@@ -112,10 +149,16 @@ impl Server {
 					ctx.socks5_udp_sessions.write().await.remove(&assoc_id).unwrap();
 				}
 
-				if let Ok(conn) = ctx.get_conn().await
-					&& let Err(err) = conn.dissociate(assoc_id).await
-				{
-					warn!("[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] failed stopping UDP relaying session: {err}")
+				let servers = std::mem::take(&mut *servers.lock().unwrap_or_else(PoisonError::into_inner));
+				for server in servers {
+					if let Ok(conn) = ctx.get_conn_to(&server).await
+						&& let Err(err) = conn.dissociate(assoc_id).await
+					{
+						warn!(
+							"[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] failed stopping UDP relaying session on \
+							 {server}: {err}"
+						)
+					}
 				}
 			}
 			Err(err) => {
@@ -152,7 +195,30 @@ impl Server {
 			Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
 		};
 
-		let relay = match ctx.get_conn().await {
+		let server = match ctx.budget_policy() {
+			Some(Exhausted::Block) => {
+				warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] refused, the data budget is exhausted");
+				match conn.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await {
+					Ok(mut conn) => {
+						let _ = conn.shutdown().await;
+					}
+					Err(err) => {
+						warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] command reply error: {err}")
+					}
+				}
+				return;
+			}
+			Some(Exhausted::Direct) => route::DIRECT,
+			Some(Exhausted::Warn) | None => ctx.route(&target_addr),
+		};
+		if server == route::DIRECT {
+			return Self::handle_direct_connect(conn, target_addr).await;
+		}
+		if server != route::DEFAULT {
+			debug!("[socks5] [{peer_addr}] [connect] [{target_addr}] routed to {server}");
+		}
+
+		let relay = match ctx.get_conn_to(server).await {
 			Ok(conn) => conn.connect(target_addr.clone()).await,
 			Err(err) => Err(err),
 		};
@@ -164,7 +230,14 @@ impl Server {
 					Err(err) => {
 						let _ = conn.shutdown().await;
 						let _ = relay.reset(ERROR_CODE);
-						warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] TCP stream relaying error: {err}");
+						match rejection(&err) {
+							Some(reject) => {
+								warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] refused by the server: {reject}")
+							}
+							None => {
+								warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] TCP stream relaying error: {err}")
+							}
+						}
 					}
 				},
 				Err(err) => {
@@ -175,6 +248,48 @@ impl Server {
 			Err(err) => {
 				warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] unable to relay TCP stream: {err}");
 
+				let reply = match err {
+					Error::Rejected(_) => Reply::ConnectionNotAllowed,
+					Error::Unreachable(_) => Reply::HostUnreachable,
+					_ => Reply::GeneralFailure,
+				};
+				match conn.reply(reply, Address::unspecified()).await {
+					Ok(mut conn) => {
+						let _ = conn.shutdown().await;
+					}
+					Err(err) => {
+						warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] command reply error: {err}")
+					}
+				}
+			}
+		}
+	}
+
+	/// Connect to the target without going through a relay, for requests
+	/// routed to `direct`.
+	async fn handle_direct_connect(conn: Connect<connect::NeedReply>, target_addr: TuicAddress) {
+		let peer_addr = conn.peer_addr().unwrap();
+		debug!("[socks5] [{peer_addr}] [connect] [{target_addr}] connecting directly");
+
+		let stream = match &target_addr {
+			TuicAddress::DomainAddress(domain, port) => TcpStream::connect((domain.as_str(), *port)).await,
+			TuicAddress::SocketAddress(addr) => TcpStream::connect(addr).await,
+			TuicAddress::None => Err(io::Error::new(io::ErrorKind::InvalidInput, "empty target address")),
+		};
+
+		match stream {
+			Ok(mut stream) => match conn.reply(Reply::Succeeded, Address::unspecified()).await {
+				Ok(mut conn) => {
+					if let Err(err) = io::copy_bidirectional(&mut conn, &mut stream).await {
+						let _ = conn.shutdown().await;
+						warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] direct TCP stream relaying error: {err}");
+					}
+				}
+				Err(err) => warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] command reply error: {err}"),
+			},
+			Err(err) => {
+				warn!("[socks5] [{peer_addr}] [connect] [{target_addr}] unable to connect directly: {err}");
+
 				match conn.reply(Reply::GeneralFailure, Address::unspecified()).await {
 					Ok(mut conn) => {
 						let _ = conn.shutdown().await;
@@ -57,10 +57,6 @@ impl Server {
 				.set_reuse_address(true)
 				.map_err(|err| Error::Socket("failed to set socks5 server socket to reuse_address", err))?;
 
-			socket
-				.set_nonblocking(true)
-				.map_err(|err| Error::Socket("failed setting socks5 server socket as non-blocking", err))?;
-
 			socket
 				.bind(&SockAddr::from(addr))
 				.map_err(|err| Error::Socket("failed to bind socks5 server socket", err))?;
@@ -69,10 +65,28 @@ impl Server {
 				.listen(i32::MAX)
 				.map_err(|err| Error::Socket("failed to listen on socks5 server socket", err))?;
 
-			TcpListener::from_std(StdTcpListener::from(socket))
-				.map_err(|err| Error::Socket("failed to create socks5 server socket", err))?
+			StdTcpListener::from(socket)
 		};
 
+		Self::from_listener(socket, dual_stack, max_pkt_size, username, password)
+	}
+
+	/// Serve SOCKS5 on an already listening socket, e.g. one passed in by
+	/// systemd socket activation.
+	pub fn from_listener(
+		listener: StdTcpListener,
+		dual_stack: Option<bool>,
+		max_pkt_size: usize,
+		username: Option<Vec<u8>>,
+		password: Option<Vec<u8>>,
+	) -> Result<Self, Error> {
+		listener
+			.set_nonblocking(true)
+			.map_err(|err| Error::Socket("failed setting socks5 server socket as non-blocking", err))?;
+
+		let socket =
+			TcpListener::from_std(listener).map_err(|err| Error::Socket("failed to create socks5 server socket", err))?;
+
 		let auth: Arc<dyn Auth + Send + Sync> = match (username, password) {
 			(Some(username), Some(password)) => Arc::new(Password::new(username, password)),
 			(None, None) => Arc::new(NoAuth),
@@ -99,7 +113,9 @@ impl Server {
 
 					let server = server.clone();
 					let ctx = ctx.clone();
+					let active = ctx.idle.enter();
 					tokio::spawn(async move {
+						let _active = active;
 						match conn.handshake().await {
 							Ok(Connection::Associate(associate, _)) => {
 								let assoc_id = server.next_assoc_id.fetch_add(1, Ordering::Relaxed);
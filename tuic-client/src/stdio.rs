@@ -0,0 +1,28 @@
+//! `stdio HOST:PORT`: relay stdin and stdout to a destination.
+//!
+//! One TCP stream to the destination is opened through `[relay]` and joined
+//! with the process' standard streams, so the client works as an OpenSSH
+//! `ProxyCommand` without a SOCKS5 listener. Logs go to stderr meanwhile,
+//! leaving stdout to the relayed data.
+
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::{io, sync::RwLock as AsyncRwLock};
+use tuic_core::Address;
+
+use crate::{config::Relay, connection::ConnectionManager};
+
+pub async fn run(relay: Relay, (host, port): (String, u16)) -> eyre::Result<()> {
+	let conn_mgr = ConnectionManager::build(relay).await?;
+	let conn = conn_mgr
+		.get_conn(
+			Arc::new(AsyncRwLock::new(HashMap::new())),
+			Arc::new(AsyncRwLock::new(HashMap::new())),
+		)
+		.await?;
+
+	let mut stream = conn.connect(Address::DomainAddress(host, port)).await?;
+	let mut stdio = io::join(io::stdin(), io::stdout());
+	io::copy_bidirectional(&mut stdio, &mut stream).await?;
+	Ok(())
+}
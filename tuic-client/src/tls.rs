@@ -0,0 +1,341 @@
+//! Server certificate verification policies for the relay connection.
+//!
+//! Precedence, strongest first: `insecure` skips every check, a non-empty
+//! `pinned_sha256` accepts exactly the pinned leaf certificates, and otherwise
+//! the chain is verified against the trusted roots, with the hostname check
+//! controlled by `verify_hostname`. A `client_certificate` is presented to
+//! servers that require one.
+
+use std::{fs, path::Path, sync::Arc};
+
+use anyhow::{Context, anyhow};
+use rustls::{
+	CertificateError, CipherSuite, ClientConfig as RustlsClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+	client::{
+		WebPkiServerVerifier,
+		danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+	},
+	crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+	pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+};
+use sha2::{Digest, Sha256};
+use tuic_core::quinn::crypto::rustls::QuicClientConfig;
+
+use crate::error::Error;
+
+/// How the server certificate is verified.
+pub enum Verification {
+	/// Accept any certificate (insecure, testing only)
+	Insecure,
+	/// Accept only leaf certificates whose SHA-256 digest is pinned
+	Pinned(Vec<[u8; 32]>),
+	/// Verify the chain against `roots`, optionally ignoring the hostname
+	WebPki { roots: RootCertStore, verify_hostname: bool },
+}
+
+/// A certificate chain and its key, for servers requiring client
+/// authentication
+pub type ClientAuth = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// Build the TLS 1.3 client configuration for `verification` on top of
+/// `provider`, presenting `client_auth` if the server asks for a certificate.
+pub fn client_config(
+	verification: Verification,
+	client_auth: Option<ClientAuth>,
+	provider: Arc<CryptoProvider>,
+) -> Result<RustlsClientConfig, Error> {
+	let builder =
+		RustlsClientConfig::builder_with_provider(provider.clone()).with_protocol_versions(&[&rustls::version::TLS13])?;
+
+	let verifier: Arc<dyn ServerCertVerifier> = match verification {
+		Verification::Insecure => Arc::new(SkipServerVerification(provider)),
+		Verification::Pinned(pins) => Arc::new(PinnedServerVerification { pins, provider }),
+		Verification::WebPki {
+			roots,
+			verify_hostname: true,
+		} => return with_client_auth(builder.with_root_certificates(roots), client_auth),
+		Verification::WebPki {
+			roots,
+			verify_hostname: false,
+		} => {
+			let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+				.build()
+				.map_err(|err| Error::Other(anyhow!("failed to build certificate verifier: {err}")))?;
+			Arc::new(NoHostnameVerification(inner))
+		}
+	};
+
+	with_client_auth(builder.dangerous().with_custom_certificate_verifier(verifier), client_auth)
+}
+
+fn with_client_auth(
+	builder: rustls::ConfigBuilder<RustlsClientConfig, rustls::client::WantsClientCert>,
+	client_auth: Option<ClientAuth>,
+) -> Result<RustlsClientConfig, Error> {
+	match client_auth {
+		Some((chain, key)) => builder
+			.with_client_auth_cert(chain, key)
+			.map_err(|err| Error::Other(anyhow!("invalid client certificate: {err}"))),
+		None => Ok(builder.with_no_client_auth()),
+	}
+}
+
+/// Load a client certificate chain and its private key, both PEM
+pub fn load_client_auth(cert_path: &Path, key_path: &Path) -> Result<ClientAuth, Error> {
+	let chain = fs::read(cert_path).context("failed to read client certificate")?;
+	let chain: Vec<_> = rustls_pemfile::certs(&mut &*chain)
+		.collect::<Result<_, _>>()
+		.context("invalid PEM-encoded client certificate")?;
+	if chain.is_empty() {
+		return Err(Error::Other(anyhow!("no certificate in {}", cert_path.display())));
+	}
+	let key = fs::read(key_path).context("failed to read client private key")?;
+	let key = rustls_pemfile::private_key(&mut &*key)
+		.context("invalid PEM-encoded client private key")?
+		.ok_or_else(|| Error::Other(anyhow!("no private key in {}", key_path.display())))?;
+	Ok((chain, key))
+}
+
+/// Parse a SHA-256 certificate fingerprint given as 64 hex digits, optionally
+/// separated by colons (as printed by `openssl x509 -fingerprint -sha256`).
+pub fn parse_pin(pin: &str) -> Option<[u8; 32]> {
+	let hex: Vec<u8> = pin.bytes().filter(|b| *b != b':').collect();
+	if hex.len() != 64 {
+		return None;
+	}
+
+	let mut out = [0u8; 32];
+	for (byte, pair) in out.iter_mut().zip(hex.chunks_exact(2)) {
+		let pair = std::str::from_utf8(pair).ok()?;
+		*byte = u8::from_str_radix(pair, 16).ok()?;
+	}
+	Some(out)
+}
+
+/// The default crypto provider, limited to `cipher_suites` and `kx_groups`
+/// when they are given, in that order of preference. Names are those of
+/// rustls, like `TLS13_CHACHA20_POLY1305_SHA256` and `X25519`.
+pub fn crypto_provider(cipher_suites: &[String], kx_groups: &[String]) -> Result<Arc<CryptoProvider>, Error> {
+	let default = provider()?;
+	if cipher_suites.is_empty() && kx_groups.is_empty() {
+		return Ok(default);
+	}
+	let mut provider = CryptoProvider::clone(&default);
+	if !cipher_suites.is_empty() {
+		// QUIC runs TLS 1.3 only
+		let supported: Vec<_> = default
+			.cipher_suites
+			.iter()
+			.copied()
+			.filter(|suite| suite.tls13().is_some())
+			.collect();
+		provider.cipher_suites = pick("cipher suite", cipher_suites, &supported, |suite| suite.suite().as_str())?;
+	}
+	if !kx_groups.is_empty() {
+		provider.kx_groups = pick("key exchange group", kx_groups, &default.kx_groups, |group| {
+			group.name().as_str()
+		})?;
+	}
+	Ok(Arc::new(provider))
+}
+
+/// The items of `supported` named in `names`, ignoring case
+fn pick<T: Copy>(
+	kind: &str,
+	names: &[String],
+	supported: &[T],
+	name: impl Fn(&T) -> Option<&'static str>,
+) -> Result<Vec<T>, Error> {
+	names
+		.iter()
+		.map(|wanted| {
+			supported
+				.iter()
+				.find(|item| name(item).is_some_and(|name| name.eq_ignore_ascii_case(wanted)))
+				.copied()
+				.ok_or_else(|| {
+					let names: Vec<_> = supported.iter().filter_map(&name).collect();
+					Error::Other(anyhow!("unsupported {kind} {wanted:?}, expected one of {}", names.join(", ")))
+				})
+		})
+		.collect()
+}
+
+/// `crypto` for QUIC. Initial packets are protected with
+/// TLS_AES_128_GCM_SHA256, as RFC 9001 requires, even when `cipher_suites`
+/// leaves it out of the handshake.
+pub fn quic_client_config(crypto: RustlsClientConfig) -> Result<QuicClientConfig, Error> {
+	let initial = provider()?
+		.cipher_suites
+		.iter()
+		.filter(|suite| suite.suite() == CipherSuite::TLS13_AES_128_GCM_SHA256)
+		.find_map(|suite| suite.tls13()?.quic_suite())
+		.ok_or_else(|| Error::Other(anyhow!("no initial cipher suite found")))?;
+	Ok(QuicClientConfig::with_initial(Arc::new(crypto), initial).context("no initial cipher suite found")?)
+}
+
+fn provider() -> Result<Arc<CryptoProvider>, Error> {
+	CryptoProvider::get_default()
+		.cloned()
+		.ok_or_else(|| Error::Other(anyhow!("no default crypto provider installed")))
+}
+
+/// Custom certificate verifier that skips all checks (dangerous, use only for
+/// testing)
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+	fn verify_server_cert(
+		&self,
+		_end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.0.signature_verification_algorithms.supported_schemes()
+	}
+}
+
+/// Accepts a leaf certificate only if its SHA-256 digest is pinned. The chain
+/// and hostname are not checked, which suits self-signed certificates.
+#[derive(Debug)]
+struct PinnedServerVerification {
+	pins: Vec<[u8; 32]>,
+	provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedServerVerification {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		let digest: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+		if self.pins.contains(&digest) {
+			Ok(ServerCertVerified::assertion())
+		} else {
+			Err(rustls::Error::InvalidCertificate(
+				CertificateError::ApplicationVerificationFailure,
+			))
+		}
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.provider.signature_verification_algorithms.supported_schemes()
+	}
+}
+
+/// Verifies the chain against the trusted roots but accepts a certificate
+/// that isn't valid for the server name, e.g. when connecting by IP.
+#[derive(Debug)]
+struct NoHostnameVerification(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for NoHostnameVerification {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		intermediates: &[CertificateDer<'_>],
+		server_name: &ServerName<'_>,
+		ocsp: &[u8],
+		now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		// The name is checked only after the chain has been verified
+		match self.0.verify_server_cert(end_entity, intermediates, server_name, ocsp, now) {
+			Err(rustls::Error::InvalidCertificate(
+				CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+			)) => Ok(ServerCertVerified::assertion()),
+			res => res,
+		}
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		self.0.verify_tls12_signature(message, cert, dss)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		self.0.verify_tls13_signature(message, cert, dss)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.0.supported_verify_schemes()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_pin() {
+		let hex = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+		let pin = parse_pin(hex).unwrap();
+		assert_eq!(pin[0], 0x9f);
+		assert_eq!(pin[31], 0x08);
+
+		let colons = hex
+			.as_bytes()
+			.chunks(2)
+			.map(|c| std::str::from_utf8(c).unwrap().to_uppercase())
+			.collect::<Vec<_>>()
+			.join(":");
+		assert_eq!(parse_pin(&colons), Some(pin));
+
+		assert_eq!(parse_pin("abcd"), None);
+		assert_eq!(parse_pin(&"zz".repeat(32)), None);
+	}
+}
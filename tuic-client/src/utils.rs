@@ -8,7 +8,7 @@ use anyhow::Context;
 use rustls::{RootCertStore, pki_types::CertificateDer};
 use tokio::net;
 // Re-export common types from tuic-core
-pub use tuic_core::{CongestionControl, StackPrefer, UdpRelayMode};
+pub use tuic_core::{CongestionControl, QuicVersion, StackPrefer, UdpRelayMode};
 
 use crate::error::Error;
 
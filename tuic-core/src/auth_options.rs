@@ -0,0 +1,157 @@
+//! Options a client appends to its `Authenticate` command.
+//!
+//! The options follow the `Authenticate` header on the same unidirectional
+//! stream as a list of `type u8 | length u8 | value` records. Servers that
+//! don't know about options stop reading the stream after the header, and
+//! records of an unknown type are skipped, so either side may be older than
+//! the other.
+
+use uuid::Uuid;
+
+use crate::CongestionControl;
+
+/// Congestion controller the client would like the server to use, by name
+const CONGESTION_CONTROL: u8 = 0x01;
+/// The client reads server messages, without a value
+const ACCEPTS_MESSAGES: u8 = 0x02;
+/// The client waits for connect replies, without a value
+const CONNECT_REPLIES: u8 = 0x03;
+/// Token the client's reconnects resume UDP sessions with, 16 bytes
+const RESUME_TOKEN: u8 = 0x04;
+
+/// Longest encoding a server reads, one record of each known type
+pub const MAX_LEN: usize = 2 + u8::MAX as usize + 2 + 2 + 2 + 16;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuthOptions {
+	pub congestion_control: Option<CongestionControl>,
+	/// Whether the server may send the client a message after authentication
+	/// (see [`crate::quinn::MESSAGE_MARKER`])
+	pub accepts_messages: bool,
+	/// Whether the server replies to each `Connect` before relaying it (see
+	/// [`crate::reject`])
+	pub connect_replies: bool,
+	/// Random token the client sends on each of its connections, so the
+	/// server hands UDP sessions kept from a dropped connection only to a
+	/// reconnect of the same client
+	pub resume_token: Option<Uuid>,
+}
+
+impl AuthOptions {
+	pub fn is_empty(&self) -> bool {
+		self.congestion_control.is_none() && !self.accepts_messages && !self.connect_replies && self.resume_token.is_none()
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut buf = Vec::new();
+		if let Some(cc) = self.congestion_control {
+			let name = name(cc).as_bytes();
+			buf.extend([CONGESTION_CONTROL, name.len() as u8]);
+			buf.extend_from_slice(name);
+		}
+		if self.accepts_messages {
+			buf.extend([ACCEPTS_MESSAGES, 0]);
+		}
+		if self.connect_replies {
+			buf.extend([CONNECT_REPLIES, 0]);
+		}
+		if let Some(token) = self.resume_token {
+			buf.extend([RESUME_TOKEN, 16]);
+			buf.extend_from_slice(token.as_bytes());
+		}
+		buf
+	}
+
+	/// Parse the records in `buf`. A truncated record ends the list, and
+	/// values a server can't make sense of are left unset.
+	pub fn decode(mut buf: &[u8]) -> Self {
+		let mut opts = Self::default();
+		while let [kind, len, rest @ ..] = buf {
+			let Some((value, rest)) = rest.split_at_checked(*len as usize) else {
+				break;
+			};
+			match *kind {
+				CONGESTION_CONTROL => {
+					opts.congestion_control = std::str::from_utf8(value).ok().and_then(|name| name.parse().ok())
+				}
+				ACCEPTS_MESSAGES => opts.accepts_messages = true,
+				CONNECT_REPLIES => opts.connect_replies = true,
+				RESUME_TOKEN => opts.resume_token = Uuid::from_slice(value).ok(),
+				_ => {}
+			}
+			buf = rest;
+		}
+		opts
+	}
+}
+
+fn name(cc: CongestionControl) -> &'static str {
+	match cc {
+		CongestionControl::Bbr => "bbr",
+		CongestionControl::Bbr3 => "bbr3",
+		CongestionControl::Cubic => "cubic",
+		CongestionControl::NewReno => "new_reno",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_round_trip() {
+		assert!(AuthOptions::default().encode().is_empty());
+		assert_eq!(AuthOptions::decode(&[]), AuthOptions::default());
+
+		for cc in [
+			CongestionControl::Bbr,
+			CongestionControl::Bbr3,
+			CongestionControl::Cubic,
+			CongestionControl::NewReno,
+		] {
+			let opts = AuthOptions {
+				congestion_control: Some(cc),
+				accepts_messages: true,
+				connect_replies: true,
+				resume_token: Some(Uuid::from_u128(0x0123_4567_89ab_cdef)),
+			};
+			assert_eq!(AuthOptions::decode(&opts.encode()), opts);
+		}
+
+		let opts = AuthOptions {
+			accepts_messages: true,
+			..Default::default()
+		};
+		assert!(!opts.is_empty());
+		assert_eq!(AuthOptions::decode(&opts.encode()), opts);
+
+		let opts = AuthOptions {
+			connect_replies: true,
+			..Default::default()
+		};
+		assert!(!opts.is_empty());
+		assert_eq!(AuthOptions::decode(&opts.encode()), opts);
+
+		let opts = AuthOptions {
+			resume_token: Some(Uuid::from_u128(7)),
+			..Default::default()
+		};
+		assert!(!opts.is_empty());
+		assert_eq!(AuthOptions::decode(&opts.encode()), opts);
+		assert_eq!(AuthOptions::decode(&[RESUME_TOKEN, 3, 1, 2, 3]).resume_token, None);
+	}
+
+	#[test]
+	fn test_decode_tolerates_unknown_and_truncated() {
+		let mut buf = vec![0x7f, 3, 1, 2, 3];
+		buf.extend([CONGESTION_CONTROL, 5]);
+		buf.extend(b"cubic");
+		assert_eq!(AuthOptions::decode(&buf).congestion_control, Some(CongestionControl::Cubic));
+
+		assert_eq!(AuthOptions::decode(&[CONGESTION_CONTROL, 5, b'c']), AuthOptions::default());
+		assert_eq!(
+			AuthOptions::decode(&[CONGESTION_CONTROL, 4, b'v', b'e', b'g', b'a']),
+			AuthOptions::default()
+		);
+	}
+}
@@ -0,0 +1,72 @@
+//! Loading configs that may hold options of newer versions.
+
+use figment::{
+	Figment,
+	providers::Serialized,
+	value::{Dict, Value},
+};
+use serde::de::DeserializeOwned;
+
+/// Extract a config, leaving out options it doesn't know unless `strict`, so
+/// that a config file can serve older versions too. Returns the config and the
+/// dotted paths of the options that were left out.
+pub fn extract<T: DeserializeOwned>(mut figment: Figment, strict: bool) -> Result<(T, Vec<String>), figment::Error> {
+	let mut unknown_options = Vec::new();
+	loop {
+		let err = match figment.extract::<T>() {
+			Ok(config) => return Ok((config, unknown_options)),
+			Err(err) => err,
+		};
+		let figment::error::Kind::UnknownField(field, _) = &err.kind else {
+			return Err(err);
+		};
+		if strict {
+			return Err(err);
+		}
+		// Serde stops at the first unknown option, so each one is taken out in
+		// turn. Later errors no longer name the file, only the option.
+		let mut options: Dict = figment.extract()?;
+		if !remove_option(&mut options, &err.path, field) {
+			return Err(err);
+		}
+		unknown_options.push(
+			err.path
+				.iter()
+				.chain([field])
+				.map(String::as_str)
+				.collect::<Vec<_>>()
+				.join("."),
+		);
+		figment = Figment::from(Serialized::defaults(options));
+	}
+}
+
+/// Remove `field` of the table at `path`, returning whether it was there
+fn remove_option(options: &mut Dict, path: &[String], field: &str) -> bool {
+	match path.split_first() {
+		Some((key, rest)) => options
+			.get_mut(key)
+			.is_some_and(|value| remove_nested_option(value, rest, field)),
+		None => options.remove(field).is_some(),
+	}
+}
+
+fn remove_nested_option(value: &mut Value, path: &[String], field: &str) -> bool {
+	match value {
+		Value::Dict(_, dict) => remove_option(dict, path, field),
+		Value::Array(_, items) => match path
+			.split_first()
+			.and_then(|(index, rest)| Some((index.parse::<usize>().ok()?, rest)))
+		{
+			Some((index, rest)) => items
+				.get_mut(index)
+				.is_some_and(|item| remove_nested_option(item, rest, field)),
+			// Paths don't always number the entries of lists, then the option is
+			// taken out of every entry that has it
+			None => items
+				.iter_mut()
+				.fold(false, |removed, item| remove_nested_option(item, path, field) | removed),
+		},
+		_ => false,
+	}
+}
@@ -0,0 +1,69 @@
+//! Single-packet authorization.
+//!
+//! A server with a knock port ignores every QUIC packet from a source address
+//! until that address sent it a knock: one UDP datagram of
+//! `uuid | unix_seconds | HMAC-SHA256(password, uuid | unix_seconds)`, with
+//! the seconds as a big-endian `u64`. Scanners that don't know a password thus
+//! get no answer at all.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use uuid::Uuid;
+
+use crate::timestamp_auth::hmac_sha256;
+
+/// Length of a knock
+pub const LEN: usize = 16 + 8 + 32;
+
+/// The knock of `uuid` at `time`
+pub fn encode(uuid: Uuid, password: &[u8], time: SystemTime) -> [u8; LEN] {
+	let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	let mut packet = [0; LEN];
+	packet[..16].copy_from_slice(uuid.as_bytes());
+	packet[16..24].copy_from_slice(&secs.to_be_bytes());
+	let mac = hmac_sha256(password, &packet[..24]);
+	packet[24..].copy_from_slice(&mac);
+	packet
+}
+
+/// The user and Unix time a knock claims, before its MAC is checked
+pub fn parse(packet: &[u8]) -> Option<(Uuid, u64)> {
+	let packet: &[u8; LEN] = packet.try_into().ok()?;
+	let uuid = Uuid::from_slice(&packet[..16]).ok()?;
+	let secs = u64::from_be_bytes(packet[16..24].try_into().ok()?);
+	Some((uuid, secs))
+}
+
+/// Whether `packet` was signed with `password`
+pub fn verify(packet: &[u8], password: &[u8]) -> bool {
+	if packet.len() != LEN {
+		return false;
+	}
+	let mac = hmac_sha256(password, &packet[..24]);
+	// Compare in constant time to not leak how much of the MAC matched
+	mac.iter().zip(&packet[24..]).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+
+	#[test]
+	fn test_knock() {
+		let uuid = Uuid::from_u128(0x1234);
+		let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		let packet = encode(uuid, b"password", time);
+
+		assert_eq!(parse(&packet), Some((uuid, 1_700_000_000)));
+		assert!(verify(&packet, b"password"));
+		assert!(!verify(&packet, b"wrong"));
+		assert!(!verify(&packet[..LEN - 1], b"password"));
+
+		let mut forged = packet;
+		forged[23] ^= 1;
+		assert!(!verify(&forged, b"password"));
+		assert_eq!(parse(&packet[1..]), None);
+	}
+}
@@ -19,12 +19,34 @@ pub mod model;
 #[cfg(test)]
 mod tests;
 
+// Loading configs of newer versions
+#[cfg(feature = "config")]
+pub mod config;
+
+// Options appended to the `Authenticate` command
+pub mod auth_options;
+
+// Datagram obfuscation for quinn endpoints
+pub mod obfs;
+
+// Single-packet authorization in front of the QUIC endpoint
+pub mod knock;
+
 // Quinn integration module
 mod quinn_impl;
 pub mod quinn {
 	pub use super::quinn_impl::*;
 }
 
+// Replies to `Connect` commands and policy denials
+pub mod reject;
+
+// Byte sizes in configs
+pub mod size;
+
+// Time-bound authentication
+pub mod timestamp_auth;
+
 // Utility types
 mod utils;
-pub use self::utils::{CongestionControl, StackPrefer, UdpRelayMode, is_private_ip, sniff_from_stream};
+pub use self::utils::{CongestionControl, QuicVersion, StackPrefer, UdpRelayMode, is_private_ip, sniff_from_stream};
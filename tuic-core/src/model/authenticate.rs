@@ -1,4 +1,7 @@
-use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::{
+	fmt::{Debug, Formatter, Result as FmtResult},
+	hint,
+};
 
 use uuid::Uuid;
 
@@ -82,10 +85,17 @@ impl Authenticate<side::Rx> {
 	pub fn is_valid(&self, password: impl AsRef<[u8]>, exporter: &impl KeyingMaterialExporter) -> Result<bool, ExportError> {
 		let Side::Rx(rx) = &self.inner else { unreachable!() };
 		let expected = exporter.export_keying_material(rx.uuid.as_ref(), password.as_ref())?;
-		Ok(rx.token == expected)
+		Ok(constant_time_eq(&rx.token, &expected))
 	}
 }
 
+/// Compares tokens in time independent of where they differ, so timing
+/// doesn't tell how much of a forged token is right
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+	let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+	hint::black_box(diff) == 0
+}
+
 impl Debug for Authenticate<side::Rx> {
 	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
 		let Side::Rx(rx) = &self.inner else { unreachable!() };
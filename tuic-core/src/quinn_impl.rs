@@ -19,12 +19,21 @@ use uuid::Uuid;
 use self::side::Side;
 use crate::{
 	Address, Header, UnmarshalError,
+	auth_options::{self, AuthOptions},
 	model::{
 		AssembleError, Authenticate as AuthenticateModel, Connect as ConnectModel, Connection as ConnectionModel,
 		KeyingMaterialExporter as KeyingMaterialExporterImpl, Packet as PacketModel, side as model_side,
 	},
 };
 
+/// First byte of a unidirectional stream carrying a message from the server,
+/// in place of the TUIC version, followed by the UTF-8 text. Only sent to
+/// clients that authenticated with `accepts_messages`.
+pub const MESSAGE_MARKER: u8 = 0xff;
+
+/// Longest message from the server, in bytes
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
 pub mod side {
 	//! Side marker types for a connection.
 
@@ -90,6 +99,8 @@ impl<R: StreamRx, B: Buffer + Send> StreamRx for AsyncPeekable<R, B> {
 pub struct Connection<Side> {
 	conn: quinn_crate::Connection,
 	model: ConnectionModel<Bytes>,
+	/// Bound on reading the options of an `Authenticate`, server side only
+	auth_timeout: Option<Duration>,
 	_marker: Side,
 }
 
@@ -153,12 +164,18 @@ impl Connection<side::Client> {
 		Self {
 			conn,
 			model: ConnectionModel::new(),
+			auth_timeout: None,
 			_marker: side::Client,
 		}
 	}
 
 	/// Sends an `Authenticate` command.
 	pub async fn authenticate(&self, uuid: Uuid, password: impl AsRef<[u8]>) -> eyre::Result<()> {
+		self.authenticate_with(uuid, password, &AuthOptions::default()).await
+	}
+
+	/// Sends an `Authenticate` command followed by `options`.
+	pub async fn authenticate_with(&self, uuid: Uuid, password: impl AsRef<[u8]>, options: &AuthOptions) -> eyre::Result<()> {
 		let model = self
 			.model
 			.send_authenticate(uuid, password, &self.keying_material_exporter())
@@ -166,6 +183,9 @@ impl Connection<side::Client> {
 
 		let mut send = self.conn.open_uni().await?;
 		model.header().async_marshal(&mut send).await?;
+		if !options.is_empty() {
+			send.write_all(&options.encode()).await?;
+		}
 		send.finish()?;
 		send.stopped().await?;
 		Ok(())
@@ -205,6 +225,11 @@ impl Connection<side::Client> {
 	pub async fn accept_uni_stream<R: StreamRx>(&self, mut recv: R) -> Result<Task<quinn_crate::SendStream, R>, Error> {
 		let header = match Header::async_unmarshal(&mut recv).await {
 			Ok(header) => header,
+			Err(UnmarshalError::InvalidVersion(MESSAGE_MARKER)) => {
+				let mut text = Vec::new();
+				(&mut recv).take(MAX_MESSAGE_LEN as u64).read_to_end(&mut text).await?;
+				return Ok(Task::Message(String::from_utf8_lossy(&text).into_owned()));
+			}
 			Err(err) => return Err(Error::UnmarshalUniStream(err)),
 		};
 
@@ -282,10 +307,33 @@ impl Connection<side::Server> {
 		Self {
 			conn,
 			model: ConnectionModel::new(),
+			auth_timeout: None,
 			_marker: side::Server,
 		}
 	}
 
+	/// Give up on an `Authenticate` whose options take longer than `timeout`
+	/// to arrive, so a client can't hold the stream open forever.
+	pub fn with_auth_timeout(mut self, timeout: Duration) -> Self {
+		self.auth_timeout = Some(timeout);
+		self
+	}
+
+	/// Sends `text` to the client, which must have authenticated with
+	/// `accepts_messages`. Longer text is cut at [`MAX_MESSAGE_LEN`] bytes.
+	pub async fn message(&self, text: &str) -> eyre::Result<()> {
+		let mut text = text.as_bytes();
+		if text.len() > MAX_MESSAGE_LEN {
+			text = &text[..MAX_MESSAGE_LEN];
+		}
+		let mut send = self.conn.open_uni().await?;
+		send.write_all(&[MESSAGE_MARKER]).await?;
+		send.write_all(text).await?;
+		send.finish()?;
+		send.stopped().await?;
+		Ok(())
+	}
+
 	/// Try to parse a unidirectional stream as a TUIC command.
 	///
 	/// The stream should be accepted by `quinn::Connection::accept_uni()`
@@ -299,7 +347,17 @@ impl Connection<side::Server> {
 		match header {
 			Header::Authenticate(auth) => {
 				let model = self.model.recv_authenticate(auth);
-				Ok(Task::Authenticate(Authenticate::new(model, self.keying_material_exporter())))
+				let mut options = Vec::new();
+				let read = (&mut recv).take(auth_options::MAX_LEN as u64).read_to_end(&mut options);
+				match self.auth_timeout {
+					Some(timeout) => tokio::time::timeout(timeout, read).await.map_err(|_| Error::AuthTimeout)??,
+					None => read.await?,
+				};
+				Ok(Task::Authenticate(Authenticate::new(
+					model,
+					self.keying_material_exporter(),
+					AuthOptions::decode(&options),
+				)))
 			}
 			Header::Connect(_) => Err(Error::BadCommandUniStream("connect")),
 			Header::Packet(pkt) => {
@@ -380,11 +438,16 @@ impl<Side> Debug for Connection<Side> {
 pub struct Authenticate {
 	model: AuthenticateModel<model_side::Rx>,
 	exporter: KeyingMaterialExporter,
+	options: AuthOptions,
 }
 
 impl Authenticate {
-	fn new(model: AuthenticateModel<model_side::Rx>, exporter: KeyingMaterialExporter) -> Self {
-		Self { model, exporter }
+	fn new(model: AuthenticateModel<model_side::Rx>, exporter: KeyingMaterialExporter, options: AuthOptions) -> Self {
+		Self {
+			model,
+			exporter,
+			options,
+		}
 	}
 
 	/// The UUID of the client.
@@ -404,6 +467,12 @@ impl Authenticate {
 	pub fn validate(&self, password: impl AsRef<[u8]>) -> Result<bool, crate::model::ExportError> {
 		self.model.is_valid(password, &self.exporter)
 	}
+
+	/// The options the client sent after the command, empty for clients
+	/// that don't send any.
+	pub fn options(&self) -> &AuthOptions {
+		&self.options
+	}
 }
 
 /// A received `Connect` command.
@@ -575,6 +644,8 @@ pub enum Task<S: StreamTx = quinn_crate::SendStream, R: StreamRx = quinn_crate::
 	Packet(Packet<R>),
 	Dissociate(u16),
 	Heartbeat,
+	/// A message from the server
+	Message(String),
 }
 
 #[derive(Debug)]
@@ -620,4 +691,6 @@ pub enum Error {
 	BadCommandDatagram(&'static str, Bytes),
 	#[error(transparent)]
 	QuicWriteError(#[from] quinn_crate::WriteError),
+	#[error("timed out reading the authentication options")]
+	AuthTimeout,
 }
@@ -0,0 +1,145 @@
+//! Replies to `Connect` commands.
+//!
+//! A client that authenticated with
+//! [`AuthOptions::connect_replies`](crate::auth_options::AuthOptions) waits for
+//! the server's reply before it relays a TCP stream. The server writes
+//! [`CONNECT_OK`] at the start of the stream once it reached the target;
+//! when it refuses the request for policy reasons it resets the stream with a
+//! [`Reject`] code instead, saying why and when the client may try again.
+//! Any other reset means the target could not be reached.
+//!
+//! Servers without support for replies ignore the option and relay the
+//! target's bytes right away, so a client can't rely on getting one: it takes
+//! a stream that starts with anything but [`CONNECT_OK`] as connected, and
+//! only waits for a reply for a bounded time.
+
+use std::{fmt, time::Duration};
+
+/// Start of a stream whose target was reached, unlikely to be how the bytes
+/// of a target start
+pub const CONNECT_OK: [u8; 4] = *b"\0TOK";
+
+/// Marks a stream reset code as a [`Reject`]. The reason is in bits 32..40
+/// and the retry-after in seconds, zero for none, in the lowest 32 bits.
+const REJECT_PREFIX: u64 = 0x524a << 40;
+const PREFIX_MASK: u64 = !((1 << 40) - 1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+	/// The ACL blocks the target
+	Acl,
+	/// A traffic or connection quota of the user is used up
+	Quota,
+	/// A reason this side doesn't know yet
+	Other(u8),
+}
+
+impl RejectReason {
+	fn to_u8(self) -> u8 {
+		match self {
+			Self::Acl => 1,
+			Self::Quota => 2,
+			Self::Other(reason) => reason,
+		}
+	}
+
+	fn from_u8(reason: u8) -> Self {
+		match reason {
+			1 => Self::Acl,
+			2 => Self::Quota,
+			reason => Self::Other(reason),
+		}
+	}
+}
+
+impl fmt::Display for RejectReason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Acl => f.write_str("blocked by policy"),
+			Self::Quota => f.write_str("quota exceeded"),
+			Self::Other(reason) => write!(f, "rejected ({reason})"),
+		}
+	}
+}
+
+/// A policy denial of a `Connect`, carried in the stream reset code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reject {
+	pub reason: RejectReason,
+	/// When the same request may succeed again, whole seconds
+	pub retry_after: Option<Duration>,
+}
+
+impl Reject {
+	pub fn new(reason: RejectReason) -> Self {
+		Self {
+			reason,
+			retry_after: None,
+		}
+	}
+
+	pub fn retry_after(mut self, retry_after: Duration) -> Self {
+		self.retry_after = Some(retry_after);
+		self
+	}
+
+	/// The stream reset code, always a valid QUIC varint
+	pub fn code(&self) -> u64 {
+		let secs = self
+			.retry_after
+			.map_or(0, |retry| u32::try_from(retry.as_secs().max(1)).unwrap_or(u32::MAX));
+		REJECT_PREFIX | u64::from(self.reason.to_u8()) << 32 | u64::from(secs)
+	}
+
+	/// The reject of a stream reset code, `None` for other codes
+	pub fn from_code(code: u64) -> Option<Self> {
+		if code & PREFIX_MASK != REJECT_PREFIX {
+			return None;
+		}
+		let secs = code as u32;
+		Some(Self {
+			reason: RejectReason::from_u8((code >> 32) as u8),
+			retry_after: (secs > 0).then(|| Duration::from_secs(secs.into())),
+		})
+	}
+}
+
+impl fmt::Display for Reject {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.retry_after {
+			Some(retry) => write!(f, "{}, retry after {}s", self.reason, retry.as_secs()),
+			None => self.reason.fmt(f),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_code_round_trip() {
+		for reject in [
+			Reject::new(RejectReason::Acl),
+			Reject::new(RejectReason::Quota).retry_after(Duration::from_secs(3600)),
+			Reject::new(RejectReason::Other(3)).retry_after(Duration::from_millis(200)),
+			Reject::new(RejectReason::Other(0x7f)),
+		] {
+			let code = reject.code();
+			assert!(code < 1 << 62);
+			let decoded = Reject::from_code(code).unwrap();
+			assert_eq!(decoded.reason, reject.reason);
+			assert_eq!(
+				decoded.retry_after,
+				reject.retry_after.map(|retry| Duration::from_secs(retry.as_secs().max(1)))
+			);
+		}
+	}
+
+	#[test]
+	fn test_other_codes_are_not_rejects() {
+		for code in [0, 6001, 7002, u64::from(u32::MAX), 1 << 61] {
+			assert_eq!(Reject::from_code(code), None);
+		}
+	}
+}
@@ -0,0 +1,156 @@
+//! Byte sizes in configs.
+//!
+//! Use with `#[serde(with = "tuic_core::size")]` on an integer field, or
+//! `tuic_core::size::option` on an `Option` of one. A size is either a plain
+//! number of bytes or a string such as `"1500"`, `"64KiB"`, `"1.5 MB"` or
+//! `"16mib"`: `K`, `M`, `G` and `T` with `B` are powers of 1000, with `iB`
+//! powers of 1024, and a bare `K`, `M`, .. is the binary one. Sizes are
+//! written back as a number of bytes.
+
+use std::fmt;
+
+use serde::{Deserializer, Serializer, de};
+
+/// Parse a size like `"64KiB"` into bytes
+pub fn parse(size: &str) -> Result<u64, String> {
+	let size = size.trim();
+	let split = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+	let (number, unit) = size.split_at(split);
+	let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+		"" | "b" => 1,
+		"kb" => 1000,
+		"mb" => 1000_u64.pow(2),
+		"gb" => 1000_u64.pow(3),
+		"tb" => 1000_u64.pow(4),
+		"k" | "kib" => 1 << 10,
+		"m" | "mib" => 1 << 20,
+		"g" | "gib" => 1 << 30,
+		"t" | "tib" => 1 << 40,
+		_ => return Err(format!("invalid size {size:?}, unknown unit {unit:?}")),
+	};
+	let invalid = || format!("invalid size {size:?}, expected a number of bytes like \"1500\" or \"64KiB\"");
+	let bytes = match number.split_once('.') {
+		None => number.parse::<u64>().map_err(|_| invalid())?.checked_mul(multiplier),
+		Some(_) => {
+			let bytes = number.parse::<f64>().map_err(|_| invalid())? * multiplier as f64;
+			// Rounded, as a fraction of a byte can't be meant
+			(bytes < u64::MAX as f64).then(|| bytes.round() as u64)
+		}
+	};
+	bytes.ok_or_else(|| format!("size {size:?} is too large"))
+}
+
+pub fn serialize<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+	S: Serializer,
+	T: Copy,
+	u64: TryFrom<T>,
+{
+	let bytes = u64::try_from(*value).map_err(|_| serde::ser::Error::custom("size out of range"))?;
+	serializer.serialize_u64(bytes)
+}
+
+pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+	D: Deserializer<'de>,
+	T: TryFrom<u64>,
+{
+	let bytes = deserializer.deserialize_any(SizeVisitor)?;
+	T::try_from(bytes).map_err(|_| de::Error::custom(format!("size of {bytes} bytes is out of range")))
+}
+
+/// Sizes in an `Option`
+pub mod option {
+	use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+	pub fn serialize<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+		T: Copy,
+		u64: TryFrom<T>,
+	{
+		#[derive(Serialize)]
+		struct Size<T: Copy>(#[serde(with = "crate::size")] T)
+		where
+			u64: TryFrom<T>;
+
+		value.map(Size).serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+	where
+		D: Deserializer<'de>,
+		T: TryFrom<u64>,
+	{
+		#[derive(Deserialize)]
+		struct Size<T: TryFrom<u64>>(#[serde(with = "crate::size")] T);
+
+		Ok(Option::<Size<T>>::deserialize(deserializer)?.map(|Size(value)| value))
+	}
+}
+
+struct SizeVisitor;
+
+impl de::Visitor<'_> for SizeVisitor {
+	type Value = u64;
+
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("a number of bytes or a size like \"64KiB\"")
+	}
+
+	fn visit_u64<E: de::Error>(self, bytes: u64) -> Result<u64, E> {
+		Ok(bytes)
+	}
+
+	fn visit_i64<E: de::Error>(self, bytes: i64) -> Result<u64, E> {
+		u64::try_from(bytes).map_err(|_| E::custom(format!("size {bytes} is negative")))
+	}
+
+	fn visit_str<E: de::Error>(self, size: &str) -> Result<u64, E> {
+		parse(size).map_err(E::custom)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde::{Deserialize, Serialize};
+
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(parse("1500"), Ok(1500));
+		assert_eq!(parse("64KiB"), Ok(65536));
+		assert_eq!(parse("64k"), Ok(65536));
+		assert_eq!(parse("1.5 MB"), Ok(1_500_000));
+		assert_eq!(parse("16mib"), Ok(16 << 20));
+		assert_eq!(parse(" 2GB "), Ok(2_000_000_000));
+		for size in ["", "KiB", "12 parsecs", "1.2.3MB", "-1", "20000000TiB"] {
+			assert!(parse(size).is_err(), "{size}");
+		}
+	}
+
+	#[derive(Serialize, Deserialize, Debug, PartialEq)]
+	struct Sizes {
+		#[serde(with = "crate::size")]
+		window: u32,
+		#[serde(default, with = "crate::size::option")]
+		quota: Option<usize>,
+	}
+
+	#[test]
+	fn test_serde() {
+		let sizes: Sizes = serde_json::from_str(r#"{"window": "8MiB", "quota": 1000}"#).unwrap();
+		assert_eq!(
+			sizes,
+			Sizes {
+				window: 8 << 20,
+				quota: Some(1000)
+			}
+		);
+		assert_eq!(serde_json::to_string(&sizes).unwrap(), r#"{"window":8388608,"quota":1000}"#);
+		let sizes: Sizes = serde_json::from_str(r#"{"window": 1}"#).unwrap();
+		assert_eq!(sizes.quota, None);
+		assert!(serde_json::from_str::<Sizes>(r#"{"window": "8GiB"}"#).is_err());
+	}
+}
@@ -0,0 +1,168 @@
+//! Time-bound authentication.
+//!
+//! In [`AuthMode::Timestamp`] the client derives its authentication token
+//! from `HMAC-SHA256(password, unix_time / step)` in place of the password,
+//! like a TOTP, so the material changes every `step` ([`DEFAULT_STEP`] unless
+//! configured) and a captured token stops being accepted once it falls
+//! outside the server's clock skew tolerance. Client and server must use the
+//! same step.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// How the authentication token is derived from the password
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+	/// From the password itself
+	#[default]
+	Token,
+	/// From the password signed with the current time step
+	Timestamp,
+}
+
+/// The time step of tokens unless configured otherwise
+pub const DEFAULT_STEP: Duration = Duration::from_secs(60);
+
+/// Shortest step client and server may be configured with
+pub const MIN_STEP: Duration = Duration::from_secs(10);
+
+/// Longest step client and server may be configured with, as a captured token
+/// is good for a whole step
+pub const MAX_STEP: Duration = Duration::from_secs(3600);
+
+/// Whether `step` is whole seconds within [`MIN_STEP`] and [`MAX_STEP`]
+pub fn is_valid_step(step: Duration) -> bool {
+	(MIN_STEP..=MAX_STEP).contains(&step) && step.subsec_nanos() == 0
+}
+
+/// Clock skew a server may tolerate at most, as each step within it costs a
+/// check of every token
+pub const MAX_SKEW: Duration = Duration::from_secs(600);
+
+/// Steps either way of now [`clock_offset`] tries at most
+pub const MAX_PROBE_STEPS: u32 = 60;
+
+const BLOCK_LEN: usize = 64;
+
+/// Steps of `step` since the Unix epoch at `time`. Steps are whole seconds,
+/// at least one.
+pub fn step_at(time: SystemTime, step: Duration) -> u64 {
+	time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / step.as_secs().max(1)
+}
+
+/// The password to authenticate with during step `counter`
+pub fn password_at(password: &[u8], counter: u64) -> [u8; 32] {
+	hmac_sha256(password, &counter.to_be_bytes())
+}
+
+/// Steps a server allowing `skew` between clocks accepts at `now`
+pub fn accepted_steps(now: SystemTime, skew: Duration, step: Duration) -> impl Iterator<Item = u64> {
+	let earliest = now.checked_sub(skew).unwrap_or(UNIX_EPOCH);
+	let latest = now.checked_add(skew).unwrap_or(now);
+	step_at(earliest, step)..=step_at(latest, step)
+}
+
+/// Seconds the clock of a client is ahead of `now` (behind, if negative),
+/// judged by the step within `range` of `now`, and no more than
+/// [`MAX_PROBE_STEPS`] off, whose password `signed` accepts. `None` if there
+/// is none, e.g. for a wrong password.
+pub fn clock_offset(
+	password: &[u8],
+	now: SystemTime,
+	range: Duration,
+	step: Duration,
+	mut signed: impl FnMut(&[u8; 32]) -> bool,
+) -> Option<i64> {
+	let range = range.min(Duration::from_secs(step.as_secs().max(1)) * MAX_PROBE_STEPS);
+	let counter = accepted_steps(now, range, step).find(|counter| signed(&password_at(password, *counter)))?;
+	Some((counter as i64 - step_at(now, step) as i64) * step.as_secs().max(1) as i64)
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+	let mut block = [0u8; BLOCK_LEN];
+	if key.len() > BLOCK_LEN {
+		let digest: [u8; 32] = Sha256::digest(key).into();
+		block[..digest.len()].copy_from_slice(&digest);
+	} else {
+		block[..key.len()].copy_from_slice(key);
+	}
+
+	let mut inner = Sha256::new();
+	inner.update(block.map(|b| b ^ 0x36));
+	inner.update(message);
+	let inner: [u8; 32] = inner.finalize().into();
+
+	let mut outer = Sha256::new();
+	outer.update(block.map(|b| b ^ 0x5c));
+	outer.update(inner);
+	outer.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_hmac_sha256() {
+		// RFC 4231, test case 2
+		let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+		assert_eq!(
+			mac.iter().map(|b| format!("{b:02x}")).collect::<String>(),
+			"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+		);
+	}
+
+	#[test]
+	fn test_accepted_steps() {
+		let now = UNIX_EPOCH + Duration::from_secs(600 * 60 + 30);
+		assert_eq!(step_at(now, DEFAULT_STEP), 600);
+		assert_eq!(
+			accepted_steps(now, Duration::from_secs(60), DEFAULT_STEP).collect::<Vec<_>>(),
+			[599, 600, 601]
+		);
+		assert_eq!(accepted_steps(now, Duration::ZERO, DEFAULT_STEP).collect::<Vec<_>>(), [600]);
+		assert_ne!(password_at(b"password", 600), password_at(b"password", 601));
+
+		let step = Duration::from_secs(30);
+		assert_eq!(step_at(now, step), 1201);
+		assert_eq!(
+			accepted_steps(now, Duration::from_secs(30), step).collect::<Vec<_>>(),
+			[1200, 1201, 1202]
+		);
+
+		assert_eq!(accepted_steps(now, Duration::MAX, DEFAULT_STEP).count(), 601);
+	}
+
+	#[test]
+	fn test_clock_offset() {
+		let now = UNIX_EPOCH + Duration::from_secs(600 * 60);
+		let range = Duration::from_secs(3600);
+		let behind = password_at(b"password", 590);
+		assert_eq!(
+			clock_offset(b"password", now, range, DEFAULT_STEP, |pw| *pw == behind),
+			Some(-600)
+		);
+		let ahead = password_at(b"password", 605);
+		assert_eq!(
+			clock_offset(b"password", now, range, DEFAULT_STEP, |pw| *pw == ahead),
+			Some(300)
+		);
+		assert_eq!(clock_offset(b"wrong", now, range, DEFAULT_STEP, |pw| *pw == ahead), None);
+
+		let ahead = password_at(b"password", 1205);
+		assert_eq!(
+			clock_offset(b"password", now, range, Duration::from_secs(30), |pw| *pw == ahead),
+			Some(150)
+		);
+
+		// At most MAX_PROBE_STEPS steps are tried, however long the range
+		let step = Duration::from_secs(10);
+		let ahead = password_at(b"password", 3660);
+		assert_eq!(clock_offset(b"password", now, range, step, |pw| *pw == ahead), Some(600));
+		let ahead = password_at(b"password", 3661);
+		assert_eq!(clock_offset(b"password", now, range, step, |pw| *pw == ahead), None);
+	}
+}
@@ -123,6 +123,111 @@ impl FromStr for StackPrefer {
 	}
 }
 
+/// QUIC wire version a client offers or a server accepts.
+///
+/// Parsed from `"v1"`, `"v2"`, `"draft-29"` to `"draft-34"`, or a raw
+/// hexadecimal version number such as `"0xff00001d"`. Only versions in
+/// [`QuicVersion::SUPPORTED`] can actually be negotiated by the QUIC stack.
+///
+/// # Examples
+///
+/// ```
+/// use tuic_core::QuicVersion;
+///
+/// let version: QuicVersion = "draft-29".parse().unwrap();
+/// assert_eq!(version.get(), 0xff00_001d);
+/// assert!(version.is_supported());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct QuicVersion(u32);
+
+impl QuicVersion {
+	pub const V1: Self = Self(0x0000_0001);
+	pub const V2: Self = Self(0x6b33_43cf);
+	pub const DRAFT_29: Self = Self(0xff00_001d);
+	pub const DRAFT_30: Self = Self(0xff00_001e);
+	pub const DRAFT_31: Self = Self(0xff00_001f);
+	pub const DRAFT_32: Self = Self(0xff00_0020);
+	pub const DRAFT_33: Self = Self(0xff00_0021);
+	pub const DRAFT_34: Self = Self(0xff00_0022);
+
+	/// Versions the bundled QUIC implementation can negotiate
+	pub const SUPPORTED: &'static [Self] = &[
+		Self::V1,
+		Self::DRAFT_29,
+		Self::DRAFT_30,
+		Self::DRAFT_31,
+		Self::DRAFT_32,
+		Self::DRAFT_33,
+		Self::DRAFT_34,
+	];
+
+	pub const fn new(version: u32) -> Self {
+		Self(version)
+	}
+
+	/// The version number as sent on the wire
+	pub const fn get(self) -> u32 {
+		self.0
+	}
+
+	pub fn is_supported(self) -> bool {
+		Self::SUPPORTED.contains(&self)
+	}
+}
+
+impl Display for QuicVersion {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		match *self {
+			Self::V1 => write!(f, "v1"),
+			Self::V2 => write!(f, "v2"),
+			Self(v @ 0xff00_001d..=0xff00_0022) => write!(f, "draft-{}", v & 0xff),
+			Self(v) => write!(f, "{v:#010x}"),
+		}
+	}
+}
+
+impl FromStr for QuicVersion {
+	type Err = &'static str;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.to_ascii_lowercase();
+		match s.as_str() {
+			"v1" | "1" => Ok(Self::V1),
+			"v2" | "2" => Ok(Self::V2),
+			_ => {
+				if let Some(draft) = s.strip_prefix("draft-").or_else(|| s.strip_prefix("draft")) {
+					match draft.parse::<u32>() {
+						Ok(n @ 29..=34) => Ok(Self(0xff00_0000 | n)),
+						_ => Err("unknown QUIC draft version"),
+					}
+				} else if let Some(hex) = s.strip_prefix("0x") {
+					u32::from_str_radix(hex, 16)
+						.map(Self)
+						.map_err(|_| "invalid QUIC version number")
+				} else {
+					Err("invalid QUIC version")
+				}
+			}
+		}
+	}
+}
+
+impl TryFrom<String> for QuicVersion {
+	type Error = &'static str;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		value.parse()
+	}
+}
+
+impl From<QuicVersion> for String {
+	fn from(value: QuicVersion) -> Self {
+		value.to_string()
+	}
+}
+
 /// Check if an IP address is private (LAN address)
 ///
 /// Returns `true` for:
@@ -368,6 +473,22 @@ mod tests {
 		let _sni = result.unwrap();
 	}
 
+	#[test]
+	fn test_quic_version_parse() {
+		assert_eq!("v1".parse::<QuicVersion>(), Ok(QuicVersion::V1));
+		assert_eq!("V2".parse::<QuicVersion>(), Ok(QuicVersion::V2));
+		assert_eq!("draft-29".parse::<QuicVersion>(), Ok(QuicVersion::DRAFT_29));
+		assert_eq!("draft32".parse::<QuicVersion>(), Ok(QuicVersion::DRAFT_32));
+		assert_eq!("0xff000021".parse::<QuicVersion>(), Ok(QuicVersion::DRAFT_33));
+		assert!("draft-28".parse::<QuicVersion>().is_err());
+		assert!("v3".parse::<QuicVersion>().is_err());
+
+		assert_eq!(QuicVersion::DRAFT_29.to_string(), "draft-29");
+		assert_eq!(QuicVersion::new(0x1a2a_3a4a).to_string(), "0x1a2a3a4a");
+		assert!(QuicVersion::V1.is_supported());
+		assert!(!QuicVersion::V2.is_supported());
+	}
+
 	#[test]
 	fn test_extract_sni_no_tls() {
 		// Non-TLS data
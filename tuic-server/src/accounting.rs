@@ -0,0 +1,225 @@
+//! Export of per-user traffic to an external billing system.
+//!
+//! Every `interval`, the bytes each user moved since the previous report are
+//! POSTed to `accounting.url` as JSON. A report stays in the spool file until
+//! the endpoint accepted it with a success status and is sent again, oldest
+//! first, at every later interval and after a restart. Reports are thus
+//! delivered at least once, and receivers deduplicate them by `id`.
+
+use std::{
+	collections::VecDeque,
+	fs, io,
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{Client, header};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{AppContext, config::AccountingConfig, stats::TrafficMeter};
+
+/// Deadline of a single POST
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Traffic of the users active between `start` and `end` (Unix seconds)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+	pub id: Uuid,
+	pub start: u64,
+	pub end: u64,
+	pub users: Vec<UserDelta>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UserDelta {
+	pub user: Uuid,
+	/// Bytes sent by the user's clients
+	pub tx: u64,
+	/// Bytes received by the user's clients
+	pub rx: u64,
+}
+
+/// Reports not delivered yet, mirrored to a file
+struct Spool {
+	path: PathBuf,
+	pending: VecDeque<Report>,
+}
+
+impl Spool {
+	fn load(path: &Path) -> io::Result<Self> {
+		let pending = match fs::read(path) {
+			Ok(content) => serde_json::from_slice(&content)?,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+			Err(err) => return Err(err),
+		};
+		Ok(Self {
+			path: path.to_owned(),
+			pending,
+		})
+	}
+
+	/// Write the pending reports, replacing the file only once complete. The
+	/// file is written on the blocking pool, not on a runtime worker.
+	async fn save(&self) -> io::Result<()> {
+		let path = self.path.clone();
+		let content = serde_json::to_vec(&self.pending)?;
+		tokio::task::spawn_blocking(move || {
+			let mut tmp = path.clone().into_os_string();
+			tmp.push(".tmp");
+			fs::write(&tmp, content)?;
+			fs::rename(tmp, &path)
+		})
+		.await
+		.map_err(io::Error::other)?
+	}
+
+	async fn push(&mut self, report: Report) {
+		self.pending.push_back(report);
+		if let Err(err) = self.save().await {
+			warn!("[accounting] failed to write {}: {err}", self.path.display());
+		}
+	}
+
+	/// Send pending reports in order, stopping at the first failure
+	async fn flush(&mut self, client: &Client, cfg: &AccountingConfig) {
+		while let Some(report) = self.pending.front() {
+			if let Err(err) = send(client, cfg, report).await {
+				warn!(
+					"[accounting] delivering report {} failed, {} report(s) pending: {err}",
+					report.id,
+					self.pending.len()
+				);
+				return;
+			}
+			debug!("[accounting] delivered report {}", report.id);
+			self.pending.pop_front();
+			if let Err(err) = self.save().await {
+				warn!("[accounting] failed to write {}: {err}", self.path.display());
+			}
+		}
+	}
+}
+
+async fn send(client: &Client, cfg: &AccountingConfig, report: &Report) -> eyre::Result<()> {
+	let mut request = client
+		.post(&cfg.url)
+		.header(header::CONTENT_TYPE, "application/json")
+		.body(serde_json::to_vec(report)?);
+	if let Some(token) = &cfg.token {
+		request = request.bearer_auth(token);
+	}
+	request.send().await?.error_for_status()?;
+	Ok(())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// The report of `delta`, `None` when nobody moved any traffic
+fn report(delta: Vec<(Uuid, usize, usize)>, start: SystemTime, end: SystemTime) -> Option<Report> {
+	if delta.is_empty() {
+		return None;
+	}
+	Some(Report {
+		id: Uuid::new_v4(),
+		start: unix_secs(start),
+		end: unix_secs(end),
+		users: delta
+			.into_iter()
+			.map(|(user, tx, rx)| UserDelta {
+				user,
+				tx: tx as u64,
+				rx: rx as u64,
+			})
+			.collect(),
+	})
+}
+
+/// Report traffic to `[accounting]`, if configured, until the server shuts
+/// down. The traffic since the last report is spooled on shutdown.
+pub async fn start(ctx: Arc<AppContext>) {
+	let Some(cfg) = ctx.cfg.accounting.clone() else {
+		return;
+	};
+	let mut spool = match Spool::load(&cfg.spool) {
+		Ok(spool) => spool,
+		Err(err) => {
+			warn!("[accounting] failed to read {}: {err}", cfg.spool.display());
+			return;
+		}
+	};
+	let client = match Client::builder().timeout(REQUEST_TIMEOUT).build() {
+		Ok(client) => client,
+		Err(err) => {
+			warn!("[accounting] failed to create the HTTP client: {err}");
+			return;
+		}
+	};
+
+	let mut meter = TrafficMeter::default();
+	meter.delta(&ctx.users.all_stats());
+	let mut start = SystemTime::now();
+	let mut ticker = time::interval(cfg.interval);
+	ticker.tick().await;
+
+	loop {
+		let shutdown = tokio::select! {
+			_ = ticker.tick() => false,
+			_ = ctx.cancel.cancelled() => true,
+		};
+
+		let end = SystemTime::now();
+		if let Some(report) = report(meter.delta(&ctx.users.all_stats()), start, end) {
+			spool.push(report).await;
+		}
+		start = end;
+		if shutdown {
+			return;
+		}
+		spool.flush(&client, &cfg).await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_report() {
+		let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		let end = start + Duration::from_secs(60);
+		assert_eq!(report(Vec::new(), start, end), None);
+
+		let user = Uuid::from_u128(1);
+		let report = report(vec![(user, 100, 2000)], start, end).unwrap();
+		let json = serde_json::to_value(&report).unwrap();
+		assert_eq!(json["start"], 1_700_000_000);
+		assert_eq!(json["end"], 1_700_000_060);
+		assert_eq!(
+			json["users"],
+			serde_json::json!([{"user": user.to_string(), "tx": 100, "rx": 2000}])
+		);
+	}
+
+	#[tokio::test]
+	async fn test_spool_survives_restart() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("accounting.json");
+		let mut spool = Spool::load(&path).unwrap();
+		assert!(spool.pending.is_empty());
+
+		let start = UNIX_EPOCH;
+		for user in 1..=2 {
+			spool
+				.push(report(vec![(Uuid::from_u128(user), 1, 1)], start, start).unwrap())
+				.await;
+		}
+		let reloaded = Spool::load(&path).unwrap();
+		assert_eq!(reloaded.pending, spool.pending);
+	}
+}
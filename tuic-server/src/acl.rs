@@ -1,4 +1,4 @@
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use derive_more::Display;
 use pest::Parser;
@@ -121,11 +121,18 @@ impl AclRule {
 		self.matches_address(addr.ip()).await && self.matches_port(port, is_tcp)
 	}
 
-	/// Check if the rule matches the given IP address
+	/// Check if the rule matches the given IP address. An IPv6 address that
+	/// embeds an IPv4 one (see [`embedded_ipv4`]) matches as that too, and
+	/// can't be used to get around rules for IPv4 addresses.
 	async fn matches_address(&self, ip: IpAddr) -> bool {
+		let canonical = embedded_ipv4(ip);
 		match &self.addr {
-			AclAddress::Ip(ip_str) => ip_str.parse::<IpAddr>() == Ok(ip),
-			AclAddress::Cidr(cidr_str) => cidr_str.parse::<ip_network::IpNetwork>().is_ok_and(|net| net.contains(ip)),
+			AclAddress::Ip(ip_str) => ip_str
+				.parse::<IpAddr>()
+				.is_ok_and(|rule_ip| rule_ip == ip || rule_ip == canonical),
+			AclAddress::Cidr(cidr_str) => cidr_str
+				.parse::<ip_network::IpNetwork>()
+				.is_ok_and(|net| net.contains(ip) || net.contains(canonical)),
 			AclAddress::Domain(domain) => {
 				if domain.eq_ignore_ascii_case("localhost") {
 					Self::is_loopback(ip)
@@ -146,7 +153,7 @@ impl AclRule {
 				}
 			}
 			AclAddress::Localhost => Self::is_loopback(ip),
-			AclAddress::Private => is_private_ip(&ip),
+			AclAddress::Private => is_private_ip(&canonical),
 			AclAddress::Any => true,
 		}
 	}
@@ -159,13 +166,30 @@ impl AclRule {
 		}
 	}
 
-	/// Check if an IP address is loopback (localhost)
+	/// Check if an IP address reaches this host: loopback, or unspecified
+	/// (`0.0.0.0`, `::`), which connects to the host itself
 	#[inline]
-	fn is_loopback(ip: IpAddr) -> bool {
-		match ip {
-			IpAddr::V4(v4) => v4.is_loopback(),
-			IpAddr::V6(v6) => v6.is_loopback(),
-		}
+	pub(crate) fn is_loopback(ip: IpAddr) -> bool {
+		let ip = embedded_ipv4(ip);
+		ip.is_loopback() || ip.is_unspecified()
+	}
+}
+
+/// Returns the IPv4 address an IPv6 one reaches, or the address itself when it
+/// embeds none: IPv4-mapped (`::ffff:0:0/96`), IPv4-compatible (`::/96`, but
+/// not `::` and `::1`), NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`)
+/// addresses all carry an IPv4 destination.
+pub(crate) fn embedded_ipv4(ip: IpAddr) -> IpAddr {
+	let IpAddr::V6(v6) = ip.to_canonical() else {
+		return ip;
+	};
+	let seg = v6.segments();
+	let low = |hi: u16, lo: u16| IpAddr::V4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)));
+	match seg {
+		[0, 0, 0, 0, 0, 0, hi, lo] if hi != 0 || lo > 1 => low(hi, lo),
+		[0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => low(hi, lo),
+		[0x2002, hi, lo, ..] => low(hi, lo),
+		_ => IpAddr::V6(v6),
 	}
 }
 
@@ -648,6 +672,78 @@ mod tests {
 		assert!(!rule.matching(v4("203.0.113.1", 0), 0, true).await);
 	}
 
+	#[tokio::test]
+	async fn mapped_ipv4_matches_as_ipv4() {
+		let rule = |addr| AclRule {
+			addr,
+			ports: None,
+			outbound: "drop".to_string(),
+			hijack: None,
+		};
+
+		let mapped_private = v6("::ffff:10.0.0.1", 0);
+		assert!(rule(AclAddress::Private).matching(mapped_private, 0, true).await);
+		assert!(
+			rule(AclAddress::Cidr("10.0.0.0/8".into()))
+				.matching(mapped_private, 0, true)
+				.await
+		);
+		assert!(
+			rule(AclAddress::Cidr("::ffff:0:0/96".into()))
+				.matching(mapped_private, 0, true)
+				.await
+		);
+		assert!(
+			rule(AclAddress::Ip("10.0.0.1".into()))
+				.matching(mapped_private, 0, true)
+				.await
+		);
+		assert!(!rule(AclAddress::Private).matching(v6("::ffff:8.8.8.8", 0), 0, true).await);
+
+		let localhost = rule(AclAddress::Localhost);
+		assert!(localhost.matching(v6("::ffff:127.0.0.1", 0), 0, true).await);
+		assert!(localhost.matching(v4("0.0.0.0", 0), 0, true).await);
+		assert!(localhost.matching(v6("::", 0), 0, true).await);
+		assert!(!localhost.matching(v6("::ffff:1.1.1.1", 0), 0, true).await);
+	}
+
+	#[tokio::test]
+	async fn embedded_ipv4_matches_as_ipv4() {
+		let rule = |addr| AclRule {
+			addr,
+			ports: None,
+			outbound: "drop".to_string(),
+			hijack: None,
+		};
+
+		let private = rule(AclAddress::Private);
+		let cidr = rule(AclAddress::Cidr("10.0.0.0/8".into()));
+		let localhost = rule(AclAddress::Localhost);
+		for addr in ["64:ff9b::10.0.0.1", "2002:a00:1::", "2002:a00:1:1::1", "::10.0.0.1"] {
+			assert!(private.matching(v6(addr, 0), 0, true).await, "{addr}");
+			assert!(cidr.matching(v6(addr, 0), 0, true).await, "{addr}");
+		}
+		for addr in ["64:ff9b::127.0.0.1", "2002:7f00:1::", "::127.0.0.1", "64:ff9b::", "2002::"] {
+			assert!(localhost.matching(v6(addr, 0), 0, true).await, "{addr}");
+		}
+		assert!(localhost.matching(v6("::1", 0), 0, true).await);
+		assert!(
+			rule(AclAddress::Ip("10.0.0.1".into()))
+				.matching(v6("64:ff9b::a00:1", 0), 0, true)
+				.await
+		);
+		assert!(
+			rule(AclAddress::Cidr("64:ff9b::/96".into()))
+				.matching(v6("64:ff9b::a00:1", 0), 0, true)
+				.await
+		);
+
+		for addr in ["64:ff9b::8.8.8.8", "2002:808:808::", "::8.8.8.8", "64:ff9b:1::10.0.0.1"] {
+			assert!(!private.matching(v6(addr, 0), 0, true).await, "{addr}");
+			assert!(!localhost.matching(v6(addr, 0), 0, true).await, "{addr}");
+		}
+	}
+
 	#[tokio::test]
 	async fn private_match_ipv6() {
 		let rule = AclRule {
@@ -1076,6 +1172,19 @@ mod tests {
 		Ok(())
 	}
 
+	#[tokio::test]
+	async fn parse_any_address_deny_ports() -> eyre::Result<()> {
+		let rule = parse_acl_rule("drop * tcp/25,tcp/465,tcp/587")?;
+
+		assert_eq!(rule.outbound, "drop");
+		assert_eq!(rule.addr, AclAddress::Any);
+		assert!(rule.matching(v4("203.0.113.1", 25), 25, true).await);
+		assert!(rule.matching(v6("::ffff:203.0.113.1", 587), 587, true).await);
+		assert!(!rule.matching(v4("203.0.113.1", 25), 25, false).await);
+		assert!(!rule.matching(v4("203.0.113.1", 443), 443, true).await);
+		Ok(())
+	}
+
 	#[tokio::test]
 	async fn parse_with_hijack() -> eyre::Result<()> {
 		let rule_str = "redirect 8.8.8.8 tcp/53 10.0.0.1";
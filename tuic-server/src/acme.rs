@@ -1,15 +1,22 @@
-use std::{path::Path, sync::Arc};
+use std::{path::Path, sync::Arc, time::Duration};
 
 use arc_swap::ArcSwapOption;
 use axum::Router;
 use eyre::{Context, Result};
-use rustls::server::ResolvesServerCert;
-use rustls_acme::{AcmeConfig, UseChallenge::Http01, caches::DirCache};
+use rustls::{ServerConfig, ServerConnection, server::ResolvesServerCert};
+use rustls_acme::{AcmeConfig, UseChallenge, caches::DirCache};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+};
 use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
-use crate::AppContext;
+use crate::{AppContext, config::AcmeChallenge};
+
+/// How long a validation server gets to complete a TLS-ALPN-01 handshake
+const CHALLENGE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Check if a domain name is valid for ACME certificate issuance.
 pub fn is_valid_domain(hostname: &str) -> bool {
@@ -33,6 +40,7 @@ pub async fn start_acme(
 	ctx: Arc<AppContext>,
 	hostname: &str,
 	acme_email: &str,
+	challenge: AcmeChallenge,
 	cache_dir: &Path,
 ) -> Result<Arc<dyn ResolvesServerCert>> {
 	if !is_valid_domain(hostname) {
@@ -55,12 +63,20 @@ pub async fn start_acme(
 		.contact(vec![contact])
 		.cache(DirCache::new(cache_dir.to_path_buf()))
 		.directory_lets_encrypt(true)
-		.challenge_type(Http01)
+		.challenge_type(match challenge {
+			AcmeChallenge::Http01 => UseChallenge::Http01,
+			AcmeChallenge::TlsAlpn01 => UseChallenge::TlsAlpn01,
+		})
 		.state();
 
 	let default_config = state.default_rustls_config();
 	let resolver = default_config.cert_resolver.clone();
 
+	// Renewals need the challenge server too, so it runs as long as the server
+	if challenge == AcmeChallenge::TlsAlpn01 {
+		serve_tls_alpn(ctx.cancel.child_token(), state.challenge_rustls_config()).await?;
+	}
+
 	let axum_cancel: ArcSwapOption<CancellationToken> = None.into();
 
 	// Drive the ACME state machine in background
@@ -69,7 +85,7 @@ pub async fn start_acme(
 			match state.next().await {
 				Some(Ok(event)) => match event {
 					// Requesting certificate for the first time or renewing an existing one
-					rustls_acme::EventOk::AccountCacheStore => {
+					rustls_acme::EventOk::AccountCacheStore if challenge == AcmeChallenge::Http01 => {
 						info!("ACME event: AccountCacheStore");
 						let cancel = Arc::new(ctx.cancel.child_token());
 						axum_cancel.swap(Some(cancel.clone())).inspect(|v| v.cancel());
@@ -114,6 +130,67 @@ async fn spawn_axum(cancel: CancellationToken, router: Router) -> eyre::Result<(
 	Ok(())
 }
 
+/// Answer TLS-ALPN-01 challenges on TCP port 443 until cancelled
+async fn serve_tls_alpn(cancel: CancellationToken, config: Arc<ServerConfig>) -> Result<()> {
+	let listener = TcpListener::bind("[::]:443")
+		.await
+		.context("Failed to bind to TCP port 443 for ACME TLS-ALPN-01 challenges")?;
+	info!("Started ACME TLS-ALPN-01 challenge server on TCP port 443");
+	tokio::spawn(async move {
+		loop {
+			let (stream, addr) = tokio::select! {
+				res = listener.accept() => match res {
+					Ok(accepted) => accepted,
+					Err(e) => {
+						error!("ACME TLS-ALPN-01 challenge server error: {:?}", e);
+						continue;
+					}
+				},
+				_ = cancel.cancelled() => break,
+			};
+			let config = config.clone();
+			tokio::spawn(async move {
+				match tokio::time::timeout(CHALLENGE_HANDSHAKE_TIMEOUT, handshake(stream, config)).await {
+					Ok(Ok(())) => info!("Answered ACME TLS-ALPN-01 challenge from {addr}"),
+					Ok(Err(e)) => debug!("ACME TLS-ALPN-01 handshake with {addr} failed: {e:#}"),
+					Err(_) => debug!("ACME TLS-ALPN-01 handshake with {addr} timed out"),
+				}
+			});
+		}
+	});
+	Ok(())
+}
+
+/// Complete a TLS handshake, which is all a TLS-ALPN-01 validation needs
+async fn handshake(mut stream: TcpStream, config: Arc<ServerConfig>) -> Result<()> {
+	let mut conn = ServerConnection::new(config)?;
+	let mut buf = vec![0; 16 * 1024];
+	loop {
+		while conn.wants_write() {
+			let mut out = Vec::new();
+			conn.write_tls(&mut out)?;
+			stream.write_all(&out).await?;
+		}
+		if !conn.is_handshaking() {
+			break;
+		}
+		let read = stream.read(&mut buf).await?;
+		if read == 0 {
+			return Err(eyre::eyre!("connection closed during the handshake"));
+		}
+		let mut data = &buf[..read];
+		while !data.is_empty() {
+			conn.read_tls(&mut data)?;
+			conn.process_new_packets()?;
+		}
+	}
+	conn.send_close_notify();
+	let mut out = Vec::new();
+	conn.write_tls(&mut out)?;
+	stream.write_all(&out).await?;
+	Ok(())
+}
+
 // ---------------------------------------------------------------------------
 // Tests
 // ---------------------------------------------------------------------------
@@ -0,0 +1,197 @@
+//! Append-only JSONL audit trail of authentication events.
+//!
+//! Each line records one event with the user UUID, the client's source
+//! address and, for disconnects, how long the connection lasted. The active
+//! file is rotated to `<path>.1`, `<path>.2`, ... once it exceeds
+//! `max_size`, and only `max_files` rotated files are kept.
+//!
+//! Records are written and files rotated on a thread of their own, like the
+//! lines of `log_file`, so a slow disk doesn't hold up the relay. Events only
+//! wait for it once a backlog of records builds up, never to be dropped.
+
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use tracing::warn;
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use uuid::Uuid;
+
+use crate::config::AuditLogConfig;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+	AuthSuccess,
+	AuthFailure,
+	Disconnect,
+}
+
+#[derive(Serialize)]
+struct Record {
+	time: String,
+	event: AuditEvent,
+	uuid: Uuid,
+	addr: SocketAddr,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	duration_secs: Option<f64>,
+}
+
+pub struct AuditLog {
+	writer: NonBlocking,
+	/// Writes out the records still queued when dropped
+	_guard: WorkerGuard,
+}
+
+impl AuditLog {
+	pub fn open(cfg: &AuditLogConfig) -> io::Result<Self> {
+		if let Some(parent) = cfg.path.parent()
+			&& !parent.as_os_str().is_empty()
+		{
+			fs::create_dir_all(parent)?;
+		}
+
+		let file = open_append(&cfg.path)?;
+		let size = file.metadata()?.len();
+		let (writer, guard) = NonBlockingBuilder::default()
+			.lossy(false)
+			.thread_name("tuic-audit")
+			.finish(Rotating {
+				path: cfg.path.clone(),
+				max_size: cfg.max_size,
+				max_files: cfg.max_files,
+				file,
+				size,
+			});
+
+		Ok(Self { writer, _guard: guard })
+	}
+
+	/// Queue one event. Failures are logged and otherwise ignored so that a
+	/// full disk never takes down the relay.
+	pub fn record(&self, event: AuditEvent, uuid: Uuid, addr: SocketAddr, duration: Option<Duration>) {
+		let record = Record {
+			time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+			event,
+			uuid,
+			addr,
+			duration_secs: duration.map(|d| d.as_secs_f64()),
+		};
+
+		let mut line = match serde_json::to_vec(&record) {
+			Ok(line) => line,
+			Err(err) => {
+				warn!("[audit] failed to encode record: {err}");
+				return;
+			}
+		};
+		line.push(b'\n');
+
+		if let Err(err) = self.writer.clone().write_all(&line) {
+			warn!("[audit] failed to queue record: {err}");
+		}
+	}
+}
+
+/// The audit file, written to by the writer thread one record at a time
+struct Rotating {
+	path: PathBuf,
+	max_size: u64,
+	max_files: usize,
+	file: File,
+	size: u64,
+}
+
+impl Rotating {
+	fn append(&mut self, line: &[u8]) -> io::Result<()> {
+		if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+			self.rotate()?;
+			self.file = open_append(&self.path)?;
+			self.size = 0;
+		}
+
+		self.file.write_all(line)?;
+		self.size += line.len() as u64;
+		Ok(())
+	}
+
+	fn rotate(&self) -> io::Result<()> {
+		if self.max_files == 0 {
+			return fs::remove_file(&self.path);
+		}
+
+		let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+		for idx in (1..self.max_files).rev() {
+			let from = rotated_path(&self.path, idx);
+			if from.exists() {
+				fs::rename(&from, rotated_path(&self.path, idx + 1))?;
+			}
+		}
+		fs::rename(&self.path, rotated_path(&self.path, 1))
+	}
+}
+
+impl Write for Rotating {
+	fn write(&mut self, line: &[u8]) -> io::Result<usize> {
+		// The writer thread has no one to report errors to
+		if let Err(err) = self.append(line) {
+			warn!("[audit] failed to write {}: {err}", self.path.display());
+		}
+		Ok(line.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+	OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, idx: usize) -> PathBuf {
+	let mut name = path.as_os_str().to_owned();
+	name.push(format!(".{idx}"));
+	PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_audit_log_rotation() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("audit.jsonl");
+		let log = AuditLog::open(&AuditLogConfig {
+			path: path.clone(),
+			max_size: 200,
+			max_files: 2,
+		})
+		.unwrap();
+
+		let addr: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+		for _ in 0..10 {
+			log.record(AuditEvent::AuthSuccess, Uuid::nil(), addr, None);
+			log.record(AuditEvent::Disconnect, Uuid::nil(), addr, Some(Duration::from_secs(3)));
+		}
+		// Waits for the queued records
+		drop(log);
+
+		assert!(fs::metadata(&path).unwrap().len() <= 200);
+		assert!(rotated_path(&path, 1).exists());
+		assert!(rotated_path(&path, 2).exists());
+		assert!(!rotated_path(&path, 3).exists());
+
+		let content = fs::read_to_string(&path).unwrap();
+		let last: serde_json::Value = serde_json::from_str(content.lines().last().unwrap()).unwrap();
+		assert_eq!(last["event"], "disconnect");
+		assert_eq!(last["addr"], "192.0.2.1:4433");
+		assert_eq!(last["duration_secs"], 3.0);
+	}
+}
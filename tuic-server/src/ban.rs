@@ -0,0 +1,155 @@
+//! Banning sources that keep failing to authenticate.
+//!
+//! With `[ban]`, a source address that fails `max_failures` authentications
+//! within `window`, by presenting wrong credentials or none before
+//! `auth_timeout`, has its new connections ignored for `duration`, before any
+//! TLS work. Connections it established earlier stay open. With `[cluster]`,
+//! a ban, or its lifting through `DELETE /bans`, applies on every instance,
+//! while failures are counted by each instance on its own.
+//!
+//! IPv6 sources are counted and banned by /64, the smallest prefix a site
+//! usually gets, so a client can't dodge a ban by hopping addresses within
+//! its own network.
+
+use std::{
+	net::{IpAddr, Ipv6Addr},
+	sync::{
+		Arc,
+		atomic::{AtomicU32, Ordering},
+	},
+};
+
+use moka::future::Cache;
+
+use crate::config::BanConfig;
+
+/// Sources tracked at most, so a flood from many addresses can't exhaust
+/// memory. Beyond, the least recently failing ones are forgotten.
+const MAX_SOURCES: u64 = 65536;
+
+pub struct Bans {
+	max_failures: u32,
+	/// Failures of each source within `window` of its first one
+	failures: Cache<IpAddr, Arc<AtomicU32>>,
+	banned: Cache<IpAddr, ()>,
+}
+
+impl Bans {
+	pub fn new(cfg: &BanConfig) -> Self {
+		Self {
+			max_failures: cfg.max_failures,
+			failures: Cache::builder().max_capacity(MAX_SOURCES).time_to_live(cfg.window).build(),
+			banned: Cache::builder().max_capacity(MAX_SOURCES).time_to_live(cfg.duration).build(),
+		}
+	}
+
+	pub fn is_banned(&self, ip: IpAddr) -> bool {
+		self.banned.contains_key(&key(ip))
+	}
+
+	/// Count a failed authentication from `ip`, returning whether it got the
+	/// source banned
+	pub async fn fail(&self, ip: IpAddr) -> bool {
+		let ip = key(ip);
+		let failures = self.failures.get_with(ip, async { Arc::new(AtomicU32::new(0)) }).await;
+		if failures.fetch_add(1, Ordering::Relaxed) + 1 != self.max_failures {
+			return false;
+		}
+		self.failures.invalidate(&ip).await;
+		self.banned.insert(ip, ()).await;
+		true
+	}
+
+	/// Ban `ip` for `duration`, as another instance did
+	pub async fn ban(&self, ip: IpAddr) {
+		self.banned.insert(key(ip), ()).await;
+	}
+
+	/// Lift the ban of `ip`, returning whether it was banned
+	pub async fn lift(&self, ip: IpAddr) -> bool {
+		let ip = key(ip);
+		self.failures.invalidate(&ip).await;
+		self.banned.remove(&ip).await.is_some()
+	}
+
+	/// Sources currently banned
+	pub fn banned_count(&self) -> u64 {
+		self.banned.entry_count()
+	}
+}
+
+/// What `ip` is tracked as: an IPv4 address, IPv4-mapped or not, or the /64
+/// of an IPv6 one
+fn key(ip: IpAddr) -> IpAddr {
+	match ip.to_canonical() {
+		IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & !(u128::MAX >> 64))),
+		ip => ip,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_ban_after_max_failures() {
+		let bans = Bans::new(&BanConfig {
+			max_failures: 3,
+			..Default::default()
+		});
+		let source: IpAddr = "192.0.2.1".parse().unwrap();
+		let other: IpAddr = "198.51.100.1".parse().unwrap();
+
+		assert!(!bans.fail(source).await);
+		assert!(!bans.fail(other).await);
+		assert!(!bans.fail("::ffff:192.0.2.1".parse().unwrap()).await);
+		assert!(!bans.is_banned(source));
+		assert!(bans.fail(source).await);
+		assert!(bans.is_banned(source));
+		assert!(!bans.is_banned(other));
+	}
+
+	#[tokio::test]
+	async fn test_ban_and_lift() {
+		let bans = Bans::new(&BanConfig::default());
+		let source: IpAddr = "192.0.2.1".parse().unwrap();
+
+		bans.ban("::ffff:192.0.2.1".parse().unwrap()).await;
+		assert!(bans.is_banned(source));
+		assert!(bans.lift(source).await);
+		assert!(!bans.is_banned(source));
+		assert!(!bans.lift(source).await);
+	}
+
+	#[tokio::test]
+	async fn test_ipv6_sources_by_prefix() {
+		let bans = Bans::new(&BanConfig {
+			max_failures: 2,
+			..Default::default()
+		});
+
+		assert!(!bans.fail("2001:db8:1:2::1".parse().unwrap()).await);
+		assert!(bans.fail("2001:db8:1:2:aaaa::1".parse().unwrap()).await);
+		assert!(bans.is_banned("2001:db8:1:2:ffff:ffff:ffff:ffff".parse().unwrap()));
+		assert!(!bans.is_banned("2001:db8:1:3::1".parse().unwrap()));
+		assert!(bans.lift("2001:db8:1:2::1234".parse().unwrap()).await);
+		assert!(!bans.is_banned("2001:db8:1:2::1".parse().unwrap()));
+	}
+
+	#[tokio::test]
+	async fn test_failures_expire_after_window() {
+		let bans = Bans::new(&BanConfig {
+			max_failures: 2,
+			window: Duration::from_millis(50),
+			..Default::default()
+		});
+		let source: IpAddr = "192.0.2.1".parse().unwrap();
+
+		assert!(!bans.fail(source).await);
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(!bans.fail(source).await);
+		assert!(!bans.is_banned(source));
+	}
+}
@@ -0,0 +1,248 @@
+//! Bandwidth shaping of connections and users.
+//!
+//! With `[bandwidth]`, each connection may move `rate` bytes per second, both
+//! directions together. The first `burst` bytes of every `burst_window` don't
+//! count against the rate, so page loads and other short transfers run at full
+//! speed while sustained transfers are clamped. TCP relays are slowed down to
+//! the rate; UDP packets above it are dropped and counted as `rate_limited`.
+//!
+//! `[user_limits]` adds an upload and a download rate per user on top, shared
+//! by all connections of the user so opening more of them gains nothing.
+
+use std::{
+	sync::{Mutex, PoisonError},
+	time::Duration,
+};
+
+use tokio::time::{self, Instant};
+
+use crate::{
+	config::{BandwidthConfig, UserLimits},
+	quota::Quota,
+};
+
+pub struct Limiter {
+	rate: u64,
+	burst: u64,
+	window: Duration,
+	state: Mutex<State>,
+}
+
+struct State {
+	window_end: Instant,
+	burst_left: u64,
+	/// When the bytes already admitted at `rate` have been sent
+	ready: Instant,
+}
+
+impl Limiter {
+	pub fn new(cfg: &BandwidthConfig) -> Self {
+		let now = Instant::now();
+		Self {
+			rate: cfg.rate,
+			burst: cfg.burst,
+			window: cfg.burst_window,
+			state: Mutex::new(State {
+				window_end: now + cfg.burst_window,
+				burst_left: cfg.burst,
+				ready: now,
+			}),
+		}
+	}
+
+	/// Wait until `len` bytes may be sent.
+	pub async fn consume(&self, len: usize) {
+		if let Some(wait) = self.reserve(len, true)
+			&& !wait.is_zero()
+		{
+			time::sleep(wait).await;
+		}
+	}
+
+	/// Whether `len` bytes may be sent right away. When not, nothing is
+	/// accounted for them.
+	pub fn try_consume(&self, len: usize) -> bool {
+		self.reserve(len, false).is_some()
+	}
+
+	/// Account for `len` bytes and return how long to wait before sending them,
+	/// or `None` if that would mean waiting and `may_wait` is unset
+	fn reserve(&self, len: usize, may_wait: bool) -> Option<Duration> {
+		let now = Instant::now();
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		if now >= state.window_end {
+			state.window_end = now + self.window;
+			state.burst_left = self.burst;
+		}
+
+		let len = len as u64;
+		let from_burst = len.min(state.burst_left);
+		let rest = len - from_burst;
+		if rest == 0 {
+			state.burst_left -= from_burst;
+			return Some(Duration::ZERO);
+		}
+
+		let start = state.ready.max(now);
+		if start > now && !may_wait {
+			return None;
+		}
+		state.burst_left -= from_burst;
+		state.ready = start + Duration::from_secs_f64(rest as f64 / self.rate as f64);
+		Some(start - now)
+	}
+}
+
+/// The rates of a user, each direction on its own
+pub struct UserLimiter {
+	upload: Option<Limiter>,
+	download: Option<Limiter>,
+}
+
+impl UserLimiter {
+	pub fn new(cfg: &UserLimits) -> Self {
+		let limiter = |rate| {
+			Limiter::new(&BandwidthConfig {
+				rate,
+				burst: 0,
+				..Default::default()
+			})
+		};
+		Self {
+			upload: cfg.upload.map(limiter),
+			download: cfg.download.map(limiter),
+		}
+	}
+}
+
+/// The limiters traffic of a connection is shaped by, and the quota it counts
+/// against
+#[derive(Clone, Copy, Default)]
+pub struct Pace<'a> {
+	pub connection: Option<&'a Limiter>,
+	pub user: Option<&'a UserLimiter>,
+	pub quota: Option<&'a Quota>,
+}
+
+impl Pace<'_> {
+	/// Wait until `len` bytes from the client may be relayed
+	pub async fn upload(&self, len: usize) {
+		for limiter in self.limiters(true) {
+			limiter.consume(len).await;
+		}
+		self.count(len);
+	}
+
+	/// Wait until `len` bytes to the client may be relayed
+	pub async fn download(&self, len: usize) {
+		for limiter in self.limiters(false) {
+			limiter.consume(len).await;
+		}
+		self.count(len);
+	}
+
+	/// Whether a packet of `len` bytes from the client may be relayed now
+	pub fn try_upload(&self, len: usize) -> bool {
+		let allowed = self.limiters(true).all(|limiter| limiter.try_consume(len));
+		if allowed {
+			self.count(len);
+		}
+		allowed
+	}
+
+	/// Whether a packet of `len` bytes to the client may be relayed now
+	pub fn try_download(&self, len: usize) -> bool {
+		let allowed = self.limiters(false).all(|limiter| limiter.try_consume(len));
+		if allowed {
+			self.count(len);
+		}
+		allowed
+	}
+
+	fn count(&self, len: usize) {
+		if let Some(quota) = self.quota {
+			quota.add(len);
+		}
+	}
+
+	fn limiters(&self, upload: bool) -> impl Iterator<Item = &Limiter> {
+		let user = self.user.and_then(|user| {
+			if upload {
+				user.upload.as_ref()
+			} else {
+				user.download.as_ref()
+			}
+		});
+		self.connection.into_iter().chain(user)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn limiter(rate: u64, burst: u64) -> Limiter {
+		Limiter::new(&BandwidthConfig {
+			rate,
+			burst,
+			burst_window: Duration::from_secs(60),
+		})
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_burst_then_rate() {
+		let limiter = limiter(1000, 5000);
+		let start = Instant::now();
+
+		// The burst passes at once
+		limiter.consume(5000).await;
+		assert_eq!(start.elapsed(), Duration::ZERO);
+
+		// Later bytes are paced at the rate
+		for _ in 0..3 {
+			limiter.consume(1000).await;
+		}
+		assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+		// The next window starts with a new burst
+		time::sleep(Duration::from_secs(60)).await;
+		let start = Instant::now();
+		limiter.consume(4000).await;
+		assert_eq!(start.elapsed(), Duration::ZERO);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_packets_above_rate_are_refused() {
+		let limiter = limiter(1000, 0);
+		assert!(limiter.try_consume(1000));
+		assert!(!limiter.try_consume(1));
+
+		time::sleep(Duration::from_secs(1)).await;
+		assert!(limiter.try_consume(500));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_user_directions_are_separate() {
+		let user = UserLimiter::new(&UserLimits {
+			upload: Some(1000),
+			..Default::default()
+		});
+		let pace = Pace {
+			user: Some(&user),
+			..Default::default()
+		};
+		let start = Instant::now();
+		pace.upload(3000).await;
+		pace.upload(1000).await;
+		assert_eq!(start.elapsed(), Duration::from_secs(3));
+		assert!(!pace.try_upload(1));
+		assert!(pace.try_download(1_000_000));
+
+		// Another connection of the user shares its rate
+		let other = Pace {
+			user: Some(&user),
+			..Default::default()
+		};
+		assert!(!other.try_upload(1));
+	}
+}
@@ -0,0 +1,188 @@
+//! Construction of a [`Config`] in code, for embedding the server.
+//!
+//! [`ServerConfigBuilder`] sets up a server without a config file or command
+//! line, e.g. with users from the embedder's database and a certificate it
+//! already holds in memory. Options without a builder method keep their
+//! defaults and can still be changed on the fields of the built [`Config`].
+//!
+//! ```no_run
+//! # async fn embed(chain: Vec<rustls::pki_types::CertificateDer<'static>>, key: rustls::pki_types::PrivateKeyDer<'static>) -> eyre::Result<()> {
+//! use tuic_server::ServerConfigBuilder;
+//!
+//! let config = ServerConfigBuilder::new("[::]:443".parse()?)
+//! 	.user(uuid::Uuid::new_v4(), "password")
+//! 	.certificate_der(chain, key)
+//! 	.alpn(["h3"])
+//! 	.build()?;
+//! let _server = tuic_server::run(config).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use uuid::Uuid;
+
+use crate::{
+	Config,
+	acl::AclRule,
+	config::{OutboundConfig, QuicConfig},
+	utils::CongestionController,
+};
+
+pub struct ServerConfigBuilder {
+	config: Config,
+}
+
+impl ServerConfigBuilder {
+	/// A server listening on `server`, with every other option at its default
+	pub fn new(server: SocketAddr) -> Self {
+		Self {
+			config: Config {
+				server,
+				..Default::default()
+			},
+		}
+	}
+
+	/// Accept `uuid` authenticating with `password`
+	pub fn user(mut self, uuid: Uuid, password: impl Into<String>) -> Self {
+		self.config.users.insert(uuid, password.into());
+		self
+	}
+
+	/// Serve the certificate chain `chain`, leaf first, with its private key
+	/// `key`, both DER encoded
+	pub fn certificate_der(mut self, chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Self {
+		self.config.tls.certificate_der = Some((chain, key));
+		self.config.tls.self_sign = false;
+		self
+	}
+
+	/// Serve the PEM certificate chain and key in these files, reloaded when
+	/// they change
+	pub fn certificate_files(mut self, certificate: impl Into<PathBuf>, private_key: impl Into<PathBuf>) -> Self {
+		self.config.tls.certificate = certificate.into();
+		self.config.tls.private_key = private_key.into();
+		self.config.tls.certificate_der = None;
+		self.config.tls.self_sign = false;
+		self
+	}
+
+	/// Serve a certificate for `hostname` generated at startup, for clients
+	/// that skip verification or pin it
+	pub fn self_signed(mut self, hostname: impl Into<String>) -> Self {
+		self.config.tls.hostname = hostname.into();
+		self.config.tls.certificate_der = None;
+		self.config.tls.self_sign = true;
+		self
+	}
+
+	/// ALPN protocols offered to clients
+	pub fn alpn<S: Into<String>>(mut self, protocols: impl IntoIterator<Item = S>) -> Self {
+		self.config.tls.alpn = protocols.into_iter().map(Into::into).collect();
+		self
+	}
+
+	/// Replace the QUIC transport options
+	pub fn quic(mut self, quic: QuicConfig) -> Self {
+		self.config.quic = quic;
+		self
+	}
+
+	/// Congestion controller of every connection
+	pub fn congestion_control(mut self, controller: CongestionController) -> Self {
+		self.config.quic.congestion_control.controller = controller;
+		self
+	}
+
+	/// Append an ACL rule. Rules are matched in the order they were added.
+	pub fn acl_rule(mut self, rule: AclRule) -> Self {
+		self.config.acl.push(rule);
+		self
+	}
+
+	/// Replace the outbounds ACL rules can route to
+	pub fn outbound(mut self, outbound: OutboundConfig) -> Self {
+		self.config.outbound = outbound;
+		self
+	}
+
+	/// Directory for state such as the ACME cache. Defaults to the current
+	/// directory.
+	pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.config.data_dir = dir.into();
+		self
+	}
+
+	/// The configuration, checked like a config file
+	pub fn build(mut self) -> eyre::Result<Config> {
+		let tls = &self.config.tls;
+		if tls.certificate_der.is_none() && !tls.self_sign && !tls.auto_ssl && tls.certificate.as_os_str().is_empty() {
+			return Err(eyre::eyre!("a certificate is required"));
+		}
+		if self.config.data_dir.as_os_str().is_empty() {
+			self.config.data_dir = std::env::current_dir()?;
+		}
+		self.config.validate()?;
+		Ok(self.config)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{acl::AclAddress, config::BandwidthConfig};
+
+	fn der() -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+		let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+		(
+			vec![CertificateDer::from(cert.cert)],
+			PrivateKeyDer::Pkcs8(cert.signing_key.serialize_der().into()),
+		)
+	}
+
+	#[test]
+	fn test_build() {
+		let uuid = Uuid::from_u128(1);
+		let (chain, key) = der();
+		let config = ServerConfigBuilder::new("127.0.0.1:8443".parse().unwrap())
+			.user(uuid, "password")
+			.certificate_der(chain.clone(), key)
+			.alpn(["h3"])
+			.congestion_control(CongestionController::Cubic)
+			.acl_rule(AclRule {
+				outbound: "reject".to_owned(),
+				addr: AclAddress::Localhost,
+				ports: None,
+				hijack: None,
+			})
+			.build()
+			.unwrap();
+
+		assert_eq!(config.server, "127.0.0.1:8443".parse().unwrap());
+		assert_eq!(config.users[&uuid], "password");
+		assert_eq!(config.tls.certificate_der.unwrap().0, chain);
+		assert_eq!(config.tls.alpn, ["h3"]);
+		assert_eq!(config.quic.congestion_control.controller, CongestionController::Cubic);
+		assert_eq!(config.acl.len(), 1);
+		assert!(!config.data_dir.as_os_str().is_empty());
+	}
+
+	#[test]
+	fn test_build_checks_config() {
+		let server: SocketAddr = "127.0.0.1:8443".parse().unwrap();
+		assert!(ServerConfigBuilder::new(server).build().is_err());
+
+		let mut builder = ServerConfigBuilder::new(server).self_signed("localhost");
+		builder.config.bandwidth = Some(BandwidthConfig {
+			rate: 0,
+			burst: 0,
+			burst_window: Duration::from_secs(60),
+		});
+		assert!(builder.build().is_err());
+	}
+}
@@ -0,0 +1,411 @@
+//! Sharing admin commands and limits between server instances through Redis.
+//!
+//! Instances with a `[cluster]` section subscribe to the same Redis pub/sub
+//! channel, so that users and sources can't get around a limit by
+//! reconnecting through another node behind the same DNS name:
+//!
+//! - A kick on one instance's admin API is applied locally and published, and
+//!   every other instance then closes the kicked users' connections too.
+//! - Each instance publishes the traffic its users relayed against their
+//!   `quota` every [`USAGE_INTERVAL`], and the others count it too. A quota
+//!   used up through several instances at once is thus cut off at most that
+//!   late.
+//! - A source banned by `[ban]`, or whose ban is lifted through `DELETE
+//!   /bans`, is banned or let in again everywhere.
+//!
+//! Only the few commands of RESP (the Redis protocol) needed for pub/sub are
+//! implemented here.
+
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc, time::Duration};
+
+use eyre::{Context, bail, eyre};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::{
+	io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+	net::TcpStream,
+	time,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{AppContext, config::ClusterConfig, quota::Usage};
+
+/// Delay before reconnecting after losing the subscription
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How often quota usage is shared with the other instances
+pub const USAGE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Address and credentials from a `redis://[user:password@]host[:port]` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisTarget {
+	host: String,
+	port: u16,
+	username: Option<String>,
+	password: Option<String>,
+}
+
+impl RedisTarget {
+	pub fn parse(url: &str) -> eyre::Result<Self> {
+		let url = Url::parse(url).context("invalid `cluster.redis` URL")?;
+		if url.scheme() != "redis" {
+			bail!("`cluster.redis` must be a redis:// URL");
+		}
+		let host = url
+			.host_str()
+			.ok_or_else(|| eyre!("`cluster.redis` has no host"))?
+			.trim_start_matches('[')
+			.trim_end_matches(']')
+			.to_owned();
+
+		Ok(Self {
+			host,
+			port: url.port().unwrap_or(6379),
+			username: Some(url.username()).filter(|user| !user.is_empty()).map(str::to_owned),
+			password: url.password().map(str::to_owned),
+		})
+	}
+
+	async fn connect(&self) -> eyre::Result<BufReader<TcpStream>> {
+		let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+		let mut stream = BufReader::new(stream);
+		if let Some(password) = &self.password {
+			let reply = match &self.username {
+				Some(username) => command(&mut stream, &["AUTH", username, password]).await?,
+				None => command(&mut stream, &["AUTH", password]).await?,
+			};
+			if reply != Value::Simple("OK".to_owned()) {
+				bail!("unexpected reply to AUTH: {reply:?}");
+			}
+		}
+		Ok(stream)
+	}
+}
+
+/// A command published on the cluster channel
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Message {
+	Kick {
+		node: Uuid,
+		users: Vec<Uuid>,
+	},
+	/// Quota usage since the node's previous message
+	Quota {
+		node: Uuid,
+		usage: BTreeMap<Uuid, Usage>,
+	},
+	Ban {
+		node: Uuid,
+		sources: Vec<IpAddr>,
+	},
+	Unban {
+		node: Uuid,
+		sources: Vec<IpAddr>,
+	},
+}
+
+impl Message {
+	fn node(&self) -> Uuid {
+		match self {
+			Self::Kick { node, .. } | Self::Quota { node, .. } | Self::Ban { node, .. } | Self::Unban { node, .. } => *node,
+		}
+	}
+}
+
+pub struct Cluster {
+	/// Identifies this instance's own messages
+	node: Uuid,
+	target: RedisTarget,
+	channel: String,
+}
+
+impl Cluster {
+	pub fn new(cfg: &ClusterConfig) -> eyre::Result<Self> {
+		Ok(Self {
+			node: Uuid::new_v4(),
+			target: RedisTarget::parse(&cfg.redis)?,
+			channel: cfg.channel.clone(),
+		})
+	}
+
+	/// Ask the other instances to kick `users`.
+	pub async fn publish_kick(&self, users: Vec<Uuid>) -> eyre::Result<()> {
+		self.publish(&Message::Kick { node: self.node, users }).await
+	}
+
+	/// Ask the other instances to ban `sources`, or to lift their bans.
+	pub async fn publish_bans(&self, sources: Vec<IpAddr>, lift: bool) -> eyre::Result<()> {
+		let node = self.node;
+		let message = match lift {
+			false => Message::Ban { node, sources },
+			true => Message::Unban { node, sources },
+		};
+		self.publish(&message).await
+	}
+
+	async fn publish(&self, message: &Message) -> eyre::Result<()> {
+		let payload = serde_json::to_string(message)?;
+		let mut stream = self.target.connect().await?;
+		if let Value::Integer(receivers) = command(&mut stream, &["PUBLISH", &self.channel, &payload]).await? {
+			// The count includes this instance's own subscription
+			debug!("[cluster] message delivered to {receivers} subscriber(s)");
+		}
+		Ok(())
+	}
+}
+
+/// Publish the quota usage of this instance every [`USAGE_INTERVAL`], until
+/// the server stops.
+pub async fn share_usage(ctx: Arc<AppContext>) {
+	let Some(cluster) = &ctx.cluster else {
+		return;
+	};
+	if ctx.quotas.is_empty() {
+		return;
+	}
+
+	let mut ticker = time::interval(USAGE_INTERVAL);
+	ticker.tick().await;
+	loop {
+		let shutdown = tokio::select! {
+			_ = ticker.tick() => false,
+			() = ctx.cancel.cancelled() => true,
+		};
+		let usage = ctx.quotas.take_unshared();
+		if !usage.is_empty() {
+			let message = Message::Quota {
+				node: cluster.node,
+				usage: usage.clone(),
+			};
+			if let Err(err) = cluster.publish(&message).await {
+				warn!("[cluster] failed to share quota usage: {err:#}");
+				ctx.quotas.untake(&usage);
+			}
+		}
+		if shutdown {
+			return;
+		}
+	}
+}
+
+/// Apply the commands published by other instances until the server stops.
+pub async fn subscribe(ctx: Arc<AppContext>) {
+	let Some(cluster) = &ctx.cluster else {
+		return;
+	};
+
+	loop {
+		tokio::select! {
+			err = listen(&ctx, cluster) => warn!("[cluster] subscription lost, reconnecting: {err:#}"),
+			() = ctx.cancel.cancelled() => return,
+		}
+		tokio::select! {
+			() = time::sleep(RECONNECT_DELAY) => {}
+			() = ctx.cancel.cancelled() => return,
+		}
+	}
+}
+
+async fn listen(ctx: &AppContext, cluster: &Cluster) -> eyre::Report {
+	let res: eyre::Result<()> = async {
+		let mut stream = cluster.target.connect().await?;
+		write_command(&mut stream, &["SUBSCRIBE", &cluster.channel]).await?;
+		info!("[cluster] subscribed to {}", cluster.channel);
+
+		loop {
+			// Pushed messages are ["message", channel, payload]
+			let Value::Array(items) = read_value(&mut stream).await? else {
+				continue;
+			};
+			let [Value::Bulk(kind), _, Value::Bulk(payload)] = items.as_slice() else {
+				continue;
+			};
+			if kind != b"message" {
+				continue;
+			}
+
+			match serde_json::from_slice::<Message>(payload) {
+				Ok(message) if message.node() == cluster.node => {}
+				Ok(Message::Kick { users, .. }) => {
+					info!("[cluster] kicking {} user(s) on request of another instance", users.len());
+					crate::restful::kick(ctx, &users).await;
+				}
+				Ok(Message::Quota { usage, .. }) => ctx.quotas.add_shared(&usage),
+				Ok(Message::Ban { sources, .. }) => {
+					if let Some(bans) = &ctx.bans {
+						info!("[cluster] banning {sources:?} on request of another instance");
+						for source in sources {
+							bans.ban(source).await;
+						}
+					}
+				}
+				Ok(Message::Unban { sources, .. }) => {
+					if let Some(bans) = &ctx.bans {
+						info!("[cluster] lifting the bans of {sources:?} on request of another instance");
+						for source in sources {
+							bans.lift(source).await;
+						}
+					}
+				}
+				Err(err) => warn!("[cluster] ignoring malformed message: {err}"),
+			}
+		}
+	}
+	.await;
+
+	match res {
+		Ok(()) => eyre!("connection closed"),
+		Err(err) => err,
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Value {
+	Simple(String),
+	Integer(i64),
+	Bulk(Vec<u8>),
+	Nil,
+	Array(Vec<Value>),
+}
+
+async fn write_command<W: AsyncWrite + Unpin>(stream: &mut W, args: &[&str]) -> eyre::Result<()> {
+	let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+	for arg in args {
+		buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+		buf.extend_from_slice(arg.as_bytes());
+		buf.extend_from_slice(b"\r\n");
+	}
+	stream.write_all(&buf).await?;
+	Ok(())
+}
+
+/// Send a command and read its reply
+async fn command(stream: &mut BufReader<TcpStream>, args: &[&str]) -> eyre::Result<Value> {
+	write_command(stream.get_mut(), args).await?;
+	read_value(stream).await
+}
+
+async fn read_value<R: AsyncBufRead + Unpin>(stream: &mut R) -> eyre::Result<Value> {
+	// Nested arrays are read iteratively: each entry is an array being filled
+	// and the number of values it still misses
+	let mut stack: Vec<(Vec<Value>, usize)> = Vec::new();
+
+	loop {
+		let mut value = match read_line(stream).await?.split_at_checked(1) {
+			Some(("+", line)) => Value::Simple(line.to_owned()),
+			Some(("-", line)) => bail!("redis error: {line}"),
+			Some((":", line)) => Value::Integer(line.parse()?),
+			Some(("$", "-1")) | Some(("*", "-1")) => Value::Nil,
+			Some(("$", len)) => {
+				let mut data = vec![0; len.parse::<usize>()? + 2];
+				stream.read_exact(&mut data).await?;
+				data.truncate(data.len() - 2);
+				Value::Bulk(data)
+			}
+			Some(("*", len)) => match len.parse::<usize>()? {
+				0 => Value::Array(Vec::new()),
+				len => {
+					stack.push((Vec::with_capacity(len), len));
+					continue;
+				}
+			},
+			_ => bail!("malformed reply from redis"),
+		};
+
+		loop {
+			let Some((items, missing)) = stack.last_mut() else {
+				return Ok(value);
+			};
+			items.push(value);
+			*missing -= 1;
+			if *missing > 0 {
+				break;
+			}
+			let Some((items, _)) = stack.pop() else {
+				bail!("malformed reply from redis");
+			};
+			value = Value::Array(items);
+		}
+	}
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(stream: &mut R) -> eyre::Result<String> {
+	let mut line = String::new();
+	if stream.read_line(&mut line).await? == 0 {
+		bail!("connection closed");
+	}
+	let Some(line) = line.strip_suffix("\r\n") else {
+		bail!("malformed reply from redis");
+	};
+	Ok(line.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_target() {
+		let target = RedisTarget::parse("redis://:secret@10.0.0.1").unwrap();
+		assert_eq!(target.host, "10.0.0.1");
+		assert_eq!(target.port, 6379);
+		assert_eq!(target.username, None);
+		assert_eq!(target.password.as_deref(), Some("secret"));
+
+		let target = RedisTarget::parse("redis://tuic:secret@[::1]:6380").unwrap();
+		assert_eq!(target.host, "::1");
+		assert_eq!(target.port, 6380);
+		assert_eq!(target.username.as_deref(), Some("tuic"));
+
+		assert!(RedisTarget::parse("http://10.0.0.1").is_err());
+	}
+
+	#[tokio::test]
+	async fn test_read_value() {
+		let mut reply: &[u8] = b"*3\r\n$7\r\nmessage\r\n$4\r\ntuic\r\n$2\r\n{}\r\n";
+		assert_eq!(
+			read_value(&mut reply).await.unwrap(),
+			Value::Array(vec![
+				Value::Bulk(b"message".to_vec()),
+				Value::Bulk(b"tuic".to_vec()),
+				Value::Bulk(b"{}".to_vec()),
+			])
+		);
+
+		let mut reply: &[u8] = b"*2\r\n*1\r\n:1\r\n+OK\r\n";
+		assert_eq!(
+			read_value(&mut reply).await.unwrap(),
+			Value::Array(vec![Value::Array(vec![Value::Integer(1)]), Value::Simple("OK".to_owned())])
+		);
+
+		let mut reply: &[u8] = b"-ERR wrong password\r\n";
+		assert!(read_value(&mut reply).await.is_err());
+	}
+
+	#[test]
+	fn test_message_format() {
+		let node = Uuid::nil();
+		let message = serde_json::to_string(&Message::Kick { node, users: vec![node] }).unwrap();
+		assert_eq!(
+			message,
+			r#"{"command":"kick","node":"00000000-0000-0000-0000-000000000000","users":["00000000-0000-0000-0000-000000000000"]}"#
+		);
+
+		let usage = BTreeMap::from([(node, Usage { bytes: 1500, since: 0 })]);
+		let message = serde_json::to_string(&Message::Quota { node, usage }).unwrap();
+		assert_eq!(
+			message,
+			r#"{"command":"quota","node":"00000000-0000-0000-0000-000000000000","usage":{"00000000-0000-0000-0000-000000000000":{"bytes":1500,"since":0}}}"#
+		);
+
+		let sources = vec!["192.0.2.1".parse().unwrap()];
+		let message = serde_json::to_string(&Message::Unban { node, sources }).unwrap();
+		assert_eq!(
+			message,
+			r#"{"command":"unban","node":"00000000-0000-0000-0000-000000000000","sources":["192.0.2.1"]}"#
+		);
+		let message: Message = serde_json::from_str(&message).unwrap();
+		assert_eq!(message.node(), node);
+	}
+}
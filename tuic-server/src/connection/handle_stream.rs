@@ -3,17 +3,23 @@ use tokio::time;
 use tracing::{debug, warn};
 use tuic_core::quinn::{StreamRx, StreamTx, Task};
 
-use super::Connection;
-use crate::{error::Error, utils::UdpRelayMode};
+use super::{CLOCK_SKEW_CODE, Connection, EXPIRED_CODE, QUOTA_EXCEEDED_CODE, TOO_MANY_CONNECTIONS_CODE};
+use crate::{
+	dump::{self, Direction},
+	error::Error,
+	utils::UdpRelayMode,
+};
 
 impl Connection {
 	pub async fn handle_uni_stream<R: StreamRx>(self, recv: R) {
 		debug!("incoming unidirectional stream");
 
 		let pre_process = async {
-			let task = time::timeout(self.ctx.cfg.task_negotiation_timeout, self.model.accept_uni_stream(recv))
+			let timeout = self.ctx.live.load().task_negotiation_timeout;
+			let task = time::timeout(timeout, self.model.accept_uni_stream(recv))
 				.await
 				.map_err(|_| Error::TaskNegotiationTimeout)??;
+			self.dump(Direction::In, || dump::describe(&task));
 
 			if let Task::Authenticate(auth) = &task {
 				self.authenticate(auth).await?;
@@ -33,15 +39,23 @@ impl Connection {
 			Ok(task)
 		};
 
-		match pre_process.await {
-			Ok(Task::Authenticate(auth)) => self.handle_authenticate(auth).await,
-			Ok(Task::Packet(pkt)) => self.handle_packet(pkt, UdpRelayMode::Quic).await,
-			Ok(Task::Dissociate(assoc_id)) => self.handle_dissociate(assoc_id).await,
-			Ok(_) => unreachable!(),
-			Err(err) => {
-				warn!("handling incoming unidirectional stream error: {err}");
-				self.close();
-			}
+		let err = match pre_process.await {
+			Ok(Task::Authenticate(auth)) => return self.handle_authenticate(auth).await,
+			Ok(Task::Packet(pkt)) => return self.handle_packet(pkt, UdpRelayMode::Quic).await,
+			Ok(Task::Dissociate(assoc_id)) => return self.handle_dissociate(assoc_id).await,
+			Ok(_) => Error::UnexpectedCommand("unidirectional stream"),
+			Err(err) => err,
+		};
+		warn!("handling incoming unidirectional stream error: {err}");
+		if err.is_auth_failure() {
+			self.count_auth_failure().await;
+		}
+		match err {
+			Error::ClockSkew(..) => self.inner.close(CLOCK_SKEW_CODE, b"Client clock is off, check its time"),
+			Error::TooManyConnections(..) => self.inner.close(TOO_MANY_CONNECTIONS_CODE, b"Too many connections"),
+			Error::QuotaExceeded(..) => self.inner.close(QUOTA_EXCEEDED_CODE, b"Quota exceeded"),
+			Error::Expired(..) => self.inner.close(EXPIRED_CODE, b"Credentials expired"),
+			_ => self.close(),
 		}
 	}
 
@@ -49,9 +63,11 @@ impl Connection {
 		debug!("incoming bidirectional stream");
 
 		let pre_process = async {
-			let task = time::timeout(self.ctx.cfg.task_negotiation_timeout, self.model.accept_bi_stream(send, recv))
+			let timeout = self.ctx.live.load().task_negotiation_timeout;
+			let task = time::timeout(timeout, self.model.accept_bi_stream(send, recv))
 				.await
 				.map_err(|_| Error::TaskNegotiationTimeout)??;
+			self.dump(Direction::In, || dump::describe(&task));
 
 			if !self.auth.is_authenticated() {
 				tokio::select! {
@@ -63,14 +79,13 @@ impl Connection {
 			Ok(task)
 		};
 
-		match pre_process.await {
-			Ok(Task::Connect(conn)) => self.handle_connect(conn).await,
-			Ok(_) => unreachable!(),
-			Err(err) => {
-				warn!("handling incoming bidirectional stream error: {err}");
-				self.close();
-			}
-		}
+		let err = match pre_process.await {
+			Ok(Task::Connect(conn)) => return self.handle_connect(conn).await,
+			Ok(_) => Error::UnexpectedCommand("bidirectional stream"),
+			Err(err) => err,
+		};
+		warn!("handling incoming bidirectional stream error: {err}");
+		self.close();
 	}
 
 	pub async fn handle_datagram(self, dg: Bytes) {
@@ -78,6 +93,7 @@ impl Connection {
 
 		let pre_process = async {
 			let task = self.model.accept_datagram(dg)?;
+			self.dump(Direction::In, || dump::describe(&task));
 
 			if !self.auth.is_authenticated() {
 				tokio::select! {
@@ -93,14 +109,13 @@ impl Connection {
 			Ok(task)
 		};
 
-		match pre_process.await {
-			Ok(Task::Packet(pkt)) => self.handle_packet(pkt, UdpRelayMode::Native).await,
-			Ok(Task::Heartbeat) => self.handle_heartbeat().await,
-			Ok(_) => unreachable!(),
-			Err(err) => {
-				warn!("handling incoming datagram error: {err}");
-				self.close();
-			}
-		}
+		let err = match pre_process.await {
+			Ok(Task::Packet(pkt)) => return self.handle_packet(pkt, UdpRelayMode::Native).await,
+			Ok(Task::Heartbeat) => return self.handle_heartbeat().await,
+			Ok(_) => Error::UnexpectedCommand("datagram"),
+			Err(err) => err,
+		};
+		warn!("handling incoming datagram error: {err}");
+		self.close();
 	}
 }
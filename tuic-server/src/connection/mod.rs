@@ -1,26 +1,79 @@
 use std::{
 	collections::HashMap,
-	sync::{Arc, Weak},
-	time::Duration,
+	net::IpAddr,
+	panic::AssertUnwindSafe,
+	sync::{
+		Arc, OnceLock, Weak,
+		atomic::{AtomicBool, Ordering},
+	},
+	time::{Duration, Instant, SystemTime},
 };
 
 use arc_swap::ArcSwap;
 use bytes::Bytes;
+use futures_util::FutureExt;
 use peekable::tokio::AsyncPeekExt;
 use smallvec::SmallVec;
 use tokio::{sync::RwLock as AsyncRwLock, time};
-use tracing::{Instrument, Span, debug, info, info_span, warn};
-use tuic_core::quinn::{Authenticate, Connecting, Connection as Model, QuinnConnection, VarInt, side};
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
+use tuic_core::{
+	quinn::{Authenticate, Connecting, Connection as Model, QuinnConnection, VarInt, side},
+	timestamp_auth::{self, AuthMode},
+};
+use uuid::Uuid;
 
 use self::{authenticated::Authenticated, udp_session::UdpSession};
-use crate::{AppContext, camouflage, error::Error, restful, utils::UdpRelayMode};
+use crate::{
+	AppContext,
+	audit::AuditEvent,
+	bandwidth::{Limiter, Pace},
+	camouflage, drain,
+	dump::Direction,
+	error::Error,
+	quota::Quota,
+	restful,
+	stats::ControllerStats,
+	users::TooManyConnections,
+	utils::{CongestionController, UdpRelayMode},
+};
 
 mod authenticated;
 mod handle_stream;
 mod handle_task;
 mod udp_session;
 
+pub use self::udp_session::ParkedUdpSessions;
+
 pub const ERROR_CODE: VarInt = VarInt::from_u32(0);
+/// Closes a connection that reached `max_session_lifetime`
+pub const SESSION_EXPIRED_CODE: VarInt = VarInt::from_u32(6004);
+/// Closes a connection whose timestamp token shows a skewed client clock
+pub const CLOCK_SKEW_CODE: VarInt = VarInt::from_u32(6005);
+/// Closes a connection whose user was removed from `users_file` or through the
+/// admin API, or got a new password
+pub const REVOKED_CODE: VarInt = VarInt::from_u32(6006);
+/// Refuses a connection beyond `max_connections` of `user_limits`, as
+/// `restful.maximum_clients_per_user` does
+pub const TOO_MANY_CONNECTIONS_CODE: VarInt = VarInt::from_u32(6001);
+/// Closes the oldest connection of a user to make room for a new one
+pub const REPLACED_CODE: VarInt = VarInt::from_u32(6007);
+/// Refuses or closes a connection of a user whose quota is used up
+pub const QUOTA_EXCEEDED_CODE: VarInt = VarInt::from_u32(6008);
+/// Refuses or closes a connection of a user past its `expires_at`
+pub const EXPIRED_CODE: VarInt = VarInt::from_u32(6009);
+/// Closes the connections of a user kicked through the admin API
+pub const KICKED_CODE: VarInt = VarInt::from_u32(6002);
+/// Closes a connection whose user is missing from the users table
+pub const INTERNAL_ERROR_CODE: VarInt = VarInt::from_u32(6003);
+
+/// How long the open streams of a connection past `max_session_lifetime` may
+/// take to finish before it is closed anyway
+const SESSION_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How far off a client clock may be for its timestamp token to be told apart
+/// from a wrong password, within [`timestamp_auth::MAX_PROBE_STEPS`] steps
+const SKEW_PROBE: Duration = Duration::from_secs(3600);
 
 enum H3Dispatch {
 	Tuic(Option<PrefetchedFirstEventTuic>),
@@ -58,10 +111,19 @@ pub struct Connection {
 	auth: Authenticated,
 	udp_sessions: Arc<AsyncRwLock<HashMap<u16, Weak<UdpSession>>>>,
 	udp_relay_mode: Arc<ArcSwap<Option<UdpRelayMode>>>,
+	established_at: Instant,
+	bandwidth: Option<Arc<Limiter>>,
+	/// Whether the client asked for replies to `Connect`, see
+	/// [`tuic_core::reject`]
+	connect_replies: Arc<AtomicBool>,
+	/// Relayed TCP streams, closed once the connection drains
+	streams: TaskTracker,
+	/// The client's resume token, see [`ParkedUdpSessions`]
+	resume_token: Arc<OnceLock<Uuid>>,
 }
 
 impl Connection {
-	pub async fn handle(ctx: Arc<AppContext>, conn: Connecting) {
+	pub async fn handle(ctx: Arc<AppContext>, conn: Connecting, controller: CongestionController) {
 		let peer_addr = conn.remote_address();
 
 		let init = async {
@@ -84,63 +146,12 @@ impl Connection {
 					id = conn.id(),
 					addr = %conn.inner.remote_address(),
 					user = tracing::field::Empty,
+					cc = ControllerStats::name(controller),
 				);
-
-				if ctx.cfg.camouflage.as_ref().is_some_and(|cfg| cfg.enabled) {
-					match conn.classify_h3_dispatch().await {
-						Ok(H3Dispatch::Camouflage {
-							prefetched_uni,
-							prefetched_bi,
-						}) => {
-							if let Err(err) =
-								camouflage::handle(ctx.clone(), conn.inner.clone(), prefetched_uni, prefetched_bi).await
-							{
-								warn!(parent: &conn_span, "camouflage: {err}");
-							}
-							return;
-						}
-						Ok(H3Dispatch::Tuic(first_event)) => {
-							info!(parent: &conn_span, "connection established");
-							tokio::spawn(
-								conn.clone()
-									.timeout_authenticate(ctx.cfg.auth_timeout)
-									.instrument(conn_span.clone()),
-							);
-							tokio::spawn(conn.clone().collect_garbage().instrument(conn_span.clone()));
-							if let Some(first_event) = first_event {
-								let span = conn_span.clone();
-								match first_event {
-									PrefetchedFirstEventTuic::Uni(recv) => {
-										tokio::spawn(conn.clone().handle_uni_stream(recv).instrument(span));
-									}
-									PrefetchedFirstEventTuic::Bi { send, recv } => {
-										tokio::spawn(conn.clone().handle_bi_stream((send, recv)).instrument(span));
-									}
-									PrefetchedFirstEventTuic::Datagram(dg) => {
-										tokio::spawn(conn.clone().handle_datagram(dg).instrument(span));
-									}
-								}
-							}
-
-							conn.run_tuic_event_loop().instrument(conn_span).await;
-							return;
-						}
-						Err(err) => {
-							warn!(parent: &conn_span, "classifier: {err}");
-							conn.close();
-							return;
-						}
-					}
-				}
-
-				info!(parent: &conn_span, "connection established");
-				tokio::spawn(
-					conn.clone()
-						.timeout_authenticate(ctx.cfg.auth_timeout)
-						.instrument(conn_span.clone()),
-				);
-				tokio::spawn(conn.clone().collect_garbage().instrument(conn_span.clone()));
-				conn.run_tuic_event_loop().instrument(conn_span).await;
+				let inner = conn.inner.clone();
+				let _tracked = ctx.stats.connections.track(1);
+				close_on_panic(conn.inner.clone(), conn.serve()).instrument(conn_span).await;
+				ctx.stats.controllers.record(controller, &inner.stats());
 			}
 			Err(err) if err.is_trivial() => {
 				debug!(id = u32::MAX, addr = %peer_addr, "{err}");
@@ -151,35 +162,299 @@ impl Connection {
 		}
 	}
 
+	/// Drive an established connection. Runs inside the connection's span.
+	async fn serve(self) {
+		if self.ctx.cfg.camouflage.as_ref().is_some_and(|cfg| cfg.enabled) {
+			match self.classify_h3_dispatch().await {
+				Ok(H3Dispatch::Camouflage {
+					prefetched_uni,
+					prefetched_bi,
+				}) => {
+					if let Err(err) =
+						camouflage::handle(self.ctx.clone(), self.inner.clone(), prefetched_uni, prefetched_bi).await
+					{
+						warn!("camouflage: {err}");
+					}
+					return;
+				}
+				Ok(H3Dispatch::Tuic(first_event)) => {
+					self.start_tuic();
+					if let Some(first_event) = first_event {
+						match first_event {
+							PrefetchedFirstEventTuic::Uni(recv) => self.spawn(self.clone().handle_uni_stream(recv)),
+							PrefetchedFirstEventTuic::Bi { send, recv } => {
+								self.spawn(self.clone().handle_bi_stream((send, recv)))
+							}
+							PrefetchedFirstEventTuic::Datagram(dg) => self.spawn(self.clone().handle_datagram(dg)),
+						}
+					}
+
+					self.run_tuic_event_loop().await;
+					return;
+				}
+				Err(err) => {
+					warn!("classifier: {err}");
+					self.close();
+					return;
+				}
+			}
+		}
+
+		self.start_tuic();
+		self.run_tuic_event_loop().await;
+	}
+
+	fn start_tuic(&self) {
+		info!("connection established");
+		self.spawn(self.clone().timeout_authenticate(self.ctx.live.load().auth_timeout));
+		self.spawn(self.clone().collect_garbage());
+		if !self.ctx.cfg.max_session_lifetime.is_zero() {
+			self.spawn(self.clone().expire_session(self.ctx.cfg.max_session_lifetime));
+		}
+		self.spawn(self.clone().close_when_revoked());
+		if !self.ctx.quotas.is_empty() {
+			self.spawn(self.clone().close_when_over_quota());
+		}
+		if self.ctx.cfg.user_limits.values().any(|limits| limits.expires_at.is_some()) {
+			self.spawn(self.clone().close_when_expired());
+		}
+	}
+
+	/// Spawn a task of this connection in the current span. A panic in the
+	/// task closes only this connection.
+	fn spawn<F>(&self, task: F)
+	where
+		F: Future + Send + 'static,
+	{
+		tokio::spawn(close_on_panic(self.inner.clone(), task).instrument(Span::current()));
+	}
+
 	fn new(ctx: Arc<AppContext>, conn: QuinnConnection) -> Self {
+		let bandwidth = ctx.cfg.bandwidth.as_ref().map(|cfg| Arc::new(Limiter::new(cfg)));
+		let model = Model::<side::Server>::new(conn.clone()).with_auth_timeout(ctx.live.load().auth_timeout);
 		Self {
 			ctx,
-			inner: conn.clone(),
-			model: Model::<side::Server>::new(conn),
+			inner: conn,
+			model,
 			auth: Authenticated::new(),
 			udp_sessions: Arc::new(AsyncRwLock::new(HashMap::new())),
 			udp_relay_mode: Arc::new(ArcSwap::new(None.into())),
+			established_at: Instant::now(),
+			bandwidth,
+			connect_replies: Arc::new(AtomicBool::new(false)),
+			streams: TaskTracker::new(),
+			resume_token: Arc::new(OnceLock::new()),
+		}
+	}
+
+	/// The limiters of this connection and of its user
+	fn pace(&self) -> Pace<'_> {
+		let uuid = self.auth.get();
+		Pace {
+			connection: self.bandwidth.as_deref(),
+			user: uuid.and_then(|uuid| self.ctx.user_bandwidth.get(&uuid)),
+			quota: uuid.and_then(|uuid| self.ctx.quotas.get(&uuid)),
+		}
+	}
+
+	fn expires_at(&self, uuid: Uuid) -> Option<SystemTime> {
+		self.ctx.cfg.user_limits.get(&uuid)?.expires_at
+	}
+
+	/// Whether the user of this connection used up its quota
+	fn over_quota(&self) -> Option<&Quota> {
+		self.ctx.quotas.get(&self.auth.get()?).filter(|quota| quota.is_exhausted())
+	}
+
+	fn audit(&self, event: AuditEvent, uuid: Uuid) {
+		if let Some(audit) = &self.ctx.audit {
+			let duration = (event == AuditEvent::Disconnect).then(|| self.established_at.elapsed());
+			audit.record(event, uuid, self.inner.remote_address(), duration);
 		}
 	}
 
 	async fn authenticate(&self, auth: &Authenticate) -> Result<(), Error> {
 		if self.auth.get().is_some() {
-			Err(Error::DuplicatedAuth)
-		} else if self
-			.ctx
-			.cfg
-			.users
-			.get(&auth.uuid())
-			.is_some_and(|password| auth.validate(password).unwrap_or(false))
+			return Err(Error::DuplicatedAuth);
+		}
+		let password = self.password_of(auth).await;
+		if let Some(password) = &password
+			&& self.password_valid(auth, password)
 		{
+			let source = self.inner.remote_address().ip().to_canonical();
+			if let Some(binding) = self.ctx.cfg.user_bindings.get(&auth.uuid())
+				&& !binding.sources.is_empty()
+				&& !binding.sources.iter().any(|net| net.contains(&source))
+			{
+				self.audit(AuditEvent::AuthFailure, auth.uuid());
+				self.ctx.stats.auth_failures.invalid.fetch_add(1, Ordering::Relaxed);
+				return Err(Error::UnboundSource(auth.uuid(), source));
+			}
+			if self
+				.expires_at(auth.uuid())
+				.is_some_and(|expires_at| expires_at <= SystemTime::now())
+			{
+				self.audit(AuditEvent::AuthFailure, auth.uuid());
+				self.ctx.stats.auth_failures.expired.fetch_add(1, Ordering::Relaxed);
+				return Err(Error::Expired(auth.uuid()));
+			}
+			if self.ctx.quotas.get(&auth.uuid()).is_some_and(Quota::is_exhausted) {
+				self.ctx.stats.auth_failures.quota.fetch_add(1, Ordering::Relaxed);
+				return Err(Error::QuotaExceeded(auth.uuid()));
+			}
+			if let Some(seen) = &self.ctx.seen_tokens
+				&& !seen.entry(auth.token()).or_insert(()).await.is_fresh()
+			{
+				self.audit(AuditEvent::AuthFailure, auth.uuid());
+				self.ctx.stats.auth_failures.replayed.fetch_add(1, Ordering::Relaxed);
+				return Err(Error::Replayed(auth.uuid()));
+			}
+
+			match self
+				.ctx
+				.users
+				.connect(auth.uuid(), &self.inner, self.ctx.cfg.user_limits.get(&auth.uuid()))
+			{
+				Ok(replaced) => {
+					for conn in replaced {
+						conn.close(REPLACED_CODE, b"Replaced by a newer connection");
+					}
+				}
+				Err(TooManyConnections) => {
+					self.ctx
+						.stats
+						.auth_failures
+						.too_many_connections
+						.fetch_add(1, Ordering::Relaxed);
+					return Err(Error::TooManyConnections(auth.uuid()));
+				}
+			}
+
+			self.remember_congestion_hint(auth, source).await;
+			if let Some(motd) = self.ctx.cfg.motd.clone()
+				&& auth.options().accepts_messages
+			{
+				let model = self.model.clone();
+				self.spawn(async move {
+					if let Err(err) = model.message(&motd).await {
+						debug!("[authenticate] failed to send the message of the day: {err}");
+					}
+				});
+			}
+			self.connect_replies.store(auth.options().connect_replies, Ordering::Relaxed);
+			self.ctx.users.track(auth.uuid());
+			if let Some(token) = auth.options().resume_token {
+				_ = self.resume_token.set(token);
+			}
+			self.ctx
+				.parked_udp
+				.resume(auth.uuid(), auth.options().resume_token, self)
+				.await;
 			self.auth.set(auth.uuid()).await;
 			Span::current().record("user", auth.uuid().to_string());
+			self.audit(AuditEvent::AuthSuccess, auth.uuid());
 			Ok(())
+		} else if let Some(password) = password
+			&& let Some(skew) = self.clock_skew(auth, &password).await
+		{
+			self.audit(AuditEvent::AuthFailure, auth.uuid());
+			self.ctx.stats.auth_failures.clock_skew.fetch_add(1, Ordering::Relaxed);
+			Err(Error::ClockSkew(auth.uuid(), skew))
 		} else {
+			self.audit(AuditEvent::AuthFailure, auth.uuid());
+			self.ctx.stats.auth_failures.invalid.fetch_add(1, Ordering::Relaxed);
 			Err(Error::AuthFailed(auth.uuid()))
 		}
 	}
 
+	/// Note the congestion controller the client asked for, so its next
+	/// connection from `source` is accepted with it. Requesting none or one
+	/// outside `client_choices` goes back to the configured controller.
+	async fn remember_congestion_hint(&self, auth: &Authenticate, source: IpAddr) {
+		let choices = &self.ctx.cfg.quic.congestion_control.client_choices;
+		if choices.is_empty() {
+			return;
+		}
+		match auth.options().congestion_control {
+			Some(controller) if choices.contains(&controller) => {
+				debug!("[authenticate] client asked for congestion controller {controller:?}");
+				self.ctx.congestion_hints.insert(source, controller).await;
+			}
+			_ => self.ctx.congestion_hints.invalidate(&source).await,
+		}
+	}
+
+	/// In timestamp mode, how many seconds the client's clock is ahead (or
+	/// behind, if negative) when `auth` was signed with a step outside
+	/// `auth_clock_skew` but within [`SKEW_PROBE`]. As probing tries many
+	/// steps, it's done for one rejected token per user a minute, and the
+	/// others are taken for wrong passwords.
+	async fn clock_skew(&self, auth: &Authenticate, password: &str) -> Option<i64> {
+		if self.ctx.cfg.auth_mode != AuthMode::Timestamp
+			|| !self.ctx.skew_probes.entry(auth.uuid()).or_insert(()).await.is_fresh()
+		{
+			return None;
+		}
+		let step = self.ctx.cfg.auth_time_step;
+		timestamp_auth::clock_offset(password.as_bytes(), SystemTime::now(), SKEW_PROBE, step, |signed| {
+			auth.validate(signed).unwrap_or(false)
+		})
+	}
+
+	/// The password of the user `auth` is for, from the users or else from
+	/// `[auth_webhook]`
+	async fn password_of(&self, auth: &Authenticate) -> Option<String> {
+		if let Some(password) = self.ctx.users.password(&auth.uuid()) {
+			return Some(password);
+		}
+		let webhook = self.ctx.auth_webhook.as_ref()?;
+		let password = webhook
+			.password(auth.uuid(), auth.token(), self.inner.remote_address())
+			.await?;
+		Some(password.to_string())
+	}
+
+	/// Whether `auth` carries a token derived from `password` the way
+	/// `auth_mode` requires
+	fn password_valid(&self, auth: &Authenticate, password: &str) -> bool {
+		match self.ctx.cfg.auth_mode {
+			AuthMode::Token => auth.validate(password).unwrap_or(false),
+			AuthMode::Timestamp => {
+				let (skew, step) = (self.ctx.cfg.auth_clock_skew, self.ctx.cfg.auth_time_step);
+				timestamp_auth::accepted_steps(SystemTime::now(), skew, step).any(|counter| {
+					auth.validate(timestamp_auth::password_at(password.as_bytes(), counter))
+						.unwrap_or(false)
+				})
+			}
+		}
+	}
+
+	/// Count a failed authentication against the source for `[ban]`
+	async fn count_auth_failure(&self) {
+		let (Some(cfg), Some(bans)) = (&self.ctx.cfg.ban, &self.ctx.bans) else {
+			return;
+		};
+		let source = self.inner.remote_address().ip();
+		if bans.fail(source).await {
+			warn!(
+				"[ban] banning {source} for {} after {} failed authentications",
+				humantime::format_duration(cfg.duration),
+				cfg.max_failures
+			);
+			self.ctx.stats.bans.banned.fetch_add(1, Ordering::Relaxed);
+			if self.ctx.cluster.is_some() {
+				let ctx = self.ctx.clone();
+				tokio::spawn(async move {
+					if let Some(cluster) = &ctx.cluster
+						&& let Err(err) = cluster.publish_bans(vec![source], false).await
+					{
+						warn!("[cluster] failed to publish ban: {err:#}");
+					}
+				});
+			}
+		}
+	}
+
 	async fn timeout_authenticate(self, timeout: Duration) {
 		tokio::select! {
 			() = self.auth.wait() => {
@@ -192,17 +467,119 @@ impl Connection {
 			}
 			() = time::sleep(timeout) => {
 				warn!("[authenticate] timeout");
+				self.count_auth_failure().await;
 				self.close();
 			}
 		}
 	}
 
+	/// Drain the connection `lifetime` after authentication: new streams are
+	/// refused and it is closed once the open ones finished, or after
+	/// [`SESSION_DRAIN_TIMEOUT`]. The client then has to authenticate again on
+	/// a new connection, so credentials revoked meanwhile stop working.
+	async fn expire_session(self, lifetime: Duration) {
+		let expired = async {
+			self.auth.wait().await;
+			time::sleep(lifetime).await;
+		};
+		tokio::select! {
+			() = expired => {
+				info!(
+					"[authenticate] session lifetime of {lifetime:?} reached, draining {} stream(s)",
+					self.streams.len()
+				);
+				drain::drain_connection(
+					&self.inner,
+					&self.streams,
+					SESSION_DRAIN_TIMEOUT,
+					SESSION_EXPIRED_CODE,
+					b"Session expired",
+				)
+				.await;
+			}
+			_ = self.inner.closed() => {}
+		}
+	}
+
+	/// Close the connection once the credentials it authenticated with no
+	/// longer work, after `users_file` or the admin API changed them.
+	async fn close_when_revoked(self) {
+		let mut changes = self.ctx.users.subscribe();
+		let revoked = async {
+			self.auth.wait().await;
+			let Some(uuid) = self.auth.get() else {
+				return std::future::pending().await;
+			};
+			let password = self.ctx.users.password(&uuid);
+			changes.mark_unchanged();
+			while changes.changed().await.is_ok() {
+				if self.ctx.users.password(&uuid) != password {
+					return uuid;
+				}
+			}
+			std::future::pending().await
+		};
+		tokio::select! {
+			uuid = revoked => {
+				info!("[authenticate] credentials of {uuid} were revoked");
+				self.inner.close(REVOKED_CODE, b"Credentials revoked");
+			}
+			_ = self.inner.closed() => {}
+		}
+	}
+
+	/// Close the connection once the quota of its user is used up, if
+	/// `quota_close` says so
+	async fn close_when_over_quota(self) {
+		let exhausted = async {
+			self.auth.wait().await;
+			let Some(quota) = self.auth.get().and_then(|uuid| self.ctx.quotas.get(&uuid)) else {
+				return std::future::pending().await;
+			};
+			if !quota.closes_connections() {
+				return std::future::pending().await;
+			}
+			let mut exhausted = quota.subscribe();
+			if exhausted.wait_for(|exhausted| *exhausted).await.is_err() {
+				std::future::pending::<()>().await;
+			}
+		};
+		tokio::select! {
+			() = exhausted => {
+				info!("[authenticate] quota used up");
+				self.inner.close(QUOTA_EXCEEDED_CODE, b"Quota exceeded");
+			}
+			_ = self.inner.closed() => {}
+		}
+	}
+
+	/// Close the connection when the credentials of its user expire
+	async fn close_when_expired(self) {
+		let expired = async {
+			self.auth.wait().await;
+			let Some(expires_at) = self.auth.get().and_then(|uuid| self.expires_at(uuid)) else {
+				return std::future::pending().await;
+			};
+			let left = expires_at.duration_since(SystemTime::now()).unwrap_or_default();
+			time::sleep(left).await;
+		};
+		tokio::select! {
+			() = expired => {
+				info!("[authenticate] credentials expired");
+				self.inner.close(EXPIRED_CODE, b"Credentials expired");
+			}
+			_ = self.inner.closed() => {}
+		}
+	}
+
 	async fn collect_garbage(self) {
 		loop {
 			time::sleep(self.ctx.cfg.gc_interval).await;
 
 			if self.is_closed() {
 				if let Some(uuid) = self.auth.get() {
+					self.audit(AuditEvent::Disconnect, uuid);
+					self.ctx.users.disconnect(&uuid, &self.inner);
 					restful::client_disconnect(&self.ctx, &uuid, self.inner).await;
 				}
 				break;
@@ -213,12 +590,16 @@ impl Connection {
 		}
 	}
 
+	fn dump(&self, dir: Direction, command: impl FnOnce() -> String) {
+		self.ctx.dump.record(self.id(), dir, command);
+	}
+
 	fn id(&self) -> u32 {
 		self.inner.stable_id() as u32
 	}
 
 	async fn classify_h3_dispatch(&self) -> Result<H3Dispatch, Error> {
-		let classify_timeout = self.ctx.cfg.task_negotiation_timeout;
+		let classify_timeout = self.ctx.live.load().task_negotiation_timeout;
 		let first_event = match time::timeout(classify_timeout, async {
 			tokio::select! {
 				res = self.inner.accept_uni() => {
@@ -305,7 +686,6 @@ impl Connection {
 	}
 
 	async fn run_tuic_event_loop(&self) {
-		let span = Span::current();
 		loop {
 			if self.is_closed() {
 				break;
@@ -313,12 +693,9 @@ impl Connection {
 
 			let handle_incoming = async {
 				tokio::select! {
-					res = self.inner.accept_uni() =>
-						tokio::spawn(self.clone().handle_uni_stream(res?, ).instrument(span.clone())),
-					res = self.inner.accept_bi() =>
-						tokio::spawn(self.clone().handle_bi_stream(res?, ).instrument(span.clone())),
-					res = self.inner.read_datagram() =>
-						tokio::spawn(self.clone().handle_datagram(res?).instrument(span.clone())),
+					res = self.inner.accept_uni() => self.spawn(self.clone().handle_uni_stream(res?)),
+					res = self.inner.accept_bi() => self.spawn(self.clone().handle_bi_stream(res?)),
+					res = self.inner.read_datagram() => self.spawn(self.clone().handle_datagram(res?)),
 				};
 
 				Ok::<_, Error>(())
@@ -342,3 +719,18 @@ impl Connection {
 		self.inner.close(ERROR_CODE, &[]);
 	}
 }
+
+/// Run `task`, closing `conn` if it panics, so that a malformed request takes
+/// down only its own connection instead of the whole server. The release
+/// profile aborts on panic, so this guards builds that unwind.
+async fn close_on_panic(conn: QuinnConnection, task: impl Future) {
+	if let Err(panic) = AssertUnwindSafe(task).catch_unwind().await {
+		let msg = panic
+			.downcast_ref::<&str>()
+			.map(|msg| msg.to_string())
+			.or_else(|| panic.downcast_ref::<String>().cloned())
+			.unwrap_or_else(|| "unknown panic payload".to_string());
+		error!("{}, closing connection", Error::Panicked(msg));
+		conn.close(ERROR_CODE, b"internal error");
+	}
+}
@@ -1,87 +1,108 @@
 use std::{
+	collections::HashMap,
 	io::Error as IoError,
 	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket},
-	sync::{Arc, Weak},
+	sync::{
+		Arc, Mutex, PoisonError, Weak,
+		atomic::{AtomicBool, Ordering},
+	},
 };
 
 use bytes::Bytes;
+use rand::prelude::IndexedRandom;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::{
 	net::UdpSocket,
 	sync::{RwLock as AsyncRwLock, oneshot},
+	time,
 };
-use tracing::{Instrument, Span, debug, warn};
+use tracing::{debug, warn};
 use tuic_core::Address;
+use uuid::Uuid;
 
 use super::Connection;
-use crate::{AppContext, error::Error, utils::FutResultExt};
+use crate::{AppContext, config::Ipv4MappedMode, error::Error, stats::GaugeGuard, utils::FutResultExt};
 
 pub struct UdpSession {
 	ctx: Arc<AppContext>,
 	assoc_id: u16,
-	conn: Connection,
-	socket_v4: UdpSocket,
+	/// `None` when IPv4 destinations are served by a dual-stack `socket_v6`
+	socket_v4: Option<UdpSocket>,
 	socket_v6: Option<UdpSocket>,
+	/// Whether the client last addressed an IPv4 target as IPv4-mapped IPv6
+	mapped_v4: AtomicBool,
 	close: AsyncRwLock<Option<oneshot::Sender<()>>>,
+	_tracked: [GaugeGuard; 3],
 }
 
 impl UdpSession {
 	// spawn a task which actually owns itself, then return its wake reference.
 	pub fn new(ctx: Arc<AppContext>, conn: Connection, assoc_id: u16) -> Result<Weak<Self>, Error> {
-		let socket_v4 = {
-			let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
-				.map_err(|err| Error::Socket("failed to create UDP associate IPv4 socket", err))?;
+		// The sockets serve every target of the session, so they are bound as
+		// the default outbound says rather than any rule an ACL picks
+		let outbound = &ctx.cfg.outbound.default;
+		let mut rng = rand::rng();
+		let bind_v4 = outbound.bind_ipv4.choose(&mut rng).copied().map(IpAddr::from);
+		let bind_v6 = outbound.bind_ipv6.choose(&mut rng).copied().map(IpAddr::from);
+		let device = outbound.bind_device.as_deref();
 
-			socket
-				.set_nonblocking(true)
-				.map_err(|err| Error::Socket("failed setting UDP associate IPv4 socket as non-blocking", err))?;
-
-			socket
-				.bind(&SockAddr::from(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))))
-				.map_err(|err| Error::Socket("failed to bind UDP associate IPv4 socket", err))?;
-
-			UdpSocket::from_std(StdUdpSocket::from(socket))?
-		};
-
-		let socket_v6 = if ctx.cfg.udp_relay_ipv6 {
-			let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
-				.map_err(|err| Error::Socket("failed to create UDP associate IPv6 socket", err))?;
-
-			socket
-				.set_nonblocking(true)
-				.map_err(|err| Error::Socket("failed setting UDP associate IPv6 socket as non-blocking", err))?;
-
-			socket
-				.set_only_v6(true)
-				.map_err(|err| Error::Socket("failed setting UDP associate IPv6 socket as IPv6-only", err))?;
-
-			socket
-				.bind(&SockAddr::from(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))))
-				.map_err(|err| Error::Socket("failed to bind UDP associate IPv6 socket", err))?;
-
-			Some(UdpSocket::from_std(StdUdpSocket::from(socket))?)
+		// A socket bound to an IPv6 address can't reach IPv4 targets
+		let dual_stack = if ctx.cfg.udp_relay_ipv6 && ctx.cfg.udp_relay_dual_stack && bind_v4.is_none() && bind_v6.is_none() {
+			bind_socket(Domain::IPV6, false, None, device)
+				.inspect_err(|err| {
+					debug!("[packet] [{assoc_id:#06x}] dual-stack UDP socket unavailable, using one per family: {err}")
+				})
+				.ok()
 		} else {
 			None
 		};
 
+		let (socket_v4, socket_v6) = match dual_stack {
+			Some(socket) => (None, Some(socket)),
+			None => {
+				let socket_v4 = bind_socket(Domain::IPV4, true, bind_v4, device)?;
+				// A host without IPv6 should still relay IPv4 traffic
+				let socket_v6 = if ctx.cfg.udp_relay_ipv6 {
+					bind_socket(Domain::IPV6, true, bind_v6, device)
+						.inspect_err(|err| {
+							warn!("[packet] [{assoc_id:#06x}] IPv6 UDP relay unavailable for this session: {err}")
+						})
+						.ok()
+				} else {
+					None
+				};
+				(Some(socket_v4), socket_v6)
+			}
+		};
+
 		let (tx, rx) = oneshot::channel();
 
+		let sockets = usize::from(socket_v4.is_some()) + usize::from(socket_v6.is_some());
+		let tracked = [
+			ctx.stats.udp_sessions.track(1),
+			ctx.stats.relay_tasks.track(1),
+			ctx.stats.sockets.track(sockets),
+		];
+
 		let session = Arc::new(Self {
 			ctx: ctx.clone(),
-			conn,
 			assoc_id,
 			socket_v4,
 			socket_v6,
+			mapped_v4: AtomicBool::new(false),
 			close: AsyncRwLock::new(Some(tx)),
+			_tracked: tracked,
 		});
 
 		let session_listening = session.clone();
+		let spawner = conn.clone();
 		// UdpSession's real owner.
-		let listen_span = Span::current();
 		let listen = async move {
-			let span = Span::current();
 			let mut rx = rx;
-			let mut timeout = tokio::time::interval(ctx.cfg.stream_timeout);
+			// The connection relaying for the session, replaced when the session
+			// survives a reconnect
+			let mut conn = conn;
+			let mut timeout = tokio::time::interval(ctx.live.load().stream_timeout);
 			timeout.reset();
 
 			loop {
@@ -91,7 +112,16 @@ impl UdpSession {
 					// Parent QUIC connection dropped without a proper `UDP-DROP`: tear down
 					// immediately instead of lingering until `stream_timeout` (or forever, if
 					// the target keeps sending and resetting the timeout).
-					_ = session_listening.conn.inner.closed() => {
+					_ = conn.inner.closed() => {
+						if let Some(next) = session_listening.park(&conn).await {
+							debug!(
+								"[packet] [{assoc_id:#06x}] session resumed by a new connection",
+								assoc_id = session_listening.assoc_id
+							);
+							conn = next;
+							timeout.reset();
+							continue;
+						}
 						debug!(
 							"[packet] [{assoc_id:#06x}] parent connection closed, cleaning up",
 							assoc_id = session_listening.assoc_id
@@ -119,38 +149,68 @@ impl UdpSession {
 					}
 				};
 
-				tokio::spawn(
-					session_listening
-						.conn
-						.clone()
+				conn.spawn(
+					conn.clone()
 						.relay_packet(pkt, Address::SocketAddress(addr), session_listening.assoc_id)
-						.log_err()
-						.instrument(span.clone()),
+						.log_err(),
 				);
 			}
 			// Only drop our own map entry. If this assoc_id was re-used and replaced by a
 			// newer session while we were shutting down, leave that entry intact.
 			let self_weak = Arc::downgrade(&session_listening);
-			let mut sessions = session_listening.conn.udp_sessions.write().await;
+			let mut sessions = conn.udp_sessions.write().await;
 			if sessions.get(&assoc_id).is_some_and(|entry| entry.ptr_eq(&self_weak)) {
 				sessions.remove(&assoc_id);
 			}
 		};
 
-		tokio::spawn(listen.instrument(listen_span));
+		spawner.spawn(listen);
 		Ok(Arc::downgrade(&session))
 	}
 
+	/// Wait up to `udp_session_grace` for the client of the closed `conn` to
+	/// reconnect, returning the connection that takes the session over. Only
+	/// clients that sent a resume token get to.
+	async fn park(self: &Arc<Self>, conn: &Connection) -> Option<Connection> {
+		let grace = self.ctx.cfg.udp_session_grace;
+		if grace.is_zero() {
+			return None;
+		}
+		let key = (conn.auth.get()?, *conn.resume_token.get()?);
+		let resume = self.ctx.parked_udp.park(key, self.assoc_id, Arc::downgrade(self));
+		debug!(
+			"[packet] [{assoc_id:#06x}] parent connection closed, keeping the session for {grace:?}",
+			assoc_id = self.assoc_id
+		);
+		let resumed = time::timeout(grace, resume).await;
+		self.ctx.parked_udp.prune(&key);
+		resumed.ok()?.ok()
+	}
+
 	pub async fn send(&self, pkt: Bytes, mut addr: SocketAddr) -> Result<(), Error> {
-		if let SocketAddr::V6(v6) = addr {
-			if let Some(v4) = v6.ip().to_ipv4_mapped() {
-				addr = SocketAddr::new(IpAddr::V4(v4), v6.port());
-			}
+		let mut mapped = false;
+		if let SocketAddr::V6(v6) = addr
+			&& let Some(v4) = v6.ip().to_ipv4_mapped()
+		{
+			addr = SocketAddr::new(IpAddr::V4(v4), v6.port());
+			mapped = true;
+		}
+		if addr.is_ipv4() {
+			self.mapped_v4.store(mapped, Ordering::Relaxed);
 		}
 
-		let socket = match addr {
-			SocketAddr::V4(_) => &self.socket_v4,
-			SocketAddr::V6(_) => self.socket_v6.as_ref().ok_or_else(|| Error::UdpRelayIpv6Disabled(addr))?,
+		let (socket, addr) = match (addr, &self.socket_v4) {
+			(SocketAddr::V4(_), Some(socket_v4)) => (socket_v4, addr),
+			// Dual-stack socket: reach IPv4 destinations through their IPv4-mapped
+			// IPv6 address
+			(SocketAddr::V4(v4), None) => (
+				self.socket_v6.as_ref().ok_or_else(|| Error::UdpRelayIpv6Disabled(addr))?,
+				SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+			),
+			(SocketAddr::V6(_), _) => (
+				self.socket_v6.as_ref().ok_or_else(|| Error::UdpRelayIpv6Disabled(addr))?,
+				addr,
+			),
 		};
 
 		socket.send_to(&pkt, addr).await?;
@@ -159,7 +219,7 @@ impl UdpSession {
 
 	async fn recv(&self) -> Result<(Bytes, SocketAddr), IoError> {
 		let recv = async |socket: &UdpSocket| -> Result<(Bytes, SocketAddr), IoError> {
-			let mut buf = vec![0u8; self.ctx.cfg.max_external_packet_size];
+			let mut buf = vec![0u8; self.ctx.live.load().max_external_packet_size];
 			let (n, mut addr) = socket.recv_from(&mut buf).await?;
 			if let SocketAddr::V6(v6) = addr {
 				if let Some(v4) = v6.ip().to_ipv4_mapped() {
@@ -167,16 +227,33 @@ impl UdpSession {
 				}
 			}
 			buf.truncate(n);
-			Ok((Bytes::from(buf), addr))
+			Ok((Bytes::from(buf), self.reply_addr(addr)))
 		};
 
-		if let Some(socket_v6) = &self.socket_v6 {
-			tokio::select! {
-				res = recv(&self.socket_v4) => res,
+		match (&self.socket_v4, &self.socket_v6) {
+			(Some(socket_v4), Some(socket_v6)) => tokio::select! {
+				res = recv(socket_v4) => res,
 				res = recv(socket_v6) => res,
-			}
+			},
+			(Some(socket), None) | (None, Some(socket)) => recv(socket).await,
+			(None, None) => std::future::pending().await,
+		}
+	}
+
+	/// Address `addr` as the client expects replies from it
+	fn reply_addr(&self, addr: SocketAddr) -> SocketAddr {
+		let SocketAddr::V4(v4) = addr else {
+			return addr;
+		};
+		let map = match self.ctx.cfg.udp_relay_ipv4_mapped {
+			Ipv4MappedMode::Preserve => self.mapped_v4.load(Ordering::Relaxed),
+			Ipv4MappedMode::Unmap => false,
+			Ipv4MappedMode::Map => true,
+		};
+		if map {
+			SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
 		} else {
-			recv(&self.socket_v4).await
+			addr
 		}
 	}
 
@@ -186,3 +263,201 @@ impl UdpSession {
 		}
 	}
 }
+
+/// UDP sessions of dropped connections, kept for `udp_session_grace` for
+/// their client to reconnect, by user and resume token.
+#[derive(Default)]
+pub struct ParkedUdpSessions(Mutex<HashMap<(Uuid, Uuid), Vec<Parked>>>);
+
+struct Parked {
+	assoc_id: u16,
+	session: Weak<UdpSession>,
+	resume: oneshot::Sender<Connection>,
+}
+
+impl ParkedUdpSessions {
+	/// Keep `session` for the next connection with `key`, which the returned
+	/// receiver yields.
+	fn park(&self, key: (Uuid, Uuid), assoc_id: u16, session: Weak<UdpSession>) -> oneshot::Receiver<Connection> {
+		let (resume, rx) = oneshot::channel();
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.entry(key)
+			.or_default()
+			.push(Parked {
+				assoc_id,
+				session,
+				resume,
+			});
+		rx
+	}
+
+	/// Forget the sessions of `key` whose grace period ran out.
+	fn prune(&self, key: &(Uuid, Uuid)) {
+		let mut parked = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+		if let Some(sessions) = parked.get_mut(key) {
+			sessions.retain(|parked| !parked.resume.is_closed());
+			if sessions.is_empty() {
+				parked.remove(key);
+			}
+		}
+	}
+
+	/// Take the sessions kept for `key` that are still waiting.
+	fn take(&self, key: &(Uuid, Uuid)) -> Vec<Parked> {
+		let parked = self.0.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+		let mut parked = parked.unwrap_or_default();
+		parked.retain(|parked| !parked.resume.is_closed());
+		parked
+	}
+
+	/// Hand the sessions kept for the client of `conn`, the user `uuid` with
+	/// `token`, over to it. Those it can't take are closed.
+	pub(super) async fn resume(&self, uuid: Uuid, token: Option<Uuid>, conn: &Connection) {
+		let Some(token) = token else {
+			return;
+		};
+		let parked = self.take(&(uuid, token));
+		if parked.is_empty() {
+			return;
+		}
+		let mut sessions = conn.udp_sessions.write().await;
+		for parked in admit(parked, &sessions, conn.ctx.cfg.max_udp_sessions.per_connection) {
+			if parked.resume.send(conn.clone()).is_ok() {
+				sessions.insert(parked.assoc_id, parked.session);
+			}
+		}
+	}
+}
+
+/// Of `parked`, the sessions a connection with the sessions `open` can take
+/// over: a session it opened itself under the same association ID wins, and
+/// resumed ones count against `per_connection` of `max_udp_sessions` (0 for
+/// no limit) like new ones do.
+fn admit(parked: Vec<Parked>, open: &HashMap<u16, Weak<UdpSession>>, per_connection: usize) -> Vec<Parked> {
+	let room = match per_connection {
+		0 => usize::MAX,
+		limit => limit.saturating_sub(open.len()),
+	};
+	let (admitted, refused): (Vec<_>, Vec<_>) = parked
+		.into_iter()
+		.filter(|parked| !open.contains_key(&parked.assoc_id))
+		.enumerate()
+		.partition(|(i, _)| *i < room);
+	if !refused.is_empty() {
+		debug!(
+			"[packet] {} resumed UDP session(s) over the per-connection limit closed",
+			refused.len()
+		);
+	}
+	admitted.into_iter().map(|(_, parked)| parked).collect()
+}
+
+/// Bind a non-blocking UDP relay socket on `local`, or the unspecified
+/// address of `domain`, and to the interface `device` where supported. IPv6
+/// sockets are dual-stack unless `only_v6` is set.
+fn bind_socket(domain: Domain, only_v6: bool, local: Option<IpAddr>, device: Option<&str>) -> Result<UdpSocket, Error> {
+	let local = match local {
+		Some(ip) => SocketAddr::new(ip, 0),
+		None if domain == Domain::IPV6 => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+		None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+	};
+
+	let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))
+		.map_err(|err| Error::Socket("failed to create UDP associate socket", err))?;
+
+	socket
+		.set_nonblocking(true)
+		.map_err(|err| Error::Socket("failed setting UDP associate socket as non-blocking", err))?;
+
+	if domain == Domain::IPV6 {
+		socket
+			.set_only_v6(only_v6)
+			.map_err(|err| Error::Socket("failed setting UDP associate IPv6 socket dual-stack mode", err))?;
+	}
+
+	socket
+		.bind(&SockAddr::from(local))
+		.map_err(|err| Error::Socket("failed to bind UDP associate socket", err))?;
+
+	let socket = UdpSocket::from_std(StdUdpSocket::from(socket))?;
+	#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+	if let Some(device) = device {
+		socket
+			.bind_device(Some(device.as_bytes()))
+			.map_err(|err| Error::Socket("failed to bind UDP associate socket to its device", err))?;
+	}
+	#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+	let _ = device;
+	Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_bind_socket_to_local_address() -> Result<(), Error> {
+		let loopback = IpAddr::from(Ipv4Addr::LOCALHOST);
+		let socket = bind_socket(Domain::IPV4, true, Some(loopback), None)?;
+		assert_eq!(socket.local_addr()?.ip(), loopback);
+		let socket = bind_socket(Domain::IPV4, true, None, None)?;
+		assert!(socket.local_addr()?.ip().is_unspecified());
+		Ok(())
+	}
+
+	fn parked_session(assoc_id: u16) -> Parked {
+		Parked {
+			assoc_id,
+			session: Weak::new(),
+			resume: oneshot::channel().0,
+		}
+	}
+
+	#[test]
+	fn test_parked_sessions_are_bound_to_the_token() {
+		let user = Uuid::from_u128(1);
+		let (token, other) = (Uuid::from_u128(10), Uuid::from_u128(11));
+		let parked = ParkedUdpSessions::default();
+		let _rx = parked.park((user, token), 1, Weak::new());
+
+		// Another client of the same user, or another user with the token
+		assert!(parked.take(&(user, other)).is_empty());
+		assert!(parked.take(&(Uuid::from_u128(2), token)).is_empty());
+
+		let taken = parked.take(&(user, token));
+		assert_eq!(taken.iter().map(|parked| parked.assoc_id).collect::<Vec<_>>(), [1]);
+		assert!(parked.take(&(user, token)).is_empty());
+	}
+
+	#[test]
+	fn test_parked_sessions_are_pruned() {
+		let key = (Uuid::from_u128(1), Uuid::from_u128(10));
+		let parked = ParkedUdpSessions::default();
+		let expired = parked.park(key, 1, Weak::new());
+		let waiting = parked.park(key, 2, Weak::new());
+
+		drop(expired);
+		parked.prune(&key);
+		let sessions = parked.0.lock().unwrap();
+		assert_eq!(sessions[&key].iter().map(|parked| parked.assoc_id).collect::<Vec<_>>(), [2]);
+		drop(sessions);
+
+		drop(waiting);
+		parked.prune(&key);
+		assert!(parked.0.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_admit() {
+		let ids = |admitted: Vec<Parked>| admitted.iter().map(|parked| parked.assoc_id).collect::<Vec<_>>();
+		let open = HashMap::from([(2, Weak::new())]);
+
+		// The connection's own session 2 wins over the parked one
+		assert_eq!(ids(admit((1..=3).map(parked_session).collect(), &open, 0)), [1, 3]);
+		// Resumed sessions count against the limit
+		assert_eq!(ids(admit((1..=3).map(parked_session).collect(), &open, 2)), [1]);
+		assert!(admit((1..=3).map(parked_session).collect(), &open, 1).is_empty());
+	}
+}
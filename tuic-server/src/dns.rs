@@ -0,0 +1,160 @@
+//! Resolution shared by all connections.
+//!
+//! With `[dns_cache]`, the addresses of a name are resolved once and reused by
+//! every connection and user for `ttl`, and concurrent lookups of the same name
+//! wait for a single query. Names are keyed by a hash salted per process, so
+//! the cache holds no readable list of the destinations users visited.
+//!
+//! The cache also learns which addresses can be reached: an address that
+//! failed to connect is tried after the other addresses of its name until it
+//! connects again or `failure_ttl` passes, so one blackholed A/AAAA record
+//! costs a connect timeout once instead of for every user.
+
+use std::{
+	io::{Error as IoError, ErrorKind},
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+	time::Duration,
+};
+
+use moka::future::Cache;
+use rand::RngExt;
+use sha2::{Digest, Sha256};
+use tokio::{net, time};
+
+use crate::{config::DnsCacheConfig, error::RelayTimeout, resolver::Resolver};
+
+pub struct DnsCache {
+	names: Cache<[u8; 32], Arc<[IpAddr]>>,
+	/// Connect failures by address since it last connected
+	failures: Cache<SocketAddr, u32>,
+	salt: [u8; 32],
+}
+
+impl DnsCache {
+	pub fn new(cfg: &DnsCacheConfig) -> Self {
+		Self {
+			names: Cache::builder().max_capacity(cfg.max_entries).time_to_live(cfg.ttl).build(),
+			failures: Cache::builder()
+				.max_capacity(cfg.max_entries)
+				.time_to_live(cfg.failure_ttl)
+				.build(),
+			salt: rand::rng().random(),
+		}
+	}
+
+	/// The addresses of `domain`, resolved within `timeout` unless cached
+	pub async fn lookup(
+		&self,
+		domain: &str,
+		port: u16,
+		timeout: Duration,
+		resolver: Option<&Resolver>,
+	) -> Result<Vec<SocketAddr>, IoError> {
+		let ips = self
+			.names
+			.try_get_with(self.key(domain), async {
+				Ok::<_, IoError>(Arc::from(lookup_ips(domain, timeout, resolver).await?))
+			})
+			.await
+			.map_err(|err| match err.kind() {
+				// Keep the timeout recognizable for the stream reset code
+				ErrorKind::TimedOut => IoError::new(ErrorKind::TimedOut, RelayTimeout::Dns),
+				kind => IoError::new(kind, err.to_string()),
+			})?;
+		let mut addrs: Vec<SocketAddr> = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+		self.rank(&mut addrs).await;
+		Ok(addrs)
+	}
+
+	/// Move addresses that failed recently behind the others, fewest failures
+	/// first
+	pub async fn rank(&self, addrs: &mut [SocketAddr]) {
+		let mut failures = Vec::with_capacity(addrs.len());
+		for addr in addrs.iter() {
+			failures.push(self.failures.get(addr).await.unwrap_or(0));
+		}
+		if failures.iter().all(|&count| count == 0) {
+			return;
+		}
+		let mut ranked: Vec<_> = failures.into_iter().zip(addrs.iter().copied()).collect();
+		ranked.sort_by_key(|(count, _)| *count);
+		for (slot, (_, addr)) in addrs.iter_mut().zip(ranked) {
+			*slot = addr;
+		}
+	}
+
+	/// Remember whether connecting to `addr` worked
+	pub async fn record(&self, addr: SocketAddr, connected: bool) {
+		if connected {
+			self.failures.invalidate(&addr).await;
+		} else {
+			let count = self.failures.get(&addr).await.unwrap_or(0);
+			self.failures.insert(addr, count.saturating_add(1)).await;
+		}
+	}
+
+	fn key(&self, domain: &str) -> [u8; 32] {
+		let mut hasher = Sha256::new();
+		hasher.update(self.salt);
+		hasher.update(domain.to_ascii_lowercase());
+		hasher.finalize().into()
+	}
+}
+
+/// The addresses of `domain` within `timeout`, asked of `resolver` or else
+/// the system resolver
+pub async fn lookup_ips(domain: &str, timeout: Duration, resolver: Option<&Resolver>) -> Result<Vec<IpAddr>, IoError> {
+	let lookup = async {
+		match resolver {
+			Some(resolver) => resolver.lookup(domain).await,
+			None => Ok(net::lookup_host((domain, 0)).await?.map(|addr| addr.ip()).collect()),
+		}
+	};
+	time::timeout(timeout, lookup)
+		.await
+		.map_err(|_| IoError::new(ErrorKind::TimedOut, RelayTimeout::Dns))?
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn cache() -> DnsCache {
+		DnsCache::new(&DnsCacheConfig::default())
+	}
+
+	#[tokio::test]
+	async fn test_failed_addresses_go_last() {
+		let cache = cache();
+		let a: SocketAddr = "192.0.2.1:443".parse().unwrap();
+		let b: SocketAddr = "192.0.2.2:443".parse().unwrap();
+		let c: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+
+		cache.record(a, false).await;
+		cache.record(a, false).await;
+		cache.record(b, false).await;
+		let mut addrs = [a, b, c];
+		cache.rank(&mut addrs).await;
+		assert_eq!(addrs, [c, b, a]);
+
+		cache.record(a, true).await;
+		let mut addrs = [a, b, c];
+		cache.rank(&mut addrs).await;
+		assert_eq!(addrs, [a, c, b]);
+	}
+
+	#[tokio::test]
+	async fn test_names_are_shared_case_insensitively() {
+		let cache = cache();
+		let addrs = cache.lookup("localhost", 80, Duration::from_secs(5), None).await.unwrap();
+		assert!(addrs.iter().all(|addr| addr.port() == 80 && addr.ip().is_loopback()));
+
+		assert_eq!(cache.key("Example.COM"), cache.key("example.com"));
+		assert!(cache.names.contains_key(&cache.key("LOCALHOST")));
+		assert_ne!(
+			cache.key("example.com"),
+			DnsCache::new(&DnsCacheConfig::default()).key("example.com")
+		);
+	}
+}
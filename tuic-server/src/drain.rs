@@ -0,0 +1,135 @@
+//! Connection draining for scheduled maintenance.
+//!
+//! Once draining starts (`POST /drain` on the admin API), the server accepts
+//! no new connections, so clients reconnecting elsewhere move to other
+//! servers. Existing connections keep working until they close or the
+//! deadline passes. The rest are then closed with [`DRAIN_ERROR_CODE`], which
+//! lets clients tell maintenance apart from a failure, and the server exits.
+//!
+//! A single connection is drained the same way by [`drain_connection`], e.g.
+//! once it reached `max_session_lifetime`.
+
+use std::{
+	sync::{Arc, OnceLock},
+	time::Duration,
+};
+
+use tokio::{sync::Notify, time};
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+use tuic_core::quinn::{Endpoint, QuinnConnection, VarInt};
+
+use crate::AppContext;
+
+/// Application error code of connections closed at the drain deadline
+pub const DRAIN_ERROR_CODE: VarInt = VarInt::from_u32(6010);
+
+#[derive(Default)]
+pub struct Drain {
+	deadline: OnceLock<Duration>,
+	started: Notify,
+}
+
+impl Drain {
+	/// Start draining with `deadline` for the existing connections. Returns
+	/// `false` if the server is already draining.
+	pub fn start(&self, deadline: Duration) -> bool {
+		let started = self.deadline.set(deadline).is_ok();
+		if started {
+			self.started.notify_one();
+		}
+		started
+	}
+
+	pub fn is_draining(&self) -> bool {
+		self.deadline.get().is_some()
+	}
+}
+
+/// Wait for draining to start, then drain `endpoints` and cancel the server.
+pub async fn serve(ctx: Arc<AppContext>, endpoints: Vec<Endpoint>) {
+	let deadline = loop {
+		if let Some(deadline) = ctx.drain.deadline.get() {
+			break *deadline;
+		}
+		tokio::select! {
+			() = ctx.drain.started.notified() => {}
+			() = ctx.cancel.cancelled() => return,
+		}
+	};
+
+	let open_connections = || endpoints.iter().map(Endpoint::open_connections).sum::<usize>();
+	info!(
+		"[drain] no longer accepting connections, draining {} connection(s) for up to {}",
+		open_connections(),
+		humantime::format_duration(deadline)
+	);
+	for ep in &endpoints {
+		ep.set_server_config(None);
+	}
+	let idle = futures::future::join_all(endpoints.iter().map(|ep| ep.wait_idle()));
+	if time::timeout(deadline, idle).await.is_err() {
+		warn!(
+			"[drain] deadline reached, closing {} remaining connection(s)",
+			open_connections()
+		);
+	}
+	for ep in &endpoints {
+		ep.close(DRAIN_ERROR_CODE, b"server maintenance");
+	}
+	ctx.cancel.cancel();
+}
+
+/// Drain `conn`: refuse new streams, wait up to `deadline` for the open ones
+/// in `streams` to finish, then close it with `code`.
+pub async fn drain_connection(conn: &QuinnConnection, streams: &TaskTracker, deadline: Duration, code: VarInt, reason: &[u8]) {
+	conn.set_max_concurrent_bi_streams(VarInt::from_u32(0));
+	streams.close();
+	tokio::select! {
+		result = time::timeout(deadline, streams.wait()) => {
+			if result.is_err() {
+				warn!("[drain] deadline reached, closing the connection with {} open stream(s)", streams.len());
+			}
+			conn.close(code, reason);
+		}
+		_ = conn.closed() => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_drain_code_is_distinct() {
+		use crate::connection::{
+			CLOCK_SKEW_CODE, ERROR_CODE, EXPIRED_CODE, INTERNAL_ERROR_CODE, KICKED_CODE, QUOTA_EXCEEDED_CODE, REPLACED_CODE,
+			REVOKED_CODE, SESSION_EXPIRED_CODE, TOO_MANY_CONNECTIONS_CODE,
+		};
+
+		for code in [
+			ERROR_CODE,
+			KICKED_CODE,
+			INTERNAL_ERROR_CODE,
+			SESSION_EXPIRED_CODE,
+			CLOCK_SKEW_CODE,
+			REVOKED_CODE,
+			TOO_MANY_CONNECTIONS_CODE,
+			REPLACED_CODE,
+			QUOTA_EXCEEDED_CODE,
+			EXPIRED_CODE,
+		] {
+			assert_ne!(DRAIN_ERROR_CODE, code);
+		}
+	}
+
+	#[test]
+	fn test_drain_starts_once() {
+		let drain = Drain::default();
+		assert!(!drain.is_draining());
+		assert!(drain.start(Duration::from_secs(600)));
+		assert!(drain.is_draining());
+		assert!(!drain.start(Duration::from_secs(1)));
+		assert_eq!(drain.deadline.get(), Some(&Duration::from_secs(600)));
+	}
+}
@@ -0,0 +1,179 @@
+//! Packet dump of selected connections for diagnosing protocol interop bugs.
+//!
+//! Connections are selected by ID (the `id` field of the `conn` log span)
+//! through the admin API. Every decoded TUIC command received on a selected
+//! connection, and every UDP packet relayed back to it, is written as one
+//! JSONL record with a timestamp. Records go to `packet_dump_file` when it is
+//! set and to the log otherwise. Payloads are never dumped. The file is
+//! written on a thread of its own, and records are dropped rather than hold
+//! up the relay when it falls behind.
+
+use std::{
+	collections::BTreeSet,
+	fs::{self, OpenOptions},
+	io::{self, Write},
+	path::Path,
+	sync::{
+		PoisonError, RwLock,
+		atomic::{AtomicBool, Ordering},
+	},
+	time::SystemTime,
+};
+
+use serde::Serialize;
+use tracing::{info, warn};
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tuic_core::quinn::{StreamRx, StreamTx, Task};
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+	/// Received from the client
+	In,
+	/// Sent to the client
+	Out,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+	time: String,
+	conn: u32,
+	dir: Direction,
+	command: &'a str,
+}
+
+pub struct PacketDump {
+	selected: RwLock<BTreeSet<u32>>,
+	/// Whether any connection is selected, checked before taking the lock
+	active: AtomicBool,
+	file: Option<(NonBlocking, WorkerGuard)>,
+}
+
+impl PacketDump {
+	pub fn new(path: Option<&Path>) -> io::Result<Self> {
+		let file = path
+			.map(|path| {
+				if let Some(parent) = path.parent()
+					&& !parent.as_os_str().is_empty()
+				{
+					fs::create_dir_all(parent)?;
+				}
+				let file = OpenOptions::new().create(true).append(true).open(path)?;
+				Ok::<_, io::Error>(NonBlockingBuilder::default().thread_name("tuic-dump").finish(file))
+			})
+			.transpose()?;
+
+		Ok(Self {
+			selected: RwLock::new(BTreeSet::new()),
+			active: AtomicBool::new(false),
+			file,
+		})
+	}
+
+	/// Start or stop dumping the connection with `id`.
+	pub fn select(&self, id: u32, enabled: bool) {
+		let mut selected = self.selected.write().unwrap_or_else(PoisonError::into_inner);
+		if enabled {
+			selected.insert(id);
+		} else {
+			selected.remove(&id);
+		}
+		self.active.store(!selected.is_empty(), Ordering::Relaxed);
+	}
+
+	pub fn selected(&self) -> Vec<u32> {
+		self.selected
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.iter()
+			.copied()
+			.collect()
+	}
+
+	pub fn is_selected(&self, id: u32) -> bool {
+		self.active.load(Ordering::Relaxed) && self.selected.read().unwrap_or_else(PoisonError::into_inner).contains(&id)
+	}
+
+	/// Dump one command of connection `id`. `command` is only evaluated when
+	/// the connection is selected.
+	pub fn record(&self, id: u32, dir: Direction, command: impl FnOnce() -> String) {
+		if !self.is_selected(id) {
+			return;
+		}
+
+		let command = command();
+		let record = Record {
+			time: humantime::format_rfc3339_micros(SystemTime::now()).to_string(),
+			conn: id,
+			dir,
+			command: &command,
+		};
+		let mut line = match serde_json::to_vec(&record) {
+			Ok(line) => line,
+			Err(err) => {
+				warn!("[dump] failed to encode record: {err}");
+				return;
+			}
+		};
+
+		match &self.file {
+			Some((file, _)) => {
+				line.push(b'\n');
+				if let Err(err) = file.clone().write_all(&line) {
+					warn!("[dump] failed to write record: {err}");
+				}
+			}
+			None => info!("[dump] {}", String::from_utf8_lossy(&line)),
+		}
+	}
+}
+
+/// Describe the header fields of a decoded command.
+pub fn describe<S: StreamTx, R: StreamRx>(task: &Task<S, R>) -> String {
+	match task {
+		Task::Authenticate(auth) => format!("AUTHENTICATE uuid={}", auth.uuid()),
+		Task::Connect(conn) => format!("CONNECT addr={}", conn.addr()),
+		Task::Packet(pkt) => format!(
+			"PACKET assoc_id={:#06x} pkt_id={} frag={}/{} via={}",
+			pkt.assoc_id(),
+			pkt.pkt_id(),
+			pkt.frag_id(),
+			pkt.frag_total(),
+			if pkt.is_from_quic() { "quic" } else { "native" },
+		),
+		Task::Dissociate(assoc_id) => format!("DISSOCIATE assoc_id={assoc_id:#06x}"),
+		Task::Heartbeat => "HEARTBEAT".to_string(),
+		Task::Message(text) => format!("MESSAGE {text:?}"),
+		_ => "UNKNOWN".to_string(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_dump_only_selected_connections() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("dump.jsonl");
+		let dump = PacketDump::new(Some(&path)).unwrap();
+
+		dump.record(1, Direction::In, || "HEARTBEAT".to_string());
+		dump.select(1, true);
+		dump.record(1, Direction::In, || "HEARTBEAT".to_string());
+		dump.record(2, Direction::In, || "HEARTBEAT".to_string());
+		dump.select(1, false);
+		dump.record(1, Direction::Out, || "HEARTBEAT".to_string());
+		assert!(dump.selected().is_empty());
+		// Waits for the queued records
+		drop(dump);
+
+		let content = fs::read_to_string(&path).unwrap();
+		let lines: Vec<_> = content.lines().collect();
+		assert_eq!(lines.len(), 1);
+		let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+		assert_eq!(record["conn"], 1);
+		assert_eq!(record["dir"], "in");
+		assert_eq!(record["command"], "HEARTBEAT");
+	}
+}
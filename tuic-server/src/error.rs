@@ -1,8 +1,11 @@
-use std::{io::Error as IoError, net::SocketAddr};
+use std::{
+	io::Error as IoError,
+	net::{IpAddr, SocketAddr},
+};
 
 use rustls::Error as RustlsError;
 use thiserror::Error;
-use tuic_core::quinn::{ConnectionError, Error as ModelError};
+use tuic_core::quinn::{ConnectionError, Error as ModelError, VarInt};
 use uuid::Uuid;
 
 #[derive(Debug, Error)]
@@ -23,8 +26,24 @@ pub enum Error {
 	DuplicatedAuth,
 	#[error("authentication failed: {0}")]
 	AuthFailed(Uuid),
+	#[error("authentication failed: {0} is not allowed to connect from {1}")]
+	UnboundSource(Uuid, IpAddr),
+	#[error("authentication failed: the clock of {0} is {1}s off the server's, more than `auth_clock_skew`")]
+	ClockSkew(Uuid, i64),
+	#[error("authentication failed: {0} already has `max_connections` connections")]
+	TooManyConnections(Uuid),
+	#[error("authentication failed: the quota of {0} is used up")]
+	QuotaExceeded(Uuid),
+	#[error("authentication failed: the credentials of {0} expired")]
+	Expired(Uuid),
+	#[error("authentication failed: {0} presented a token accepted before")]
+	Replayed(Uuid),
 	#[error("received packet from unexpected source")]
 	UnexpectedPacketSource,
+	#[error("unexpected command on {0}")]
+	UnexpectedCommand(&'static str),
+	#[error("task panicked: {0}")]
+	Panicked(String),
 	#[error("{0}: {1}")]
 	Socket(&'static str, IoError),
 	#[error("task negotiation timed out")]
@@ -39,6 +58,15 @@ impl Error {
 	pub fn is_trivial(&self) -> bool {
 		matches!(self, Self::TimedOut | Self::LocallyClosed)
 	}
+
+	/// Whether the client failed to prove it knows the credentials of a user,
+	/// which `[ban]` counts against its source
+	pub fn is_auth_failure(&self) -> bool {
+		matches!(
+			self,
+			Self::AuthFailed(_) | Self::UnboundSource(..) | Self::ClockSkew(..) | Self::Replayed(_)
+		)
+	}
 }
 
 impl From<ConnectionError> for Error {
@@ -50,3 +78,33 @@ impl From<ConnectionError> for Error {
 		}
 	}
 }
+
+/// A timeout of one stage of relaying a TCP request. The stream is reset with
+/// [`RelayTimeout::code`], so the client can tell the stages apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum RelayTimeout {
+	#[error("resolving the target timed out")]
+	Dns,
+	#[error("connecting to the target timed out")]
+	Connect,
+	#[error("the target sent nothing before the first-byte timeout")]
+	FirstByte,
+	#[error("the relay was idle for too long")]
+	Idle,
+}
+
+impl RelayTimeout {
+	pub fn code(self) -> VarInt {
+		match self {
+			Self::Dns => VarInt::from_u32(7001),
+			Self::Connect => VarInt::from_u32(7002),
+			Self::FirstByte => VarInt::from_u32(7003),
+			Self::Idle => VarInt::from_u32(7004),
+		}
+	}
+
+	/// The timeout carried by an I/O error of the relay, if any
+	pub fn from_io(err: &IoError) -> Option<Self> {
+		err.get_ref()?.downcast_ref::<Self>().copied()
+	}
+}
@@ -0,0 +1,253 @@
+//! Active health checks of upstream (SOCKS5) outbounds.
+//!
+//! Every outbound with a non-zero `health_check_interval` is probed by
+//! connecting to its proxy and completing the SOCKS5 method negotiation. While
+//! an outbound is unhealthy, traffic routed to it moves on to its `fallback`
+//! outbound, so a dead second hop doesn't blackhole everything routed through
+//! it.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::{
+		Arc, Mutex, PoisonError,
+		atomic::{AtomicBool, AtomicU32, Ordering},
+	},
+	time::{Duration, SystemTime},
+};
+
+use eyre::{OptionExt, eyre};
+use serde::Serialize;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+	time,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+	AppContext,
+	config::{OutboundConfig, OutboundRule},
+};
+
+#[derive(Default)]
+struct OutboundHealth {
+	healthy: AtomicBool,
+	consecutive_failures: AtomicU32,
+	last_checked: Mutex<Option<SystemTime>>,
+	last_error: Mutex<Option<String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthSnapshot {
+	pub healthy: bool,
+	pub consecutive_failures: u32,
+	pub last_checked: Option<String>,
+	pub last_error: Option<String>,
+}
+
+/// Health of the outbounds that have checks enabled. Outbounds without checks
+/// always count as healthy.
+pub struct UpstreamHealth {
+	outbounds: HashMap<String, OutboundHealth>,
+}
+
+impl UpstreamHealth {
+	pub fn new(cfg: &OutboundConfig) -> Self {
+		let outbounds = rules(cfg)
+			.filter(|(_, rule)| is_checked(rule))
+			.map(|(name, _)| {
+				// Assume healthy until the first probe says otherwise
+				let health = OutboundHealth::default();
+				health.healthy.store(true, Ordering::Relaxed);
+				(name.to_owned(), health)
+			})
+			.collect();
+
+		Self { outbounds }
+	}
+
+	pub fn is_healthy(&self, name: &str) -> bool {
+		self.outbounds
+			.get(canonical_name(name))
+			.is_none_or(|health| health.healthy.load(Ordering::Relaxed))
+	}
+
+	/// Follow `fallback` links from `name` until a healthy outbound is found.
+	/// Returns the last outbound tried when every hop is unhealthy or a hop
+	/// has no fallback.
+	pub fn route<'a>(&self, name: &'a str, cfg: &'a OutboundConfig) -> &'a str {
+		let mut name = name;
+		// At most one hop per outbound, which also breaks fallback cycles
+		for _ in 0..=cfg.named.len() {
+			if self.is_healthy(name) {
+				break;
+			}
+			let Some(fallback) = cfg.rule(name).fallback.as_deref() else {
+				break;
+			};
+			debug!("[outbound] '{name}' is unhealthy, falling back to '{fallback}'");
+			name = fallback;
+		}
+		name
+	}
+
+	pub fn snapshot(&self) -> BTreeMap<String, HealthSnapshot> {
+		self.outbounds
+			.iter()
+			.map(|(name, health)| {
+				let last_checked = health
+					.last_checked
+					.lock()
+					.unwrap_or_else(PoisonError::into_inner)
+					.map(|at| humantime::format_rfc3339_seconds(at).to_string());
+				let snapshot = HealthSnapshot {
+					healthy: health.healthy.load(Ordering::Relaxed),
+					consecutive_failures: health.consecutive_failures.load(Ordering::Relaxed),
+					last_checked,
+					last_error: health.last_error.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+				};
+				(name.clone(), snapshot)
+			})
+			.collect()
+	}
+
+	fn record(&self, name: &str, res: eyre::Result<()>) {
+		let Some(health) = self.outbounds.get(name) else {
+			return;
+		};
+		*health.last_checked.lock().unwrap_or_else(PoisonError::into_inner) = Some(SystemTime::now());
+
+		match res {
+			Ok(()) => {
+				if !health.healthy.swap(true, Ordering::Relaxed) {
+					info!("[outbound] '{name}' is healthy again");
+				}
+				health.consecutive_failures.store(0, Ordering::Relaxed);
+				*health.last_error.lock().unwrap_or_else(PoisonError::into_inner) = None;
+			}
+			Err(err) => {
+				if health.healthy.swap(false, Ordering::Relaxed) {
+					warn!("[outbound] '{name}' failed its health check: {err}");
+				}
+				health.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+				*health.last_error.lock().unwrap_or_else(PoisonError::into_inner) = Some(err.to_string());
+			}
+		}
+	}
+}
+
+/// Spawn one probe loop per checked outbound.
+pub fn start(ctx: Arc<AppContext>) {
+	for (name, rule) in rules(&ctx.cfg.outbound).filter(|(_, rule)| is_checked(rule)) {
+		tokio::spawn(check_loop(ctx.clone(), name.to_owned(), rule.health_check_interval));
+	}
+}
+
+async fn check_loop(ctx: Arc<AppContext>, name: String, interval: Duration) {
+	let mut interval = time::interval(interval);
+	loop {
+		tokio::select! {
+			_ = interval.tick() => {}
+			_ = ctx.cancel.cancelled() => return,
+		}
+
+		let rule = ctx.cfg.outbound.rule(&name);
+		let timeout = ctx.cfg.connect_timeout.min(rule.health_check_interval);
+		let res = match time::timeout(timeout, probe(rule)).await {
+			Ok(res) => res,
+			Err(_) => Err(eyre!("timed out after {}", humantime::format_duration(timeout))),
+		};
+		ctx.health.record(&name, res);
+	}
+}
+
+/// Connect to the proxy and, for SOCKS5, check that it accepts one of our
+/// auth methods.
+async fn probe(rule: &OutboundRule) -> eyre::Result<()> {
+	let addr = rule.addr.as_deref().ok_or_eyre("proxy outbound requires 'addr'")?;
+	let mut stream = TcpStream::connect(addr).await?;
+	// Anything sent to an HTTP proxy before CONNECT would be a request
+	if rule.kind.eq_ignore_ascii_case("http") {
+		return Ok(());
+	}
+
+	if rule.username.is_some() && rule.password.is_some() {
+		stream.write_all(&[0x05, 0x02, 0x00, 0x02]).await?;
+	} else {
+		stream.write_all(&[0x05, 0x01, 0x00]).await?;
+	}
+
+	let mut resp = [0u8; 2];
+	stream.read_exact(&mut resp).await?;
+	match resp {
+		[0x05, 0xff] => Err(eyre!("socks5 proxy has no acceptable auth methods")),
+		[0x05, _] => Ok(()),
+		[ver, _] => Err(eyre!("invalid socks5 version in method selection: {ver}")),
+	}
+}
+
+fn rules(cfg: &OutboundConfig) -> impl Iterator<Item = (&str, &OutboundRule)> {
+	std::iter::once(("default", &cfg.default)).chain(cfg.named.iter().map(|(name, rule)| (name.as_str(), rule)))
+}
+
+fn is_checked(rule: &OutboundRule) -> bool {
+	(rule.kind.eq_ignore_ascii_case("socks5") || rule.kind.eq_ignore_ascii_case("http"))
+		&& !rule.health_check_interval.is_zero()
+}
+
+fn canonical_name(name: &str) -> &str {
+	if name.eq_ignore_ascii_case("default") || name.eq_ignore_ascii_case("direct") {
+		"default"
+	} else {
+		name
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn socks5(fallback: Option<&str>) -> OutboundRule {
+		OutboundRule {
+			kind: "socks5".to_string(),
+			addr: Some("127.0.0.1:1080".to_string()),
+			health_check_interval: Duration::from_secs(10),
+			fallback: fallback.map(str::to_string),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_route_follows_fallbacks() {
+		let mut cfg = OutboundConfig::default();
+		cfg.named.insert("primary".to_string(), socks5(Some("secondary")));
+		cfg.named.insert("secondary".to_string(), socks5(Some("direct")));
+		let health = UpstreamHealth::new(&cfg);
+
+		assert_eq!(health.route("primary", &cfg), "primary");
+
+		health.record("primary", Err(eyre!("refused")));
+		assert_eq!(health.route("primary", &cfg), "secondary");
+
+		health.record("secondary", Err(eyre!("refused")));
+		assert_eq!(health.route("primary", &cfg), "direct");
+
+		health.record("primary", Ok(()));
+		assert_eq!(health.route("primary", &cfg), "primary");
+		assert_eq!(health.snapshot()["secondary"].consecutive_failures, 1);
+	}
+
+	#[test]
+	fn test_route_breaks_cycles() {
+		let mut cfg = OutboundConfig::default();
+		cfg.named.insert("a".to_string(), socks5(Some("b")));
+		cfg.named.insert("b".to_string(), socks5(Some("a")));
+		let health = UpstreamHealth::new(&cfg);
+		health.record("a", Err(eyre!("down")));
+		health.record("b", Err(eyre!("down")));
+
+		// Terminates instead of looping forever
+		let routed = health.route("a", &cfg);
+		assert!(routed == "a" || routed == "b");
+	}
+}
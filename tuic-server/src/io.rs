@@ -1,14 +1,40 @@
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io::{Error as IoError, ErrorKind};
 
-const BUFFER_SIZE: usize = 16 * 1024;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	time::{self, Duration, Instant},
+};
 
-pub async fn copy_io<A, B>(a: &mut A, b: &mut B) -> (usize, usize, Option<std::io::Error>)
+use crate::{bandwidth::Pace, error::RelayTimeout};
+
+/// Default size of each of the two relay buffers of a TCP stream
+pub const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Timeouts of [`copy_io`], zero disables each
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyTimeouts {
+	/// Deadline for the first byte from `b`
+	pub first_byte: Duration,
+	/// Longest time without data in either direction, once `b` has answered
+	pub idle: Duration,
+}
+
+/// Copy between `a`, the client, and `b` until both reach EOF, at `pace`.
+/// A timeout ends the copy with an [`ErrorKind::TimedOut`] error carrying the
+/// [`RelayTimeout`].
+pub async fn copy_io<A, B>(
+	a: &mut A,
+	b: &mut B,
+	buffer_size: usize,
+	timeouts: CopyTimeouts,
+	pace: Pace<'_>,
+) -> (usize, usize, Option<std::io::Error>)
 where
 	A: AsyncRead + AsyncWrite + Unpin + ?Sized,
 	B: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
-	let mut a2b = bytes::BytesMut::with_capacity(BUFFER_SIZE);
-	let mut b2a = bytes::BytesMut::with_capacity(BUFFER_SIZE);
+	let mut a2b = bytes::BytesMut::with_capacity(buffer_size);
+	let mut b2a = bytes::BytesMut::with_capacity(buffer_size);
 
 	let mut a2b_num = 0;
 	let mut b2a_num = 0;
@@ -18,7 +44,17 @@ where
 
 	let mut last_err = None;
 
+	let mut awaiting_first_byte = !timeouts.first_byte.is_zero();
+	let timer = time::sleep(if awaiting_first_byte {
+		timeouts.first_byte
+	} else {
+		timeouts.idle
+	});
+	tokio::pin!(timer);
+
 	loop {
+		let timer_armed = awaiting_first_byte || !timeouts.idle.is_zero();
+
 		tokio::select! {
 		   a2b_res = a.read_buf(&mut a2b), if !a_eof => match a2b_res {
 			  Ok(num) => {
@@ -32,6 +68,10 @@ where
 					}
 				 } else {
 					a2b_num += num;
+					pace.upload(num).await;
+					if !awaiting_first_byte {
+						timer.as_mut().reset(Instant::now() + timeouts.idle);
+					}
 					if let Err(err) = b.write_all(&a2b[..num]).await {
 						last_err = Some(err);
 						break;
@@ -56,6 +96,9 @@ where
 					}
 				 } else {
 					b2a_num += num;
+					pace.download(num).await;
+					awaiting_first_byte = false;
+					timer.as_mut().reset(Instant::now() + timeouts.idle);
 					if let Err(err) = a.write_all(&b2a[..num]).await {
 						last_err = Some(err);
 						break;
@@ -67,6 +110,11 @@ where
 				 last_err = Some(err);
 				 break;
 			  },
+		   },
+		   () = &mut timer, if timer_armed => {
+			  let timeout = if awaiting_first_byte { RelayTimeout::FirstByte } else { RelayTimeout::Idle };
+			  last_err = Some(IoError::new(ErrorKind::TimedOut, timeout));
+			  break;
 		   }
 		}
 	}
@@ -106,7 +154,14 @@ mod tests {
 			buf
 		});
 
-		let (a2b, b2a, err) = copy_io(&mut server_side, &mut remote).await;
+		let (a2b, b2a, err) = copy_io(
+			&mut server_side,
+			&mut remote,
+			BUFFER_SIZE,
+			CopyTimeouts::default(),
+			Pace::default(),
+		)
+		.await;
 
 		assert_eq!(a2b, data_to_remote.len());
 		assert_eq!(b2a, data_to_client.len());
@@ -131,7 +186,14 @@ mod tests {
 			remote_side.shutdown().await.unwrap();
 		});
 
-		let (a2b, b2a, _err) = copy_io(&mut server_side, &mut remote).await;
+		let (a2b, b2a, _err) = copy_io(
+			&mut server_side,
+			&mut remote,
+			BUFFER_SIZE,
+			CopyTimeouts::default(),
+			Pace::default(),
+		)
+		.await;
 
 		assert_eq!(a2b, 0);
 		assert_eq!(b2a, 0);
@@ -159,7 +221,14 @@ mod tests {
 			let _ = remote_side.read_to_end(&mut buf).await;
 		});
 
-		let (a2b, b2a, _err) = copy_io(&mut server_side, &mut remote).await;
+		let (a2b, b2a, _err) = copy_io(
+			&mut server_side,
+			&mut remote,
+			BUFFER_SIZE,
+			CopyTimeouts::default(),
+			Pace::default(),
+		)
+		.await;
 
 		assert_eq!(a2b, data.len());
 		assert_eq!(b2a, 0);
@@ -187,9 +256,35 @@ mod tests {
 			let _ = remote_side.read_to_end(&mut buf).await;
 		});
 
-		let (a2b, b2a, _err) = copy_io(&mut server_side, &mut remote).await;
+		let (a2b, b2a, _err) = copy_io(
+			&mut server_side,
+			&mut remote,
+			BUFFER_SIZE,
+			CopyTimeouts::default(),
+			Pace::default(),
+		)
+		.await;
 
 		assert_eq!(a2b, 100_000);
 		assert_eq!(b2a, 0);
 	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_copy_io_timeouts() {
+		let (_client, mut server_side) = duplex(1024);
+		let (mut remote, mut remote_side) = duplex(1024);
+		let timeouts = CopyTimeouts {
+			first_byte: Duration::from_secs(5),
+			idle: Duration::from_secs(30),
+		};
+
+		let (_, _, err) = copy_io(&mut server_side, &mut remote, BUFFER_SIZE, timeouts, Pace::default()).await;
+		assert_eq!(RelayTimeout::from_io(&err.unwrap()), Some(RelayTimeout::FirstByte));
+
+		let (_client, mut server_side) = duplex(1024);
+		remote_side.write_all(b"hello").await.unwrap();
+		let (_, b2a, err) = copy_io(&mut server_side, &mut remote, BUFFER_SIZE, timeouts, Pace::default()).await;
+		assert_eq!(b2a, 5);
+		assert_eq!(RelayTimeout::from_io(&err.unwrap()), Some(RelayTimeout::Idle));
+	}
 }
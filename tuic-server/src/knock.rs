@@ -0,0 +1,130 @@
+//! Single-packet authorization in front of the endpoint.
+//!
+//! With `[knock]`, connections are ignored unless their source address sent
+//! a valid knock (see [`tuic_core::knock`]) to `knock.listen` within
+//! `knock.lifetime`. Knocks are never answered, so the server stays silent to
+//! anyone without a password.
+
+use std::{
+	collections::HashMap,
+	net::IpAddr,
+	sync::Arc,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use moka::future::Cache;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+use tuic_core::knock;
+use uuid::Uuid;
+
+use crate::{AppContext, config::KnockConfig};
+
+/// Sources admitted by a knock, and knocks already used
+pub struct Gate {
+	admitted: Cache<IpAddr, ()>,
+	seen: Cache<[u8; knock::LEN], ()>,
+}
+
+impl Gate {
+	pub fn new(cfg: &KnockConfig) -> Self {
+		Self {
+			admitted: Cache::builder().time_to_live(cfg.lifetime).build(),
+			// A knock is stale once outside the window on either side
+			seen: Cache::builder().time_to_live(cfg.window * 2).build(),
+		}
+	}
+
+	/// Whether a knock from `ip` admits a new connection
+	pub fn admits(&self, ip: IpAddr) -> bool {
+		self.admitted.contains_key(&ip.to_canonical())
+	}
+
+	/// Admit `source` if `packet` is a fresh knock of a user, signed with the
+	/// user's password.
+	async fn knock(&self, cfg: &KnockConfig, users: &HashMap<Uuid, String>, packet: &[u8], source: IpAddr) -> bool {
+		let Some((uuid, secs)) = knock::parse(packet) else {
+			return false;
+		};
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+		if now.abs_diff(secs) > cfg.window.as_secs() {
+			return false;
+		}
+		let valid = users
+			.get(&uuid)
+			.is_some_and(|password| knock::verify(packet, password.as_bytes()));
+		// A replayed knock must not admit the address replaying it
+		let Ok(packet) = <[u8; knock::LEN]>::try_from(packet) else {
+			return false;
+		};
+		if !valid || self.seen.contains_key(&packet) {
+			return false;
+		}
+		self.seen.insert(packet, ()).await;
+		self.admitted.insert(source.to_canonical(), ()).await;
+		true
+	}
+}
+
+/// Receive knocks on `knock.listen` until the server shuts down.
+pub async fn serve(ctx: Arc<AppContext>) {
+	let (Some(cfg), Some(gate)) = (&ctx.cfg.knock, &ctx.knock) else {
+		return;
+	};
+	let socket = match UdpSocket::bind(cfg.listen).await {
+		Ok(socket) => socket,
+		Err(err) => {
+			warn!("[knock] failed to listen on {}: {err}", cfg.listen);
+			return;
+		}
+	};
+	warn!("[knock] receiving knocks on {}", cfg.listen);
+
+	let mut buf = [0; knock::LEN + 1];
+	loop {
+		let (n, source) = tokio::select! {
+			res = socket.recv_from(&mut buf) => match res {
+				Ok(v) => v,
+				Err(err) => {
+					debug!("[knock] receiving failed: {err}");
+					continue;
+				}
+			},
+			_ = ctx.cancel.cancelled() => return,
+		};
+		if gate.knock(cfg, &ctx.users.passwords(), &buf[..n], source.ip()).await {
+			debug!("[knock] admitted {}", source.ip());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_knock_admits_source_once() {
+		let cfg = KnockConfig::default();
+		let gate = Gate::new(&cfg);
+		let uuid = Uuid::from_u128(1);
+		let users = HashMap::from([(uuid, "password".to_owned())]);
+		let source: IpAddr = "192.0.2.1".parse().unwrap();
+		let replayer: IpAddr = "198.51.100.1".parse().unwrap();
+
+		let packet = knock::encode(uuid, b"password", SystemTime::now());
+		assert!(!gate.admits(source));
+		assert!(gate.knock(&cfg, &users, &packet, source).await);
+		assert!(gate.admits("::ffff:192.0.2.1".parse().unwrap()));
+		assert!(!gate.knock(&cfg, &users, &packet, replayer).await);
+		assert!(!gate.admits(replayer));
+
+		let stale = knock::encode(uuid, b"password", SystemTime::now() - Duration::from_secs(60));
+		assert!(!gate.knock(&cfg, &users, &stale, replayer).await);
+		let forged = knock::encode(uuid, b"guess", SystemTime::now());
+		assert!(!gate.knock(&cfg, &users, &forged, replayer).await);
+		let unknown = knock::encode(Uuid::from_u128(2), b"password", SystemTime::now());
+		assert!(!gate.knock(&cfg, &users, &unknown, replayer).await);
+	}
+}
@@ -1,36 +1,105 @@
 // Library interface for tuic-server
 // This allows the server to be used as a library in integration tests
 
-use std::{
-	collections::HashMap,
-	sync::{Arc, atomic::AtomicUsize},
-};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
 use moka::future::Cache;
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
+pub mod accounting;
 pub mod acl;
 pub mod acme;
+pub mod audit;
+pub mod ban;
+pub mod bandwidth;
+pub mod builder;
 pub mod camouflage;
+pub mod cluster;
 pub mod compat;
 pub mod config;
 pub mod connection;
+pub mod dns;
+pub mod drain;
+pub mod dump;
 pub mod error;
+pub mod health;
 pub mod io;
+pub mod knock;
 pub mod log;
+pub mod metrics;
+pub mod mirror;
+pub mod ocsp;
+pub mod pool;
+pub mod quota;
+pub mod reload;
+pub mod resolver;
 pub mod restful;
+pub mod rollout;
 pub mod server;
+pub mod stats;
 pub mod tls;
+pub mod upgrade;
+pub mod upstream;
+pub mod users;
 pub mod utils;
+pub mod webhook;
 
+pub use builder::ServerConfigBuilder;
 pub use config::{Cli, Config, Control};
 
+/// Source addresses whose requested congestion controller is remembered
+const CONGESTION_HINTS: u64 = 65536;
+/// How long a requested congestion controller is remembered without the
+/// client reconnecting
+const CONGESTION_HINT_IDLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Users whose last clock skew probe is remembered at most
+const SKEW_PROBES: u64 = 65536;
+/// How often the clock skew of one user's clients is probed at most
+const SKEW_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Accepted tokens remembered at most. Beyond, the oldest may be replayed.
+const SEEN_TOKENS: u64 = 262144;
+
 pub struct AppContext {
 	pub cfg: Config,
-	pub online_counter: HashMap<Uuid, AtomicUsize>,
+	/// Settings of `cfg` that apply anew on SIGHUP
+	pub live: ArcSwap<reload::Live>,
+	pub users: users::Users,
+	/// Rates of `user_limits`, by user
+	pub user_bandwidth: HashMap<Uuid, bandwidth::UserLimiter>,
+	pub quotas: quota::Quotas,
+	pub auth_webhook: Option<webhook::AuthWebhook>,
+	/// Tokens accepted within `auth_replay_window`
+	pub seen_tokens: Option<Cache<[u8; 32], ()>>,
+	/// Users whose rejected timestamp tokens were probed for clock skew within
+	/// the last minute
+	pub skew_probes: Cache<Uuid, ()>,
 	pub online_clients: Cache<Uuid, Arc<Cache<usize, compat::QuicClient>>>,
-	pub traffic_stats: HashMap<Uuid, (AtomicUsize, AtomicUsize)>,
+	pub stats: stats::ResourceStats,
+	pub audit: Option<audit::AuditLog>,
+	pub cluster: Option<cluster::Cluster>,
+	pub dump: dump::PacketDump,
+	pub drain: drain::Drain,
+	pub pool: Option<pool::ConnPool>,
+	pub dns: Option<dns::DnsCache>,
+	/// The DNS servers of `[dns]`, in place of the system resolver
+	pub resolver: Option<resolver::Resolver>,
+	pub health: health::UpstreamHealth,
+	/// Congestion controllers clients asked for, by source address, for
+	/// their next connection
+	pub congestion_hints: Cache<IpAddr, utils::CongestionController>,
+	pub parked_udp: connection::ParkedUdpSessions,
+	/// Sources admitted by `[knock]`
+	pub knock: Option<knock::Gate>,
+	/// Sources banned by `[ban]`
+	pub bans: Option<ban::Bans>,
+	/// Upstream servers of `tuic` outbounds
+	pub upstreams: upstream::Upstreams,
+	/// Transport settings tried on part of the new connections
+	pub rollout: rollout::Rollout,
 	pub cancel: CancellationToken,
 }
 
@@ -43,24 +112,102 @@ pub struct ServerGuard {
 /// Returns a [`ServerGuard`] containing the actual bound address and
 /// a cancellation token for graceful shutdown.
 pub async fn run(cfg: Config) -> eyre::Result<ServerGuard> {
-	let mut online_counter = HashMap::new();
-	for user in cfg.users.keys() {
-		online_counter.insert(user.to_owned(), AtomicUsize::new(0));
-	}
+	start(cfg, false).await
+}
 
-	let mut traffic_stats = HashMap::new();
-	for user in cfg.users.keys() {
-		traffic_stats.insert(user.to_owned(), (AtomicUsize::new(0), AtomicUsize::new(0)));
+/// Like [`run`], but take the listening socket over from the running server
+/// configured with the same `[upgrade]` socket, which then drains and exits.
+pub async fn run_upgrade(cfg: Config) -> eyre::Result<ServerGuard> {
+	start(cfg, true).await
+}
+
+/// Load what the server would load at start, from certificates to
+/// `users_file`, without binding any socket, for `--check`
+pub async fn check(cfg: &Config) -> eyre::Result<()> {
+	let tls = &cfg.tls;
+	let passphrase = tls.private_key_passphrase.as_deref();
+	if !tls.auto_ssl && tls.certificate_der.is_none() {
+		if let (Some(cert_pem), Some(key_pem)) = (&tls.certificate_pem, &tls.private_key_pem) {
+			tls::parse_pem(cert_pem, key_pem, passphrase)?;
+		} else if !tls.self_sign {
+			tls::check(&tls.certificate, &tls.private_key, passphrase).await?;
+			if let (Some(certificate), Some(private_key)) = (&tls.fallback_certificate, &tls.fallback_private_key) {
+				tls::check(certificate, private_key, passphrase).await?;
+			}
+			if let Some(path) = &tls.ocsp_response {
+				tokio::fs::read(path)
+					.await
+					.map_err(|err| eyre::eyre!("failed to read `tls.ocsp_response` {}: {err}", path.display()))?;
+			}
+		}
+	}
+	let provider = tls::crypto_provider(&tls.cipher_suites, &tls.kx_groups)?;
+	tls::client_verifier(tls.client_ca.as_deref(), provider).await?;
+	for sni in tls.sni.values() {
+		tls::check(&sni.certificate, &sni.private_key, passphrase).await?;
 	}
+	users::Users::load(cfg).await?;
+	quota::Quotas::new(cfg)?;
+	cfg.auth_webhook.as_ref().map(webhook::AuthWebhook::new).transpose()?;
+	upstream::Upstreams::new(&cfg.outbound, &cfg.tls).await?;
+	cfg.dns.as_ref().map(resolver::Resolver::new).transpose()?;
+	Ok(())
+}
+
+async fn start(cfg: Config, inherit: bool) -> eyre::Result<ServerGuard> {
+	let users = users::Users::load(&cfg).await?;
+	let quotas = quota::Quotas::new(&cfg)?;
+	let auth_webhook = cfg.auth_webhook.as_ref().map(webhook::AuthWebhook::new).transpose()?;
+	let audit = cfg.audit_log.as_ref().map(audit::AuditLog::open).transpose()?;
+	let dump = dump::PacketDump::new(cfg.packet_dump_file.as_deref())?;
+	let cluster = cfg.cluster.as_ref().map(cluster::Cluster::new).transpose()?;
+	let upstreams = upstream::Upstreams::new(&cfg.outbound, &cfg.tls).await?;
+	let resolver = cfg.dns.as_ref().map(resolver::Resolver::new).transpose()?;
 
 	let ctx = Arc::new(AppContext {
-		online_counter,
-		online_clients: Cache::new(cfg.users.len() as u64),
-		traffic_stats,
+		// Unbounded, as `users_file` may add users
+		online_clients: Cache::builder().build(),
+		users,
+		user_bandwidth: cfg
+			.user_limits
+			.iter()
+			.map(|(uuid, limits)| (*uuid, bandwidth::UserLimiter::new(limits)))
+			.collect(),
+		quotas,
+		auth_webhook,
+		seen_tokens: (!cfg.auth_replay_window.is_zero()).then(|| {
+			Cache::builder()
+				.max_capacity(SEEN_TOKENS)
+				.time_to_live(cfg.auth_replay_window)
+				.build()
+		}),
+		skew_probes: Cache::builder()
+			.max_capacity(SKEW_PROBES)
+			.time_to_live(SKEW_PROBE_INTERVAL)
+			.build(),
+		stats: stats::ResourceStats::default(),
+		audit,
+		cluster,
+		dump,
+		drain: drain::Drain::default(),
+		pool: cfg.connection_pool.clone().map(pool::ConnPool::new),
+		dns: cfg.dns_cache.as_ref().map(dns::DnsCache::new),
+		resolver,
+		health: health::UpstreamHealth::new(&cfg.outbound),
+		congestion_hints: Cache::builder()
+			.max_capacity(CONGESTION_HINTS)
+			.time_to_idle(CONGESTION_HINT_IDLE)
+			.build(),
+		parked_udp: connection::ParkedUdpSessions::default(),
+		knock: cfg.knock.as_ref().map(knock::Gate::new),
+		bans: cfg.ban.as_ref().map(ban::Bans::new),
+		upstreams,
+		rollout: rollout::Rollout::default(),
+		live: ArcSwap::from_pointee(reload::Live::new(&cfg)),
 		cfg,
 		cancel: CancellationToken::new(),
 	});
-	let server = server::Server::init(ctx.clone()).await?;
+	let server = server::Server::init(ctx.clone(), inherit).await?;
 	let local_addr = server.local_addr()?;
 	let cancel = ctx.cancel.clone();
 	tokio::spawn(async move {
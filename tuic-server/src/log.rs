@@ -1,10 +1,15 @@
-use std::io;
+use std::{io, sync::OnceLock};
 
 use eyre::Context as _;
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{Layer, fmt::time::LocalTime, layer::SubscriberExt as _, util::SubscriberInitExt as _};
+use tracing_subscriber::{
+	Layer, Registry, filter::Targets, fmt::time::LocalTime, layer::SubscriberExt as _, reload, util::SubscriberInitExt as _,
+};
 
-use crate::config::{Config, LogConfig, LogFormat, LogRotation};
+use crate::config::{Config, LogConfig, LogFormat, LogLevel, LogRotation};
+
+/// Swaps the filter of the subscriber [`init`] installed
+static FILTER: OnceLock<reload::Handle<Targets, Registry>> = OnceLock::new();
 
 /// RAII guards that keep background tasks alive for the program's lifetime.
 pub struct LogGuards {
@@ -13,13 +18,8 @@ pub struct LogGuards {
 
 /// Initialise tracing from [`Config`].
 pub fn init(config: &Config) -> eyre::Result<LogGuards> {
-	let filter = tracing_subscriber::filter::Targets::new()
-		.with_targets(vec![
-			("tuic", config.log_level),
-			("tuic_quinn", config.log_level),
-			("tuic_server", config.log_level),
-		])
-		.with_default(LevelFilter::INFO);
+	let (filter, handle) = reload::Layer::new(targets(config.log_level));
+	_ = FILTER.set(handle);
 
 	let (file_writer, file_guard) = build_file_writer(&config.log)?;
 	let writer = move || -> Box<dyn io::Write + Send> {
@@ -71,6 +71,20 @@ pub fn init(config: &Config) -> eyre::Result<LogGuards> {
 	Ok(LogGuards { _file_guard: file_guard })
 }
 
+/// Log at `level` from now on, if [`init`] installed the subscriber
+pub fn set_level(level: LogLevel) -> eyre::Result<()> {
+	if let Some(handle) = FILTER.get() {
+		handle.reload(targets(level)).context("swapping the log filter")?;
+	}
+	Ok(())
+}
+
+fn targets(level: LogLevel) -> Targets {
+	Targets::new()
+		.with_targets(vec![("tuic", level), ("tuic_quinn", level), ("tuic_server", level)])
+		.with_default(LevelFilter::INFO)
+}
+
 /// Build a cloneable, non-blocking file writer if `log_file` is set.
 fn build_file_writer(
 	t: &LogConfig,
@@ -98,7 +112,9 @@ fn build_file_writer(
 		LogRotation::Hourly => tracing_appender::rolling::hourly(&dir, &file_name),
 		LogRotation::Daily => tracing_appender::rolling::daily(&dir, &file_name),
 	};
-	let (nb, guard) = tracing_appender::non_blocking(appender);
+	let (nb, guard) = tracing_appender::non_blocking::NonBlockingBuilder::default()
+		.buffered_lines_limit(t.buffered_lines)
+		.finish(appender);
 	Ok((Some(nb), Some(guard)))
 }
 
@@ -1,10 +1,10 @@
-use std::process;
+use std::{collections::BTreeSet, process};
 
 use clap::Parser;
 #[cfg(all(feature = "jemallocator", not(feature = "dhat-heap")))]
 use tikv_jemallocator::Jemalloc;
 use tuic_server::{
-	config::{Cli, Control, EnvState, ResolvedRuntime, parse_config},
+	config::{Cli, Command, Control, EnvState, ResolvedRuntime, generate_config, parse_instances},
 	log,
 };
 
@@ -36,17 +36,22 @@ fn main() -> eyre::Result<()> {
 		_ = rustls::crypto::ring::default_provider().install_default();
 	}
 	let cli = Cli::parse();
+	if let Some(Command::GenerateConfig { output, self_signed }) = &cli.command {
+		return generate_config(output.as_deref(), self_signed.as_deref());
+	}
+	let upgrade = cli.upgrade;
+	let dump_config = cli.dump_config;
+	let check = cli.check;
+	let ask_passphrase = cli.ask_passphrase;
 	let env_state = EnvState::from_system();
 
 	// Create a temporary single-threaded runtime just to parse config
 	// asynchronously
-	let cfg = tokio::runtime::Builder::new_current_thread()
-		.enable_all()
-		.build()?
-		.block_on(async { parse_config(cli, env_state).await });
+	let parse_rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+	let cfgs = parse_rt.block_on(async { parse_instances(cli, env_state).await });
 
-	let cfg = match cfg {
-		Ok(cfg) => cfg,
+	let mut cfgs = match cfgs {
+		Ok(cfgs) => cfgs,
 		Err(err) => {
 			// Check if it's a Control error (Help or Version)
 			if let Some(control) = err.downcast_ref::<Control>() {
@@ -56,9 +61,53 @@ fn main() -> eyre::Result<()> {
 			return Err(err);
 		}
 	};
-	let _log_guards = log::init(&cfg)?;
+	if dump_config {
+		for cfg in &cfgs {
+			if let Some(index) = cfg.instance {
+				println!("# instances[{index}]");
+			}
+			print!("{}", cfg.to_redacted_toml()?);
+		}
+		return Ok(());
+	}
+	if ask_passphrase {
+		let passphrase = rpassword::prompt_password("TLS private key passphrase: ")?;
+		for cfg in &mut cfgs {
+			cfg.tls.private_key_passphrase = Some(passphrase.clone());
+		}
+	}
+	// The same option may be unknown to every instance
+	let unknown_options: BTreeSet<_> = cfgs.iter().flat_map(|cfg| &cfg.unknown_options).collect();
+	if check {
+		for option in &unknown_options {
+			eprintln!("warning: ignoring unknown option `{option}`");
+		}
+		for cfg in &cfgs {
+			if let Err(err) = parse_rt.block_on(tuic_server::check(cfg)) {
+				eprintln!("configuration check failed: {err:#}");
+				process::exit(1);
+			}
+		}
+		let addrs: Vec<_> = cfgs
+			.iter()
+			.flat_map(|cfg| cfg.listen_addrs())
+			.map(|addr| addr.to_string())
+			.collect();
+		println!("configuration OK, the server would listen on {}", addrs.join(", "));
+		return Ok(());
+	}
+	drop(parse_rt);
+	// Logging and the runtime are the same for every instance
+	let first = cfgs.first().ok_or_else(|| eyre::eyre!("no server configured"))?;
+	let _log_guards = log::init(first)?;
+	for option in unknown_options {
+		tracing::warn!("ignoring unknown option `{option}`, pass --strict-config to fail on it instead");
+	}
+	for cfg in &cfgs {
+		tracing::info!("effective config: {cfg:?}");
+	}
 
-	let mut builder = match cfg.tokio_runtime.resolve() {
+	let mut builder = match first.tokio_runtime.resolve() {
 		ResolvedRuntime::MultiThread => tokio::runtime::Builder::new_multi_thread(),
 		ResolvedRuntime::CurrentThread => tokio::runtime::Builder::new_current_thread(),
 	};
@@ -66,10 +115,28 @@ fn main() -> eyre::Result<()> {
 	let rt = builder.enable_all().build()?;
 
 	rt.block_on(async move {
-		let guard = tuic_server::run(cfg).await?;
-		tokio::signal::ctrl_c().await?;
-		guard.cancel.cancel();
-		tracing::info!("Received Ctrl-C, shutting down.");
+		let mut guards = Vec::with_capacity(cfgs.len());
+		for cfg in cfgs {
+			guards.push(if upgrade {
+				tuic_server::run_upgrade(cfg).await?
+			} else {
+				tuic_server::run(cfg).await?
+			});
+		}
+		// Only a single server is handed over by an upgrade
+		let handed_over = futures::future::select_all(guards.iter().map(|guard| Box::pin(guard.cancel.cancelled())));
+		tokio::select! {
+			res = tokio::signal::ctrl_c() => {
+				res?;
+				for guard in &guards {
+					guard.cancel.cancel();
+				}
+				tracing::info!("Received Ctrl-C, shutting down.");
+			}
+			_ = handed_over => {
+				tracing::info!("Handed over to the upgraded server, exiting.");
+			}
+		}
 		Ok(())
 	})
 }
@@ -0,0 +1,310 @@
+//! Export of server metrics to a monitoring system.
+//!
+//! [`collect`] takes a snapshot of the counters and gauges the server keeps,
+//! and each backend renders it in its own format: pushed to statsd over UDP
+//! or to graphite over TCP every `interval`, or served for Prometheus to
+//! scrape at `GET /metrics`.
+
+use std::{
+	fmt::Write as _,
+	sync::{Arc, atomic::Ordering},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Router, extract::State, http::header, response::IntoResponse, routing::get};
+use tokio::{
+	io::AsyncWriteExt,
+	net::{TcpListener, TcpStream, UdpSocket},
+	time,
+};
+use tracing::{debug, warn};
+
+use crate::{
+	AppContext,
+	config::{MetricsBackend, MetricsConfig},
+};
+
+/// Largest statsd datagram, small enough to avoid IP fragmentation
+const STATSD_PACKET_SIZE: usize = 1432;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+	Gauge,
+	Counter,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metric {
+	pub name: &'static str,
+	pub help: &'static str,
+	pub kind: Kind,
+	/// Label name and value, e.g. the reason of dropped packets
+	pub label: Option<(&'static str, String)>,
+	pub value: u64,
+}
+
+impl Metric {
+	fn gauge(name: &'static str, help: &'static str, value: usize) -> Self {
+		Self {
+			name,
+			help,
+			kind: Kind::Gauge,
+			label: None,
+			value: value as u64,
+		}
+	}
+
+	fn counter(name: &'static str, help: &'static str, label: (&'static str, String), value: u64) -> Self {
+		Self {
+			name,
+			help,
+			kind: Kind::Counter,
+			label: Some(label),
+			value,
+		}
+	}
+
+	/// Dotted path of statsd and graphite, e.g. `tuic.dropped_packets.too_large`
+	fn path(&self, prefix: &str) -> String {
+		let mut path = format!("{prefix}.{}", self.name);
+		if let Some((_, value)) = &self.label {
+			path.push('.');
+			path.push_str(value);
+		}
+		path
+	}
+}
+
+/// Current values of the server's metrics, grouped by name.
+pub fn collect(ctx: &AppContext) -> Vec<Metric> {
+	let s = ctx.stats.snapshot();
+	let mut metrics = vec![
+		Metric::gauge("connections", "QUIC connections established", s.connections.current),
+		Metric::gauge("tcp_streams", "TCP streams relayed", s.tcp_streams.current),
+		Metric::gauge("udp_sessions", "UDP associations alive", s.udp_sessions.current),
+		Metric::gauge("relay_tasks", "Relay tasks running", s.relay_tasks.current),
+		Metric::gauge("sockets", "Outbound sockets open", s.sockets.current),
+		Metric::gauge(
+			"relay_buffer_bytes",
+			"Bytes held by relay buffers",
+			s.relay_buffer_bytes.current,
+		),
+	];
+	metrics.extend(s.dropped_packets.into_iter().map(|(reason, count)| {
+		Metric::counter("dropped_packets", "UDP packets dropped", ("reason", reason.to_owned()), count)
+	}));
+	metrics.extend(s.auth_failures.into_iter().map(|(reason, count)| {
+		Metric::counter(
+			"auth_failures",
+			"Authentications rejected",
+			("reason", reason.to_owned()),
+			count,
+		)
+	}));
+	if let Some(bans) = &ctx.bans {
+		metrics.push(Metric::gauge(
+			"banned_sources",
+			"Sources banned for failed authentications",
+			bans.banned_count() as usize,
+		));
+	}
+	metrics.extend(s.bans.into_iter().map(|(event, count)| {
+		Metric::counter(
+			"bans",
+			"Sources banned and connections ignored",
+			("event", event.to_owned()),
+			count,
+		)
+	}));
+
+	let users = ctx.users.all_stats();
+	for (name, help, rx) in [
+		("user_tx_bytes", "Bytes sent by a user's clients", false),
+		("user_rx_bytes", "Bytes received by a user's clients", true),
+	] {
+		metrics.extend(users.iter().map(|(uuid, stats)| {
+			let value = if rx { &stats.rx } else { &stats.tx };
+			Metric::counter(name, help, ("user", uuid.to_string()), value.load(Ordering::Relaxed) as u64)
+		}));
+	}
+	metrics
+}
+
+/// Prometheus text exposition format
+pub fn prometheus(prefix: &str, metrics: &[Metric]) -> String {
+	let mut out = String::new();
+	let mut last = None;
+	for metric in metrics {
+		let name = format!("{prefix}_{}", metric.name);
+		if last != Some(metric.name) {
+			let kind = match metric.kind {
+				Kind::Gauge => "gauge",
+				Kind::Counter => "counter",
+			};
+			_ = writeln!(out, "# HELP {name} {}", metric.help);
+			_ = writeln!(out, "# TYPE {name} {kind}");
+			last = Some(metric.name);
+		}
+		match &metric.label {
+			Some((label, value)) => _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {}", metric.value),
+			None => _ = writeln!(out, "{name} {}", metric.value),
+		}
+	}
+	out
+}
+
+/// statsd datagrams. Every metric is sent as a gauge, counters with their
+/// running total.
+pub fn statsd(prefix: &str, metrics: &[Metric]) -> Vec<String> {
+	let mut packets = Vec::new();
+	let mut packet = String::new();
+	for metric in metrics {
+		let line = format!("{}:{}|g", metric.path(prefix), metric.value);
+		if !packet.is_empty() && packet.len() + 1 + line.len() > STATSD_PACKET_SIZE {
+			packets.push(std::mem::take(&mut packet));
+		}
+		if !packet.is_empty() {
+			packet.push('\n');
+		}
+		packet.push_str(&line);
+	}
+	if !packet.is_empty() {
+		packets.push(packet);
+	}
+	packets
+}
+
+/// graphite plaintext protocol, stamped with `timestamp` seconds
+pub fn graphite(prefix: &str, metrics: &[Metric], timestamp: u64) -> String {
+	let mut out = String::new();
+	for metric in metrics {
+		_ = writeln!(out, "{} {} {timestamp}", metric.path(prefix), metric.value);
+	}
+	out
+}
+
+/// Run the backend configured in `[metrics]`, if any.
+pub async fn start(ctx: Arc<AppContext>) {
+	let Some(cfg) = ctx.cfg.metrics.clone() else {
+		return;
+	};
+	let res = match cfg.backend {
+		MetricsBackend::Prometheus => serve(ctx, &cfg).await,
+		MetricsBackend::Statsd | MetricsBackend::Graphite => push(ctx, &cfg).await,
+	};
+	if let Err(err) = res {
+		warn!("[metrics] {:?} backend stopped: {err}", cfg.backend);
+	}
+}
+
+async fn serve(ctx: Arc<AppContext>, cfg: &MetricsConfig) -> eyre::Result<()> {
+	let listener = TcpListener::bind(cfg.address()).await?;
+	warn!("[metrics] serving Prometheus metrics on http://{}/metrics", cfg.address());
+	let app = Router::new().route("/metrics", get(scrape)).with_state(ctx.clone());
+	axum::serve(listener, app)
+		.with_graceful_shutdown(async move { ctx.cancel.cancelled().await })
+		.await?;
+	Ok(())
+}
+
+async fn scrape(State(ctx): State<Arc<AppContext>>) -> impl IntoResponse {
+	let prefix = ctx.cfg.metrics.as_ref().map_or("tuic", |cfg| cfg.prefix.as_str());
+	(
+		[(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+		prometheus(prefix, &collect(&ctx)),
+	)
+}
+
+async fn push(ctx: Arc<AppContext>, cfg: &MetricsConfig) -> eyre::Result<()> {
+	let statsd = match cfg.backend {
+		MetricsBackend::Statsd => {
+			let bind = if cfg.address().is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+			let socket = UdpSocket::bind(bind).await?;
+			socket.connect(cfg.address()).await?;
+			Some(socket)
+		}
+		_ => None,
+	};
+
+	let mut ticker = time::interval(cfg.interval);
+	loop {
+		tokio::select! {
+			_ = ticker.tick() => {}
+			_ = ctx.cancel.cancelled() => return Ok(()),
+		}
+
+		let metrics = collect(&ctx);
+		let res = match &statsd {
+			Some(socket) => send_statsd(socket, &cfg.prefix, &metrics).await,
+			None => send_graphite(cfg, &metrics).await,
+		};
+		// A collector that is down for a moment shouldn't stop the exporter
+		if let Err(err) = res {
+			debug!("[metrics] pushing to {} failed: {err}", cfg.address());
+		}
+	}
+}
+
+async fn send_statsd(socket: &UdpSocket, prefix: &str, metrics: &[Metric]) -> std::io::Result<()> {
+	for packet in statsd(prefix, metrics) {
+		socket.send(packet.as_bytes()).await?;
+	}
+	Ok(())
+}
+
+async fn send_graphite(cfg: &MetricsConfig, metrics: &[Metric]) -> std::io::Result<()> {
+	let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	let mut stream = TcpStream::connect(cfg.address()).await?;
+	stream.write_all(graphite(&cfg.prefix, metrics, timestamp).as_bytes()).await?;
+	stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sample() -> Vec<Metric> {
+		vec![
+			Metric::gauge("connections", "QUIC connections established", 3),
+			Metric::counter("dropped_packets", "UDP packets dropped", ("reason", "too_large".into()), 5),
+			Metric::counter("dropped_packets", "UDP packets dropped", ("reason", "blocked".into()), 0),
+		]
+	}
+
+	#[test]
+	fn test_prometheus_format() {
+		assert_eq!(
+			prometheus("tuic", &sample()),
+			concat!(
+				"# HELP tuic_connections QUIC connections established\n",
+				"# TYPE tuic_connections gauge\n",
+				"tuic_connections 3\n",
+				"# HELP tuic_dropped_packets UDP packets dropped\n",
+				"# TYPE tuic_dropped_packets counter\n",
+				"tuic_dropped_packets{reason=\"too_large\"} 5\n",
+				"tuic_dropped_packets{reason=\"blocked\"} 0\n",
+			)
+		);
+	}
+
+	#[test]
+	fn test_statsd_and_graphite_format() {
+		assert_eq!(
+			statsd("tuic", &sample()),
+			["tuic.connections:3|g\ntuic.dropped_packets.too_large:5|g\ntuic.dropped_packets.blocked:0|g"]
+		);
+		assert_eq!(
+			graphite("tuic", &sample()[..2], 1700000000),
+			"tuic.connections 3 1700000000\ntuic.dropped_packets.too_large 5 1700000000\n"
+		);
+	}
+
+	#[test]
+	fn test_statsd_splits_packets() {
+		let metrics = vec![Metric::gauge("connections", "", 1); 200];
+		let packets = statsd("tuic", &metrics);
+		assert!(packets.len() > 1);
+		assert!(packets.iter().all(|packet| packet.len() <= STATSD_PACKET_SIZE));
+		assert_eq!(packets.iter().map(|packet| packet.lines().count()).sum::<usize>(), 200);
+	}
+}
@@ -0,0 +1,143 @@
+//! Shadow traffic for testing an outbound against live requests.
+//!
+//! TCP streams of the users in `[mirror]` are connected a second time through
+//! the mirror outbound, and everything the client sends is copied there too.
+//! The answers of the mirror are read and thrown away, and a mirror that
+//! falls behind or fails is dropped without affecting the real stream.
+
+use std::{
+	io::Result as IoResult,
+	pin::{Pin, pin},
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::{
+	io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+	sync::mpsc::{self, Receiver, Sender},
+	time,
+};
+use tracing::debug;
+
+/// Chunks buffered for a mirror that is still connecting or writing slowly
+const BACKLOG: usize = 64;
+/// How long answers are still read once the client's side has been copied
+const LINGER: Duration = Duration::from_secs(10);
+
+/// A stream whose writes are also sent to a mirror.
+pub struct Mirrored<S> {
+	inner: S,
+	mirror: Option<Sender<Bytes>>,
+}
+
+impl<S> Mirrored<S> {
+	/// Copy writes to `inner` to the receiver returned along with the stream.
+	pub fn new(inner: S) -> (Self, Receiver<Bytes>) {
+		let (tx, rx) = mpsc::channel(BACKLOG);
+		(Self { inner, mirror: Some(tx) }, rx)
+	}
+
+	/// A stream without a mirror.
+	pub fn plain(inner: S) -> Self {
+		Self { inner, mirror: None }
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Mirrored<S> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<IoResult<()>> {
+		Pin::new(&mut self.inner).poll_read(cx, buf)
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Mirrored<S> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+		let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let (Poll::Ready(Ok(n)), Some(mirror)) = (&res, &self.mirror) {
+			// A mirror missing part of the stream is useless, so stop feeding it
+			if mirror.try_send(Bytes::copy_from_slice(&buf[..*n])).is_err() {
+				debug!("[mirror] mirror fell behind or failed, dropping it");
+				self.mirror = None;
+			}
+		}
+		res
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		// Closing the channel lets the mirror shut down its write half as well
+		self.mirror = None;
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+/// Write the chunks of `rx` to `stream` while discarding what it answers.
+pub async fn feed<S: AsyncRead + AsyncWrite + Unpin>(stream: S, mut rx: Receiver<Bytes>) -> IoResult<()> {
+	let (mut reader, mut writer) = io::split(stream);
+	let mut sink = io::sink();
+	let mut discard = pin!(io::copy(&mut reader, &mut sink));
+	let write = async {
+		while let Some(chunk) = rx.recv().await {
+			writer.write_all(&chunk).await?;
+		}
+		writer.shutdown().await
+	};
+
+	tokio::select! {
+		biased;
+		written = write => written?,
+		discarded = &mut discard => return discarded.map(drop),
+	}
+	_ = time::timeout(LINGER, discard).await;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{AsyncReadExt, duplex};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_writes_reach_mirror() {
+		let (stream, mut target) = duplex(1024);
+		let (mut stream, rx) = Mirrored::new(stream);
+		let (mirror, mut mirror_target) = duplex(1024);
+		let feeder = tokio::spawn(feed(mirror, rx));
+
+		stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+		stream.shutdown().await.unwrap();
+		mirror_target.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+
+		let mut real = Vec::new();
+		target.read_to_end(&mut real).await.unwrap();
+		let mut shadow = Vec::new();
+		mirror_target.read_to_end(&mut shadow).await.unwrap();
+		assert_eq!(real, b"GET / HTTP/1.1\r\n\r\n");
+		assert_eq!(shadow, real);
+
+		drop(mirror_target);
+		feeder.await.unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_lagging_mirror_is_dropped() {
+		let (stream, mut target) = duplex(64 * 1024);
+		let (mut stream, mut rx) = Mirrored::new(stream);
+		for _ in 0..=BACKLOG {
+			stream.write_all(b"x").await.unwrap();
+		}
+		assert!(stream.mirror.is_none());
+
+		let mut real = [0; BACKLOG + 1];
+		target.read_exact(&mut real).await.unwrap();
+		let mut mirrored = 0;
+		while rx.recv().await.is_some() {
+			mirrored += 1;
+		}
+		assert_eq!(mirrored, BACKLOG);
+	}
+}
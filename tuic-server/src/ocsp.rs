@@ -0,0 +1,181 @@
+//! OCSP responses stapled to TLS handshakes.
+//!
+//! With `tls.ocsp_response`, a DER response kept up to date by another tool is
+//! read along with the certificate. With `tls.ocsp_fetch`, the server asks the
+//! responder named in the certificate's Authority Information Access
+//! extension itself, every [`REFRESH`] and after each certificate reload. The
+//! request identifies the certificate by SHA-1 hashes of its issuer's name and
+//! key, as RFC 6960 has it and every responder supports, so the configured
+//! chain must include the issuer. Responses are stapled as they come; clients
+//! check their signature and validity.
+
+use std::{path::PathBuf, time::Duration};
+
+use eyre::{Context, Result};
+use rustls::pki_types::CertificateDer;
+use sha1::{Digest, Sha1};
+use x509_parser::{
+	extensions::{GeneralName, ParsedExtension},
+	oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP,
+	parse_x509_certificate,
+};
+
+/// Fetched responses are replaced after this long. They are typically valid
+/// for several days.
+pub const REFRESH: Duration = Duration::from_secs(12 * 60 * 60);
+/// A failed fetch is tried again after this long
+pub const RETRY: Duration = Duration::from_secs(10 * 60);
+/// Time given to the responder to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a [`crate::tls::CertResolver`] gets the OCSP response it staples
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Ocsp {
+	#[default]
+	Off,
+	/// A DER response in a file, read again whenever the certificate is
+	/// reloaded
+	File(PathBuf),
+	/// Fetched from the responder the certificate names
+	Fetch,
+}
+
+/// A response for the leaf of `chain`, from its responder
+pub async fn fetch(chain: &[CertificateDer<'_>]) -> Result<Vec<u8>> {
+	let (url, request) = request(chain)?;
+	let response = reqwest::Client::new()
+		.post(&url)
+		.header(reqwest::header::CONTENT_TYPE, "application/ocsp-request")
+		.timeout(TIMEOUT)
+		.body(request)
+		.send()
+		.await
+		.and_then(reqwest::Response::error_for_status)
+		.with_context(|| format!("OCSP responder {url}"))?
+		.bytes()
+		.await
+		.with_context(|| format!("OCSP responder {url}"))?;
+	match response_status(&response) {
+		Some(0) => Ok(response.to_vec()),
+		Some(status) => Err(eyre::eyre!("OCSP responder {url} answered with status {status}")),
+		None => Err(eyre::eyre!(
+			"OCSP responder {url} answered with something else than an OCSP response"
+		)),
+	}
+}
+
+/// The responder URL of the leaf of `chain`, and the DER request for it
+fn request(chain: &[CertificateDer<'_>]) -> Result<(String, Vec<u8>)> {
+	let [leaf, issuer, ..] = chain else {
+		return Err(eyre::eyre!("the certificate chain must include the issuer for OCSP"));
+	};
+	let (_, leaf) = parse_x509_certificate(leaf).context("invalid certificate")?;
+	let (_, issuer) = parse_x509_certificate(issuer).context("invalid issuer certificate")?;
+	let url = leaf
+		.extensions()
+		.iter()
+		.find_map(|ext| match ext.parsed_extension() {
+			ParsedExtension::AuthorityInfoAccess(aia) => aia.accessdescs.iter().find_map(|desc| match &desc.access_location {
+				GeneralName::URI(uri) if desc.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP => Some(uri.to_string()),
+				_ => None,
+			}),
+			_ => None,
+		})
+		.ok_or_else(|| eyre::eyre!("the certificate names no OCSP responder"))?;
+
+	// AlgorithmIdentifier { sha1, NULL }
+	const SHA1: &[u8] = &[0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00];
+	let name_hash = Sha1::digest(leaf.issuer().as_raw());
+	let key_hash = Sha1::digest(&*issuer.public_key().subject_public_key.data);
+	let cert_id = der(
+		0x30,
+		&[
+			SHA1,
+			&der(0x04, &name_hash),
+			&der(0x04, &key_hash),
+			&der(0x02, leaf.raw_serial()),
+		]
+		.concat(),
+	);
+	// OCSPRequest { TBSRequest { requestList { Request { CertID } } } }
+	let request = der(0x30, &der(0x30, &der(0x30, &der(0x30, &cert_id))));
+	Ok((url, request))
+}
+
+/// A DER tag, length and value
+fn der(tag: u8, value: &[u8]) -> Vec<u8> {
+	let mut out = vec![tag];
+	if value.len() < 0x80 {
+		out.push(value.len() as u8);
+	} else {
+		let len = value.len().to_be_bytes();
+		let skip = len.iter().take_while(|byte| **byte == 0).count();
+		out.push(0x80 | (len.len() - skip) as u8);
+		out.extend_from_slice(&len[skip..]);
+	}
+	out.extend_from_slice(value);
+	out
+}
+
+/// The value of the DER `tag` at the start of `data`
+fn value(data: &[u8], tag: u8) -> Option<&[u8]> {
+	let (&found, rest) = data.split_first()?;
+	let (&len, rest) = rest.split_first()?;
+	if found != tag {
+		return None;
+	}
+	let (len, rest) = if len < 0x80 {
+		(usize::from(len), rest)
+	} else {
+		let octets = usize::from(len & 0x7f);
+		if octets > size_of::<usize>() || rest.len() < octets {
+			return None;
+		}
+		let (len_octets, rest) = rest.split_at(octets);
+		(len_octets.iter().fold(0, |len, byte| len << 8 | usize::from(*byte)), rest)
+	};
+	rest.get(..len)
+}
+
+/// The `responseStatus` of a DER OCSPResponse, 0 for success
+fn response_status(response: &[u8]) -> Option<u8> {
+	// OCSPResponse { responseStatus ENUMERATED, responseBytes [0] OPTIONAL }
+	match value(value(response, 0x30)?, 0x0a)? {
+		[status] => Some(*status),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_der_lengths() {
+		assert_eq!(der(0x04, b"ab"), [0x04, 0x02, b'a', b'b']);
+		let long = der(0x04, &[0; 300]);
+		assert_eq!(long[..4], [0x04, 0x82, 0x01, 0x2c]);
+		assert_eq!(value(&long, 0x04), Some(&[0; 300][..]));
+		assert_eq!(value(&long, 0x30), None);
+		assert_eq!(value(&long[..100], 0x04), None);
+	}
+
+	#[test]
+	fn test_response_status() {
+		let successful = der(0x30, &[der(0x0a, &[0]), der(0xa0, &[0; 200])].concat());
+		assert_eq!(response_status(&successful), Some(0));
+		// unauthorized
+		assert_eq!(response_status(&der(0x30, &der(0x0a, &[6]))), Some(6));
+		assert_eq!(response_status(b"<html>"), None);
+	}
+
+	#[test]
+	fn test_request_needs_responder() -> Result<()> {
+		let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+		let leaf = CertificateDer::from(cert.cert);
+		assert!(request(std::slice::from_ref(&leaf)).is_err());
+		let err = request(&[leaf.clone(), leaf]).unwrap_err();
+		assert!(err.to_string().contains("no OCSP responder"), "{err}");
+		Ok(())
+	}
+}
@@ -0,0 +1,188 @@
+//! Spare pre-connected outbound TCP connections.
+//!
+//! A relayed TCP stream carries arbitrary bytes, so a connection is never
+//! handed out twice. Instead, when a user connects to the same destination
+//! again within `ttl`, a spare connection is opened in the background. The
+//! next stream of that user to that destination takes it over without waiting
+//! for a TCP handshake. Spares older than `ttl` are closed unused.
+
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{Arc, Mutex, PoisonError},
+};
+
+use futures_util::FutureExt;
+use tokio::{
+	net::TcpStream,
+	time::{self, Instant},
+};
+use uuid::Uuid;
+
+use crate::{AppContext, config::ConnectionPoolConfig};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+	uuid: Uuid,
+	outbound: String,
+	target: String,
+}
+
+impl PoolKey {
+	pub fn new(uuid: Uuid, outbound: &str, target: &str) -> Self {
+		Self {
+			uuid,
+			outbound: outbound.to_owned(),
+			target: target.to_owned(),
+		}
+	}
+}
+
+#[derive(Default)]
+struct State {
+	idle: HashMap<PoolKey, VecDeque<(Instant, TcpStream)>>,
+	last_used: HashMap<PoolKey, Instant>,
+}
+
+impl State {
+	fn total(&self) -> usize {
+		self.idle.values().map(VecDeque::len).sum()
+	}
+}
+
+pub struct ConnPool {
+	cfg: ConnectionPoolConfig,
+	state: Mutex<State>,
+}
+
+impl ConnPool {
+	pub fn new(cfg: ConnectionPoolConfig) -> Self {
+		Self {
+			cfg,
+			state: Mutex::new(State::default()),
+		}
+	}
+
+	/// Take a live spare connection for `key`, if there is one.
+	pub fn take(&self, key: &PoolKey) -> Option<TcpStream> {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		let spares = state.idle.get_mut(key)?;
+
+		let mut found = None;
+		while let Some((created, stream)) = spares.pop_front() {
+			if created.elapsed() < self.cfg.ttl && is_alive(&stream) {
+				found = Some(stream);
+				break;
+			}
+		}
+		if spares.is_empty() {
+			state.idle.remove(key);
+		}
+		found
+	}
+
+	/// Record a connect to `key` and return whether a spare connection should
+	/// be opened for it: the destination was used before within `ttl` and the
+	/// pool has room.
+	pub fn should_warm(&self, key: &PoolKey) -> bool {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		let now = Instant::now();
+		let recent = state
+			.last_used
+			.insert(key.clone(), now)
+			.is_some_and(|last| now.duration_since(last) < self.cfg.ttl);
+
+		recent && self.has_room(&state, key)
+	}
+
+	/// Add a freshly opened spare connection, or drop it if the pool filled up
+	/// in the meantime.
+	pub fn put(&self, key: PoolKey, stream: TcpStream) {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		if self.has_room(&state, &key) {
+			state.idle.entry(key).or_default().push_back((Instant::now(), stream));
+		}
+	}
+
+	fn has_room(&self, state: &State, key: &PoolKey) -> bool {
+		let spares = state.idle.get(key).map_or(0, VecDeque::len);
+		spares < self.cfg.per_destination && state.total() < self.cfg.max_idle
+	}
+
+	/// Close expired spares and forget destinations not used within `ttl`.
+	fn purge(&self) {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		let ttl = self.cfg.ttl;
+		state.idle.retain(|_, spares| {
+			spares.retain(|(created, _)| created.elapsed() < ttl);
+			!spares.is_empty()
+		});
+		state.last_used.retain(|_, last| last.elapsed() < ttl);
+	}
+
+	#[cfg(test)]
+	fn idle_count(&self) -> usize {
+		self.state.lock().unwrap_or_else(PoisonError::into_inner).total()
+	}
+}
+
+/// A spare is usable unless the peer closed it or it failed. Bytes the peer
+/// sent first (e.g. an SMTP banner) stay queued for the relay.
+fn is_alive(stream: &TcpStream) -> bool {
+	let mut buf = [0u8; 1];
+	match stream.peek(&mut buf).now_or_never() {
+		None => true,
+		Some(Ok(n)) => n > 0,
+		Some(Err(_)) => false,
+	}
+}
+
+/// Periodically close spare connections that outlived `ttl`.
+pub async fn sweep(ctx: Arc<AppContext>) {
+	let Some(pool) = &ctx.pool else {
+		return;
+	};
+
+	let mut interval = time::interval(pool.cfg.ttl);
+	loop {
+		tokio::select! {
+			_ = interval.tick() => pool.purge(),
+			_ = ctx.cancel.cancelled() => return,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_pool_warms_repeated_destinations() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let pool = ConnPool::new(ConnectionPoolConfig {
+			per_destination: 1,
+			max_idle: 4,
+			ttl: Duration::from_secs(10),
+		});
+		let key = PoolKey::new(Uuid::nil(), "default", &addr.to_string());
+
+		// Only a destination used twice within the TTL gets a spare
+		assert!(!pool.should_warm(&key));
+		assert!(pool.should_warm(&key));
+
+		let stream = TcpStream::connect(addr).await.unwrap();
+		let (_accepted, _) = listener.accept().await.unwrap();
+		pool.put(key.clone(), stream);
+		pool.put(key.clone(), TcpStream::connect(addr).await.unwrap());
+		assert_eq!(pool.idle_count(), 1);
+		assert!(!pool.should_warm(&key));
+
+		assert!(pool.take(&key).is_some());
+		assert!(pool.take(&key).is_none());
+		assert_eq!(pool.idle_count(), 0);
+	}
+}
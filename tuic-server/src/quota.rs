@@ -0,0 +1,394 @@
+//! Traffic quotas of users.
+//!
+//! A user with `quota` in `user_limits` may relay that many bytes, both
+//! directions together, in total or per calendar month (UTC). Once it's used
+//! up, new connections of the user fail authentication, TCP requests are
+//! rejected with [`RejectReason::Quota`](tuic_core::reject::RejectReason) and
+//! UDP packets are dropped; with `quota_close`, open connections are closed as
+//! well. A monthly quota starts over within [`SAVE_INTERVAL`] of the new month.
+//!
+//! Usage is kept in `quota_file`, if set, so a restart doesn't hand out a
+//! fresh quota. It's written every [`SAVE_INTERVAL`] and at shutdown. With
+//! `[cluster]`, instances add up each other's usage, see [`crate::cluster`].
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	fs, io,
+	path::{Path, PathBuf},
+	sync::{
+		Arc,
+		atomic::{AtomicU64, Ordering},
+	},
+	time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use time::{Date, Month, OffsetDateTime};
+use tokio::{sync::watch, time as tokio_time};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+	AppContext,
+	config::{Config, QuotaPeriod},
+};
+
+/// How often usage is written to `quota_file` and monthly quotas roll over
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Quotas {
+	users: HashMap<Uuid, Quota>,
+	file: Option<PathBuf>,
+}
+
+pub struct Quota {
+	limit: u64,
+	period: QuotaPeriod,
+	close: bool,
+	used: AtomicU64,
+	/// Bytes counted here and not yet shared with the cluster
+	unshared: AtomicU64,
+	/// Start of the current period in Unix seconds, zero for a total quota
+	since: AtomicU64,
+	exhausted: watch::Sender<bool>,
+}
+
+/// Bytes a user relayed through one instance in the period starting at
+/// `since`, as shared with the others
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+	pub bytes: u64,
+	pub since: u64,
+}
+
+/// A quota as reported by `GET /quota`
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct QuotaStatus {
+	pub used: u64,
+	pub limit: u64,
+	pub period: QuotaPeriod,
+	/// When a monthly quota starts over, in Unix seconds
+	pub resets_at: Option<u64>,
+}
+
+/// Usage of a user in `quota_file`
+#[derive(Serialize, Deserialize)]
+struct Saved {
+	used: u64,
+	since: u64,
+}
+
+impl Quotas {
+	pub fn new(cfg: &Config) -> eyre::Result<Self> {
+		let saved = match &cfg.quota_file {
+			Some(path) => load(path).map_err(|err| eyre::eyre!("failed to load {}: {err}", path.display()))?,
+			None => HashMap::new(),
+		};
+		let now = OffsetDateTime::now_utc();
+		let users = cfg
+			.user_limits
+			.iter()
+			.filter_map(|(uuid, limits)| {
+				let limit = limits.quota?;
+				let since = match limits.quota_period {
+					QuotaPeriod::Total => 0,
+					QuotaPeriod::Monthly => month_bounds(now).0,
+				};
+				let used = saved
+					.get(uuid)
+					.filter(|saved| saved.since == since)
+					.map_or(0, |saved| saved.used);
+				Some((
+					*uuid,
+					Quota {
+						limit,
+						period: limits.quota_period,
+						close: limits.quota_close,
+						used: AtomicU64::new(used),
+						unshared: AtomicU64::new(0),
+						since: AtomicU64::new(since),
+						exhausted: watch::Sender::new(used >= limit),
+					},
+				))
+			})
+			.collect();
+		Ok(Self {
+			users,
+			file: cfg.quota_file.clone(),
+		})
+	}
+
+	pub fn get(&self, uuid: &Uuid) -> Option<&Quota> {
+		self.users.get(uuid)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.users.is_empty()
+	}
+
+	pub fn status(&self) -> BTreeMap<Uuid, QuotaStatus> {
+		let now = OffsetDateTime::now_utc();
+		self.users
+			.iter()
+			.map(|(uuid, quota)| {
+				(
+					*uuid,
+					QuotaStatus {
+						used: quota.used.load(Ordering::Relaxed),
+						limit: quota.limit,
+						period: quota.period,
+						resets_at: (quota.period == QuotaPeriod::Monthly).then(|| month_bounds(now).1),
+					},
+				)
+			})
+			.collect()
+	}
+
+	/// The usage counted here since the last call, to share with the cluster
+	pub fn take_unshared(&self) -> BTreeMap<Uuid, Usage> {
+		self.users
+			.iter()
+			.filter_map(|(uuid, quota)| {
+				let bytes = quota.unshared.swap(0, Ordering::Relaxed);
+				let since = quota.since.load(Ordering::Relaxed);
+				(bytes > 0).then_some((*uuid, Usage { bytes, since }))
+			})
+			.collect()
+	}
+
+	/// Put back usage [`Self::take_unshared`] took but couldn't share
+	pub fn untake(&self, usage: &BTreeMap<Uuid, Usage>) {
+		for (uuid, usage) in usage {
+			if let Some(quota) = self.users.get(uuid)
+				&& quota.since.load(Ordering::Relaxed) == usage.since
+			{
+				quota.unshared.fetch_add(usage.bytes, Ordering::Relaxed);
+			}
+		}
+	}
+
+	/// Count the usage another instance shared. That of a past period, or of a
+	/// user without a quota here, is ignored.
+	pub fn add_shared(&self, usage: &BTreeMap<Uuid, Usage>) {
+		for (uuid, usage) in usage {
+			if let Some(quota) = self.users.get(uuid)
+				&& quota.since.load(Ordering::Relaxed) == usage.since
+			{
+				quota.count(usage.bytes);
+			}
+		}
+	}
+
+	/// Start monthly quotas over once their month has passed
+	fn roll(&self, now: OffsetDateTime) {
+		let (start, _) = month_bounds(now);
+		for quota in self.users.values().filter(|quota| quota.period == QuotaPeriod::Monthly) {
+			if quota.since.swap(start, Ordering::Relaxed) != start {
+				quota.used.store(0, Ordering::Relaxed);
+				quota.unshared.store(0, Ordering::Relaxed);
+				quota.exhausted.send_replace(false);
+			}
+		}
+	}
+
+	/// Write the usage to `quota_file`, on the blocking pool rather than a
+	/// runtime worker
+	async fn save(&self) -> io::Result<()> {
+		let Some(path) = self.file.clone() else {
+			return Ok(());
+		};
+		let saved: HashMap<_, _> = self
+			.users
+			.iter()
+			.map(|(uuid, quota)| {
+				(
+					*uuid,
+					Saved {
+						used: quota.used.load(Ordering::Relaxed),
+						since: quota.since.load(Ordering::Relaxed),
+					},
+				)
+			})
+			.collect();
+		let content = serde_json::to_vec(&saved)?;
+		tokio::task::spawn_blocking(move || {
+			let mut tmp = path.clone().into_os_string();
+			tmp.push(".tmp");
+			fs::write(&tmp, content)?;
+			fs::rename(tmp, &path)
+		})
+		.await
+		.map_err(io::Error::other)?
+	}
+}
+
+impl Quota {
+	/// Count `len` bytes relayed for the user
+	pub fn add(&self, len: usize) {
+		let len = len as u64;
+		self.count(len);
+		self.unshared.fetch_add(len, Ordering::Relaxed);
+	}
+
+	fn count(&self, len: u64) {
+		let before = self.used.fetch_add(len, Ordering::Relaxed);
+		if before < self.limit && before.saturating_add(len) >= self.limit {
+			self.exhausted.send_replace(true);
+		}
+	}
+
+	pub fn is_exhausted(&self) -> bool {
+		self.used.load(Ordering::Relaxed) >= self.limit
+	}
+
+	/// How long until a monthly quota starts over
+	pub fn retry_after(&self) -> Option<Duration> {
+		let now = OffsetDateTime::now_utc();
+		let secs = u64::try_from(now.unix_timestamp()).unwrap_or(0);
+		(self.period == QuotaPeriod::Monthly).then(|| Duration::from_secs(month_bounds(now).1.saturating_sub(secs)))
+	}
+
+	/// Whether open connections are closed once the quota is used up
+	pub fn closes_connections(&self) -> bool {
+		self.close
+	}
+
+	/// Changes to `true` when the quota gets used up
+	pub fn subscribe(&self) -> watch::Receiver<bool> {
+		self.exhausted.subscribe()
+	}
+}
+
+fn load(path: &Path) -> io::Result<HashMap<Uuid, Saved>> {
+	match fs::read(path) {
+		Ok(content) => Ok(serde_json::from_slice(&content)?),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+		Err(err) => Err(err),
+	}
+}
+
+/// Start of the calendar month `now` is in and of the next one, in Unix seconds
+fn month_bounds(now: OffsetDateTime) -> (u64, u64) {
+	let start = |year, month| {
+		Date::from_calendar_date(year, month, 1).map_or(0, |date| {
+			u64::try_from(date.midnight().assume_utc().unix_timestamp()).unwrap_or(0)
+		})
+	};
+	let next_year = now.year() + i32::from(now.month() == Month::December);
+	(start(now.year(), now.month()), start(next_year, now.month().next()))
+}
+
+/// Roll monthly quotas over and write `quota_file`, until the server shuts
+/// down.
+pub async fn persist(ctx: Arc<AppContext>) {
+	if ctx.quotas.is_empty() {
+		return;
+	}
+	let mut ticker = tokio_time::interval(SAVE_INTERVAL);
+	ticker.tick().await;
+	loop {
+		let shutdown = tokio::select! {
+			_ = ticker.tick() => false,
+			_ = ctx.cancel.cancelled() => true,
+		};
+		ctx.quotas.roll(OffsetDateTime::now_utc());
+		if let Err(err) = ctx.quotas.save().await {
+			warn!("[quota] failed to save usage: {err}");
+		}
+		if shutdown {
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tempfile::tempdir;
+	use time::macros::datetime;
+
+	use super::*;
+	use crate::config::UserLimits;
+
+	fn config(period: QuotaPeriod) -> Config {
+		let mut cfg = Config::default();
+		cfg.user_limits.insert(
+			Uuid::from_u128(1),
+			UserLimits {
+				quota: Some(1000),
+				quota_period: period,
+				..Default::default()
+			},
+		);
+		cfg
+	}
+
+	#[test]
+	fn test_month_bounds() {
+		assert_eq!(month_bounds(datetime!(2026-10-14 12:00 UTC)), (1790812800, 1793491200));
+		assert_eq!(month_bounds(datetime!(2026-12-31 23:59 UTC)).1, 1798761600);
+	}
+
+	#[test]
+	fn test_quota_runs_out_and_rolls_over() {
+		let quotas = Quotas::new(&config(QuotaPeriod::Monthly)).unwrap();
+		let quota = quotas.get(&Uuid::from_u128(1)).unwrap();
+		let exhausted = quota.subscribe();
+		quota.add(600);
+		assert!(!quota.is_exhausted());
+		quota.add(600);
+		assert!(quota.is_exhausted());
+		assert!(*exhausted.borrow());
+		assert!(quota.retry_after().is_some());
+
+		// The same month keeps the usage, the next one starts over
+		quotas.roll(OffsetDateTime::now_utc());
+		assert!(quota.is_exhausted());
+		quotas.roll(OffsetDateTime::now_utc() + Duration::from_secs(32 * 24 * 3600));
+		assert!(!quota.is_exhausted());
+		assert!(!*exhausted.borrow());
+	}
+
+	#[test]
+	fn test_shared_usage() {
+		let here = Quotas::new(&config(QuotaPeriod::Monthly)).unwrap();
+		let there = Quotas::new(&config(QuotaPeriod::Monthly)).unwrap();
+		let uuid = Uuid::from_u128(1);
+
+		here.get(&uuid).unwrap().add(600);
+		let usage = here.take_unshared();
+		assert_eq!(usage[&uuid].bytes, 600);
+		assert!(here.take_unshared().is_empty());
+
+		// Usage shared by another instance counts, but isn't shared again
+		there.get(&uuid).unwrap().add(600);
+		there.add_shared(&usage);
+		assert!(there.get(&uuid).unwrap().is_exhausted());
+		assert_eq!(there.take_unshared()[&uuid].bytes, 600);
+
+		// Nor does that of a past month
+		let stale = BTreeMap::from([(uuid, Usage { bytes: 600, since: 0 })]);
+		here.add_shared(&stale);
+		assert!(!here.get(&uuid).unwrap().is_exhausted());
+
+		// Usage that couldn't be shared is shared the next time
+		here.untake(&usage);
+		assert_eq!(here.take_unshared(), usage);
+	}
+
+	#[tokio::test]
+	async fn test_usage_survives_restart() {
+		let dir = tempdir().unwrap();
+		let mut cfg = config(QuotaPeriod::Total);
+		cfg.quota_file = Some(dir.path().join("quota.json"));
+
+		let quotas = Quotas::new(&cfg).unwrap();
+		quotas.get(&Uuid::from_u128(1)).unwrap().add(1500);
+		quotas.save().await.unwrap();
+
+		let quotas = Quotas::new(&cfg).unwrap();
+		let quota = quotas.get(&Uuid::from_u128(1)).unwrap();
+		assert!(quota.is_exhausted());
+		assert_eq!(quota.retry_after(), None);
+		assert_eq!(quotas.status()[&Uuid::from_u128(1)].used, 1500);
+	}
+}
@@ -0,0 +1,215 @@
+//! Reloading the config on SIGHUP.
+//!
+//! The config file is parsed again the way it was at startup, `--user` and
+//! `TUIC_` overrides included. These settings then apply without a restart:
+//! `users` (with `users_file` read again, see [`crate::users`]), `log_level`,
+//! `auth_timeout`, `task_negotiation_timeout`, `stream_timeout`,
+//! `max_external_packet_size` and `acl`. Open connections pick up the new
+//! values, from their next stream or packet on. Other changes, like the
+//! listening address, TLS or the outbounds, are logged as needing a restart.
+//! A config that fails to load leaves everything as it was. With
+//! `instances`, each server reloads its own entry.
+
+use std::{sync::Arc, time::Duration};
+
+use tracing::{info, warn};
+
+use crate::{
+	AppContext,
+	acl::AclRule,
+	config::{self, Cli, Config, EnvState},
+	log,
+	users::Reloaded,
+};
+
+/// The settings that apply without a restart, as of the last reload
+pub struct Live {
+	pub auth_timeout: Duration,
+	pub task_negotiation_timeout: Duration,
+	pub stream_timeout: Duration,
+	pub max_external_packet_size: usize,
+	pub acl: Vec<AclRule>,
+}
+
+impl Live {
+	pub fn new(cfg: &Config) -> Self {
+		Self {
+			auth_timeout: cfg.auth_timeout,
+			task_negotiation_timeout: cfg.task_negotiation_timeout,
+			stream_timeout: cfg.stream_timeout,
+			max_external_packet_size: cfg.max_external_packet_size,
+			acl: cfg.acl.clone(),
+		}
+	}
+}
+
+/// Options applied on reload, those of [`Live`], `users` and `log_level`
+const RELOADED: [&str; 7] = [
+	"users",
+	"log_level",
+	"auth_timeout",
+	"task_negotiation_timeout",
+	"stream_timeout",
+	"max_external_packet_size",
+	"acl",
+];
+
+/// Whether `new` differs from `current` in more than the [`RELOADED`]
+/// options, compared in their serialized form
+fn needs_restart(current: &Config, new: &Config) -> bool {
+	let fixed = |cfg: &Config| {
+		let mut value = serde_json::to_value(cfg).ok()?;
+		let options = value.as_object_mut()?;
+		for option in RELOADED {
+			options.remove(option);
+		}
+		Some(value)
+	};
+	match (fixed(current), fixed(new)) {
+		(Some(current), Some(new)) => current != new,
+		// Can't tell, so better warn
+		_ => true,
+	}
+}
+
+/// Whether `new` has changes from `running` that need a restart and weren't
+/// already in `last`, the config of the previous reload, so each is warned
+/// about once
+fn new_restart_changes(running: &Config, last: Option<&Config>, new: &Config) -> bool {
+	needs_restart(running, new) && last.is_none_or(|last| needs_restart(last, new))
+}
+
+/// Reload the config on each SIGHUP, until the server shuts down.
+pub async fn watch(ctx: Arc<AppContext>) {
+	let mut hangup = Hangup::new();
+	let mut last = None;
+	loop {
+		tokio::select! {
+			() = hangup.recv() => {}
+			_ = ctx.cancel.cancelled() => return,
+		}
+		// Without a file to read again, as for a config built in code, only
+		// `users_file` can change
+		let Some(source) = &ctx.cfg.source else {
+			match ctx.users.reload(&ctx.cfg).await {
+				Ok(reloaded) => log_users(&reloaded),
+				Err(err) => warn!("[reload] keeping the current users: {err:#}"),
+			}
+			continue;
+		};
+		let mut new = match load(source, ctx.cfg.instance).await {
+			Ok(new) => new,
+			Err(err) => {
+				warn!("[reload] keeping the current config: {err:#}");
+				continue;
+			}
+		};
+		// Asked for once, at startup
+		if source.ask_passphrase {
+			new.tls.private_key_passphrase.clone_from(&ctx.cfg.tls.private_key_passphrase);
+		}
+		for option in &new.unknown_options {
+			warn!("[reload] ignoring unknown option `{option}`");
+		}
+		match ctx.users.reconfigure(new.users.clone(), &ctx.cfg).await {
+			Ok(reloaded) => log_users(&reloaded),
+			Err(err) => warn!("[reload] keeping the current users: {err:#}"),
+		}
+		if let Err(err) = log::set_level(new.log_level) {
+			warn!("[reload] keeping the current log level: {err:#}");
+		}
+		ctx.live.store(Arc::new(Live::new(&new)));
+		info!("[reload] config reloaded");
+		if new_restart_changes(&ctx.cfg, last.as_ref(), &new) {
+			warn!("[reload] the config has changes that only apply after a restart");
+		}
+		last = Some(new);
+	}
+}
+
+/// The config of the server at `instance` of `instances`, as the file now has
+/// it
+async fn load(source: &Cli, instance: Option<usize>) -> eyre::Result<Config> {
+	let configs = config::parse_instances(source.clone(), EnvState::from_system()).await?;
+	configs
+		.into_iter()
+		.find(|config| config.instance == instance)
+		.ok_or_else(|| eyre::eyre!("the config no longer has this server, the change only applies after a restart"))
+}
+
+fn log_users(&Reloaded { added, removed, changed }: &Reloaded) {
+	info!("[reload] users reloaded: {added} added, {removed} removed, {changed} changed");
+}
+
+/// Resolves on each SIGHUP, never on platforms without signals
+struct Hangup {
+	#[cfg(unix)]
+	signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+	fn new() -> Self {
+		#[cfg(unix)]
+		{
+			use tokio::signal::unix::{SignalKind, signal};
+			let signal = signal(SignalKind::hangup())
+				.inspect_err(|err| warn!("[reload] cannot reload on SIGHUP: {err}"))
+				.ok();
+			Self { signal }
+		}
+		#[cfg(not(unix))]
+		Self {}
+	}
+
+	async fn recv(&mut self) {
+		#[cfg(unix)]
+		if let Some(signal) = &mut self.signal {
+			signal.recv().await;
+			return;
+		}
+		std::future::pending::<()>().await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use uuid::Uuid;
+
+	use super::*;
+	use crate::config::LogLevel;
+
+	#[test]
+	fn test_needs_restart() {
+		let current = Config::default();
+		let mut new = Config::default();
+		new.users.insert(Uuid::from_u128(1), "secret".to_owned());
+		new.log_level = LogLevel::Debug;
+		new.auth_timeout = Duration::from_secs(1);
+		new.max_external_packet_size = 1200;
+		assert!(!needs_restart(&current, &new));
+
+		new.server = "[::]:443".parse().unwrap();
+		assert!(needs_restart(&current, &new));
+	}
+
+	#[test]
+	fn test_restart_changes_are_warned_once() {
+		let running = Config::default();
+		let mut moved = Config::default();
+		moved.server = "[::]:443".parse().unwrap();
+		assert!(new_restart_changes(&running, None, &moved));
+
+		// Reloading the same change again, or along with a reloaded option
+		let mut again = Config::default();
+		again.server = moved.server;
+		again.log_level = LogLevel::Debug;
+		assert!(!new_restart_changes(&running, Some(&moved), &again));
+
+		// Another change, or undoing it
+		let mut further = Config::default();
+		further.server = moved.server;
+		further.zero_rtt_handshake = true;
+		assert!(new_restart_changes(&running, Some(&again), &further));
+		assert!(!new_restart_changes(&running, Some(&further), &Config::default()));
+	}
+}
@@ -0,0 +1,491 @@
+//! DNS servers for relayed domains.
+//!
+//! With `[dns]`, the domains of TCP and UDP requests are resolved by asking
+//! `dns.servers` instead of the system resolver, so what they resolve to no
+//! longer depends on the host's `/etc/resolv.conf`. A and AAAA are asked for
+//! together. Each query is given `query_timeout`, and a server that fails or
+//! doesn't answer in time hands the query on to the next one. Answers are not
+//! cached here, `[dns_cache]` caches them as it does those of the system.
+//!
+//! Servers are written as:
+//! - `1.1.1.1` or `udp://1.1.1.1:53`, plain DNS over UDP, asked again over TCP
+//!   when the answer is truncated
+//! - `tcp://1.1.1.1:53`
+//! - `tls://1.1.1.1` or `tls://dns.google:853`, DNS over TLS (RFC 7858), with
+//!   the certificate verified against the system roots
+//! - `https://dns.google/dns-query`, DNS over HTTPS (RFC 8484)
+//!
+//! Host names in `tls://` and `https://` servers are resolved by the system.
+//! Their connections are kept open for the next queries: up to
+//! [`IDLE_TLS_STREAMS`] idle ones by `tls://` server, and those HTTP keeps
+//! alive for `https://` servers.
+
+use std::{
+	collections::HashMap,
+	fmt,
+	io::{Error as IoError, ErrorKind},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	sync::{Arc, Mutex, PoisonError},
+	time::Duration,
+};
+
+use rand::RngExt;
+use rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::{TcpStream, UdpSocket},
+	time,
+};
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+use crate::{config::DnsConfig, tls};
+
+const A: u16 = 1;
+const AAAA: u16 = 28;
+
+/// Idle connections kept at most by DNS over TLS server
+pub const IDLE_TLS_STREAMS: usize = 4;
+
+/// A DNS server of `dns.servers`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Server {
+	Udp(SocketAddr),
+	Tcp(SocketAddr),
+	Tls { host: String, port: u16 },
+	Https(String),
+}
+
+impl Server {
+	pub fn parse(server: &str) -> Result<Self, String> {
+		let invalid = || {
+			format!(
+				"invalid DNS server {server:?}, expected one like \"1.1.1.1\", \"tcp://1.1.1.1:53\", \"tls://dns.google\" or \
+				 \"https://dns.google/dns-query\""
+			)
+		};
+		if server.starts_with("https://") {
+			return reqwest::Url::parse(server)
+				.map(|_| Self::Https(server.to_owned()))
+				.map_err(|_| invalid());
+		}
+		match server.split_once("://").unwrap_or(("udp", server)) {
+			("udp", addr) => socket_addr(addr, 53).map(Self::Udp).ok_or_else(invalid),
+			("tcp", addr) => socket_addr(addr, 53).map(Self::Tcp).ok_or_else(invalid),
+			("tls", addr) => {
+				let (host, port) = host_port(addr, 853).ok_or_else(invalid)?;
+				ServerName::try_from(host.as_str()).map_err(|_| invalid())?;
+				Ok(Self::Tls { host, port })
+			}
+			_ => Err(invalid()),
+		}
+	}
+}
+
+impl fmt::Display for Server {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Udp(addr) => write!(f, "udp://{addr}"),
+			Self::Tcp(addr) => write!(f, "tcp://{addr}"),
+			Self::Tls { host, port } => write!(f, "tls://{host}:{port}"),
+			Self::Https(url) => f.write_str(url),
+		}
+	}
+}
+
+/// `addr` as an IP address with an optional port, `default_port` without
+fn socket_addr(addr: &str, default_port: u16) -> Option<SocketAddr> {
+	if let Ok(addr) = addr.parse() {
+		return Some(addr);
+	}
+	let ip = addr.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')).unwrap_or(addr);
+	ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, default_port))
+}
+
+/// `addr` as a host name or IP address with an optional port
+fn host_port(addr: &str, default_port: u16) -> Option<(String, u16)> {
+	if let Some(addr) = socket_addr(addr, default_port) {
+		return Some((addr.ip().to_string(), addr.port()));
+	}
+	let (host, port) = match addr.rsplit_once(':') {
+		Some((host, port)) => (host, port.parse().ok()?),
+		None => (addr, default_port),
+	};
+	(!host.is_empty() && !host.contains(['/', '[', ']'])).then(|| (host.to_owned(), port))
+}
+
+pub struct Resolver {
+	servers: Vec<Server>,
+	query_timeout: Duration,
+	tls: TlsConnector,
+	/// Idle connections to the DNS over TLS servers, by host and port
+	idle_tls: Mutex<HashMap<(String, u16), Vec<TlsStream<TcpStream>>>>,
+	https: reqwest::Client,
+}
+
+impl Resolver {
+	pub fn new(cfg: &DnsConfig) -> eyre::Result<Self> {
+		let servers = cfg
+			.servers
+			.iter()
+			.map(|server| Server::parse(server))
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|err| eyre::eyre!("`dns.servers`: {err}"))?;
+		let mut roots = RootCertStore::empty();
+		for cert in rustls_native_certs::load_native_certs().certs {
+			_ = roots.add(cert);
+		}
+		let tls = ClientConfig::builder_with_provider(tls::crypto_provider(&[], &[])?)
+			.with_safe_default_protocol_versions()?
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+		Ok(Self {
+			servers,
+			query_timeout: cfg.query_timeout,
+			tls: TlsConnector::from(Arc::new(tls)),
+			idle_tls: Mutex::default(),
+			https: reqwest::Client::new(),
+		})
+	}
+
+	/// The IPv4 and IPv6 addresses of `domain`
+	pub async fn lookup(&self, domain: &str) -> Result<Vec<IpAddr>, IoError> {
+		let (v4, v6) = tokio::join!(self.query(domain, A), self.query(domain, AAAA));
+		let mut ips = Vec::new();
+		let mut error = None;
+		for found in [v4, v6] {
+			match found {
+				Ok(found) => ips.extend(found),
+				Err(err) => error = Some(err),
+			}
+		}
+		match error {
+			Some(err) if ips.is_empty() => Err(err),
+			_ if ips.is_empty() => Err(IoError::new(ErrorKind::NotFound, format!("{domain} has no address"))),
+			_ => Ok(ips),
+		}
+	}
+
+	/// The addresses of `qtype` of `domain`, from the first server that
+	/// answers
+	async fn query(&self, domain: &str, qtype: u16) -> Result<Vec<IpAddr>, IoError> {
+		let mut last_error = None;
+		for server in &self.servers {
+			match time::timeout(self.query_timeout, self.exchange(server, domain, qtype)).await {
+				Ok(Ok(ips)) => return Ok(ips),
+				Ok(Err(err)) => last_error = Some(IoError::new(err.kind(), format!("DNS server {server}: {err}"))),
+				Err(_) => last_error = Some(IoError::new(ErrorKind::TimedOut, format!("DNS server {server} timed out"))),
+			}
+		}
+		Err(last_error.unwrap_or_else(|| IoError::other("no DNS server")))
+	}
+
+	async fn exchange(&self, server: &Server, domain: &str, qtype: u16) -> Result<Vec<IpAddr>, IoError> {
+		let id = rand::rng().random();
+		let query = message(id, domain, qtype)?;
+		let response = match server {
+			Server::Udp(addr) => {
+				let response = exchange_udp(*addr, &query, id).await?;
+				if is_truncated(&response) {
+					exchange_stream(TcpStream::connect(addr).await?, &query).await?
+				} else {
+					response
+				}
+			}
+			Server::Tcp(addr) => exchange_stream(TcpStream::connect(addr).await?, &query).await?,
+			Server::Tls { host, port } => self.exchange_tls(host, *port, &query).await?,
+			Server::Https(url) => self
+				.https
+				.post(url)
+				.header(reqwest::header::CONTENT_TYPE, "application/dns-message")
+				.header(reqwest::header::ACCEPT, "application/dns-message")
+				.body(query)
+				.send()
+				.await
+				.and_then(reqwest::Response::error_for_status)
+				.map_err(IoError::other)?
+				.bytes()
+				.await
+				.map_err(IoError::other)?
+				.to_vec(),
+		};
+		answer(&response, &query)
+	}
+
+	/// Exchange `query` with a DNS over TLS server, over an idle connection to
+	/// it if there is one
+	async fn exchange_tls(&self, host: &str, port: u16, query: &[u8]) -> Result<Vec<u8>, IoError> {
+		let key = (host.to_owned(), port);
+		let idle = self
+			.idle_tls
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get_mut(&key)
+			.and_then(Vec::pop);
+		// The server may have closed it since, then a new one is opened
+		if let Some(mut stream) = idle
+			&& let Ok(response) = exchange_stream(&mut stream, query).await
+		{
+			self.park_tls(key, stream);
+			return Ok(response);
+		}
+
+		let name = ServerName::try_from(key.0.clone()).map_err(|err| IoError::new(ErrorKind::InvalidInput, err))?;
+		let stream = TcpStream::connect((host, port)).await?;
+		let mut stream = self.tls.connect(name, stream).await?;
+		let response = exchange_stream(&mut stream, query).await?;
+		self.park_tls(key, stream);
+		Ok(response)
+	}
+
+	/// Keep `stream` for the next query to its server
+	fn park_tls(&self, key: (String, u16), stream: TlsStream<TcpStream>) {
+		let mut idle = self.idle_tls.lock().unwrap_or_else(PoisonError::into_inner);
+		let streams = idle.entry(key).or_default();
+		if streams.len() < IDLE_TLS_STREAMS {
+			streams.push(stream);
+		}
+	}
+}
+
+async fn exchange_udp(addr: SocketAddr, query: &[u8], id: u16) -> Result<Vec<u8>, IoError> {
+	let local = match addr {
+		SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+		SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+	};
+	let socket = UdpSocket::bind(local).await?;
+	socket.connect(addr).await?;
+	socket.send(query).await?;
+	let mut buf = vec![0; 4096];
+	loop {
+		let len = socket.recv(&mut buf).await?;
+		// Late answers to earlier queries are not ours
+		if buf[..len].starts_with(&id.to_be_bytes()) {
+			buf.truncate(len);
+			return Ok(buf);
+		}
+	}
+}
+
+/// Exchange `query` over a stream, each message prefixed with its length
+async fn exchange_stream(mut stream: impl AsyncRead + AsyncWrite + Unpin, query: &[u8]) -> Result<Vec<u8>, IoError> {
+	let len = u16::try_from(query.len()).map_err(|_| IoError::new(ErrorKind::InvalidInput, "DNS query too long"))?;
+	stream.write_all(&[&len.to_be_bytes(), query].concat()).await?;
+	let len = stream.read_u16().await?;
+	let mut response = vec![0; usize::from(len)];
+	stream.read_exact(&mut response).await?;
+	Ok(response)
+}
+
+/// A recursive query for the records of `qtype` of `domain`
+fn message(id: u16, domain: &str, qtype: u16) -> Result<Vec<u8>, IoError> {
+	let invalid = || IoError::new(ErrorKind::InvalidInput, format!("invalid domain {domain:?}"));
+	let domain = domain.strip_suffix('.').unwrap_or(domain);
+	if domain.len() > 253 {
+		return Err(invalid());
+	}
+	// ID, flags with recursion desired, and one question
+	let mut msg = [&id.to_be_bytes()[..], &[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]].concat();
+	for label in domain.split('.') {
+		if label.is_empty() || label.len() > 63 {
+			return Err(invalid());
+		}
+		msg.push(label.len() as u8);
+		msg.extend_from_slice(label.as_bytes());
+	}
+	msg.push(0);
+	msg.extend_from_slice(&qtype.to_be_bytes());
+	// Class IN
+	msg.extend_from_slice(&[0, 1]);
+	Ok(msg)
+}
+
+fn is_truncated(response: &[u8]) -> bool {
+	response.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+/// The addresses answering `query`, as made by [`message`], in `response`. A
+/// name that doesn't exist has none.
+fn answer(response: &[u8], query: &[u8]) -> Result<Vec<IpAddr>, IoError> {
+	let invalid = || IoError::new(ErrorKind::InvalidData, "malformed DNS response");
+	let header = response.get(..12).ok_or_else(invalid)?;
+	let field = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]);
+	if !query.starts_with(&header[..2]) || header[2] & 0x80 == 0 {
+		return Err(invalid());
+	}
+	// The question must be the one asked, its name in any case (RFC 4343)
+	let (name, rest) = query
+		.get(12..)
+		.and_then(|question| question.split_at_checked(question.len().checked_sub(4)?))
+		.ok_or_else(invalid)?;
+	let qtype = u16::from_be_bytes([rest[0], rest[1]]);
+	let asked = response
+		.get(12..12 + name.len())
+		.is_some_and(|echoed| echoed.eq_ignore_ascii_case(name))
+		&& response.get(12 + name.len()..12 + name.len() + 4) == Some(rest);
+	if field(4) != 1 || !asked {
+		return Err(IoError::new(ErrorKind::InvalidData, "DNS response to another question"));
+	}
+	match header[3] & 0x0f {
+		0 => {}
+		// NXDOMAIN
+		3 => return Ok(Vec::new()),
+		rcode => return Err(IoError::other(format!("DNS query failed with rcode {rcode}"))),
+	}
+
+	let mut pos = 12 + name.len() + 4;
+	let mut ips = Vec::new();
+	for _ in 0..field(6) {
+		pos = skip_name(response, pos).ok_or_else(invalid)?;
+		// TYPE, CLASS, TTL and RDLENGTH
+		let fixed = response.get(pos..pos + 10).ok_or_else(invalid)?;
+		let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+		let len = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+		let data = response.get(pos + 10..pos + 10 + len).ok_or_else(invalid)?;
+		pos += 10 + len;
+		// The aliases of a CNAME chain come along with the addresses
+		if rtype != qtype {
+			continue;
+		}
+		if let Ok(octets) = <[u8; 4]>::try_from(data) {
+			ips.push(IpAddr::from(octets));
+		} else if let Ok(octets) = <[u8; 16]>::try_from(data) {
+			ips.push(IpAddr::from(octets));
+		}
+	}
+	Ok(ips)
+}
+
+/// The position after the name at `pos` of `msg`
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+	loop {
+		let len = *msg.get(pos)?;
+		match len & 0xc0 {
+			// A pointer to the rest of the name elsewhere
+			0xc0 => return Some(pos + 2),
+			0 if len == 0 => return Some(pos + 1),
+			0 => pos += 1 + usize::from(len),
+			_ => return None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	/// An answer to `query` with a CNAME to `alias.example.com` and `ips`
+	fn respond(query: &[u8], rcode: u8, ips: &[IpAddr]) -> Vec<u8> {
+		let name_end = query.len() - 4;
+		let qtype = &query[name_end..name_end + 2];
+		let mut msg = query[..2].to_vec();
+		msg.extend_from_slice(&[0x81, 0x80 | rcode, 0, 1, 0, 1 + ips.len() as u8, 0, 0, 0, 0]);
+		msg.extend_from_slice(&query[12..]);
+		// CNAME of the question's name, at offset 12
+		let alias = b"\x05alias\x07example\x03com\x00";
+		msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, alias.len() as u8]);
+		let alias_at = msg.len() as u8;
+		msg.extend_from_slice(alias);
+		for ip in ips {
+			let data = match ip {
+				IpAddr::V4(ip) => ip.octets().to_vec(),
+				IpAddr::V6(ip) => ip.octets().to_vec(),
+			};
+			msg.extend_from_slice(&[0xc0, alias_at]);
+			msg.extend_from_slice(qtype);
+			msg.extend_from_slice(&[0, 1, 0, 0, 0, 60, 0, data.len() as u8]);
+			msg.extend_from_slice(&data);
+		}
+		msg
+	}
+
+	#[test]
+	fn test_parse_servers() {
+		let udp = |addr: &str| Server::Udp(addr.parse().unwrap());
+		assert_eq!(Server::parse("1.1.1.1"), Ok(udp("1.1.1.1:53")));
+		assert_eq!(Server::parse("udp://[2606:4700::1111]"), Ok(udp("[2606:4700::1111]:53")));
+		assert_eq!(
+			Server::parse("tcp://8.8.8.8:5353"),
+			Ok(Server::Tcp("8.8.8.8:5353".parse().unwrap()))
+		);
+		assert_eq!(
+			Server::parse("tls://dns.google"),
+			Ok(Server::Tls {
+				host: "dns.google".to_owned(),
+				port: 853
+			})
+		);
+		assert_eq!(Server::parse("tls://1.1.1.1:8853").unwrap().to_string(), "tls://1.1.1.1:8853");
+		assert!(matches!(Server::parse("https://dns.google/dns-query"), Ok(Server::Https(_))));
+		for server in ["dns.google", "tcp://dns.google", "quic://1.1.1.1", "tls://", "https://"] {
+			assert!(Server::parse(server).is_err(), "{server}");
+		}
+	}
+
+	#[test]
+	fn test_message() {
+		let msg = message(0x1234, "www.Example.com.", AAAA).unwrap();
+		assert_eq!(&msg[..12], [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(&msg[12..], b"\x03www\x07Example\x03com\x00\x00\x1c\x00\x01");
+		assert!(message(1, "a..b", A).is_err());
+		assert!(message(1, &"a".repeat(64), A).is_err());
+	}
+
+	#[test]
+	fn test_answer() {
+		let query = message(7, "example.com", A).unwrap();
+		let ips = ["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()];
+		assert_eq!(answer(&respond(&query, 0, &ips), &query).unwrap(), ips);
+		// NXDOMAIN, SERVFAIL
+		assert!(answer(&respond(&query, 3, &[]), &query).unwrap().is_empty());
+		assert!(answer(&respond(&query, 2, &[]), &query).is_err());
+		// Another query's answer, or cut short
+		assert!(answer(&respond(&query, 0, &ips), &message(8, "example.com", A).unwrap()).is_err());
+		let response = respond(&query, 0, &ips);
+		assert!(answer(&response[..response.len() - 2], &query).is_err());
+		// An answer to another question with the same ID, and one that only
+		// differs in the case of the name
+		for other in [message(7, "example.org", A), message(7, "example.com", AAAA)] {
+			assert!(answer(&respond(&other.unwrap(), 3, &[]), &query).is_err());
+		}
+		let echoed = message(7, "EXAMPLE.com", A).unwrap();
+		assert_eq!(answer(&respond(&echoed, 0, &ips), &query).unwrap(), ips);
+	}
+
+	#[tokio::test]
+	async fn test_lookup_falls_back_to_next_server() {
+		// Nothing listens on the first server
+		let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let tcp = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			loop {
+				let (mut stream, _) = listener.accept().await.unwrap();
+				let len = stream.read_u16().await.unwrap();
+				let mut query = vec![0; usize::from(len)];
+				stream.read_exact(&mut query).await.unwrap();
+				let ip = if query.ends_with(&[0, 1, 0, 1]) {
+					IpAddr::from([192, 0, 2, 1])
+				} else {
+					IpAddr::from(Ipv6Addr::LOCALHOST)
+				};
+				let response = respond(&query, 0, &[ip]);
+				stream.write_u16(response.len() as u16).await.unwrap();
+				stream.write_all(&response).await.unwrap();
+			}
+		});
+
+		#[cfg(feature = "aws-lc-rs")]
+		let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+		#[cfg(feature = "ring")]
+		let _ = rustls::crypto::ring::default_provider().install_default();
+		let resolver = Resolver::new(&DnsConfig {
+			servers: vec![format!("udp://{closed}"), format!("tcp://{tcp}")],
+			query_timeout: Duration::from_secs(1),
+		})
+		.unwrap();
+		let ips = resolver.lookup("example.com").await.unwrap();
+		assert_eq!(ips, [IpAddr::from([192, 0, 2, 1]), IpAddr::from(Ipv6Addr::LOCALHOST)]);
+	}
+}
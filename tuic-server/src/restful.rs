@@ -1,151 +1,34 @@
-use std::{
-	collections::HashMap,
-	net::SocketAddr,
-	sync::{
-		Arc,
-		atomic::{AtomicUsize, Ordering},
-	},
+use std::sync::{
+	Arc,
+	atomic::{AtomicUsize, Ordering},
 };
 
-use axum::{
-	Json, Router,
-	extract::State,
-	http::StatusCode,
-	routing::{get, post},
-};
-use axum_extra::{
-	TypedHeader,
-	headers::{Authorization, authorization::Bearer},
-};
 use moka::future::Cache;
-use serde_json::json;
 use tracing::warn;
-use tuic_core::quinn::{QuinnConnection, VarInt};
+use tuic_core::quinn::QuinnConnection;
 use uuid::Uuid;
 
-use crate::AppContext;
+use crate::{
+	AppContext,
+	connection::{INTERNAL_ERROR_CODE, KICKED_CODE, TOO_MANY_CONNECTIONS_CODE},
+};
 
-pub async fn start(ctx: Arc<AppContext>) {
-	let restful = ctx.cfg.restful.as_ref().unwrap();
-	let addr = restful.addr;
-	let app = Router::new()
-		.route("/kick", post(kick))
-		.route("/online", get(list_online))
-		.route("/detailed_online", get(list_detailed_online))
-		.route("/traffic", get(list_traffic))
-		.route("/reset_traffic", get(reset_traffic))
-		.with_state(ctx);
-	let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-	warn!("RESTful server started, listening on {addr}");
-	axum::serve(listener, app).await.unwrap();
-}
+#[cfg(feature = "admin-api")]
+mod api;
 
-async fn kick(
-	State(ctx): State<Arc<AppContext>>,
-	token: TypedHeader<Authorization<Bearer>>,
-	Json(users): Json<Vec<Uuid>>,
-) -> StatusCode {
-	if let Some(restful) = &ctx.cfg.restful
-		&& !restful.secret.is_empty()
-		&& restful.secret != token.token()
-	{
-		return StatusCode::UNAUTHORIZED;
-	}
+#[cfg(feature = "admin-api")]
+pub use api::start;
+
+/// Close every connection of `users`. Only tracked while `[restful]` is
+/// configured.
+pub async fn kick(ctx: &AppContext, users: &[Uuid]) {
 	for user in users {
-		if let Some(cache) = ctx.online_clients.get(&user).await {
+		if let Some(cache) = ctx.online_clients.get(user).await {
 			for (_id, client) in cache.iter() {
-				client.close(VarInt::from_u32(6002), "Client got kicked".as_bytes());
+				client.close(KICKED_CODE, "Client got kicked".as_bytes());
 			}
 		}
 	}
-	StatusCode::OK
-}
-
-async fn list_online(
-	State(ctx): State<Arc<AppContext>>,
-	token: TypedHeader<Authorization<Bearer>>,
-) -> (StatusCode, Json<HashMap<Uuid, usize>>) {
-	if let Some(restful) = &ctx.cfg.restful
-		&& !restful.secret.is_empty()
-		&& restful.secret != token.token()
-	{
-		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
-	}
-	let mut result = HashMap::new();
-	for (user, cache) in ctx.online_clients.iter() {
-		let count = cache.iter().count();
-		if count != 0 {
-			result.insert(*user, count);
-		}
-	}
-
-	(StatusCode::OK, Json(result))
-}
-
-async fn list_detailed_online(
-	State(ctx): State<Arc<AppContext>>,
-	token: TypedHeader<Authorization<Bearer>>,
-) -> (StatusCode, Json<HashMap<Uuid, Vec<SocketAddr>>>) {
-	if let Some(restful) = &ctx.cfg.restful
-		&& !restful.secret.is_empty()
-		&& restful.secret != token.token()
-	{
-		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
-	}
-	let mut result = HashMap::new();
-	for (user, cache) in ctx.online_clients.iter() {
-		let addrs: Vec<SocketAddr> = cache.iter().map(|(_, client)| client.remote_address()).collect();
-		if addrs.is_empty() {
-			continue;
-		}
-		result.insert(*user, addrs);
-	}
-
-	(StatusCode::OK, Json(result))
-}
-
-async fn list_traffic(
-	State(ctx): State<Arc<AppContext>>,
-	token: TypedHeader<Authorization<Bearer>>,
-) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
-	if let Some(restful) = &ctx.cfg.restful
-		&& !restful.secret.is_empty()
-		&& restful.secret != token.token()
-	{
-		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
-	}
-	let mut result = HashMap::new();
-	for (uuid, (tx, rx)) in ctx.traffic_stats.iter() {
-		let tx = tx.load(Ordering::Relaxed);
-		let rx = rx.load(Ordering::Relaxed);
-		if tx != 0 || rx != 0 {
-			result.insert(*uuid, json!({"tx": tx, "rx":rx}));
-		}
-	}
-
-	(StatusCode::OK, Json(result))
-}
-
-async fn reset_traffic(
-	State(ctx): State<Arc<AppContext>>,
-	token: TypedHeader<Authorization<Bearer>>,
-) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
-	if let Some(restful) = &ctx.cfg.restful
-		&& !restful.secret.is_empty()
-		&& restful.secret != token.token()
-	{
-		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
-	}
-	let mut result = HashMap::new();
-	for (uuid, (tx, rx)) in ctx.traffic_stats.iter() {
-		let tx = tx.swap(0, Ordering::Relaxed);
-		let rx = rx.swap(0, Ordering::Relaxed);
-		if tx != 0 || rx != 0 {
-			result.insert(*uuid, json!({"tx": tx, "rx":rx}));
-		}
-	}
-
-	(StatusCode::OK, Json(result))
 }
 
 pub async fn client_connect(ctx: &AppContext, uuid: &Uuid, conn: QuinnConnection) {
@@ -154,11 +37,12 @@ pub async fn client_connect(ctx: &AppContext, uuid: &Uuid, conn: QuinnConnection
 			return;
 		}
 
-		let Some(counter) = ctx.online_counter.get(uuid) else {
+		let Some(stats) = ctx.users.user_stats(uuid) else {
 			warn!("UUID {uuid} not in users table during client_connect, closing connection");
-			conn.close(VarInt::from_u32(6003), b"Internal error");
+			conn.close(INTERNAL_ERROR_CODE, b"Internal error");
 			return;
 		};
+		let counter = &stats.online;
 
 		let cap = if cfg.maximum_clients_per_user == 0 {
 			counter.fetch_add(1, Ordering::AcqRel);
@@ -171,7 +55,7 @@ pub async fn client_connect(ctx: &AppContext, uuid: &Uuid, conn: QuinnConnection
 				})
 				.is_err()
 			{
-				conn.close(VarInt::from_u32(6001), b"Reached maximum clients limitation");
+				conn.close(TOO_MANY_CONNECTIONS_CODE, b"Reached maximum clients limitation");
 				return;
 			}
 			max as u64
@@ -197,8 +81,8 @@ pub async fn client_disconnect(ctx: &AppContext, uuid: &Uuid, conn: QuinnConnect
 	}
 
 	if was_online {
-		if let Some(counter) = ctx.online_counter.get(uuid) {
-			decrement_online_counter(counter);
+		if let Some(stats) = ctx.users.user_stats(uuid) {
+			decrement_online_counter(&stats.online);
 		} else {
 			warn!("UUID {uuid} not in users table during client_disconnect");
 		}
@@ -210,13 +94,13 @@ fn decrement_online_counter(counter: &AtomicUsize) {
 }
 
 pub fn traffic_tx(ctx: &AppContext, uuid: &Uuid, size: usize) {
-	if let Some((tx, _)) = ctx.traffic_stats.get(uuid) {
-		tx.fetch_add(size, Ordering::SeqCst);
+	if let Some(stats) = ctx.users.user_stats(uuid) {
+		stats.tx.fetch_add(size, Ordering::SeqCst);
 	}
 }
 
 pub fn traffic_rx(ctx: &AppContext, uuid: &Uuid, size: usize) {
-	if let Some((__, rx)) = ctx.traffic_stats.get(uuid) {
-		rx.fetch_add(size, Ordering::SeqCst);
+	if let Some(stats) = ctx.users.user_stats(uuid) {
+		stats.rx.fetch_add(size, Ordering::SeqCst);
 	}
 }
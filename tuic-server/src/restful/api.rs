@@ -0,0 +1,467 @@
+//! HTTP handlers of the admin API.
+
+use std::{
+	collections::HashMap,
+	net::{IpAddr, SocketAddr},
+	sync::{Arc, atomic::Ordering},
+	time::Duration,
+};
+
+use axum::{
+	Json, Router,
+	extract::{State, rejection::JsonRejection},
+	http::StatusCode,
+	routing::{delete, get, post},
+};
+use axum_extra::{
+	TypedHeader,
+	headers::{Authorization, authorization::Bearer},
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
+use tuic_core::quinn::VarInt;
+use uuid::Uuid;
+
+use crate::{AppContext, rollout::TransportSettings};
+
+pub async fn start(ctx: Arc<AppContext>) {
+	let Some(restful) = ctx.cfg.restful.as_ref() else {
+		return;
+	};
+	let addr = restful.addr;
+	let app = Router::new()
+		.route("/kick", post(kick))
+		.route("/online", get(list_online))
+		.route("/detailed_online", get(list_detailed_online))
+		.route("/traffic", get(list_traffic))
+		.route("/reset_traffic", get(reset_traffic))
+		.route("/stats", get(resource_stats))
+		.route("/quota", get(quota_status))
+		.route("/users", get(list_users).post(add_user).delete(remove_users))
+		.route("/outbounds", get(outbound_health))
+		.route("/dump", get(list_dump).post(select_dump))
+		.route("/drain", get(drain_status).post(start_drain))
+		.route("/transport", get(transport_status).post(start_canary).delete(stop_canary))
+		.route("/bans", delete(lift_bans))
+		.with_state(ctx);
+	let listener = match tokio::net::TcpListener::bind(addr).await {
+		Ok(listener) => listener,
+		Err(err) => {
+			warn!("RESTful server failed to listen on {addr}: {err}");
+			return;
+		}
+	};
+	warn!("RESTful server started, listening on {addr}");
+	if let Err(err) = axum::serve(listener, app).await {
+		warn!("RESTful server stopped: {err}");
+	}
+}
+
+async fn kick(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+	Json(users): Json<Vec<Uuid>>,
+) -> StatusCode {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return StatusCode::UNAUTHORIZED;
+	}
+	super::kick(&ctx, &users).await;
+	if ctx.cluster.is_some() {
+		let ctx = ctx.clone();
+		tokio::spawn(async move {
+			if let Some(cluster) = &ctx.cluster
+				&& let Err(err) = cluster.publish_kick(users).await
+			{
+				warn!("[cluster] failed to publish kick: {err:#}");
+			}
+		});
+	}
+	StatusCode::OK
+}
+
+async fn list_online(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<HashMap<Uuid, usize>>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
+	}
+	let mut result = HashMap::new();
+	for (user, cache) in ctx.online_clients.iter() {
+		let count = cache.iter().count();
+		if count != 0 {
+			result.insert(*user, count);
+		}
+	}
+
+	(StatusCode::OK, Json(result))
+}
+
+async fn list_detailed_online(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<HashMap<Uuid, Vec<SocketAddr>>>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
+	}
+	let mut result = HashMap::new();
+	for (user, cache) in ctx.online_clients.iter() {
+		let addrs: Vec<SocketAddr> = cache.iter().map(|(_, client)| client.remote_address()).collect();
+		if addrs.is_empty() {
+			continue;
+		}
+		result.insert(*user, addrs);
+	}
+
+	(StatusCode::OK, Json(result))
+}
+
+async fn list_traffic(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
+	}
+	let mut result = HashMap::new();
+	for (uuid, stats) in ctx.users.all_stats() {
+		let tx = stats.tx.load(Ordering::Relaxed);
+		let rx = stats.rx.load(Ordering::Relaxed);
+		if tx != 0 || rx != 0 {
+			result.insert(uuid, json!({"tx": tx, "rx":rx}));
+		}
+	}
+
+	(StatusCode::OK, Json(result))
+}
+
+async fn reset_traffic(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
+	}
+	let mut result = HashMap::new();
+	for (uuid, stats) in ctx.users.all_stats() {
+		let tx = stats.tx.swap(0, Ordering::Relaxed);
+		let rx = stats.rx.swap(0, Ordering::Relaxed);
+		if tx != 0 || rx != 0 {
+			result.insert(uuid, json!({"tx": tx, "rx":rx}));
+		}
+	}
+
+	(StatusCode::OK, Json(result))
+}
+
+async fn resource_stats(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(json!({})));
+	}
+
+	(StatusCode::OK, Json(json!(ctx.stats.snapshot())))
+}
+
+#[derive(Deserialize)]
+struct NewUser {
+	uuid: Uuid,
+	password: String,
+}
+
+async fn list_users(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
+	}
+	let mut result = HashMap::new();
+	for (uuid, stats) in ctx.users.all_stats() {
+		result.insert(
+			uuid,
+			json!({
+				"connections": ctx.users.connection_count(&uuid),
+				"tx": stats.tx.load(Ordering::Relaxed),
+				"rx": stats.rx.load(Ordering::Relaxed),
+			}),
+		);
+	}
+
+	(StatusCode::OK, Json(result))
+}
+
+async fn add_user(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+	Json(user): Json<NewUser>,
+) -> StatusCode {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return StatusCode::UNAUTHORIZED;
+	}
+	if user.password.is_empty() {
+		return StatusCode::BAD_REQUEST;
+	}
+	let password = match ctx.cfg.expand_env {
+		true => match crate::users::expand_env(&user.password) {
+			Ok(password) => password,
+			Err(err) => {
+				warn!("[users] rejected the password of {}: {err}", user.uuid);
+				return StatusCode::BAD_REQUEST;
+			}
+		},
+		false => user.password,
+	};
+	if ctx.users.add(user.uuid, password) {
+		StatusCode::CREATED
+	} else {
+		StatusCode::OK
+	}
+}
+
+async fn remove_users(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+	Json(users): Json<Vec<Uuid>>,
+) -> (StatusCode, Json<Vec<Uuid>>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(Vec::new()));
+	}
+	let removed = users.into_iter().filter(|uuid| ctx.users.remove(uuid)).collect();
+
+	(StatusCode::OK, Json(removed))
+}
+
+async fn lift_bans(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+	Json(sources): Json<Vec<IpAddr>>,
+) -> (StatusCode, Json<Vec<IpAddr>>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(Vec::new()));
+	}
+	let Some(bans) = &ctx.bans else {
+		return (StatusCode::NOT_FOUND, Json(Vec::new()));
+	};
+	let mut lifted = Vec::new();
+	for source in &sources {
+		if bans.lift(*source).await {
+			lifted.push(*source);
+		}
+	}
+	if ctx.cluster.is_some() {
+		let ctx = ctx.clone();
+		tokio::spawn(async move {
+			if let Some(cluster) = &ctx.cluster
+				&& let Err(err) = cluster.publish_bans(sources, true).await
+			{
+				warn!("[cluster] failed to publish ban lifting: {err:#}");
+			}
+		});
+	}
+
+	(StatusCode::OK, Json(lifted))
+}
+
+async fn quota_status(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(json!({})));
+	}
+
+	(StatusCode::OK, Json(json!(ctx.quotas.status())))
+}
+
+async fn outbound_health(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(json!({})));
+	}
+
+	(StatusCode::OK, Json(json!(ctx.health.snapshot())))
+}
+
+#[derive(Deserialize)]
+struct DumpSelection {
+	/// Connection ID, as logged in the `conn` span
+	id: u32,
+	enabled: bool,
+}
+
+async fn list_dump(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<Vec<u32>>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(Vec::new()));
+	}
+
+	(StatusCode::OK, Json(ctx.dump.selected()))
+}
+
+async fn select_dump(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+	Json(selection): Json<DumpSelection>,
+) -> StatusCode {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return StatusCode::UNAUTHORIZED;
+	}
+	ctx.dump.select(selection.id, selection.enabled);
+	StatusCode::OK
+}
+
+#[derive(Deserialize)]
+struct DrainRequest {
+	/// How long existing connections may keep going
+	#[serde(with = "humantime_serde")]
+	deadline: Duration,
+}
+
+async fn drain_status(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(json!({})));
+	}
+
+	(StatusCode::OK, Json(json!({ "draining": ctx.drain.is_draining() })))
+}
+
+async fn start_drain(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+	Json(request): Json<DrainRequest>,
+) -> StatusCode {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return StatusCode::UNAUTHORIZED;
+	}
+	if ctx.drain.start(request.deadline) {
+		StatusCode::ACCEPTED
+	} else {
+		StatusCode::CONFLICT
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CanaryRequest {
+	#[serde(default)]
+	settings: TransportSettings,
+	/// Share of new connections the settings apply to
+	percent: u8,
+}
+
+async fn transport_status(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(json!({})));
+	}
+
+	(StatusCode::OK, Json(json!({ "canary": ctx.rollout.status() })))
+}
+
+async fn start_canary(
+	State(ctx): State<Arc<AppContext>>,
+	token: TypedHeader<Authorization<Bearer>>,
+	request: Result<Json<CanaryRequest>, JsonRejection>,
+) -> (StatusCode, Json<serde_json::Value>) {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return (StatusCode::UNAUTHORIZED, Json(json!({})));
+	}
+	// Axum answers bodies that parse but don't fit with 422, invalid
+	// settings are a 400 like the other errors
+	let Json(request) = match request {
+		Ok(request) => request,
+		Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": err.body_text() }))),
+	};
+	match ctx.rollout.start(&ctx.cfg, request.settings, request.percent) {
+		Ok(()) => {
+			warn!(
+				"[restful] transport canary applies to {}% of new connections",
+				request.percent
+			);
+			(StatusCode::OK, Json(json!({ "canary": ctx.rollout.status() })))
+		}
+		Err(err) => (StatusCode::BAD_REQUEST, Json(json!({ "error": err.to_string() }))),
+	}
+}
+
+async fn stop_canary(State(ctx): State<Arc<AppContext>>, token: TypedHeader<Authorization<Bearer>>) -> StatusCode {
+	if let Some(restful) = &ctx.cfg.restful
+		&& !restful.secret.is_empty()
+		&& restful.secret != token.token()
+	{
+		return StatusCode::UNAUTHORIZED;
+	}
+	if ctx.rollout.stop() {
+		warn!("[restful] transport canary rolled back");
+		StatusCode::OK
+	} else {
+		StatusCode::NOT_FOUND
+	}
+}
@@ -0,0 +1,151 @@
+//! Canary rollout of transport settings.
+//!
+//! `POST /transport` of the admin API sets transport parameters that override
+//! `[quic]` for a percentage of new connections, so a change can be tried on
+//! part of the live traffic before it applies to all of it. Established
+//! connections keep the parameters they were accepted with. Raising `percent`
+//! to 100 rolls the change out to every new connection, and `DELETE
+//! /transport` rolls it back at once. The canary is not persisted: after a
+//! restart the server uses its config again.
+
+use std::{
+	sync::{Arc, OnceLock, PoisonError, RwLock},
+	time::Duration,
+};
+
+use eyre::{OptionExt, eyre};
+use rand::RngExt;
+use serde::{Deserialize, Serialize};
+use tuic_core::quinn::{ServerConfig, crypto::rustls::QuicServerConfig};
+
+use crate::{config::Config, server::transport_config, utils::CongestionController};
+
+/// Transport parameters replacing those of `[quic]`, unset ones are kept
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSettings {
+	/// Replaces `quic.congestion_control.controller`
+	pub controller: Option<CongestionController>,
+	/// Replaces `quic.congestion_control.initial_window`
+	pub initial_window: Option<u64>,
+	/// Replaces `quic.send_window`
+	pub send_window: Option<u64>,
+	/// Replaces `quic.receive_window`
+	pub receive_window: Option<u32>,
+	/// Replaces `quic.max_idle_time`
+	#[serde(with = "humantime_serde")]
+	pub max_idle_time: Option<Duration>,
+	/// Replaces `quic.max_concurrent_streams`
+	pub max_concurrent_streams: Option<u32>,
+	/// Replaces `quic.pmtu`
+	pub pmtu: Option<bool>,
+}
+
+/// The canary of the running server, if any
+#[derive(Default)]
+pub struct Rollout {
+	crypto: OnceLock<Arc<QuicServerConfig>>,
+	canary: RwLock<Option<Arc<Canary>>>,
+}
+
+struct Canary {
+	settings: TransportSettings,
+	percent: u8,
+	controller: CongestionController,
+	config: Arc<ServerConfig>,
+}
+
+/// A canary as reported by `GET /transport`
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CanaryStatus {
+	pub settings: TransportSettings,
+	pub percent: u8,
+}
+
+impl Rollout {
+	/// Build canary server configs with the TLS config of the endpoint
+	pub fn attach(&self, crypto: Arc<QuicServerConfig>) {
+		_ = self.crypto.set(crypto);
+	}
+
+	/// Apply `settings` to `percent` of the new connections, replacing the
+	/// previous canary
+	pub fn start(&self, cfg: &Config, settings: TransportSettings, percent: u8) -> eyre::Result<()> {
+		if percent > 100 {
+			return Err(eyre!("`percent` must be at most 100"));
+		}
+		if settings.send_window == Some(0) || settings.receive_window == Some(0) {
+			return Err(eyre!("windows must be greater than zero"));
+		}
+		let crypto = self.crypto.get().ok_or_eyre("the endpoint is not running")?;
+		let controller = settings.controller.unwrap_or(cfg.quic.congestion_control.controller);
+		let mut config = ServerConfig::with_crypto(crypto.clone());
+		config.transport_config(Arc::new(transport_config(cfg, controller, &settings)?));
+
+		*self.canary.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(Canary {
+			settings,
+			percent,
+			controller,
+			config: Arc::new(config),
+		}));
+		Ok(())
+	}
+
+	/// Go back to `[quic]` for new connections, `false` without a canary
+	pub fn stop(&self) -> bool {
+		self.canary.write().unwrap_or_else(PoisonError::into_inner).take().is_some()
+	}
+
+	pub fn status(&self) -> Option<CanaryStatus> {
+		self.canary
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.as_ref()
+			.map(|canary| CanaryStatus {
+				settings: canary.settings.clone(),
+				percent: canary.percent,
+			})
+	}
+
+	/// Draw whether a new connection joins the canary, and if so the config
+	/// to accept it with
+	pub(crate) fn pick(&self) -> Option<(CongestionController, Arc<ServerConfig>)> {
+		let canary = self.canary.read().unwrap_or_else(PoisonError::into_inner).clone()?;
+		(rand::rng().random_range(0..100u8) < canary.percent).then(|| (canary.controller, canary.config.clone()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_settings_from_json() {
+		let settings: TransportSettings =
+			serde_json::from_str(r#"{"controller": "cubic", "send_window": 33554432, "max_idle_time": "1m"}"#).unwrap();
+		assert_eq!(settings.controller, Some(CongestionController::Cubic));
+		assert_eq!(settings.send_window, Some(33554432));
+		assert_eq!(settings.max_idle_time, Some(Duration::from_secs(60)));
+		assert_eq!(settings.receive_window, None);
+
+		assert!(serde_json::from_str::<TransportSettings>(r#"{"pacing": true}"#).is_err());
+	}
+
+	#[test]
+	fn test_invalid_canary_is_refused() {
+		let rollout = Rollout::default();
+		let cfg = Config::default();
+		assert!(rollout.start(&cfg, TransportSettings::default(), 101).is_err());
+		let settings = TransportSettings {
+			send_window: Some(0),
+			..Default::default()
+		};
+		assert!(rollout.start(&cfg, settings, 10).is_err());
+		// Without an endpoint there is nothing to build the config from
+		assert!(rollout.start(&cfg, TransportSettings::default(), 10).is_err());
+
+		assert!(rollout.status().is_none());
+		assert!(rollout.pick().is_none());
+		assert!(!rollout.stop());
+	}
+}
@@ -1,35 +1,54 @@
 use std::{
-	net::{SocketAddr, UdpSocket as StdUdpSocket},
-	sync::Arc,
-	time::Duration,
+	collections::HashMap,
+	net::{IpAddr, SocketAddr, UdpSocket as StdUdpSocket},
+	sync::{Arc, atomic::Ordering},
 };
 
 use eyre::Context;
-use rustls::{
-	ServerConfig as RustlsServerConfig,
-	pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
-};
+use rand::RngExt;
+use rustls::{ServerConfig as RustlsServerConfig, pki_types::CertificateDer};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tracing::{debug, info, warn};
-use tuic_core::quinn::{
-	Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime, TransportConfig, VarInt,
-	bbr::BbrConfig,
-	congestion::{Bbr3Config, CubicConfig, NewRenoConfig},
-	crypto::rustls::QuicServerConfig,
+use tuic_core::{
+	obfs::{ObfsUdpSocket, Salamander},
+	quinn::{
+		Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime, TransportConfig, VarInt,
+		bbr::BbrConfig,
+		congestion::{Bbr3Config, CubicConfig, NewRenoConfig},
+	},
 };
 
 use crate::{
 	AppContext,
 	acme::{is_valid_domain, start_acme},
+	config::Config,
 	connection::Connection,
 	error::Error,
-	tls::CertResolver,
+	ocsp::Ocsp,
+	rollout::TransportSettings,
+	tls::{self, CertOptions, CertResolver, SniResolver},
+	upgrade,
 	utils::CongestionController,
 };
 
 pub struct Server {
+	/// The endpoint on `server`
 	ep: Endpoint,
+	/// Endpoints on the other addresses of [`Config::listen_addrs`]
+	extra: Vec<Endpoint>,
 	ctx: Arc<AppContext>,
+	/// Duplicate of the endpoint socket, kept to hand over on `--upgrade`
+	handoff_socket: Option<StdUdpSocket>,
+	/// Server configs of `quic.congestion_control.experiment`
+	experiment: Vec<ExperimentArm>,
+	/// Server configs of `quic.congestion_control.client_choices`
+	client_choices: Vec<(CongestionController, Arc<ServerConfig>)>,
+}
+
+struct ExperimentArm {
+	controller: CongestionController,
+	percent: u8,
+	config: Arc<ServerConfig>,
 }
 
 impl Server {
@@ -37,139 +56,335 @@ impl Server {
 		self.ep.local_addr()
 	}
 
-	pub async fn init(ctx: Arc<AppContext>) -> Result<Self, Error> {
+	fn endpoints(&self) -> impl Iterator<Item = &Endpoint> {
+		std::iter::once(&self.ep).chain(&self.extra)
+	}
+
+	/// With `inherit`, take over the UDP socket of the running server instead
+	/// of binding a new one (see [`crate::upgrade`]).
+	pub async fn init(ctx: Arc<AppContext>, inherit: bool) -> Result<Self, Error> {
 		let mut crypto: RustlsServerConfig;
 		let hostname = ctx.cfg.tls.hostname.clone();
 		let acme_email = ctx.cfg.tls.acme_email.clone();
+		let provider = tls::crypto_provider(&ctx.cfg.tls.cipher_suites, &ctx.cfg.tls.kx_groups)?;
+		let client_auth = tls::client_verifier(ctx.cfg.tls.client_ca.as_deref(), provider.clone()).await?;
+		let tls_builder =
+			|| RustlsServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(&[&rustls::version::TLS13]);
+		let cert_options = CertOptions {
+			passphrase: ctx.cfg.tls.private_key_passphrase.clone(),
+			reload_interval: ctx.cfg.tls.reload_interval,
+			ocsp: match &ctx.cfg.tls.ocsp_response {
+				Some(path) => Ocsp::File(path.clone()),
+				None if ctx.cfg.tls.ocsp_fetch => Ocsp::Fetch,
+				None => Ocsp::Off,
+			},
+		};
 
 		if ctx.cfg.tls.auto_ssl && is_valid_domain(hostname.as_str()) {
 			warn!("Attempting automatic SSL certificate provisioning for domain: {}", hostname);
-			let cache_dir = ctx.cfg.data_dir.join("acme");
+			let cache_dir = ctx
+				.cfg
+				.tls
+				.acme_cache_dir
+				.clone()
+				.unwrap_or_else(|| ctx.cfg.data_dir.join("acme"));
+			let challenge = ctx.cfg.tls.acme_challenge;
 
-			match start_acme(ctx.clone(), hostname.as_str(), acme_email.as_str(), &cache_dir).await {
+			match start_acme(ctx.clone(), hostname.as_str(), acme_email.as_str(), challenge, &cache_dir).await {
 				Ok(resolver) => {
 					info!("ACME certificate management active for {}", hostname);
-					crypto = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-						.with_no_client_auth()
+					crypto = tls_builder()?
+						.with_client_cert_verifier(client_auth.clone())
 						.with_cert_resolver(resolver);
 				}
 				Err(e) => {
 					warn!("ACME setup failed: {e}, falling back to self-signed certificate");
-					let cert = rcgen::generate_simple_self_signed(vec![hostname.clone()]).unwrap();
-					let cert_der = CertificateDer::from(cert.cert);
-					let priv_key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
-					crypto = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-						.with_no_client_auth()
-						.with_single_cert(vec![cert_der], PrivateKeyDer::Pkcs8(priv_key))?;
+					let (chain, key) = tls::self_signed(&hostname, None).await?;
+					log_self_signed(&hostname, &chain);
+					crypto = tls_builder()?
+						.with_client_cert_verifier(client_auth.clone())
+						.with_single_cert(chain, key)?;
 				}
 			}
+		} else if let Some((chain, key)) = &ctx.cfg.tls.certificate_der {
+			crypto = tls_builder()?
+				.with_client_cert_verifier(client_auth.clone())
+				.with_single_cert(chain.clone(), key.clone_key())?;
+		} else if let (Some(cert_pem), Some(key_pem)) = (&ctx.cfg.tls.certificate_pem, &ctx.cfg.tls.private_key_pem) {
+			let (chain, key) = tls::parse_pem(cert_pem, key_pem, ctx.cfg.tls.private_key_passphrase.as_deref())?;
+			crypto = tls_builder()?
+				.with_client_cert_verifier(client_auth.clone())
+				.with_single_cert(chain, key)?;
 		} else if ctx.cfg.tls.self_sign {
-			let cert = rcgen::generate_simple_self_signed(vec![ctx.cfg.tls.hostname.clone()]).unwrap();
-			let cert_der = CertificateDer::from(cert.cert);
-			let priv_key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
-			crypto = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-				.with_no_client_auth()
-				.with_single_cert(vec![cert_der], PrivateKeyDer::Pkcs8(priv_key))?;
+			let (chain, key) = tls::self_signed(&hostname, ctx.cfg.tls.self_sign_dir.as_deref()).await?;
+			log_self_signed(&hostname, &chain);
+			crypto = tls_builder()?
+				.with_client_cert_verifier(client_auth.clone())
+				.with_single_cert(chain, key)?;
 		} else {
-			let cert_resolver =
-				CertResolver::new(&ctx.cfg.tls.certificate, &ctx.cfg.tls.private_key, Duration::from_secs(30)).await?;
+			let tls = &ctx.cfg.tls;
+			let cert_resolver = match (&tls.fallback_certificate, &tls.fallback_private_key) {
+				(Some(fallback_cert), Some(fallback_key)) => {
+					CertResolver::with_fallback(&tls.certificate, &tls.private_key, fallback_cert, fallback_key, &cert_options)
+						.await?
+				}
+				_ => CertResolver::new(&tls.certificate, &tls.private_key, &cert_options).await?,
+			};
 
-			crypto = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-				.with_no_client_auth()
+			crypto = tls_builder()?
+				.with_client_cert_verifier(client_auth.clone())
 				.with_cert_resolver(cert_resolver);
 		}
 
+		// Certificates of other server names take over from the one above for
+		// clients asking for them
+		if !ctx.cfg.tls.sni.is_empty() {
+			let sni_options = cert_options.for_other_cert();
+			let mut names = HashMap::new();
+			for (name, sni) in &ctx.cfg.tls.sni {
+				let resolver = CertResolver::new(&sni.certificate, &sni.private_key, &sni_options)
+					.await
+					.with_context(|| format!("failed to load the certificate of `tls.sni.\"{name}\"`"))?;
+				names.insert(name.clone(), resolver);
+			}
+			crypto.cert_resolver = Arc::new(SniResolver::new(crypto.cert_resolver.clone(), names));
+		}
+
+		if ctx.cfg.tls.key_log {
+			crypto.key_log = tls::key_log();
+		}
 		crypto.alpn_protocols = ctx.cfg.tls.alpn.iter().cloned().map(|alpn| alpn.into_bytes()).collect();
 		// TODO only set when 0-RTT enabled
 		crypto.max_early_data_size = u32::MAX;
 		crypto.send_half_rtt_data = ctx.cfg.zero_rtt_handshake;
 
-		let mut config = ServerConfig::with_crypto(Arc::new(
-			QuicServerConfig::try_from(crypto).context("no initial cipher suite found")?,
-		));
-		let mut tp_cfg = TransportConfig::default();
-
-		tp_cfg
-			.max_concurrent_bidi_streams(VarInt::from(ctx.cfg.quic.max_concurrent_streams))
-			.max_concurrent_uni_streams(VarInt::from(ctx.cfg.quic.max_concurrent_streams))
-			.send_window(ctx.cfg.quic.send_window)
-			.stream_receive_window(VarInt::from_u32(ctx.cfg.quic.receive_window))
-			.max_idle_timeout(Some(
-				IdleTimeout::try_from(ctx.cfg.quic.max_idle_time).map_err(|_| Error::InvalidMaxIdleTime)?,
-			))
-			.initial_mtu(ctx.cfg.quic.initial_mtu)
-			.min_mtu(ctx.cfg.quic.min_mtu)
-			.enable_segmentation_offload(ctx.cfg.quic.gso)
-			.mtu_discovery_config(if !ctx.cfg.quic.pmtu { None } else { Some(Default::default()) });
-
-		match ctx.cfg.quic.congestion_control.controller {
-			CongestionController::Bbr => {
-				let mut bbr_config = BbrConfig::default();
-				bbr_config.initial_window(ctx.cfg.quic.congestion_control.initial_window);
-				tp_cfg.congestion_controller_factory(Arc::new(bbr_config))
-			}
-			CongestionController::Cubic => {
-				let mut cubic_config = CubicConfig::default();
-				cubic_config.initial_window(ctx.cfg.quic.congestion_control.initial_window);
-				tp_cfg.congestion_controller_factory(Arc::new(cubic_config))
-			}
-			CongestionController::NewReno => {
-				let mut new_reno = NewRenoConfig::default();
-				new_reno.initial_window(ctx.cfg.quic.congestion_control.initial_window);
-				tp_cfg.congestion_controller_factory(Arc::new(new_reno))
-			}
-			CongestionController::Bbr3 => {
-				let mut bbr3_config = Bbr3Config::default();
-				bbr3_config.initial_window(ctx.cfg.quic.congestion_control.initial_window);
-				tp_cfg.congestion_controller_factory(Arc::new(bbr3_config))
-			}
+		let crypto = Arc::new(tls::quic_server_config(crypto)?);
+		let server_config = |controller: CongestionController| -> Result<ServerConfig, Error> {
+			let mut config = ServerConfig::with_crypto(crypto.clone());
+			config.transport_config(Arc::new(transport_config(
+				&ctx.cfg,
+				controller,
+				&TransportSettings::default(),
+			)?));
+			Ok(config)
 		};
+		ctx.rollout.attach(crypto.clone());
+		let config = server_config(ctx.cfg.quic.congestion_control.controller)?;
+		let experiment = ctx
+			.cfg
+			.quic
+			.congestion_control
+			.experiment
+			.iter()
+			.map(|arm| {
+				Ok(ExperimentArm {
+					controller: arm.controller,
+					percent: arm.percent,
+					config: Arc::new(server_config(arm.controller)?),
+				})
+			})
+			.collect::<Result<_, Error>>()?;
+		let client_choices = ctx
+			.cfg
+			.quic
+			.congestion_control
+			.client_choices
+			.iter()
+			.map(|&controller| Ok((controller, Arc::new(server_config(controller)?))))
+			.collect::<Result<_, Error>>()?;
 
-		config.transport_config(Arc::new(tp_cfg));
-
-		let socket = {
-			let domain = match ctx.cfg.server {
-				SocketAddr::V4(_) => Domain::IPV4,
-				SocketAddr::V6(_) => Domain::IPV6,
-			};
-
-			let socket =
-				Socket::new(domain, Type::DGRAM, Some(Protocol::UDP)).context("failed to create endpoint UDP socket")?;
-
-			if ctx.cfg.dual_stack {
-				socket
-					.set_only_v6(!ctx.cfg.dual_stack)
-					.map_err(|err| Error::Socket("endpoint dual-stack socket setting error", err))?;
+		let (socket, handoff) = if inherit {
+			let path = ctx
+				.cfg
+				.upgrade
+				.as_ref()
+				.map(|upgrade| upgrade.socket.as_path())
+				.ok_or_else(|| eyre::eyre!("`--upgrade` requires the [upgrade] section in the config"))?;
+			let (socket, handoff) =
+				upgrade::inherit(path).with_context(|| format!("failed to take over the socket from {}", path.display()))?;
+			if socket.local_addr().ok() != Some(ctx.cfg.server) {
+				warn!(
+					"inherited socket is bound to {:?}, not the configured {}",
+					socket.local_addr().ok(),
+					ctx.cfg.server
+				);
 			}
+			(socket, Some(handoff))
+		} else {
+			(bind(&ctx.cfg, ctx.cfg.server)?, None)
+		};
+		let handoff_socket = if ctx.cfg.upgrade.is_some() {
+			Some(socket.try_clone().context("failed to duplicate endpoint UDP socket")?)
+		} else {
+			None
+		};
 
-			socket
-				.bind(&SockAddr::from(ctx.cfg.server))
-				.context("failed to bind endpoint UDP socket")?;
+		let mut ep_cfg = EndpointConfig::default();
+		if !ctx.cfg.quic.versions.is_empty() {
+			ep_cfg.supported_versions(ctx.cfg.quic.versions.iter().map(|v| v.get()).collect());
+		}
 
-			StdUdpSocket::from(socket)
+		let endpoint = |socket: StdUdpSocket| -> Result<Endpoint, Error> {
+			Ok(if let Some(obfs) = &ctx.cfg.obfs {
+				let socket = ObfsUdpSocket::new(socket, Salamander::new(&obfs.password))?;
+				Endpoint::new_with_abstract_socket(
+					ep_cfg.clone(),
+					Some(config.clone()),
+					Box::new(socket),
+					Arc::new(TokioRuntime),
+				)?
+			} else {
+				Endpoint::new(ep_cfg.clone(), Some(config.clone()), socket, Arc::new(TokioRuntime))?
+			})
 		};
+		let ep = endpoint(socket)?;
+		let extra = ctx
+			.cfg
+			.listen_addrs()
+			.into_iter()
+			.skip(1)
+			.map(|addr| endpoint(bind(&ctx.cfg, addr)?))
+			.collect::<Result<_, Error>>()?;
 
-		let ep = Endpoint::new(EndpointConfig::default(), Some(config), socket, Arc::new(TokioRuntime))?;
+		if let Some(handoff) = handoff {
+			handoff.ready().context("failed to notify the previous server")?;
+			info!("took over the endpoint socket, the previous server is draining");
+		}
 
-		Ok(Self { ep, ctx })
+		Ok(Self {
+			ep,
+			extra,
+			ctx,
+			handoff_socket,
+			experiment,
+			client_choices,
+		})
+	}
+
+	/// The server config of the congestion controller a client connecting
+	/// from `ip` asked for before, if it's still allowed
+	async fn hinted_config(&self, ip: IpAddr) -> Option<(CongestionController, Arc<ServerConfig>)> {
+		if self.client_choices.is_empty() {
+			return None;
+		}
+		let controller = self.ctx.congestion_hints.get(&ip.to_canonical()).await?;
+		self.client_choices
+			.iter()
+			.find(|(choice, _)| *choice == controller)
+			.map(|(choice, config)| (*choice, config.clone()))
+	}
+
+	/// Draw the experiment arm of a new connection, `None` without an
+	/// experiment
+	fn pick_experiment_arm(&self) -> Option<&ExperimentArm> {
+		if self.experiment.is_empty() {
+			return None;
+		}
+		let roll = rand::rng().random_range(0..100u8);
+		let mut threshold = 0;
+		self.experiment.iter().find(|arm| {
+			threshold += arm.percent;
+			roll < threshold
+		})
 	}
 
 	pub async fn start(&self) {
-		warn!("server started, listening on {}", self.ep.local_addr().unwrap());
+		for ep in self.endpoints() {
+			match ep.local_addr() {
+				Ok(addr) => warn!("server started, listening on {addr}"),
+				Err(err) => warn!("server started, failed to get the local address: {err}"),
+			}
+		}
 		if self.ctx.cfg.restful.is_some() {
+			#[cfg(feature = "admin-api")]
 			tokio::spawn(crate::restful::start(self.ctx.clone()));
+			#[cfg(not(feature = "admin-api"))]
+			warn!("[restful] is configured, but this build has no admin API (feature `admin-api`)");
 		}
+		tokio::spawn(crate::stats::report(self.ctx.clone(), self.ctx.cfg.stats_interval));
+		tokio::spawn(crate::metrics::start(self.ctx.clone()));
+		tokio::spawn(crate::accounting::start(self.ctx.clone()));
+		tokio::spawn(crate::pool::sweep(self.ctx.clone()));
+		crate::health::start(self.ctx.clone());
+		let endpoints = self.endpoints().cloned().collect();
+		tokio::spawn(crate::drain::serve(self.ctx.clone(), endpoints));
+		tokio::spawn(crate::cluster::subscribe(self.ctx.clone()));
+		tokio::spawn(crate::cluster::share_usage(self.ctx.clone()));
+		tokio::spawn(crate::knock::serve(self.ctx.clone()));
+		tokio::spawn(crate::users::watch(self.ctx.clone()));
+		tokio::spawn(crate::reload::watch(self.ctx.clone()));
+		tokio::spawn(crate::quota::persist(self.ctx.clone()));
+		if let Some(socket) = &self.handoff_socket {
+			match socket.try_clone() {
+				Ok(socket) => {
+					tokio::spawn(upgrade::serve(self.ctx.clone(), self.ep.clone(), socket));
+				}
+				Err(err) => warn!("[upgrade] failed to duplicate endpoint socket: {err}"),
+			}
+		}
+
+		futures::future::join_all(self.endpoints().map(|ep| self.accept(ep))).await;
+	}
 
+	/// Accept the connections of `ep` until it's closed
+	async fn accept(&self, ep: &Endpoint) {
 		loop {
-			match self.ep.accept().await {
-				Some(conn) => match conn.accept() {
-					Ok(conn) => {
-						tokio::spawn(Connection::handle(self.ctx.clone(), conn));
-					}
-					Err(e) => {
-						debug!("[Incoming] Failed to accept connection: {e}");
+			match ep.accept().await {
+				// Ignoring sends nothing back, so refused sources cost no TLS work
+				Some(conn) if !self.ctx.cfg.source_allowed(conn.remote_address().ip()) => {
+					debug!("[Incoming] ignoring connection from {}", conn.remote_address());
+					conn.ignore();
+				}
+				Some(conn)
+					if self
+						.ctx
+						.bans
+						.as_ref()
+						.is_some_and(|bans| bans.is_banned(conn.remote_address().ip())) =>
+				{
+					debug!("[Incoming] ignoring connection from banned {}", conn.remote_address());
+					self.ctx.stats.bans.ignored.fetch_add(1, Ordering::Relaxed);
+					conn.ignore();
+				}
+				Some(conn)
+					if self
+						.ctx
+						.knock
+						.as_ref()
+						.is_some_and(|gate| !gate.admits(conn.remote_address().ip())) =>
+				{
+					debug!(
+						"[Incoming] ignoring connection from {} without a knock",
+						conn.remote_address()
+					);
+					conn.ignore();
+				}
+				Some(conn) => {
+					// A controller the client asked for takes precedence over the canary,
+					// and the canary over the experiment
+					let choice = match self.hinted_config(conn.remote_address().ip()).await {
+						Some(choice) => Some(choice),
+						None => match self.ctx.rollout.pick() {
+							Some(canary) => {
+								debug!("[Incoming] accepting {} with the canary transport", conn.remote_address());
+								Some(canary)
+							}
+							None => self.pick_experiment_arm().map(|arm| (arm.controller, arm.config.clone())),
+						},
+					};
+					let (controller, accepted) = match choice {
+						Some((controller, config)) => (controller, conn.accept_with(config)),
+						None => (self.ctx.cfg.quic.congestion_control.controller, conn.accept()),
+					};
+					match accepted {
+						Ok(conn) => {
+							tokio::spawn(Connection::handle(self.ctx.clone(), conn, controller));
+						}
+						Err(e) => {
+							debug!("[Incoming] Failed to accept connection: {e}");
+						}
 					}
-				},
+				}
 				None => {
 					debug!("[Incoming] the endpoint is closed");
 					return;
@@ -178,3 +393,151 @@ impl Server {
 		}
 	}
 }
+
+/// A UDP socket bound to `addr` for an endpoint, with the `[socket]` options
+fn bind(cfg: &Config, addr: SocketAddr) -> Result<StdUdpSocket, Error> {
+	let domain = match addr {
+		SocketAddr::V4(_) => Domain::IPV4,
+		SocketAddr::V6(_) => Domain::IPV6,
+	};
+
+	let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP)).context("failed to create endpoint UDP socket")?;
+
+	// An IPv4 socket has no dual-stack setting, and with `server_ipv4` IPv4
+	// has a socket of its own
+	if domain == Domain::IPV6 {
+		socket
+			.set_only_v6(!cfg.dual_stack || cfg.server_ipv4.is_some())
+			.map_err(|err| Error::Socket("endpoint dual-stack socket setting error", err))?;
+	}
+
+	let opts = &cfg.socket;
+	if opts.recv_buffer_size > 0 {
+		socket
+			.set_recv_buffer_size(opts.recv_buffer_size)
+			.map_err(|err| Error::Socket("endpoint receive buffer size setting error", err))?;
+	}
+	if opts.send_buffer_size > 0 {
+		socket
+			.set_send_buffer_size(opts.send_buffer_size)
+			.map_err(|err| Error::Socket("endpoint send buffer size setting error", err))?;
+	}
+	if opts.exclusive_address_use {
+		set_exclusive_address_use(&socket).map_err(|err| Error::Socket("endpoint exclusive address use setting error", err))?;
+	}
+
+	socket
+		.bind(&SockAddr::from(addr))
+		.with_context(|| format!("failed to bind endpoint UDP socket to {addr}"))?;
+
+	Ok(StdUdpSocket::from(socket))
+}
+
+/// Tell how clients can trust the self-signed certificate `chain`
+fn log_self_signed(hostname: &str, chain: &[CertificateDer]) {
+	if let Some(cert) = chain.first() {
+		let fingerprint = tls::fingerprint(cert);
+		warn!(
+			"Serving a self-signed certificate for {hostname}, SHA-256 fingerprint {fingerprint}. Clients can pin it with \
+			 `pinned_sha256 = [\"{fingerprint}\"]` in [relay.tls]"
+		);
+	}
+}
+
+/// Transport parameters of new connections, with `controller` as the
+/// congestion controller and `settings` replacing those of `[quic]`
+pub(crate) fn transport_config(
+	cfg: &Config,
+	controller: CongestionController,
+	settings: &TransportSettings,
+) -> Result<TransportConfig, Error> {
+	let mut tp_cfg = TransportConfig::default();
+	let max_concurrent_streams = settings.max_concurrent_streams.unwrap_or(cfg.quic.max_concurrent_streams);
+	let max_idle_time = settings.max_idle_time.unwrap_or(cfg.quic.max_idle_time);
+	let initial_window = settings.initial_window.unwrap_or(cfg.quic.congestion_control.initial_window);
+
+	let max_concurrent_bidi_streams = cfg.quic.max_concurrent_bidi_streams.unwrap_or(max_concurrent_streams);
+	let max_concurrent_uni_streams = cfg.quic.max_concurrent_uni_streams.unwrap_or(max_concurrent_streams);
+
+	tp_cfg
+		.max_concurrent_bidi_streams(VarInt::from(max_concurrent_bidi_streams))
+		.max_concurrent_uni_streams(VarInt::from(max_concurrent_uni_streams))
+		.send_window(settings.send_window.unwrap_or(cfg.quic.send_window))
+		.stream_receive_window(VarInt::from_u32(settings.receive_window.unwrap_or(cfg.quic.receive_window)))
+		.max_idle_timeout(Some(
+			IdleTimeout::try_from(max_idle_time).map_err(|_| Error::InvalidMaxIdleTime)?,
+		))
+		.keep_alive_interval(cfg.quic.keep_alive_interval)
+		.initial_rtt(cfg.quic.initial_rtt)
+		.initial_mtu(cfg.quic.initial_mtu)
+		.min_mtu(cfg.quic.min_mtu)
+		.enable_segmentation_offload(cfg.quic.gso)
+		.mtu_discovery_config(if !settings.pmtu.unwrap_or(cfg.quic.pmtu) {
+			None
+		} else {
+			Some(Default::default())
+		});
+
+	if let Some(window) = cfg.quic.connection_receive_window {
+		// Checked by `Config::validate`
+		tp_cfg.receive_window(VarInt::from_u64(window).unwrap_or(VarInt::MAX));
+	}
+
+	match controller {
+		CongestionController::Bbr => {
+			let mut bbr_config = BbrConfig::default();
+			bbr_config.initial_window(initial_window);
+			tp_cfg.congestion_controller_factory(Arc::new(bbr_config))
+		}
+		CongestionController::Cubic => {
+			let mut cubic_config = CubicConfig::default();
+			cubic_config.initial_window(initial_window);
+			tp_cfg.congestion_controller_factory(Arc::new(cubic_config))
+		}
+		CongestionController::NewReno => {
+			let mut new_reno = NewRenoConfig::default();
+			new_reno.initial_window(initial_window);
+			tp_cfg.congestion_controller_factory(Arc::new(new_reno))
+		}
+		CongestionController::Bbr3 => {
+			let mut bbr3_config = Bbr3Config::default();
+			bbr3_config.initial_window(initial_window);
+			tp_cfg.congestion_controller_factory(Arc::new(bbr3_config))
+		}
+	};
+
+	Ok(tp_cfg)
+}
+
+/// Keep other sockets from binding the endpoint's port, even with
+/// `SO_REUSEADDR`. Must be set before binding.
+#[cfg(windows)]
+fn set_exclusive_address_use(socket: &Socket) -> std::io::Result<()> {
+	use std::os::windows::io::AsRawSocket;
+
+	use windows_sys::Win32::Networking::WinSock::{SO_EXCLUSIVEADDRUSE, SOCKET, SOCKET_ERROR, SOL_SOCKET, setsockopt};
+
+	let enabled: i32 = 1;
+	// SAFETY: the socket handle is valid for the duration of the call and the
+	// option value points to an `i32` of the given length.
+	let ret = unsafe {
+		setsockopt(
+			socket.as_raw_socket() as SOCKET,
+			SOL_SOCKET,
+			SO_EXCLUSIVEADDRUSE,
+			(&raw const enabled).cast(),
+			size_of::<i32>() as i32,
+		)
+	};
+	if ret == SOCKET_ERROR {
+		Err(std::io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(not(windows))]
+fn set_exclusive_address_use(_socket: &Socket) -> std::io::Result<()> {
+	warn!("`socket.exclusive_address_use` only has an effect on Windows");
+	Ok(())
+}
@@ -0,0 +1,575 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::{
+		Arc,
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+	},
+	time::Duration,
+};
+
+use serde::Serialize;
+use tokio::time;
+use tracing::info;
+use tuic_core::quinn::ConnectionStats;
+use uuid::Uuid;
+
+use crate::{AppContext, users::UserStats, utils::CongestionController};
+
+/// A usage gauge that remembers the highest value it has ever reached.
+#[derive(Debug, Default)]
+pub struct Gauge {
+	current: AtomicUsize,
+	peak: AtomicUsize,
+}
+
+impl Gauge {
+	pub fn add(&self, amount: usize) {
+		let now = self.current.fetch_add(amount, Ordering::AcqRel) + amount;
+		self.peak.fetch_max(now, Ordering::AcqRel);
+	}
+
+	pub fn sub(&self, amount: usize) {
+		let _ = self
+			.current
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |v| Some(v.saturating_sub(amount)));
+	}
+
+	pub fn current(&self) -> usize {
+		self.current.load(Ordering::Acquire)
+	}
+
+	pub fn peak(&self) -> usize {
+		self.peak.load(Ordering::Acquire)
+	}
+
+	/// Increase the gauge by `amount` until the returned guard is dropped.
+	pub fn track(self: &Arc<Self>, amount: usize) -> GaugeGuard {
+		self.add(amount);
+		GaugeGuard {
+			gauge: self.clone(),
+			amount,
+		}
+	}
+
+	pub fn snapshot(&self) -> GaugeSnapshot {
+		GaugeSnapshot {
+			current: self.current(),
+			peak: self.peak(),
+		}
+	}
+}
+
+/// Releases its share of a [`Gauge`] on drop.
+#[derive(Debug)]
+pub struct GaugeGuard {
+	gauge: Arc<Gauge>,
+	amount: usize,
+}
+
+impl Drop for GaugeGuard {
+	fn drop(&mut self) {
+		self.gauge.sub(self.amount);
+	}
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct GaugeSnapshot {
+	pub current: usize,
+	pub peak: usize,
+}
+
+/// Why a relayed UDP packet was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+	/// Larger than the connection can carry in a QUIC datagram
+	TooLarge,
+	/// No (longer a) UDP session for the association
+	NoSession,
+	/// Rejected by a rate limit
+	RateLimited,
+	/// The datagram send buffer was full, so queued datagrams were discarded
+	SendBufferFull,
+	/// The peer or the path doesn't support QUIC datagrams
+	DatagramUnsupported,
+	/// Blocked by ACL or outbound policy
+	Blocked,
+	/// A new UDP session would exceed `max_udp_sessions`
+	SessionLimit,
+	/// The quota of the user is used up
+	Quota,
+	/// Any other failure (resolution, socket or stream errors, malformed fragments)
+	Error,
+}
+
+impl DropReason {
+	pub const ALL: [Self; 9] = [
+		Self::TooLarge,
+		Self::NoSession,
+		Self::RateLimited,
+		Self::SendBufferFull,
+		Self::DatagramUnsupported,
+		Self::Blocked,
+		Self::SessionLimit,
+		Self::Quota,
+		Self::Error,
+	];
+
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::TooLarge => "too_large",
+			Self::NoSession => "no_session",
+			Self::RateLimited => "rate_limited",
+			Self::SendBufferFull => "send_buffer_full",
+			Self::DatagramUnsupported => "datagram_unsupported",
+			Self::Blocked => "blocked",
+			Self::SessionLimit => "session_limit",
+			Self::Quota => "quota",
+			Self::Error => "error",
+		}
+	}
+}
+
+/// Dropped UDP packet counters, one per [`DropReason`].
+#[derive(Debug, Default)]
+pub struct PacketDrops {
+	counts: [AtomicU64; DropReason::ALL.len()],
+}
+
+impl PacketDrops {
+	pub fn record(&self, reason: DropReason) {
+		self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn get(&self, reason: DropReason) -> u64 {
+		self.counts[reason as usize].load(Ordering::Relaxed)
+	}
+
+	pub fn total(&self) -> u64 {
+		DropReason::ALL.iter().map(|&reason| self.get(reason)).sum()
+	}
+
+	pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+		DropReason::ALL
+			.iter()
+			.map(|&reason| (reason.as_str(), self.get(reason)))
+			.collect()
+	}
+}
+
+/// Rejected authentications, by reason.
+#[derive(Debug, Default)]
+pub struct AuthFailures {
+	/// Unknown user, wrong password or a source outside `user_bindings`
+	pub invalid: AtomicU64,
+	/// A timestamp token signed with a minute outside `auth_clock_skew`, from
+	/// a client whose clock is off
+	pub clock_skew: AtomicU64,
+	/// A user already at `max_connections` under the `reject` policy
+	pub too_many_connections: AtomicU64,
+	/// A user whose quota is used up
+	pub quota: AtomicU64,
+	/// A user past its `expires_at`
+	pub expired: AtomicU64,
+	/// A token accepted before, within `auth_replay_window`
+	pub replayed: AtomicU64,
+}
+
+impl AuthFailures {
+	pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+		BTreeMap::from([
+			("invalid", self.invalid.load(Ordering::Relaxed)),
+			("clock_skew", self.clock_skew.load(Ordering::Relaxed)),
+			("too_many_connections", self.too_many_connections.load(Ordering::Relaxed)),
+			("quota", self.quota.load(Ordering::Relaxed)),
+			("expired", self.expired.load(Ordering::Relaxed)),
+			("replayed", self.replayed.load(Ordering::Relaxed)),
+		])
+	}
+}
+
+/// What `[ban]` did so far.
+#[derive(Debug, Default)]
+pub struct BanCounts {
+	/// Times a source got banned
+	pub banned: AtomicU64,
+	/// Connections ignored from banned sources
+	pub ignored: AtomicU64,
+}
+
+impl BanCounts {
+	pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+		BTreeMap::from([
+			("banned", self.banned.load(Ordering::Relaxed)),
+			("ignored", self.ignored.load(Ordering::Relaxed)),
+		])
+	}
+}
+
+/// Transfer totals of the connections served by one congestion controller.
+#[derive(Debug, Default)]
+pub struct ControllerTotals {
+	connections: AtomicU64,
+	bytes_sent: AtomicU64,
+	sent_packets: AtomicU64,
+	lost_packets: AtomicU64,
+	rtt_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct ControllerSnapshot {
+	pub connections: u64,
+	pub bytes_sent: u64,
+	/// Share of sent packets that were lost
+	pub loss_rate: f64,
+	/// Mean of the connections' final RTT estimates
+	pub avg_rtt_ms: f64,
+}
+
+/// Totals of closed connections per congestion controller, for comparing the
+/// controllers of `quic.congestion_control.experiment`.
+#[derive(Debug, Default)]
+pub struct ControllerStats {
+	totals: [ControllerTotals; 4],
+}
+
+impl ControllerStats {
+	const ALL: [CongestionController; 4] = [
+		CongestionController::Bbr,
+		CongestionController::Bbr3,
+		CongestionController::Cubic,
+		CongestionController::NewReno,
+	];
+
+	pub fn name(controller: CongestionController) -> &'static str {
+		match controller {
+			CongestionController::Bbr => "bbr",
+			CongestionController::Bbr3 => "bbr3",
+			CongestionController::Cubic => "cubic",
+			CongestionController::NewReno => "new_reno",
+		}
+	}
+
+	fn totals(&self, controller: CongestionController) -> &ControllerTotals {
+		match controller {
+			CongestionController::Bbr => &self.totals[0],
+			CongestionController::Bbr3 => &self.totals[1],
+			CongestionController::Cubic => &self.totals[2],
+			CongestionController::NewReno => &self.totals[3],
+		}
+	}
+
+	/// Add a closed connection served by `controller`.
+	pub fn record(&self, controller: CongestionController, stats: &ConnectionStats) {
+		let totals = self.totals(controller);
+		totals.connections.fetch_add(1, Ordering::Relaxed);
+		totals.bytes_sent.fetch_add(stats.udp_tx.bytes, Ordering::Relaxed);
+		totals.sent_packets.fetch_add(stats.path.sent_packets, Ordering::Relaxed);
+		totals.lost_packets.fetch_add(stats.path.lost_packets, Ordering::Relaxed);
+		totals.rtt_micros.fetch_add(
+			u64::try_from(stats.path.rtt.as_micros()).unwrap_or(u64::MAX),
+			Ordering::Relaxed,
+		);
+	}
+
+	/// Controllers that have served at least one connection
+	pub fn snapshot(&self) -> BTreeMap<&'static str, ControllerSnapshot> {
+		Self::ALL
+			.iter()
+			.filter_map(|&controller| {
+				let totals = self.totals(controller);
+				let connections = totals.connections.load(Ordering::Relaxed);
+				if connections == 0 {
+					return None;
+				}
+				let sent_packets = totals.sent_packets.load(Ordering::Relaxed);
+				let lost_packets = totals.lost_packets.load(Ordering::Relaxed);
+				Some((
+					Self::name(controller),
+					ControllerSnapshot {
+						connections,
+						bytes_sent: totals.bytes_sent.load(Ordering::Relaxed),
+						loss_rate: if sent_packets == 0 {
+							0.0
+						} else {
+							lost_packets as f64 / sent_packets as f64
+						},
+						avg_rtt_ms: totals.rtt_micros.load(Ordering::Relaxed) as f64 / connections as f64 / 1000.0,
+					},
+				))
+			})
+			.collect()
+	}
+}
+
+/// Server-wide resource usage with high-water marks.
+#[derive(Debug, Default)]
+pub struct ResourceStats {
+	/// QUIC connections currently established
+	pub connections: Arc<Gauge>,
+	/// TCP streams currently relayed
+	pub tcp_streams: Arc<Gauge>,
+	/// Bytes held by TCP relay copy buffers
+	pub relay_buffer_bytes: Arc<Gauge>,
+	/// Relay tasks currently running (TCP connects and UDP sessions)
+	pub relay_tasks: Arc<Gauge>,
+	/// Outbound TCP and UDP sockets currently open
+	pub sockets: Arc<Gauge>,
+	/// UDP associations currently alive
+	pub udp_sessions: Arc<Gauge>,
+	/// UDP packets dropped so far, by reason
+	pub dropped_packets: PacketDrops,
+	/// Authentications rejected so far, by reason
+	pub auth_failures: AuthFailures,
+	/// Sources banned and connections ignored so far
+	pub bans: BanCounts,
+	/// Closed connections, by congestion controller
+	pub controllers: ControllerStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceSnapshot {
+	pub connections: GaugeSnapshot,
+	pub tcp_streams: GaugeSnapshot,
+	pub relay_buffer_bytes: GaugeSnapshot,
+	pub relay_tasks: GaugeSnapshot,
+	pub sockets: GaugeSnapshot,
+	pub udp_sessions: GaugeSnapshot,
+	pub dropped_packets: BTreeMap<&'static str, u64>,
+	pub auth_failures: BTreeMap<&'static str, u64>,
+	pub bans: BTreeMap<&'static str, u64>,
+	pub controllers: BTreeMap<&'static str, ControllerSnapshot>,
+}
+
+impl ResourceStats {
+	pub fn snapshot(&self) -> ResourceSnapshot {
+		ResourceSnapshot {
+			connections: self.connections.snapshot(),
+			tcp_streams: self.tcp_streams.snapshot(),
+			relay_buffer_bytes: self.relay_buffer_bytes.snapshot(),
+			relay_tasks: self.relay_tasks.snapshot(),
+			sockets: self.sockets.snapshot(),
+			udp_sessions: self.udp_sessions.snapshot(),
+			dropped_packets: self.dropped_packets.snapshot(),
+			auth_failures: self.auth_failures.snapshot(),
+			bans: self.bans.snapshot(),
+			controllers: self.controllers.snapshot(),
+		}
+	}
+}
+
+/// Users listed in the summary of who moved the most traffic
+const TOP_TALKERS: usize = 3;
+
+/// Per-user traffic totals as of the previous report.
+#[derive(Debug, Default)]
+pub(crate) struct TrafficMeter {
+	last: HashMap<Uuid, (usize, usize)>,
+}
+
+impl TrafficMeter {
+	/// Bytes each user sent and received since the previous call, busiest
+	/// first, leaving out idle users. A total below the previous one was reset,
+	/// by `/reset_traffic` or re-adding the user, and counts in full.
+	pub(crate) fn delta(&mut self, traffic: &[(Uuid, Arc<UserStats>)]) -> Vec<(Uuid, usize, usize)> {
+		let mut delta: Vec<_> = traffic
+			.iter()
+			.filter_map(|(uuid, stats)| {
+				let now = (stats.tx.load(Ordering::Relaxed), stats.rx.load(Ordering::Relaxed));
+				let (last_tx, last_rx) = self.last.insert(*uuid, now).unwrap_or_default();
+				let since = |now: usize, last| now.checked_sub(last).unwrap_or(now);
+				let delta = (since(now.0, last_tx), since(now.1, last_rx));
+				(delta != (0, 0)).then_some((*uuid, delta.0, delta.1))
+			})
+			.collect();
+		delta.sort_by_key(|(_, tx, rx)| std::cmp::Reverse(tx + rx));
+		delta
+	}
+}
+
+/// `bytes` moved over `interval` as a rate, e.g. `1.5 MiB/s`
+fn rate(bytes: usize, interval: Duration) -> String {
+	const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+	let mut rate = bytes as f64 / interval.as_secs_f64();
+	let mut unit = 0;
+	while rate >= 1024.0 && unit < UNITS.len() - 1 {
+		rate /= 1024.0;
+		unit += 1;
+	}
+	format!("{rate:.1} {}/s", UNITS[unit])
+}
+
+/// Periodically log a summary of connections, traffic and the busiest users,
+/// followed by current usage and high-water marks. Does nothing when
+/// `interval` is zero.
+pub async fn report(ctx: Arc<AppContext>, interval: Duration) {
+	if interval.is_zero() {
+		return;
+	}
+
+	let mut ticker = time::interval(interval);
+	ticker.tick().await;
+	let mut meter = TrafficMeter::default();
+	meter.delta(&ctx.users.all_stats());
+
+	loop {
+		tokio::select! {
+			_ = ticker.tick() => {}
+			_ = ctx.cancel.cancelled() => return,
+		}
+
+		let s = ctx.stats.snapshot();
+		let traffic = meter.delta(&ctx.users.all_stats());
+		let (tx, rx) = traffic.iter().fold((0, 0), |(tx, rx), (_, t, r)| (tx + t, rx + r));
+		info!(
+			"[stats] {} connection(s), {} TCP stream(s), {} UDP session(s), {} up, {} down",
+			s.connections.current,
+			s.tcp_streams.current,
+			s.udp_sessions.current,
+			rate(tx, interval),
+			rate(rx, interval),
+		);
+		if !traffic.is_empty() {
+			let top = traffic
+				.iter()
+				.take(TOP_TALKERS)
+				.map(|(uuid, tx, rx)| format!("{uuid} {}", rate(tx + rx, interval)))
+				.collect::<Vec<_>>()
+				.join(", ");
+			info!("[stats] top talkers: {top}");
+		}
+
+		info!(
+			"[stats] relay buffers {}/{} bytes, relay tasks {}/{}, sockets {}/{}, UDP sessions {}/{} (current/peak)",
+			s.relay_buffer_bytes.current,
+			s.relay_buffer_bytes.peak,
+			s.relay_tasks.current,
+			s.relay_tasks.peak,
+			s.sockets.current,
+			s.sockets.peak,
+			s.udp_sessions.current,
+			s.udp_sessions.peak,
+		);
+
+		if ctx.stats.dropped_packets.total() > 0 {
+			let drops = s
+				.dropped_packets
+				.iter()
+				.filter(|(_, count)| **count > 0)
+				.map(|(reason, count)| format!("{reason}={count}"))
+				.collect::<Vec<_>>()
+				.join(", ");
+			info!("[stats] dropped UDP packets: {drops}");
+		}
+
+		if !ctx.cfg.quic.congestion_control.experiment.is_empty() {
+			for (controller, c) in &s.controllers {
+				info!(
+					"[stats] congestion controller {controller}: {} connection(s), {} bytes sent, {:.2}% loss, {:.1} ms \
+					 average RTT",
+					c.connections,
+					c.bytes_sent,
+					c.loss_rate * 100.0,
+					c.avg_rtt_ms,
+				);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_gauge_tracks_peak() {
+		let gauge = Arc::new(Gauge::default());
+
+		let a = gauge.track(10);
+		let b = gauge.track(5);
+		assert_eq!(gauge.snapshot(), GaugeSnapshot { current: 15, peak: 15 });
+
+		drop(a);
+		assert_eq!(gauge.snapshot(), GaugeSnapshot { current: 5, peak: 15 });
+
+		drop(b);
+		gauge.add(3);
+		assert_eq!(gauge.snapshot(), GaugeSnapshot { current: 3, peak: 15 });
+	}
+
+	#[test]
+	fn test_controller_stats() {
+		let controllers = ControllerStats::default();
+		let mut stats = ConnectionStats::default();
+		stats.udp_tx.bytes = 1000;
+		stats.path.sent_packets = 100;
+		stats.path.lost_packets = 5;
+		stats.path.rtt = Duration::from_millis(40);
+		controllers.record(CongestionController::Cubic, &stats);
+		stats.path.rtt = Duration::from_millis(60);
+		controllers.record(CongestionController::Cubic, &stats);
+
+		let snapshot = controllers.snapshot();
+		assert_eq!(snapshot.len(), 1);
+		assert_eq!(
+			snapshot["cubic"],
+			ControllerSnapshot {
+				connections: 2,
+				bytes_sent: 2000,
+				loss_rate: 0.05,
+				avg_rtt_ms: 50.0,
+			}
+		);
+	}
+
+	#[test]
+	fn test_packet_drops_by_reason() {
+		let drops = PacketDrops::default();
+		drops.record(DropReason::TooLarge);
+		drops.record(DropReason::TooLarge);
+		drops.record(DropReason::NoSession);
+
+		assert_eq!(drops.get(DropReason::TooLarge), 2);
+		assert_eq!(drops.get(DropReason::RateLimited), 0);
+		assert_eq!(drops.total(), 3);
+
+		let snapshot = drops.snapshot();
+		assert_eq!(snapshot.len(), DropReason::ALL.len());
+		assert_eq!(snapshot["too_large"], 2);
+		assert_eq!(snapshot["no_session"], 1);
+	}
+
+	#[test]
+	fn test_traffic_meter_delta() {
+		let (quiet, busy, idle) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+		let traffic: Vec<(_, Arc<UserStats>)> = [quiet, busy, idle].into_iter().map(|uuid| (uuid, Arc::default())).collect();
+		let mut meter = TrafficMeter::default();
+		traffic[2].1.tx.store(500, Ordering::Relaxed);
+		meter.delta(&traffic);
+
+		traffic[0].1.tx.store(10, Ordering::Relaxed);
+		traffic[1].1.tx.store(100, Ordering::Relaxed);
+		traffic[1].1.rx.store(900, Ordering::Relaxed);
+		assert_eq!(meter.delta(&traffic), [(busy, 100, 900), (quiet, 10, 0)]);
+		assert!(meter.delta(&traffic).is_empty());
+
+		// Traffic after a reset isn't lost
+		traffic[1].1.tx.store(30, Ordering::Relaxed);
+		traffic[1].1.rx.store(0, Ordering::Relaxed);
+		assert_eq!(meter.delta(&traffic), [(busy, 30, 0)]);
+	}
+
+	#[test]
+	fn test_rate() {
+		assert_eq!(rate(512, Duration::from_secs(1)), "512.0 B/s");
+		assert_eq!(rate(3 * 1024 * 1024, Duration::from_secs(2)), "1.5 MiB/s");
+	}
+
+	#[test]
+	fn test_gauge_sub_saturates() {
+		let gauge = Gauge::default();
+		gauge.add(1);
+		gauge.sub(4);
+		assert_eq!(gauge.current(), 0);
+		assert_eq!(gauge.peak(), 1);
+	}
+}
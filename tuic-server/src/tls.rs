@@ -1,95 +1,409 @@
 use std::{
+	collections::HashMap,
+	io,
 	ops::Deref,
 	path::{Path, PathBuf},
-	sync::{Arc, RwLock},
+	sync::{Arc, RwLock, Weak},
 	time::Duration,
 };
 
 use arc_swap::ArcSwap;
 use eyre::{Context, Result};
 use rustls::{
+	CipherSuite, KeyLog, KeyLogFile, RootCertStore, ServerConfig, SignatureScheme,
+	crypto::CryptoProvider,
 	pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
-	server::{ClientHello, ResolvesServerCert},
+	server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier, danger::ClientCertVerifier},
 	sign::CertifiedKey,
 };
 use sha2::{Digest, Sha256};
-use tokio::fs;
-use tracing::warn;
+use tokio::{fs, sync::Notify};
+use tracing::{info, warn};
+use tuic_core::quinn::crypto::rustls::QuicServerConfig;
+
+use crate::ocsp::{self, Ocsp};
+
+/// How a [`CertResolver`] loads its certificate and keeps it current
+#[derive(Clone, Debug, Default)]
+pub struct CertOptions {
+	/// Decrypts an encrypted key, or a PKCS#12 bundle, see [`is_pkcs12`]
+	pub passphrase: Option<String>,
+	/// How often the files are checked for changes, zero for never
+	pub reload_interval: Duration,
+	/// The OCSP response stapled to handshakes
+	pub ocsp: Ocsp,
+}
+impl CertOptions {
+	/// The options for another certificate, which an [`Ocsp::File`] response
+	/// isn't for
+	pub fn for_other_cert(&self) -> Self {
+		Self {
+			ocsp: match self.ocsp {
+				Ocsp::File(_) => Ocsp::Off,
+				ref ocsp => ocsp.clone(),
+			},
+			..self.clone()
+		}
+	}
+}
 
 #[derive(Debug)]
 pub struct CertResolver {
 	cert_path: PathBuf,
 	key_path: PathBuf,
+	options: CertOptions,
 	cert_key: RwLock<Arc<CertifiedKey>>,
 	hash: ArcSwap<[u8; 32]>,
+	/// Wakes the OCSP refresh of [`Ocsp::Fetch`] after a reload
+	reloaded: Arc<Notify>,
+	/// Served to clients that support none of the signature schemes of
+	/// `cert_key`
+	fallback: Option<Arc<CertResolver>>,
 }
 impl CertResolver {
-	pub async fn new(cert_path: &Path, key_path: &Path, interval: Duration) -> Result<Arc<Self>> {
-		let cert_key = load_cert_key(cert_path, key_path).await?;
-		let hash = Self::calc_hash(cert_path, key_path).await?;
+	/// A resolver serving the certificate at `cert_path`
+	pub async fn new(cert_path: &Path, key_path: &Path, options: &CertOptions) -> Result<Arc<Self>> {
+		Self::build(cert_path, key_path, options, None).await
+	}
+
+	/// A resolver serving the certificate at `cert_path`, and the one at
+	/// `fallback_cert_path` to clients that can't verify signatures of the
+	/// former's key, e.g. an RSA certificate to clients without ECDSA
+	pub async fn with_fallback(
+		cert_path: &Path,
+		key_path: &Path,
+		fallback_cert_path: &Path,
+		fallback_key_path: &Path,
+		options: &CertOptions,
+	) -> Result<Arc<Self>> {
+		let fallback = Self::build(fallback_cert_path, fallback_key_path, &options.for_other_cert(), None)
+			.await
+			.context("Failed to load fallback certificate")?;
+		Self::build(cert_path, key_path, options, Some(fallback)).await
+	}
+
+	async fn build(cert_path: &Path, key_path: &Path, options: &CertOptions, fallback: Option<Arc<Self>>) -> Result<Arc<Self>> {
+		let cert_key = Self::load(cert_path, key_path, options).await?;
+		let hash = Self::calc_hash(cert_path, key_path, &options.ocsp).await?;
 		let resolver = Arc::new(Self {
 			cert_path: cert_path.to_owned(),
 			key_path: key_path.to_owned(),
+			options: options.clone(),
 			cert_key: RwLock::new(cert_key),
 			hash: ArcSwap::new(Arc::new(hash)),
+			reloaded: Arc::new(Notify::new()),
+			fallback,
 		});
-		// Start file watcher in background
-		let resolver_clone = resolver.clone();
-		tokio::spawn(async move {
-			if let Err(e) = resolver_clone.start_watch(interval).await {
-				warn!("Certificate watcher exited with error: {e}");
-			}
-		});
+		// A zero interval turns the watch off
+		if !options.reload_interval.is_zero() {
+			tokio::spawn(Self::watch(Arc::downgrade(&resolver), options.reload_interval));
+		}
+		if options.ocsp == Ocsp::Fetch {
+			tokio::spawn(Self::refresh_ocsp(Arc::downgrade(&resolver), resolver.reloaded.clone()));
+		}
 		Ok(resolver)
 	}
 
-	async fn start_watch(&self, interval: Duration) -> Result<()> {
+	async fn load(cert_path: &Path, key_path: &Path, options: &CertOptions) -> Result<Arc<CertifiedKey>> {
+		let cert_key = load_cert_key(cert_path, key_path, options.passphrase.as_deref()).await?;
+		let Ocsp::File(path) = &options.ocsp else {
+			return Ok(cert_key);
+		};
+		let mut cert_key = Arc::unwrap_or_clone(cert_key);
+		cert_key.ocsp = Some(fs::read(path).await.context("Failed to read OCSP response")?);
+		Ok(Arc::new(cert_key))
+	}
+
+	/// Reload the certificate and key whenever the files change, until the
+	/// resolver is dropped. Handshakes from then on get the new certificate,
+	/// established connections are left alone.
+	async fn watch(resolver: Weak<Self>, interval: Duration) {
 		let mut interval = tokio::time::interval(interval);
+		interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+		interval.tick().await;
 		loop {
 			interval.tick().await;
-			let hash = Self::calc_hash(&self.cert_path, &self.key_path).await?;
-			if &hash != self.hash.swap(hash.into()).deref() {
-				match self.reload_cert_key().await {
-					Ok(_) => warn!("Successfully reloaded TLS certificate and key"),
-					Err(e) => warn!("Failed to reload TLS certificate and key: {e}"),
+			let Some(resolver) = resolver.upgrade() else {
+				return;
+			};
+			// A renewal may be caught halfway, with one file missing or not
+			// matching the other. The current certificate is then kept, and the
+			// files tried again on the next tick.
+			let hash = match Self::calc_hash(&resolver.cert_path, &resolver.key_path, &resolver.options.ocsp).await {
+				Ok(hash) => hash,
+				Err(e) => {
+					warn!("Failed to read TLS certificate and key, keeping the current ones: {e}");
+					continue;
+				}
+			};
+			if &hash == resolver.hash.load().deref().deref() {
+				continue;
+			}
+			match resolver.reload_cert_key().await {
+				Ok(()) => {
+					resolver.hash.store(hash.into());
+					warn!("Successfully reloaded TLS certificate and key");
 				}
+				Err(e) => warn!("Failed to reload TLS certificate and key, keeping the current ones: {e:#}"),
 			}
 		}
 	}
 
 	async fn reload_cert_key(&self) -> Result<()> {
-		let new_cert_key = load_cert_key(&self.cert_path, &self.key_path).await?;
+		let new_cert_key = Self::load(&self.cert_path, &self.key_path, &self.options).await?;
+		new_cert_key.keys_match().context("the key is not the certificate's")?;
 		let mut guard = self.cert_key.write().map_err(|_| eyre::eyre!("Certificate lock poisoned"))?;
 		*guard = new_cert_key;
+		drop(guard);
+		self.reloaded.notify_one();
 		Ok(())
 	}
 
-	async fn calc_hash(cert_path: &Path, key_path: &Path) -> Result<[u8; 32]> {
+	async fn calc_hash(cert_path: &Path, key_path: &Path, ocsp: &Ocsp) -> Result<[u8; 32]> {
 		let mut hasher = Sha256::new();
 		hasher.update(fs::read(cert_path).await?);
 		hasher.update(fs::read(key_path).await?);
+		if let Ocsp::File(path) = ocsp {
+			hasher.update(fs::read(path).await?);
+		}
 		let result: [u8; 32] = hasher.finalize().into();
 		Ok(result)
 	}
+
+	/// Staple a response fetched from the certificate's OCSP responder, again
+	/// every [`ocsp::REFRESH`] and after each reload, until the resolver is
+	/// dropped
+	async fn refresh_ocsp(resolver: Weak<Self>, reloaded: Arc<Notify>) {
+		loop {
+			let Some(current) = resolver.upgrade().and_then(|resolver| resolver.current()) else {
+				return;
+			};
+			let wait = match ocsp::fetch(&current.cert).await {
+				Ok(response) => {
+					if let Some(resolver) = resolver.upgrade() {
+						resolver.staple(&current, response);
+					}
+					info!("Stapled a fresh OCSP response");
+					ocsp::REFRESH
+				}
+				Err(e) => {
+					warn!(
+						"Failed to fetch OCSP response, retrying in {}: {e:#}",
+						humantime::format_duration(ocsp::RETRY)
+					);
+					ocsp::RETRY
+				}
+			};
+			tokio::select! {
+				() = tokio::time::sleep(wait) => {}
+				() = reloaded.notified() => {}
+			}
+		}
+	}
+
+	/// Staple `response` to the certificate, unless it was replaced since
+	/// `fetched_for`
+	fn staple(&self, fetched_for: &CertifiedKey, response: Vec<u8>) {
+		let Ok(mut guard) = self.cert_key.write() else {
+			return;
+		};
+		if guard.cert == fetched_for.cert {
+			let mut cert_key = CertifiedKey::clone(&guard);
+			cert_key.ocsp = Some(response);
+			*guard = Arc::new(cert_key);
+		}
+	}
+
+	fn current(&self) -> Option<Arc<CertifiedKey>> {
+		self.cert_key.read().map(|guard| guard.deref().clone()).ok()
+	}
+
+	/// The certificate whose key can sign with one of `schemes`, the primary
+	/// one first. With neither, the primary one, and the handshake fails on it.
+	fn choose(&self, schemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+		let cert_key = self.current()?;
+		if cert_key.key.choose_scheme(schemes).is_some() {
+			return Some(cert_key);
+		}
+		self.fallback
+			.as_ref()
+			.and_then(|fallback| fallback.current())
+			.filter(|fallback| fallback.key.choose_scheme(schemes).is_some())
+			.or(Some(cert_key))
+	}
 }
 impl ResolvesServerCert for CertResolver {
-	fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
-		self.cert_key.read().map(|guard| guard.deref().clone()).ok()
+	fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+		self.choose(client_hello.signature_schemes())
+	}
+}
+
+/// Picks the certificate of the server name a client asks for by SNI, and
+/// that of `default` for other names or none. A name like `*.example.com`
+/// stands for the names one label below `example.com`.
+#[derive(Debug)]
+pub struct SniResolver {
+	default: Arc<dyn ResolvesServerCert>,
+	/// In lowercase
+	names: HashMap<String, Arc<CertResolver>>,
+}
+impl SniResolver {
+	pub fn new(default: Arc<dyn ResolvesServerCert>, names: HashMap<String, Arc<CertResolver>>) -> Self {
+		Self { default, names }
+	}
+
+	fn find(&self, name: &str) -> Option<&Arc<CertResolver>> {
+		let name = name.to_ascii_lowercase();
+		self.names.get(&name).or_else(|| {
+			let (_, parent) = name.split_once('.')?;
+			self.names.get(&format!("*.{parent}"))
+		})
+	}
+}
+impl ResolvesServerCert for SniResolver {
+	fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+		match client_hello.server_name().and_then(|name| self.find(name)) {
+			Some(resolver) => resolver.choose(client_hello.signature_schemes()),
+			None => self.default.resolve(client_hello),
+		}
+	}
+}
+
+/// The crypto provider of the process, limited to `cipher_suites` and
+/// `kx_groups` when they are given, in that order of preference. Names are
+/// those of rustls, like `TLS13_CHACHA20_POLY1305_SHA256` and `X25519`.
+pub fn crypto_provider(cipher_suites: &[String], kx_groups: &[String]) -> Result<Arc<CryptoProvider>> {
+	let default = default_provider();
+	if cipher_suites.is_empty() && kx_groups.is_empty() {
+		return Ok(default);
+	}
+	let mut provider = CryptoProvider::clone(&default);
+	if !cipher_suites.is_empty() {
+		// QUIC runs TLS 1.3 only
+		let supported: Vec<_> = default
+			.cipher_suites
+			.iter()
+			.copied()
+			.filter(|suite| suite.tls13().is_some())
+			.collect();
+		provider.cipher_suites = pick("cipher suite", cipher_suites, &supported, |suite| suite.suite().as_str())?;
+	}
+	if !kx_groups.is_empty() {
+		provider.kx_groups = pick("key exchange group", kx_groups, &default.kx_groups, |group| {
+			group.name().as_str()
+		})?;
+	}
+	Ok(Arc::new(provider))
+}
+
+fn default_provider() -> Arc<CryptoProvider> {
+	CryptoProvider::get_default().cloned().unwrap_or_else(|| {
+		#[cfg(feature = "aws-lc-rs")]
+		let provider = rustls::crypto::aws_lc_rs::default_provider();
+		#[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+		let provider = rustls::crypto::ring::default_provider();
+		Arc::new(provider)
+	})
+}
+
+/// The items of `supported` named in `names`, ignoring case
+fn pick<T: Copy>(kind: &str, names: &[String], supported: &[T], name: impl Fn(&T) -> Option<&'static str>) -> Result<Vec<T>> {
+	names
+		.iter()
+		.map(|wanted| {
+			supported
+				.iter()
+				.find(|item| name(item).is_some_and(|name| name.eq_ignore_ascii_case(wanted)))
+				.copied()
+				.ok_or_else(|| {
+					let names: Vec<_> = supported.iter().filter_map(&name).collect();
+					eyre::eyre!("unsupported {kind} {wanted:?}, expected one of {}", names.join(", "))
+				})
+		})
+		.collect()
+}
+
+/// `crypto` for QUIC. Initial packets are protected with
+/// TLS_AES_128_GCM_SHA256, as RFC 9001 requires, even when the configured
+/// cipher suites leave it out of the handshake.
+pub fn quic_server_config(crypto: ServerConfig) -> Result<QuicServerConfig> {
+	let initial = default_provider()
+		.cipher_suites
+		.iter()
+		.filter(|suite| suite.suite() == CipherSuite::TLS13_AES_128_GCM_SHA256)
+		.find_map(|suite| suite.tls13()?.quic_suite())
+		.ok_or_else(|| eyre::eyre!("no initial cipher suite found"))?;
+	QuicServerConfig::with_initial(Arc::new(crypto), initial).context("no initial cipher suite found")
+}
+
+/// The key log of `tls.key_log`, writing to `SSLKEYLOGFILE`
+pub fn key_log() -> Arc<dyn KeyLog> {
+	match std::env::var_os("SSLKEYLOGFILE") {
+		Some(path) => warn!(
+			"TLS secrets are written to {}, anyone reading it can decrypt the traffic",
+			Path::new(&path).display()
+		),
+		None => warn!("`tls.key_log` is set but SSLKEYLOGFILE is not, no TLS secrets are written"),
+	}
+	Arc::new(KeyLogFile::new())
+}
+
+/// The verifier of client certificates: with `client_ca`, clients must
+/// present a certificate issued by one of the CAs in that file, else none is
+/// asked for. Signatures are checked with `provider`, as from
+/// [`crypto_provider`].
+pub async fn client_verifier(client_ca: Option<&Path>, provider: Arc<CryptoProvider>) -> Result<Arc<dyn ClientCertVerifier>> {
+	let Some(path) = client_ca else {
+		return Ok(WebPkiClientVerifier::no_client_auth());
+	};
+	let mut roots = RootCertStore::empty();
+	for cert in load_cert_chain(path).await? {
+		roots.add(cert).context("Invalid client CA certificate")?;
 	}
+	let verifier: Arc<dyn ClientCertVerifier> = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+		.build()
+		.context("Failed to build client certificate verifier")?;
+	Ok(verifier)
 }
 
-async fn load_cert_key(cert_path: &Path, key_path: &Path) -> eyre::Result<Arc<CertifiedKey>> {
-	let cert_chain = load_cert_chain(cert_path).await?;
-	let der = load_priv_key(key_path).await?;
+/// Load a certificate chain and key like [`CertResolver`] does, and check
+/// that the key is the certificate's.
+pub async fn check(cert_path: &Path, key_path: &Path, passphrase: Option<&str>) -> Result<()> {
+	let cert_key = load_cert_key(cert_path, key_path, passphrase)
+		.await
+		.with_context(|| format!("{} and {}", cert_path.display(), key_path.display()))?;
+	cert_key
+		.keys_match()
+		.with_context(|| format!("{} is not the key of {}", key_path.display(), cert_path.display()))
+}
+
+/// Whether `path` names a PKCS#12 bundle, by its `.p12` or `.pfx` extension.
+/// Such a bundle holds the key along with the certificate chain.
+pub fn is_pkcs12(path: &Path) -> bool {
+	path.extension()
+		.and_then(|ext| ext.to_str())
+		.is_some_and(|ext| ext.eq_ignore_ascii_case("p12") || ext.eq_ignore_ascii_case("pfx"))
+}
+
+async fn load_cert_key(cert_path: &Path, key_path: &Path, passphrase: Option<&str>) -> eyre::Result<Arc<CertifiedKey>> {
+	let (cert_chain, der) = if is_pkcs12(cert_path) {
+		load_pkcs12(cert_path, passphrase).await?
+	} else {
+		(load_cert_chain(cert_path).await?, load_priv_key(key_path, passphrase).await?)
+	};
+	Ok(Arc::new(certified_key(cert_chain, &der)?))
+}
+
+fn certified_key(cert_chain: Vec<CertificateDer<'static>>, der: &PrivateKeyDer<'_>) -> eyre::Result<CertifiedKey> {
 	#[cfg(feature = "aws-lc-rs")]
-	let key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&der).context("Unsupported private key type")?;
+	let key = rustls::crypto::aws_lc_rs::sign::any_supported_type(der).context("Unsupported private key type")?;
 	#[cfg(feature = "ring")]
-	let key = rustls::crypto::ring::sign::any_supported_type(&der).context("Unsupported private key type")?;
+	let key = rustls::crypto::ring::sign::any_supported_type(der).context("Unsupported private key type")?;
 
-	Ok(Arc::new(CertifiedKey::new(cert_chain, key)))
+	Ok(CertifiedKey::new(cert_chain, key))
 }
 
-async fn load_cert_chain(cert_path: &Path) -> eyre::Result<Vec<CertificateDer<'static>>> {
+pub(crate) async fn load_cert_chain(cert_path: &Path) -> eyre::Result<Vec<CertificateDer<'static>>> {
 	let data = tokio::fs::read(cert_path).await.context("Failed to read certificate chain")?;
 
 	let pem_result = rustls_pemfile::certs(&mut data.as_slice())
@@ -107,9 +421,13 @@ async fn load_cert_chain(cert_path: &Path) -> eyre::Result<Vec<CertificateDer<'s
 	}
 }
 
-async fn load_priv_key(key_path: &Path) -> eyre::Result<PrivateKeyDer<'static>> {
+async fn load_priv_key(key_path: &Path, passphrase: Option<&str>) -> eyre::Result<PrivateKeyDer<'static>> {
 	let data = tokio::fs::read(key_path).await.context("Failed to read private key")?;
 
+	if let Some(key) = decrypt_priv_key(&data, passphrase)? {
+		return Ok(key);
+	}
+
 	if let Ok(Some(key)) = rustls_pemfile::private_key(&mut data.as_slice()).context("Malformed PEM private key") {
 		return Ok(key);
 	}
@@ -121,6 +439,122 @@ async fn load_priv_key(key_path: &Path) -> eyre::Result<PrivateKeyDer<'static>>
 	Ok(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(data)))
 }
 
+/// A certificate chain and key given as PEM strings, as in
+/// `tls.certificate_pem` and `tls.private_key_pem`, checked to be a pair
+pub fn parse_pem(
+	cert_pem: &str,
+	key_pem: &str,
+	passphrase: Option<&str>,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+	let chain = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+		.collect::<Result<Vec<_>, _>>()
+		.context("Invalid PEM certificate(s)")?;
+	if chain.is_empty() {
+		return Err(eyre::eyre!("No PEM certificate in `tls.certificate_pem`"));
+	}
+	let key = match decrypt_priv_key(key_pem.as_bytes(), passphrase)? {
+		Some(key) => key,
+		None => rustls_pemfile::private_key(&mut key_pem.as_bytes())
+			.context("Malformed PEM private key")?
+			.ok_or_else(|| eyre::eyre!("No PEM private key in `tls.private_key_pem`"))?,
+	};
+	certified_key(chain.clone(), &key)?
+		.keys_match()
+		.context("`tls.private_key_pem` is not the key of `tls.certificate_pem`")?;
+	Ok((chain, key))
+}
+
+/// Decrypt `data` if it is an encrypted PKCS#8 key, in PEM or DER
+fn decrypt_priv_key(data: &[u8], passphrase: Option<&str>) -> eyre::Result<Option<PrivateKeyDer<'static>>> {
+	let pem = std::str::from_utf8(data)
+		.ok()
+		.and_then(|pem| pkcs8::der::Document::from_pem(pem).ok());
+	let der = match &pem {
+		Some((label, document)) if *label == "ENCRYPTED PRIVATE KEY" => document.as_bytes(),
+		Some(_) => return Ok(None),
+		None => data,
+	};
+	let Ok(info) = pkcs8::EncryptedPrivateKeyInfo::try_from(der) else {
+		return Ok(None);
+	};
+	let passphrase = passphrase.ok_or_else(|| eyre::eyre!("The private key is encrypted but no passphrase is set"))?;
+	let key = info
+		.decrypt(passphrase)
+		.map_err(|e| eyre::eyre!("Failed to decrypt private key, is the passphrase right? {e}"))?;
+	Ok(Some(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.as_bytes().to_vec()))))
+}
+
+/// The certificate chain and key of a PKCS#12 bundle
+async fn load_pkcs12(
+	path: &Path,
+	passphrase: Option<&str>,
+) -> eyre::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+	let data = tokio::fs::read(path).await.context("Failed to read PKCS#12 bundle")?;
+	let store = p12_keystore::KeyStore::from_pkcs12(&data, passphrase.unwrap_or_default())
+		.map_err(|e| eyre::eyre!("Invalid PKCS#12 bundle, or wrong passphrase: {e}"))?;
+	let (_, entry) = store
+		.private_key_chain()
+		.ok_or_else(|| eyre::eyre!("PKCS#12 bundle holds no private key"))?;
+	let chain = entry
+		.chain()
+		.iter()
+		.map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+		.collect::<Vec<_>>();
+	if chain.is_empty() {
+		return Err(eyre::eyre!("PKCS#12 bundle holds no certificate"));
+	}
+	Ok((chain, PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(entry.key().to_vec()))))
+}
+
+/// Hex SHA-256 digest of `cert`, as clients pin it with `pinned_sha256`
+pub fn fingerprint(cert: &CertificateDer) -> String {
+	Sha256::digest(cert.as_ref())
+		.iter()
+		.map(|byte| format!("{byte:02x}"))
+		.collect()
+}
+
+/// A self-signed certificate for `hostname` and its key. With `dir`, they are
+/// kept there, so the certificate and its fingerprint stay the same across
+/// restarts; without, a new one is made each time.
+pub async fn self_signed(hostname: &str, dir: Option<&Path>) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+	let generate =
+		|| rcgen::generate_simple_self_signed(vec![hostname.to_owned()]).context("failed to generate self-signed certificate");
+	let Some(dir) = dir else {
+		let cert = generate()?;
+		let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+		return Ok((vec![CertificateDer::from(cert.cert)], PrivateKeyDer::Pkcs8(key)));
+	};
+	let cert_path = dir.join(format!("{hostname}.cert.pem"));
+	let key_path = dir.join(format!("{hostname}.key.pem"));
+	// The certificate marks a complete pair: it goes first when the pair is
+	// made again and is renamed into place last, so a crash midway leaves no
+	// certificate next to a key it doesn't belong to
+	if !(fs::try_exists(&cert_path).await? && fs::try_exists(&key_path).await?) {
+		let cert = generate()?;
+		fs::create_dir_all(dir).await?;
+		if let Err(err) = fs::remove_file(&cert_path).await
+			&& err.kind() != io::ErrorKind::NotFound
+		{
+			return Err(err.into());
+		}
+		replace(&key_path, &cert.signing_key.serialize_pem(), true).await?;
+		replace(&cert_path, &cert.cert.pem(), false).await?;
+	}
+	Ok((load_cert_chain(&cert_path).await?, load_priv_key(&key_path, None).await?))
+}
+
+/// Write `content` to a temporary file renamed to `path` once complete
+async fn replace(path: &Path, content: &str, private: bool) -> Result<()> {
+	let mut tmp = path.as_os_str().to_owned();
+	tmp.push(".tmp");
+	let tmp = PathBuf::from(tmp);
+	_ = fs::remove_file(&tmp).await;
+	crate::config::write_new(&tmp, content, private)?;
+	fs::rename(&tmp, path).await?;
+	Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 	use std::io::Write;
@@ -130,6 +564,13 @@ mod tests {
 
 	use super::*;
 
+	fn reload_every(reload_interval: Duration) -> CertOptions {
+		CertOptions {
+			reload_interval,
+			..Default::default()
+		}
+	}
+
 	fn generate_test_cert() -> eyre::Result<(String, String)> {
 		let mut params = CertificateParams::default();
 		let mut dn = rcgen::DistinguishedName::new();
@@ -196,7 +637,7 @@ mod tests {
 	async fn test_load_priv_key_pem() -> Result<()> {
 		let (_, key_pem) = generate_test_cert()?;
 		let (_, key_file) = create_temp_cert_file(b"", key_pem.as_bytes()).await;
-		let result = load_priv_key(key_file.path()).await;
+		let result = load_priv_key(key_file.path(), None).await;
 		assert!(result.is_ok());
 		Ok(())
 	}
@@ -205,16 +646,64 @@ mod tests {
 	async fn test_load_priv_key_der() -> Result<()> {
 		let (_, key_der) = generate_test_cert_der()?;
 		let (_, key_file) = create_temp_cert_file(b"", &key_der).await;
-		let result = load_priv_key(key_file.path()).await;
+		let result = load_priv_key(key_file.path(), None).await;
 		assert!(result.is_ok());
 		Ok(())
 	}
 
+	#[tokio::test]
+	async fn test_load_encrypted_priv_key() -> Result<()> {
+		let (_, key_der) = generate_test_cert_der()?;
+		let params = pkcs8::pkcs5::pbes2::Parameters::pbkdf2_sha256_aes256cbc(2048, b"tuic salt", &[7; 16])
+			.map_err(|e| eyre::eyre!("{e}"))?;
+		let encrypted = pkcs8::PrivateKeyInfo::try_from(key_der.as_slice())
+			.map_err(|e| eyre::eyre!("{e}"))?
+			.encrypt_with_params(params, "correct horse")
+			.map_err(|e| eyre::eyre!("{e}"))?;
+		let pem = encrypted
+			.to_pem("ENCRYPTED PRIVATE KEY", pkcs8::LineEnding::LF)
+			.map_err(|e| eyre::eyre!("{e}"))?;
+
+		for data in [encrypted.as_bytes(), pem.as_bytes()] {
+			let (_, key_file) = create_temp_cert_file(b"", data).await;
+			let key = load_priv_key(key_file.path(), Some("correct horse")).await?;
+			assert_eq!(key.secret_der(), key_der.as_slice());
+			assert!(load_priv_key(key_file.path(), Some("wrong")).await.is_err());
+			assert!(load_priv_key(key_file.path(), None).await.is_err());
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_is_pkcs12() {
+		assert!(is_pkcs12(Path::new("/etc/tuic/server.p12")));
+		assert!(is_pkcs12(Path::new("server.PFX")));
+		assert!(!is_pkcs12(Path::new("server.pem")));
+		assert!(!is_pkcs12(Path::new("p12")));
+	}
+
+	#[tokio::test]
+	async fn test_check_cert_key() -> Result<()> {
+		let (cert_pem, key_pem) = generate_test_cert()?;
+		let (cert_file, key_file) = create_temp_cert_file(cert_pem.as_bytes(), key_pem.as_bytes()).await;
+		check(cert_file.path(), key_file.path(), None).await?;
+
+		let (_, other_key_pem) = generate_test_cert()?;
+		let (_, other_key_file) = create_temp_cert_file(b"", other_key_pem.as_bytes()).await;
+		assert!(check(cert_file.path(), other_key_file.path(), None).await.is_err());
+		assert!(
+			check(cert_file.path(), Path::new("/nonexistent/key.pem"), None)
+				.await
+				.is_err()
+		);
+		Ok(())
+	}
+
 	#[tokio::test]
 	async fn test_cert_resolver_initial_load() -> Result<()> {
 		let (cert_der, key_der) = generate_test_cert_der()?;
 		let (cert_file, key_file) = create_temp_cert_file(&cert_der, &key_der).await;
-		let resolver = CertResolver::new(cert_file.path(), key_file.path(), Duration::from_secs(10))
+		let resolver = CertResolver::new(cert_file.path(), key_file.path(), &CertOptions::default())
 			.await
 			.unwrap();
 		let certified_key = resolver.cert_key.read().unwrap();
@@ -232,7 +721,7 @@ mod tests {
 		tokio::fs::write(&cert_path, &cert_pem.as_bytes()).await.unwrap();
 		tokio::fs::write(&key_path, &key_pem.as_bytes()).await.unwrap();
 
-		let resolver = CertResolver::new(&cert_path, &key_path, Duration::from_micros(100))
+		let resolver = CertResolver::new(&cert_path, &key_path, &reload_every(Duration::from_micros(100)))
 			.await
 			.unwrap();
 
@@ -256,12 +745,202 @@ mod tests {
 		Ok(())
 	}
 
+	#[tokio::test]
+	async fn test_cert_resolver_keeps_cert_during_renewal() -> Result<()> {
+		let temp_dir = tempdir().unwrap();
+		let cert_path = temp_dir.path().join("cert.pem");
+		let key_path = temp_dir.path().join("key.pem");
+
+		let (cert_pem, key_pem) = generate_test_cert()?;
+		tokio::fs::write(&cert_path, &cert_pem).await?;
+		tokio::fs::write(&key_path, &key_pem).await?;
+		let resolver = CertResolver::new(&cert_path, &key_path, &reload_every(Duration::from_millis(10))).await?;
+		let initial = resolver.current().unwrap();
+
+		// The new certificate is written before its key
+		let (new_cert_pem, new_key_pem) = generate_test_cert()?;
+		tokio::fs::write(&cert_path, &new_cert_pem).await?;
+		tokio::time::sleep(Duration::from_millis(200)).await;
+		assert!(Arc::ptr_eq(&resolver.current().unwrap(), &initial));
+		tokio::fs::remove_file(&key_path).await?;
+		tokio::time::sleep(Duration::from_millis(200)).await;
+		assert!(Arc::ptr_eq(&resolver.current().unwrap(), &initial));
+
+		tokio::fs::write(&key_path, &new_key_pem).await?;
+		tokio::time::sleep(Duration::from_millis(500)).await;
+		let renewed = resolver.current().unwrap();
+		assert_ne!(renewed.cert[0], initial.cert[0]);
+		renewed.keys_match()?;
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_cert_resolver_staples_ocsp_file() -> Result<()> {
+		let (cert_pem, key_pem) = generate_test_cert()?;
+		let (cert_file, key_file) = create_temp_cert_file(cert_pem.as_bytes(), key_pem.as_bytes()).await;
+		let (ocsp_file, _) = create_temp_cert_file(b"first", b"").await;
+		let options = CertOptions {
+			reload_interval: Duration::from_millis(10),
+			ocsp: Ocsp::File(ocsp_file.path().to_owned()),
+			..Default::default()
+		};
+		let resolver = CertResolver::new(cert_file.path(), key_file.path(), &options).await?;
+		assert_eq!(resolver.current().unwrap().ocsp.as_deref(), Some(&b"first"[..]));
+
+		// A renewed response is picked up like a renewed certificate
+		tokio::fs::write(ocsp_file.path(), b"second").await?;
+		tokio::time::sleep(Duration::from_millis(500)).await;
+		assert_eq!(resolver.current().unwrap().ocsp.as_deref(), Some(&b"second"[..]));
+
+		let fetched_for = resolver.current().unwrap();
+		resolver.staple(&fetched_for, b"fetched".to_vec());
+		assert_eq!(resolver.current().unwrap().ocsp.as_deref(), Some(&b"fetched"[..]));
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_cert_resolver_fallback() -> Result<()> {
+		let generate = |alg: &'static rcgen::SignatureAlgorithm| -> eyre::Result<(String, String)> {
+			let key_pair = KeyPair::generate_for(alg)?;
+			let cert = CertificateParams::new(vec!["localhost".to_string()])?.self_signed(&key_pair)?;
+			Ok((cert.pem(), key_pair.serialize_pem()))
+		};
+		let (cert_pem, key_pem) = generate(&rcgen::PKCS_ECDSA_P384_SHA384)?;
+		let (fallback_cert_pem, fallback_key_pem) = generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+		let (cert_file, key_file) = create_temp_cert_file(cert_pem.as_bytes(), key_pem.as_bytes()).await;
+		let (fallback_cert_file, fallback_key_file) =
+			create_temp_cert_file(fallback_cert_pem.as_bytes(), fallback_key_pem.as_bytes()).await;
+
+		let resolver = CertResolver::with_fallback(
+			cert_file.path(),
+			key_file.path(),
+			fallback_cert_file.path(),
+			fallback_key_file.path(),
+			&CertOptions::default(),
+		)
+		.await?;
+		let primary = resolver.current().unwrap();
+		let fallback = resolver.fallback.as_ref().unwrap().current().unwrap();
+
+		let modern = [SignatureScheme::ECDSA_NISTP384_SHA384, SignatureScheme::ECDSA_NISTP256_SHA256];
+		assert!(Arc::ptr_eq(&resolver.choose(&modern).unwrap(), &primary));
+		let legacy = [SignatureScheme::ECDSA_NISTP256_SHA256];
+		assert!(Arc::ptr_eq(&resolver.choose(&legacy).unwrap(), &fallback));
+		// With neither key usable, the handshake fails on the primary one
+		let unsupported = [SignatureScheme::RSA_PKCS1_SHA256];
+		assert!(Arc::ptr_eq(&resolver.choose(&unsupported).unwrap(), &primary));
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_sni_resolver_find() -> Result<()> {
+		let (cert_pem, key_pem) = generate_test_cert()?;
+		let (cert_file, key_file) = create_temp_cert_file(cert_pem.as_bytes(), key_pem.as_bytes()).await;
+		let load = || CertResolver::new(cert_file.path(), key_file.path(), &CertOptions::default());
+		let (exact, wildcard, default) = (load().await?, load().await?, load().await?);
+		let resolver = SniResolver::new(
+			default,
+			HashMap::from([
+				("tuic.example.com".to_owned(), exact.clone()),
+				("*.example.com".to_owned(), wildcard.clone()),
+			]),
+		);
+
+		assert!(Arc::ptr_eq(resolver.find("tuic.example.com").unwrap(), &exact));
+		assert!(Arc::ptr_eq(resolver.find("TUIC.Example.com").unwrap(), &exact));
+		assert!(Arc::ptr_eq(resolver.find("www.example.com").unwrap(), &wildcard));
+		assert!(resolver.find("example.com").is_none());
+		assert!(resolver.find("a.b.example.com").is_none());
+		assert!(resolver.find("example.org").is_none());
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_client_verifier() -> Result<()> {
+		#[cfg(feature = "aws-lc-rs")]
+		let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+		#[cfg(feature = "ring")]
+		let _ = rustls::crypto::ring::default_provider().install_default();
+		let (cert_pem, _) = generate_test_cert()?;
+		let (ca_file, invalid_file) = create_temp_cert_file(cert_pem.as_bytes(), b"").await;
+
+		let provider = crypto_provider(&[], &["X25519".to_string()])?;
+		assert!(!client_verifier(None, provider.clone()).await?.offer_client_auth());
+		let verifier = client_verifier(Some(ca_file.path()), provider.clone()).await?;
+		assert!(verifier.offer_client_auth());
+		assert!(verifier.client_auth_mandatory());
+		assert!(client_verifier(Some(invalid_file.path()), provider).await.is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_crypto_provider() -> Result<()> {
+		let default = crypto_provider(&[], &[])?;
+		let provider = crypto_provider(&["tls13_chacha20_poly1305_sha256".to_owned()], &["X25519".to_owned()])?;
+		assert_eq!(provider.cipher_suites.len(), 1);
+		assert_eq!(provider.cipher_suites[0].suite(), CipherSuite::TLS13_CHACHA20_POLY1305_SHA256);
+		assert_eq!(provider.kx_groups.len(), 1);
+		assert_eq!(provider.kx_groups[0].name(), rustls::NamedGroup::X25519);
+		assert!(provider.cipher_suites.len() < default.cipher_suites.len());
+
+		// TLS 1.2 suites can't carry QUIC
+		assert!(crypto_provider(&["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_owned()], &[]).is_err());
+		assert!(crypto_provider(&[], &["x448".to_owned()]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_parse_pem() -> Result<()> {
+		let (cert_pem, key_pem) = generate_test_cert()?;
+		let (chain, _) = parse_pem(&cert_pem, &key_pem, None)?;
+		assert_eq!(chain.len(), 1);
+		// The halves swapped, or missing
+		assert!(parse_pem(&key_pem, &key_pem, None).is_err());
+		assert!(parse_pem(&cert_pem, &cert_pem, None).is_err());
+		assert!(parse_pem("", &key_pem, None).is_err());
+		Ok(())
+	}
+
 	#[tokio::test]
 	async fn test_invalid_cert_handling() {
 		let (cert_file, key_file) = create_temp_cert_file(b"invalid", b"invalid").await;
-		let load_result = load_cert_key(cert_file.path(), key_file.path()).await;
+		let load_result = load_cert_key(cert_file.path(), key_file.path(), None).await;
 		assert!(load_result.is_err());
-		let resolver_result = CertResolver::new(cert_file.path(), key_file.path(), Duration::from_secs(10)).await;
+		let resolver_result = CertResolver::new(cert_file.path(), key_file.path(), &CertOptions::default()).await;
 		assert!(resolver_result.is_err());
 	}
+
+	#[tokio::test]
+	async fn test_self_signed_is_kept_in_dir() -> eyre::Result<()> {
+		let dir = tempdir()?;
+		let cache = dir.path().join("self-signed");
+
+		let (chain, _) = self_signed("tuic.example.com", Some(&cache)).await?;
+		let (again, _) = self_signed("tuic.example.com", Some(&cache)).await?;
+		assert_eq!(fingerprint(&chain[0]), fingerprint(&again[0]));
+		check(
+			&cache.join("tuic.example.com.cert.pem"),
+			&cache.join("tuic.example.com.key.pem"),
+			None,
+		)
+		.await?;
+
+		// A pair cut short by a crash is made again
+		std::fs::remove_file(cache.join("tuic.example.com.cert.pem"))?;
+		let (remade, _) = self_signed("tuic.example.com", Some(&cache)).await?;
+		assert_ne!(fingerprint(&chain[0]), fingerprint(&remade[0]));
+		std::fs::remove_file(cache.join("tuic.example.com.key.pem"))?;
+		self_signed("tuic.example.com", Some(&cache)).await?;
+		check(
+			&cache.join("tuic.example.com.cert.pem"),
+			&cache.join("tuic.example.com.key.pem"),
+			None,
+		)
+		.await?;
+
+		let (fresh, _) = self_signed("tuic.example.com", None).await?;
+		assert_ne!(fingerprint(&chain[0]), fingerprint(&fresh[0]));
+		assert_eq!(fingerprint(&fresh[0]).len(), 64);
+		Ok(())
+	}
 }
@@ -0,0 +1,201 @@
+//! Zero-downtime binary upgrades.
+//!
+//! A server with an `[upgrade]` section listens on a Unix socket. A new
+//! process started with `--upgrade` connects to it and receives the QUIC
+//! endpoint's UDP socket via `SCM_RIGHTS`. Once the new process reports its
+//! endpoint is up, the old one stops accepting connections, waits up to
+//! `drain_timeout` for the existing ones to close and then exits.
+//!
+//! While both processes read the shared socket, a datagram can reach the
+//! process that doesn't own its connection. That process answers with a
+//! stateless reset which the client ignores, as each process has its own reset
+//! key, so draining connections only see packet loss instead of being torn
+//! down.
+//!
+//! The Unix socket is only open to the user the server runs as: its file has
+//! mode 0600, and peers of any other uid are turned away.
+
+use std::{io, net::UdpSocket as StdUdpSocket, path::Path, sync::Arc};
+
+use tuic_core::quinn::Endpoint;
+
+use crate::AppContext;
+
+#[cfg(unix)]
+const TAG_SOCKET: u8 = b'S';
+#[cfg(unix)]
+const TAG_READY: u8 = b'R';
+#[cfg(unix)]
+const HANDOFF_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The new process's side of a handoff, kept until its endpoint is running.
+pub struct Handoff(#[cfg(unix)] std::os::unix::net::UnixStream);
+
+impl Handoff {
+	/// Tell the old process to start draining.
+	#[cfg(unix)]
+	pub fn ready(self) -> io::Result<()> {
+		use std::io::Write;
+
+		(&self.0).write_all(&[TAG_READY])
+	}
+
+	#[cfg(not(unix))]
+	pub fn ready(self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Take the endpoint socket over from the server listening on `path`.
+#[cfg(unix)]
+pub fn inherit(path: &Path) -> io::Result<(StdUdpSocket, Handoff)> {
+	use std::os::{
+		fd::{FromRawFd, RawFd},
+		unix::net::UnixStream,
+	};
+
+	use sendfd::RecvWithFd;
+
+	let stream = UnixStream::connect(path)?;
+	stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+
+	let mut tag = [0u8; 1];
+	let mut fds: [RawFd; 1] = [-1];
+	match stream.recv_with_fd(&mut tag, &mut fds)? {
+		(1, 1) if tag[0] == TAG_SOCKET => {}
+		_ => {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"unexpected message from the running server",
+			));
+		}
+	}
+
+	// SAFETY: the descriptor was just received via `SCM_RIGHTS`, so this process
+	// owns it and nothing else refers to it yet.
+	let socket = unsafe { StdUdpSocket::from_raw_fd(fds[0]) };
+	Ok((socket, Handoff(stream)))
+}
+
+#[cfg(not(unix))]
+pub fn inherit(_path: &Path) -> io::Result<(StdUdpSocket, Handoff)> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"socket handoff is only supported on unix",
+	))
+}
+
+/// Wait for a newer process to ask for `socket`, hand it over, drain `ep` and
+/// finally cancel the server. A failed handoff leaves this server running.
+#[cfg(unix)]
+pub async fn serve(ctx: Arc<AppContext>, ep: Endpoint, socket: StdUdpSocket) {
+	use std::{
+		fs::{self, Permissions},
+		os::unix::fs::{MetadataExt, PermissionsExt},
+	};
+
+	use tokio::{net::UnixListener, time};
+	use tracing::{info, warn};
+
+	use crate::connection::ERROR_CODE;
+
+	let Some(cfg) = &ctx.cfg.upgrade else {
+		return;
+	};
+
+	// A previous process leaves its socket file behind
+	_ = fs::remove_file(&cfg.socket);
+	let listener = match UnixListener::bind(&cfg.socket) {
+		Ok(listener) => listener,
+		Err(err) => {
+			warn!("[upgrade] failed to listen on {}: {err}", cfg.socket.display());
+			return;
+		}
+	};
+	// Whoever connects takes the socket or makes this server drain, so only
+	// the user the server runs as may. The socket file was just created by
+	// this process, so it has that uid.
+	let uid = match fs::set_permissions(&cfg.socket, Permissions::from_mode(0o600)).and_then(|()| fs::metadata(&cfg.socket)) {
+		Ok(metadata) => metadata.uid(),
+		Err(err) => {
+			warn!("[upgrade] failed to restrict {}: {err}", cfg.socket.display());
+			_ = fs::remove_file(&cfg.socket);
+			return;
+		}
+	};
+
+	loop {
+		let stream = tokio::select! {
+			res = listener.accept() => match res {
+				Ok((stream, _)) => stream,
+				Err(err) => {
+					warn!("[upgrade] accept error: {err}");
+					continue;
+				}
+			},
+			_ = ctx.cancel.cancelled() => return,
+		};
+		match stream.peer_cred() {
+			Ok(cred) if cred.uid() == uid => {}
+			Ok(cred) => {
+				warn!("[upgrade] refused a handoff to uid {}, the server runs as {uid}", cred.uid());
+				continue;
+			}
+			Err(err) => {
+				warn!("[upgrade] failed to check the peer of a handoff: {err}");
+				continue;
+			}
+		}
+
+		match hand_over(stream, &socket).await {
+			Ok(()) => break,
+			Err(err) => warn!("[upgrade] handoff failed, continuing to serve: {err}"),
+		}
+	}
+	drop(listener);
+
+	info!(
+		"[upgrade] new process took over, draining connections for up to {}",
+		humantime::format_duration(cfg.drain_timeout)
+	);
+	ep.set_server_config(None);
+	if time::timeout(cfg.drain_timeout, ep.wait_idle()).await.is_err() {
+		warn!(
+			"[upgrade] drain timeout, closing {} remaining connection(s)",
+			ep.open_connections()
+		);
+	}
+	ep.close(ERROR_CODE, b"server upgraded");
+	ctx.cancel.cancel();
+}
+
+#[cfg(unix)]
+async fn hand_over(stream: tokio::net::UnixStream, socket: &StdUdpSocket) -> io::Result<()> {
+	use std::{io::Read, os::fd::AsRawFd};
+
+	use sendfd::SendWithFd;
+
+	let stream = stream.into_std()?;
+	stream.set_nonblocking(false)?;
+	stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+	let socket = socket.try_clone()?;
+
+	tokio::task::spawn_blocking(move || {
+		stream.send_with_fd(&[TAG_SOCKET], &[socket.as_raw_fd()])?;
+
+		// The new process reports back once its endpoint is running
+		let mut tag = [0u8; 1];
+		(&stream).read_exact(&mut tag)?;
+		if tag[0] != TAG_READY {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected handoff reply"));
+		}
+		Ok(())
+	})
+	.await
+	.map_err(io::Error::other)?
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_ctx: Arc<AppContext>, _ep: Endpoint, _socket: StdUdpSocket) {
+	tracing::warn!("[upgrade] socket handoff is only supported on unix");
+}
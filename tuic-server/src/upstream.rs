@@ -0,0 +1,193 @@
+//! Relaying through upstream TUIC servers.
+//!
+//! An outbound with `type = "tuic"` sends the TCP connections routed to it
+//! through another TUIC server as a client would, so servers can be chained
+//! into multi-hop routes. The server acts as client on a QUIC endpoint of its
+//! own, bound to an ephemeral port, so upstream connections get neither the
+//! local `[obfs]` nor the server's transport settings, and use the TLS cipher
+//! suites and key exchange groups of `[tls]`. All streams routed to an
+//! outbound share one connection, opened on first use and again after it
+//! closed.
+
+use std::{
+	collections::HashMap,
+	net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+	sync::Arc,
+	time::Duration,
+};
+
+use eyre::{Context, OptionExt, eyre};
+use rustls::{ClientConfig as RustlsClientConfig, RootCertStore};
+use tokio::{
+	net,
+	sync::{Mutex as AsyncMutex, OnceCell},
+};
+use tracing::{info, warn};
+use tuic_core::{
+	Address,
+	quinn::{
+		ClientConfig, Connect, Connection as Model, Endpoint, QuinnConnection, TransportConfig,
+		crypto::rustls::QuicClientConfig, side,
+	},
+};
+use uuid::Uuid;
+
+use crate::{
+	config::{OutboundConfig, OutboundRule, TlsConfig},
+	tls,
+};
+
+/// Keeps idle upstream connections from timing out
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// The upstreams of all `tuic` outbounds
+pub struct Upstreams {
+	/// The client endpoint, bound on first use
+	endpoint: OnceCell<Endpoint>,
+	outbounds: HashMap<String, Upstream>,
+}
+
+struct Upstream {
+	addr: String,
+	server_name: String,
+	uuid: Uuid,
+	password: String,
+	config: ClientConfig,
+	conn: AsyncMutex<Option<(QuinnConnection, Model<side::Client>)>>,
+}
+
+impl Upstreams {
+	pub async fn new(cfg: &OutboundConfig, tls_cfg: &TlsConfig) -> eyre::Result<Self> {
+		let mut outbounds = HashMap::new();
+		let rules =
+			std::iter::once(("default", &cfg.default)).chain(cfg.named.iter().map(|(name, rule)| (name.as_str(), rule)));
+		for (name, rule) in rules.filter(|(_, rule)| is_tuic(rule)) {
+			let upstream = Upstream::new(rule, tls_cfg)
+				.await
+				.with_context(|| format!("invalid tuic outbound '{name}'"))?;
+			outbounds.insert(name.to_owned(), upstream);
+		}
+		Ok(Self {
+			endpoint: OnceCell::new(),
+			outbounds,
+		})
+	}
+
+	/// The client endpoint, dual-stack where IPv6 is available
+	async fn endpoint(&self) -> eyre::Result<&Endpoint> {
+		self.endpoint
+			.get_or_try_init(|| async {
+				Endpoint::client((Ipv6Addr::UNSPECIFIED, 0).into())
+					.or_else(|_| Endpoint::client((Ipv4Addr::UNSPECIFIED, 0).into()))
+					.context("failed to bind the upstream endpoint")
+			})
+			.await
+	}
+
+	/// Open a stream to `addr` through the upstream of outbound `name`
+	pub async fn connect(&self, name: &str, addr: Address) -> eyre::Result<Connect> {
+		let upstream = self
+			.outbounds
+			.get(name)
+			.ok_or_else(|| eyre!("outbound '{name}' is not a tuic outbound"))?;
+		let endpoint = self.endpoint().await?;
+		let model = upstream.connection(endpoint).await?;
+		Ok(model.connect(addr).await?)
+	}
+}
+
+impl Upstream {
+	async fn new(rule: &OutboundRule, tls_cfg: &TlsConfig) -> eyre::Result<Self> {
+		let addr = rule.addr.clone().ok_or_eyre("'addr' is required")?;
+		let uuid = rule.uuid.ok_or_eyre("'uuid' is required")?;
+		let password = rule.password.clone().ok_or_eyre("'password' is required")?;
+		let server_name = match &rule.sni {
+			Some(sni) => sni.clone(),
+			None => host(&addr).to_owned(),
+		};
+
+		let mut roots = RootCertStore::empty();
+		for cert in rustls_native_certs::load_native_certs().certs {
+			_ = roots.add(cert);
+		}
+		if let Some(path) = &rule.ca_file {
+			roots.add_parsable_certificates(tls::load_cert_chain(path).await?);
+		}
+		let provider = tls::crypto_provider(&tls_cfg.cipher_suites, &tls_cfg.kx_groups)?;
+		let mut crypto = RustlsClientConfig::builder_with_provider(provider)
+			.with_protocol_versions(&[&rustls::version::TLS13])?
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+		crypto.alpn_protocols = rule.alpn.iter().map(|alpn| alpn.clone().into_bytes()).collect();
+
+		let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+		let mut transport = TransportConfig::default();
+		transport.keep_alive_interval(Some(KEEP_ALIVE));
+		config.transport_config(Arc::new(transport));
+
+		Ok(Self {
+			addr,
+			server_name,
+			uuid,
+			password,
+			config,
+			conn: AsyncMutex::new(None),
+		})
+	}
+
+	/// The open connection, connecting and authenticating first if there is
+	/// none
+	async fn connection(&self, endpoint: &Endpoint) -> eyre::Result<Model<side::Client>> {
+		let mut conn = self.conn.lock().await;
+		if let Some((quinn, model)) = &*conn
+			&& quinn.close_reason().is_none()
+		{
+			return Ok(model.clone());
+		}
+
+		let ipv6 = endpoint.local_addr()?.is_ipv6();
+		let remote = net::lookup_host(self.addr.as_str())
+			.await?
+			.find(|addr: &SocketAddr| ipv6 || addr.is_ipv4())
+			.ok_or_else(|| eyre!("no usable address resolved for {}", self.addr))?;
+		let quinn = endpoint
+			.connect_with(self.config.clone(), remote, &self.server_name)?
+			.await
+			.with_context(|| format!("failed to connect to upstream {}", self.addr))?;
+		info!("[upstream] connected to {} ({remote})", self.addr);
+
+		let model = Model::<side::Client>::new(quinn.clone());
+		let (auth, uuid, password) = (model.clone(), self.uuid, self.password.clone());
+		let addr = self.addr.clone();
+		tokio::spawn(async move {
+			if let Err(err) = auth.authenticate(uuid, password).await {
+				warn!("[upstream] authenticating to {addr} failed: {err}");
+			}
+		});
+
+		*conn = Some((quinn, model.clone()));
+		Ok(model)
+	}
+}
+
+pub fn is_tuic(rule: &OutboundRule) -> bool {
+	rule.kind.eq_ignore_ascii_case("tuic")
+}
+
+/// Host part of `host:port`, without the brackets of an IPv6 address
+fn host(addr: &str) -> &str {
+	let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+	host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_host() {
+		assert_eq!(host("upstream.example.com:443"), "upstream.example.com");
+		assert_eq!(host("[2001:db8::1]:443"), "2001:db8::1");
+		assert_eq!(host("192.0.2.1:8443"), "192.0.2.1");
+	}
+}
@@ -0,0 +1,514 @@
+//! The users allowed to authenticate.
+//!
+//! These are the `users` of the config, plus those of `users_file` if set. The
+//! file maps UUIDs to passwords like `users`, in TOML, JSON or YAML by its
+//! extension, and wins for a UUID in both. It is read again when its
+//! modification time changes, and with the `users` of the config on SIGHUP
+//! (see [`crate::reload`]), so users can be added or revoked without a
+//! restart. Connections of a user who was removed or whose password changed
+//! are closed; all other connections carry on. A file that fails to load
+//! leaves the users as they were.
+//!
+//! Users added or removed through `/users` of the admin API apply on top of
+//! both and outlast reloads, but not a restart. With `expand_env`, passwords
+//! from all of these have `${NAME}` replaced alike.
+
+use std::{
+	collections::{HashMap, HashSet},
+	path::Path,
+	sync::{Arc, Mutex, PoisonError, atomic::AtomicUsize},
+	time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use eyre::Context;
+use figment::{
+	Figment,
+	providers::{Format, Toml, Yaml},
+};
+use figment_json5::Json5;
+use tokio::{sync::watch, time};
+use tracing::{info, warn};
+use tuic_core::quinn::QuinnConnection;
+use uuid::Uuid;
+
+use crate::{
+	AppContext,
+	config::{self, Config, MaxConnectionsPolicy, UserLimits},
+};
+
+/// How often the modification time of `users_file` is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Counters of a user, kept across reloads while the user exists
+#[derive(Default)]
+pub struct UserStats {
+	/// Connected clients, counted while `[restful]` is configured
+	pub online: AtomicUsize,
+	pub tx: AtomicUsize,
+	pub rx: AtomicUsize,
+}
+
+pub struct Users {
+	/// The `users` of the config, as of the last SIGHUP
+	configured: ArcSwap<HashMap<Uuid, String>>,
+	passwords: ArcSwap<HashMap<Uuid, String>>,
+	stats: ArcSwap<HashMap<Uuid, Arc<UserStats>>>,
+	/// Bumped whenever `passwords` is replaced
+	changes: watch::Sender<()>,
+	/// Authenticated connections of each user, oldest first
+	connections: Mutex<HashMap<Uuid, Vec<QuinnConnection>>>,
+	/// Held while `passwords` is replaced
+	edits: Mutex<Edits>,
+}
+
+/// Changes made through the admin API
+#[derive(Default)]
+struct Edits {
+	added: HashMap<Uuid, String>,
+	removed: HashSet<Uuid>,
+}
+
+impl Edits {
+	fn apply(&self, mut users: HashMap<Uuid, String>) -> HashMap<Uuid, String> {
+		users.retain(|uuid, _| !self.removed.contains(uuid));
+		users.extend(self.added.iter().map(|(uuid, password)| (*uuid, password.clone())));
+		users
+	}
+}
+
+/// A connection beyond `max_connections` under [`MaxConnectionsPolicy::Reject`]
+#[derive(Debug)]
+pub struct TooManyConnections;
+
+/// What a reload changed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reloaded {
+	pub added: usize,
+	pub removed: usize,
+	pub changed: usize,
+}
+
+impl Users {
+	pub async fn load(cfg: &Config) -> eyre::Result<Self> {
+		let passwords = table(&cfg.users, cfg).await?;
+		let stats = passwords.keys().map(|uuid| (*uuid, Arc::default())).collect();
+		Ok(Self {
+			configured: ArcSwap::from_pointee(cfg.users.clone()),
+			passwords: ArcSwap::from_pointee(passwords),
+			stats: ArcSwap::from_pointee(stats),
+			changes: watch::Sender::new(()),
+			connections: Mutex::default(),
+			edits: Mutex::default(),
+		})
+	}
+
+	pub fn password(&self, uuid: &Uuid) -> Option<String> {
+		self.passwords.load().get(uuid).cloned()
+	}
+
+	/// All users and their passwords, as of now
+	pub fn passwords(&self) -> Arc<HashMap<Uuid, String>> {
+		self.passwords.load_full()
+	}
+
+	pub fn user_stats(&self, uuid: &Uuid) -> Option<Arc<UserStats>> {
+		self.stats.load().get(uuid).cloned()
+	}
+
+	/// Keep counters for `uuid` if it's not in the table, as for a user of
+	/// `[auth_webhook]`
+	pub fn track(&self, uuid: Uuid) {
+		if self.stats.load().contains_key(&uuid) {
+			return;
+		}
+		self.stats.rcu(|stats| {
+			let mut stats = HashMap::clone(stats);
+			stats.entry(uuid).or_insert_with(Arc::default);
+			stats
+		});
+	}
+
+	/// The counters of all users, by UUID
+	pub fn all_stats(&self) -> Vec<(Uuid, Arc<UserStats>)> {
+		let mut stats: Vec<_> = self.stats.load().iter().map(|(uuid, stats)| (*uuid, stats.clone())).collect();
+		stats.sort_by_key(|(uuid, _)| *uuid);
+		stats
+	}
+
+	/// Wakes up whenever the users change
+	pub fn subscribe(&self) -> watch::Receiver<()> {
+		self.changes.subscribe()
+	}
+
+	/// Count `conn` as a connection of `uuid`, within `max_connections` of
+	/// `limits`. Returns the oldest connections to close for it, if the policy
+	/// says so.
+	pub fn connect(
+		&self,
+		uuid: Uuid,
+		conn: &QuinnConnection,
+		limits: Option<&UserLimits>,
+	) -> Result<Vec<QuinnConnection>, TooManyConnections> {
+		let mut connections = self.connections.lock().unwrap_or_else(PoisonError::into_inner);
+		let open = connections.entry(uuid).or_default();
+		// Closed connections are only removed once noticed by `disconnect`
+		open.retain(|open| open.close_reason().is_none());
+		admit(open, conn, limits)
+	}
+
+	/// Open connections of `uuid`
+	pub fn connection_count(&self, uuid: &Uuid) -> usize {
+		let connections = self.connections.lock().unwrap_or_else(PoisonError::into_inner);
+		connections
+			.get(uuid)
+			.map_or(0, |open| open.iter().filter(|open| open.close_reason().is_none()).count())
+	}
+
+	pub fn disconnect(&self, uuid: &Uuid, conn: &QuinnConnection) {
+		let mut connections = self.connections.lock().unwrap_or_else(PoisonError::into_inner);
+		if let Some(open) = connections.get_mut(uuid) {
+			open.retain(|open| open.stable_id() != conn.stable_id());
+			if open.is_empty() {
+				connections.remove(uuid);
+			}
+		}
+	}
+
+	/// Add `uuid` or give it a new password, `false` if it existed
+	pub fn add(&self, uuid: Uuid, password: String) -> bool {
+		let mut edits = self.edits.lock().unwrap_or_else(PoisonError::into_inner);
+		edits.removed.remove(&uuid);
+		edits.added.insert(uuid, password.clone());
+		let mut passwords = HashMap::clone(&self.passwords.load());
+		let existed = passwords.insert(uuid, password).is_some();
+		self.replace(passwords);
+		!existed
+	}
+
+	/// Remove `uuid`, closing its connections. `false` if there was no such
+	/// user.
+	pub fn remove(&self, uuid: &Uuid) -> bool {
+		let mut edits = self.edits.lock().unwrap_or_else(PoisonError::into_inner);
+		edits.added.remove(uuid);
+		edits.removed.insert(*uuid);
+		let mut passwords = HashMap::clone(&self.passwords.load());
+		let existed = passwords.remove(uuid).is_some();
+		if existed {
+			self.replace(passwords);
+		}
+		existed
+	}
+
+	/// Read `users_file` again
+	pub async fn reload(&self, cfg: &Config) -> eyre::Result<Reloaded> {
+		self.reconfigure(HashMap::clone(&self.configured.load()), cfg).await
+	}
+
+	/// Switch to `configured` in place of the `users` of the config, and read
+	/// `users_file` again
+	pub async fn reconfigure(&self, configured: HashMap<Uuid, String>, cfg: &Config) -> eyre::Result<Reloaded> {
+		let users = table(&configured, cfg).await?;
+		let edits = self.edits.lock().unwrap_or_else(PoisonError::into_inner);
+		self.configured.store(Arc::new(configured));
+		Ok(self.replace(edits.apply(users)))
+	}
+
+	/// Switch to `passwords`, keeping the counters of the users that remain.
+	/// Only called with `edits` locked.
+	fn replace(&self, passwords: HashMap<Uuid, String>) -> Reloaded {
+		let old = self.passwords.load_full();
+		let reloaded = Reloaded {
+			added: passwords.keys().filter(|uuid| !old.contains_key(uuid)).count(),
+			removed: old.keys().filter(|uuid| !passwords.contains_key(uuid)).count(),
+			changed: passwords
+				.iter()
+				.filter(|(uuid, password)| old.get(uuid).is_some_and(|old| old != *password))
+				.count(),
+		};
+
+		let old_stats = self.stats.load_full();
+		let stats = passwords
+			.keys()
+			.map(|uuid| (*uuid, old_stats.get(uuid).cloned().unwrap_or_default()))
+			// Users that were never in the table came from `[auth_webhook]`
+			.chain(
+				old_stats
+					.iter()
+					.filter(|(uuid, _)| !old.contains_key(uuid))
+					.map(|(uuid, stats)| (*uuid, stats.clone())),
+			)
+			.collect();
+		self.stats.store(Arc::new(stats));
+		self.passwords.store(Arc::new(passwords));
+		self.changes.send_replace(());
+		reloaded
+	}
+}
+
+/// `configured` with the users of `users_file` on top
+async fn table(configured: &HashMap<Uuid, String>, cfg: &Config) -> eyre::Result<HashMap<Uuid, String>> {
+	let mut users = configured.clone();
+	if let Some(path) = &cfg.users_file {
+		let mut file = read(path)
+			.await
+			.with_context(|| format!("failed to load {}", path.display()))?;
+		if cfg.expand_env {
+			for (uuid, password) in &mut file {
+				*password =
+					expand_env(password).with_context(|| format!("failed to load {}: password of {uuid}", path.display()))?;
+			}
+		}
+		users.extend(file);
+	}
+	Ok(users)
+}
+
+/// A password with `${NAME}` replaced as `expand_env` asks
+pub fn expand_env(password: &str) -> eyre::Result<String> {
+	config::expand_env(password, |name| std::env::var(name).ok())
+}
+
+async fn read(path: &Path) -> eyre::Result<HashMap<Uuid, String>> {
+	// Read it here, as figment takes a missing file for an empty one
+	let content = tokio::fs::read_to_string(path).await?;
+	let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+	let figment = match extension.to_lowercase().as_str() {
+		"json" | "json5" => Figment::from(Json5::string(&content)),
+		"yaml" | "yml" => Figment::from(Yaml::string(&content)),
+		_ => Figment::from(Toml::string(&content)),
+	};
+	Ok(figment.extract()?)
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+	tokio::fs::metadata(path).await.and_then(|meta| meta.modified()).ok()
+}
+
+/// Reload `users_file` when it changes, until the server shuts down.
+pub async fn watch(ctx: Arc<AppContext>) {
+	let Some(path) = ctx.cfg.users_file.clone() else {
+		return;
+	};
+	let mut last_modified = modified(&path).await;
+	let mut ticker = time::interval(POLL_INTERVAL);
+	ticker.tick().await;
+
+	loop {
+		tokio::select! {
+			_ = ticker.tick() => {}
+			_ = ctx.cancel.cancelled() => return,
+		}
+		let now = modified(&path).await;
+		if now == last_modified {
+			continue;
+		}
+		last_modified = now;
+
+		match ctx.users.reload(&ctx.cfg).await {
+			Ok(Reloaded { added, removed, changed }) => {
+				info!(
+					"[users] reloaded {}: {added} added, {removed} removed, {changed} changed",
+					path.display()
+				)
+			}
+			Err(err) => warn!("[users] keeping the current users: {err:#}"),
+		}
+	}
+}
+
+/// Add `conn` to the `open` connections of a user, oldest first, within
+/// `max_connections` of `limits`. Returns the connections it replaces.
+fn admit<C: Clone>(open: &mut Vec<C>, conn: &C, limits: Option<&UserLimits>) -> Result<Vec<C>, TooManyConnections> {
+	let mut excess = Vec::new();
+	if let Some(limits) = limits
+		&& let Some(max) = limits.max_connections
+		&& open.len() >= max
+	{
+		match limits.max_connections_policy {
+			MaxConnectionsPolicy::Reject => return Err(TooManyConnections),
+			MaxConnectionsPolicy::CloseOldest => excess = open.drain(..=open.len() - max).collect(),
+		}
+	}
+	open.push(conn.clone());
+	Ok(excess)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs, sync::atomic::Ordering};
+
+	use tempfile::tempdir;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_users_file_reload() {
+		let dir = tempdir().unwrap();
+		let path = dir.path().join("users.toml");
+		let (kept, changed, removed, added) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3), Uuid::from_u128(4));
+		fs::write(
+			&path,
+			format!("\"{changed}\" = \"old\"\n\"{removed}\" = \"gone\"\n\"{kept}\" = \"from-file\"\n"),
+		)
+		.unwrap();
+		let mut cfg = Config::default();
+		cfg.users.insert(kept, "from-config".to_owned());
+		cfg.users_file = Some(path.clone());
+
+		let users = Users::load(&cfg).await.unwrap();
+		assert_eq!(users.password(&kept).as_deref(), Some("from-file"));
+		assert_eq!(users.passwords().len(), 3);
+		users.user_stats(&changed).unwrap().tx.store(42, Ordering::Relaxed);
+
+		let mut changes = users.subscribe();
+		fs::write(&path, format!("\"{changed}\" = \"new\"\n\"{added}\" = \"hello\"\n")).unwrap();
+		let reloaded = users.reload(&cfg).await.unwrap();
+		assert_eq!(
+			reloaded,
+			Reloaded {
+				added: 1,
+				removed: 1,
+				changed: 1
+			}
+		);
+		assert!(changes.has_changed().unwrap());
+		assert_eq!(users.password(&kept).as_deref(), Some("from-config"));
+		assert_eq!(users.password(&changed).as_deref(), Some("new"));
+		assert_eq!(users.password(&removed), None);
+		assert!(users.user_stats(&removed).is_none());
+		assert_eq!(users.user_stats(&changed).unwrap().tx.load(Ordering::Relaxed), 42);
+	}
+
+	#[tokio::test]
+	async fn test_users_file_expand_env() {
+		let dir = tempdir().unwrap();
+		let path = dir.path().join("users.toml");
+		let (expanded, escaped) = (Uuid::from_u128(1), Uuid::from_u128(2));
+		fs::write(
+			&path,
+			format!("\"{expanded}\" = \"${{PATH}}\"\n\"{escaped}\" = \"$${{PATH}}\"\n"),
+		)
+		.unwrap();
+		let mut cfg = Config::default();
+		cfg.users_file = Some(path.clone());
+
+		let users = Users::load(&cfg).await.unwrap();
+		assert_eq!(users.password(&expanded).as_deref(), Some("${PATH}"));
+		assert_eq!(users.password(&escaped).as_deref(), Some("$${PATH}"));
+
+		cfg.expand_env = true;
+		users.reload(&cfg).await.unwrap();
+		assert_eq!(users.password(&expanded), std::env::var("PATH").ok());
+		assert_eq!(users.password(&escaped).as_deref(), Some("${PATH}"));
+	}
+
+	#[tokio::test]
+	async fn test_broken_users_file_keeps_users() {
+		let dir = tempdir().unwrap();
+		let path = dir.path().join("users.json");
+		let uuid = Uuid::from_u128(1);
+		fs::write(&path, format!(r#"{{"{uuid}": "secret"}}"#)).unwrap();
+		let cfg = Config {
+			users_file: Some(path.clone()),
+			..Default::default()
+		};
+		let users = Users::load(&cfg).await.unwrap();
+
+		fs::write(&path, "{ not json").unwrap();
+		assert!(users.reload(&cfg).await.is_err());
+		fs::remove_file(&path).unwrap();
+		assert!(users.reload(&cfg).await.is_err());
+		assert_eq!(users.password(&uuid).as_deref(), Some("secret"));
+	}
+
+	#[tokio::test]
+	async fn test_edits_outlast_reloads() {
+		let dir = tempdir().unwrap();
+		let path = dir.path().join("users.toml");
+		let (in_file, added) = (Uuid::from_u128(1), Uuid::from_u128(2));
+		fs::write(&path, format!("\"{in_file}\" = \"secret\"\n")).unwrap();
+		let cfg = Config {
+			users_file: Some(path.clone()),
+			..Default::default()
+		};
+		let users = Users::load(&cfg).await.unwrap();
+
+		assert!(users.add(added, "hello".to_owned()));
+		assert!(!users.add(added, "hello again".to_owned()));
+		assert!(users.remove(&in_file));
+		assert!(!users.remove(&in_file));
+		assert!(users.user_stats(&added).is_some());
+
+		users.reload(&cfg).await.unwrap();
+		assert_eq!(users.password(&added).as_deref(), Some("hello again"));
+		assert_eq!(users.password(&in_file), None);
+
+		// Adding a removed user back wins over the file again
+		assert!(users.add(in_file, "back".to_owned()));
+		users.reload(&cfg).await.unwrap();
+		assert_eq!(users.password(&in_file).as_deref(), Some("back"));
+	}
+
+	#[tokio::test]
+	async fn test_reconfigure_replaces_config_users() {
+		let dir = tempdir().unwrap();
+		let path = dir.path().join("users.toml");
+		let (in_both, dropped, new, added) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3), Uuid::from_u128(4));
+		fs::write(&path, format!("\"{in_both}\" = \"from-file\"\n")).unwrap();
+		let mut cfg = Config::default();
+		cfg.users.insert(in_both, "from-config".to_owned());
+		cfg.users.insert(dropped, "old".to_owned());
+		cfg.users_file = Some(path.clone());
+		let users = Users::load(&cfg).await.unwrap();
+		assert!(users.add(added, "hello".to_owned()));
+
+		let configured = HashMap::from([(in_both, "changed".to_owned()), (new, "secret".to_owned())]);
+		users.reconfigure(configured, &cfg).await.unwrap();
+		assert_eq!(users.password(&in_both).as_deref(), Some("from-file"));
+		assert_eq!(users.password(&dropped), None);
+		assert_eq!(users.password(&new).as_deref(), Some("secret"));
+		assert_eq!(users.password(&added).as_deref(), Some("hello"));
+
+		// Later reloads of the file keep the new `users`
+		users.reload(&cfg).await.unwrap();
+		assert_eq!(users.password(&new).as_deref(), Some("secret"));
+		assert_eq!(users.password(&dropped), None);
+	}
+
+	fn max_connections(max: usize, policy: MaxConnectionsPolicy) -> UserLimits {
+		UserLimits {
+			max_connections: Some(max),
+			max_connections_policy: policy,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_admit_reject() {
+		let limits = max_connections(2, MaxConnectionsPolicy::Reject);
+		let mut open = Vec::new();
+		assert!(admit(&mut open, &1, Some(&limits)).unwrap().is_empty());
+		assert!(admit(&mut open, &2, Some(&limits)).unwrap().is_empty());
+		assert!(matches!(admit(&mut open, &3, Some(&limits)), Err(TooManyConnections)));
+		assert_eq!(open, [1, 2]);
+
+		// Without a limit, any number is admitted
+		assert!(admit(&mut open, &3, None).unwrap().is_empty());
+		assert_eq!(open, [1, 2, 3]);
+	}
+
+	#[test]
+	fn test_admit_close_oldest() {
+		let limits = max_connections(2, MaxConnectionsPolicy::CloseOldest);
+		let mut open = vec![1, 2];
+		assert_eq!(admit(&mut open, &3, Some(&limits)).unwrap(), [1]);
+		assert_eq!(open, [2, 3]);
+
+		// A lowered limit closes as many as it takes
+		let limits = max_connections(1, MaxConnectionsPolicy::CloseOldest);
+		let mut open = vec![1, 2, 3];
+		assert_eq!(admit(&mut open, &4, Some(&limits)).unwrap(), [1, 2, 3]);
+		assert_eq!(open, [4]);
+	}
+}
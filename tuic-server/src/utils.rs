@@ -1,5 +1,5 @@
 // Re-export common types from tuic-core
-pub use tuic_core::{StackPrefer, UdpRelayMode};
+pub use tuic_core::{QuicVersion, StackPrefer, UdpRelayMode};
 
 pub type CongestionController = tuic_core::CongestionControl;
 
@@ -0,0 +1,165 @@
+//! Authentication against an external user system.
+//!
+//! With `[auth_webhook]`, a user unknown to `users` and `users_file` is looked
+//! up by POSTing its UUID, the token it presented and its address to `url`:
+//!
+//! ```json
+//! {"uuid": "f0e12827-fe60-458c-8269-a05ccb0ff8da", "token": "9c1f..", "addr": "203.0.113.7:51234"}
+//! ```
+//!
+//! A `200` answer accepts the user with `{"password": ".."}`, and the token is
+//! then checked against that password like for `users`. The token is bound to
+//! the TLS session, so only the server can check it. Any other status denies
+//! the user. Answers, denials included, are kept for `cache_ttl` per user and
+//! source address; failed requests deny without being kept.
+
+use std::{
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+	time::Duration,
+};
+
+use moka::future::Cache;
+use reqwest::{Client, StatusCode, header};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::AuthWebhookConfig;
+
+/// Answers kept at most, so a flood of made-up UUIDs can't exhaust memory
+const MAX_ANSWERS: u64 = 65536;
+
+pub struct AuthWebhook {
+	cfg: AuthWebhookConfig,
+	client: Client,
+	answers: Cache<(Uuid, IpAddr), Option<Arc<str>>>,
+}
+
+#[derive(Serialize)]
+struct Request {
+	uuid: Uuid,
+	token: String,
+	addr: SocketAddr,
+}
+
+#[derive(Deserialize)]
+struct Answer {
+	password: String,
+}
+
+impl AuthWebhook {
+	pub fn new(cfg: &AuthWebhookConfig) -> eyre::Result<Self> {
+		Ok(Self {
+			cfg: cfg.clone(),
+			client: Client::builder().timeout(cfg.timeout).build()?,
+			answers: Cache::builder()
+				.max_capacity(MAX_ANSWERS)
+				.time_to_live(cfg.cache_ttl.max(Duration::from_millis(1)))
+				.build(),
+		})
+	}
+
+	/// The password of `uuid` connecting from `addr`, `None` if the endpoint
+	/// denied the user or couldn't be asked
+	pub async fn password(&self, uuid: Uuid, token: [u8; 32], addr: SocketAddr) -> Option<Arc<str>> {
+		let key = (uuid, addr.ip().to_canonical());
+		if let Some(answer) = self.answers.get(&key).await {
+			return answer;
+		}
+		match self.ask(uuid, token, addr).await {
+			Ok(answer) => {
+				if !self.cfg.cache_ttl.is_zero() {
+					self.answers.insert(key, answer.clone()).await;
+				}
+				answer
+			}
+			Err(err) => {
+				warn!("[auth_webhook] failed to look up {uuid}: {err:#}");
+				None
+			}
+		}
+	}
+
+	async fn ask(&self, uuid: Uuid, token: [u8; 32], addr: SocketAddr) -> eyre::Result<Option<Arc<str>>> {
+		let body = Request {
+			uuid,
+			token: token.iter().map(|byte| format!("{byte:02x}")).collect(),
+			addr,
+		};
+		let mut request = self
+			.client
+			.post(&self.cfg.url)
+			.header(header::CONTENT_TYPE, "application/json")
+			.body(serde_json::to_vec(&body)?);
+		if let Some(token) = &self.cfg.token {
+			request = request.bearer_auth(token);
+		}
+		let response = request.send().await?;
+		match response.status() {
+			StatusCode::OK => {
+				let answer: Answer = serde_json::from_slice(&response.bytes().await?)?;
+				Ok(Some(answer.password.into()))
+			}
+			status if status.is_server_error() => Err(eyre::eyre!("the endpoint answered {status}")),
+			_ => Ok(None),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use axum::{Json, Router, extract::State, http::StatusCode as HttpStatus, routing::post};
+
+	use super::*;
+
+	async fn endpoint(State(calls): State<Arc<AtomicUsize>>, Json(request): Json<serde_json::Value>) -> (HttpStatus, String) {
+		calls.fetch_add(1, Ordering::Relaxed);
+		assert_eq!(request["token"].as_str().map(str::len), Some(64));
+		if request["uuid"] == Uuid::from_u128(1).to_string() {
+			(HttpStatus::OK, r#"{"password": "secret"}"#.to_owned())
+		} else {
+			(HttpStatus::FORBIDDEN, String::new())
+		}
+	}
+
+	#[tokio::test]
+	async fn test_webhook_answers_are_cached() {
+		let calls = Arc::new(AtomicUsize::new(0));
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/auth", listener.local_addr().unwrap());
+		let app = Router::new().route("/auth", post(endpoint)).with_state(calls.clone());
+		tokio::spawn(async move { axum::serve(listener, app).await });
+
+		let webhook = AuthWebhook::new(&AuthWebhookConfig {
+			url,
+			..Default::default()
+		})
+		.unwrap();
+		let addr: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+
+		for _ in 0..2 {
+			assert_eq!(
+				webhook.password(Uuid::from_u128(1), [7; 32], addr).await.as_deref(),
+				Some("secret")
+			);
+			assert_eq!(webhook.password(Uuid::from_u128(2), [7; 32], addr).await, None);
+		}
+		assert_eq!(calls.load(Ordering::Relaxed), 2);
+	}
+
+	#[tokio::test]
+	async fn test_unreachable_webhook_denies() {
+		let webhook = AuthWebhook::new(&AuthWebhookConfig {
+			url: "http://127.0.0.1:9/auth".to_owned(),
+			timeout: Duration::from_secs(1),
+			..Default::default()
+		})
+		.unwrap();
+		let addr: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+		assert_eq!(webhook.password(Uuid::from_u128(1), [7; 32], addr).await, None);
+		assert!(webhook.answers.get(&(Uuid::from_u128(1), addr.ip())).await.is_none());
+	}
+}
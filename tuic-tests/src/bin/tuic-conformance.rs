@@ -0,0 +1,467 @@
+//! Protocol conformance checks against a running TUIC server.
+//!
+//! Each case opens its own QUIC connection to the server, sends well-formed or
+//! deliberately broken TUIC commands and compares the server's reaction with
+//! what this crate's server does: malformed commands and failed
+//! authentication close the connection, a connection that never
+//! authenticates is closed after the auth timeout, oversized UDP packets are
+//! dropped without closing the connection, and the number of concurrent
+//! streams is bounded. The last case checks that the server still accepts new
+//! connections after all of this.
+//!
+//! ```plain
+//! tuic-conformance --server example.com:443 --uuid <UUID> --password <PASSWORD>
+//! ```
+//!
+//! Cases that need credentials are skipped without `--uuid` and `--password`.
+//! The exit status is non-zero if any case fails.
+
+use std::{
+	net::{SocketAddr, ToSocketAddrs},
+	path::PathBuf,
+	process::ExitCode,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use eyre::{Context, OptionExt, bail};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	time::{sleep, timeout},
+};
+use tuic_client::tls::{self, Verification};
+use tuic_core::{
+	Address, Header, VERSION,
+	quinn::{
+		ClientConfig, Connection as Model, ConnectionError, Endpoint, QuinnConnection, crypto::rustls::QuicClientConfig, side,
+	},
+};
+use uuid::Uuid;
+
+/// How long a connection must stay open to count as kept alive
+const ALIVE_FOR: Duration = Duration::from_secs(1);
+/// How long the server may take to close a connection it should close
+const CLOSE_WITHIN: Duration = Duration::from_secs(5);
+/// Streams opened at most by `stream_flood`
+const FLOOD_STREAMS: usize = 10000;
+/// Size of the UDP packet of `oversized_packet`, above any path MTU
+const OVERSIZED_PACKET: usize = 65000;
+
+const HELP: &str = "\
+Usage: tuic-conformance --server <HOST:PORT> [OPTIONS]
+
+Options:
+  --server <HOST:PORT>    Server to check
+  --sni <NAME>            TLS server name, the host of --server by default
+  --uuid <UUID>           Credentials of a user of the server
+  --password <PASSWORD>
+  --alpn <PROTOCOL>       ALPN protocol to offer, repeatable
+  --ca <FILE>             Extra trusted certificate (PEM or DER), repeatable
+  --insecure              Skip certificate verification
+  --auth-timeout <SECS>   `auth_timeout` of the server [default: 3]
+  --echo <HOST:PORT>      TCP echo service the server can reach, enables `connect_echo`
+  --case <NAME>           Run only this case, repeatable
+  --list                  List the cases and exit
+  -h, --help              Print help";
+
+const CASES: &[&str] = &[
+	"authenticate",
+	"wrong_password",
+	"slow_auth",
+	"version_mismatch",
+	"unknown_command",
+	"malformed_address",
+	"command_on_wrong_stream",
+	"truncated_datagram",
+	"oversized_packet",
+	"stream_flood",
+	"connect_echo",
+	"still_serving",
+];
+
+struct Args {
+	server: String,
+	sni: Option<String>,
+	credentials: Option<(Uuid, String)>,
+	alpn: Vec<String>,
+	ca: Vec<PathBuf>,
+	insecure: bool,
+	auth_timeout: Duration,
+	echo: Option<String>,
+	cases: Vec<String>,
+}
+
+enum Outcome {
+	Pass(String),
+	Fail(String),
+	Skip(&'static str),
+}
+
+/// The server under test
+struct Target {
+	endpoint: Endpoint,
+	addr: SocketAddr,
+	server_name: String,
+	credentials: Option<(Uuid, String)>,
+	auth_timeout: Duration,
+	echo: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<ExitCode> {
+	#[cfg(feature = "aws-lc-rs")]
+	let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+	#[cfg(feature = "ring")]
+	let _ = rustls::crypto::ring::default_provider().install_default();
+
+	let Some(args) = parse_args()? else {
+		return Ok(ExitCode::SUCCESS);
+	};
+	for case in &args.cases {
+		if !CASES.contains(&case.as_str()) {
+			bail!("unknown case '{case}', see --list");
+		}
+	}
+	let target = Target::new(&args)?;
+
+	let (mut passed, mut failed) = (0, 0);
+	for case in CASES
+		.iter()
+		.filter(|case| args.cases.is_empty() || args.cases.iter().any(|c| c == *case))
+	{
+		let started = Instant::now();
+		let outcome = target.run(case).await.unwrap_or_else(|err| Outcome::Fail(format!("{err:#}")));
+		let elapsed = started.elapsed().as_secs_f32();
+		match outcome {
+			Outcome::Pass(detail) => {
+				passed += 1;
+				println!("PASS {case:<24} {elapsed:>5.1}s  {detail}");
+			}
+			Outcome::Fail(detail) => {
+				failed += 1;
+				println!("FAIL {case:<24} {elapsed:>5.1}s  {detail}");
+			}
+			Outcome::Skip(reason) => println!("SKIP {case:<24}         {reason}"),
+		}
+	}
+	println!("\n{passed} passed, {failed} failed");
+	target.endpoint.close(0u32.into(), b"");
+	target.endpoint.wait_idle().await;
+
+	Ok(if failed == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+/// The arguments, `None` if only help or the case list was asked for
+fn parse_args() -> eyre::Result<Option<Args>> {
+	use lexopt::prelude::*;
+
+	let mut server = None;
+	let mut sni = None;
+	let mut uuid = None;
+	let mut password = None;
+	let mut alpn = Vec::new();
+	let mut ca = Vec::new();
+	let mut insecure = false;
+	let mut auth_timeout = Duration::from_secs(3);
+	let mut echo = None;
+	let mut cases = Vec::new();
+
+	let mut parser = lexopt::Parser::from_env();
+	while let Some(arg) = parser.next()? {
+		match arg {
+			Long("server") => server = Some(parser.value()?.string()?),
+			Long("sni") => sni = Some(parser.value()?.string()?),
+			Long("uuid") => uuid = Some(parser.value()?.parse::<Uuid>()?),
+			Long("password") => password = Some(parser.value()?.string()?),
+			Long("alpn") => alpn.push(parser.value()?.string()?),
+			Long("ca") => ca.push(PathBuf::from(parser.value()?)),
+			Long("insecure") => insecure = true,
+			Long("auth-timeout") => auth_timeout = Duration::from_secs(parser.value()?.parse()?),
+			Long("echo") => echo = Some(parser.value()?.string()?),
+			Long("case") => cases.push(parser.value()?.string()?),
+			Long("list") => {
+				CASES.iter().for_each(|case| println!("{case}"));
+				return Ok(None);
+			}
+			Short('h') | Long("help") => {
+				println!("{HELP}");
+				return Ok(None);
+			}
+			_ => return Err(arg.unexpected().into()),
+		}
+	}
+
+	let credentials = match (uuid, password) {
+		(Some(uuid), Some(password)) => Some((uuid, password)),
+		(None, None) => None,
+		_ => bail!("--uuid and --password go together"),
+	};
+	Ok(Some(Args {
+		server: server.ok_or_eyre("--server is required, see --help")?,
+		sni,
+		credentials,
+		alpn,
+		ca,
+		insecure,
+		auth_timeout,
+		echo,
+		cases,
+	}))
+}
+
+impl Target {
+	fn new(args: &Args) -> eyre::Result<Self> {
+		let addr = args
+			.server
+			.to_socket_addrs()
+			.with_context(|| format!("failed to resolve {}", args.server))?
+			.next()
+			.ok_or_eyre("the server name resolved to no address")?;
+		let server_name = match &args.sni {
+			Some(sni) => sni.clone(),
+			None => host(&args.server).to_owned(),
+		};
+
+		let verification = if args.insecure {
+			Verification::Insecure
+		} else {
+			Verification::WebPki {
+				roots: tuic_client::utils::load_certs(args.ca.clone(), false)?,
+				verify_hostname: true,
+			}
+		};
+		let mut crypto = tls::client_config(verification, None, tls::crypto_provider(&[], &[])?)?;
+		crypto.alpn_protocols = args.alpn.iter().map(|alpn| alpn.clone().into_bytes()).collect();
+
+		let bind: SocketAddr = if addr.is_ipv4() {
+			([0, 0, 0, 0], 0).into()
+		} else {
+			([0; 16], 0).into()
+		};
+		let mut endpoint = Endpoint::client(bind)?;
+		endpoint.set_default_client_config(ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?)));
+
+		Ok(Self {
+			endpoint,
+			addr,
+			server_name,
+			credentials: args.credentials.clone(),
+			auth_timeout: args.auth_timeout,
+			echo: args.echo.clone(),
+		})
+	}
+
+	async fn run(&self, case: &str) -> eyre::Result<Outcome> {
+		let needs_credentials = !matches!(case, "slow_auth" | "version_mismatch");
+		if needs_credentials && self.credentials.is_none() {
+			return Ok(Outcome::Skip("needs --uuid and --password"));
+		}
+
+		match case {
+			"authenticate" => {
+				let (conn, model) = self.authenticated().await?;
+				Ok(still_open(&conn, &model).await)
+			}
+			"wrong_password" => {
+				let (uuid, password) = self.credentials()?;
+				let conn = self.connect().await?;
+				// The server may close the connection before the stream is acknowledged
+				_ = Model::<side::Client>::new(conn.clone())
+					.authenticate(uuid, format!("{password}-wrong"))
+					.await;
+				Ok(closed_by_server(&conn, CLOSE_WITHIN).await)
+			}
+			"slow_auth" => {
+				let conn = self.connect().await?;
+				Ok(closed_by_server(&conn, self.auth_timeout + CLOSE_WITHIN).await)
+			}
+			"version_mismatch" => {
+				let conn = self.connect().await?;
+				send_uni(&conn, &version_mismatch()).await?;
+				Ok(closed_by_server(&conn, CLOSE_WITHIN).await)
+			}
+			"unknown_command" => {
+				let (conn, _model) = self.authenticated().await?;
+				send_uni(&conn, &unknown_command()).await?;
+				Ok(closed_by_server(&conn, CLOSE_WITHIN).await)
+			}
+			"malformed_address" => {
+				let (conn, _model) = self.authenticated().await?;
+				let (mut send, _recv) = conn.open_bi().await?;
+				send.write_all(&malformed_address()).await?;
+				send.finish()?;
+				Ok(closed_by_server(&conn, CLOSE_WITHIN).await)
+			}
+			"command_on_wrong_stream" => {
+				let (conn, _model) = self.authenticated().await?;
+				let (mut send, _recv) = conn.open_bi().await?;
+				send.write_all(&heartbeat()).await?;
+				send.finish()?;
+				Ok(closed_by_server(&conn, CLOSE_WITHIN).await)
+			}
+			"truncated_datagram" => {
+				let (conn, _model) = self.authenticated().await?;
+				conn.send_datagram(truncated_packet().into())?;
+				Ok(closed_by_server(&conn, CLOSE_WITHIN).await)
+			}
+			"oversized_packet" => {
+				let (conn, model) = self.authenticated().await?;
+				let discard = Address::SocketAddress(([127, 0, 0, 1], 9).into());
+				model.packet_quic(vec![0u8; OVERSIZED_PACKET], discard, 0).await?;
+				Ok(still_open(&conn, &model).await)
+			}
+			"stream_flood" => {
+				let (conn, _model) = self.authenticated().await?;
+				Ok(stream_limit(&conn).await)
+			}
+			"connect_echo" => {
+				let Some(echo) = &self.echo else {
+					return Ok(Outcome::Skip("needs --echo"));
+				};
+				let (_conn, model) = self.authenticated().await?;
+				Ok(echo_roundtrip(&model, echo).await?)
+			}
+			"still_serving" => {
+				let (conn, model) = self.authenticated().await?;
+				Ok(still_open(&conn, &model).await)
+			}
+			_ => bail!("unknown case '{case}'"),
+		}
+	}
+
+	async fn connect(&self) -> eyre::Result<QuinnConnection> {
+		let conn = timeout(CLOSE_WITHIN, self.endpoint.connect(self.addr, &self.server_name)?)
+			.await
+			.context("QUIC handshake timed out")?
+			.context("QUIC handshake failed")?;
+		Ok(conn)
+	}
+
+	async fn authenticated(&self) -> eyre::Result<(QuinnConnection, Model<side::Client>)> {
+		let (uuid, password) = self.credentials()?;
+		let conn = self.connect().await?;
+		let model = Model::<side::Client>::new(conn.clone());
+		model.authenticate(uuid, password).await?;
+		Ok((conn, model))
+	}
+
+	fn credentials(&self) -> eyre::Result<(Uuid, &str)> {
+		let (uuid, password) = self.credentials.as_ref().ok_or_eyre("no credentials")?;
+		Ok((*uuid, password))
+	}
+}
+
+async fn send_uni(conn: &QuinnConnection, bytes: &[u8]) -> eyre::Result<()> {
+	let mut send = conn.open_uni().await?;
+	send.write_all(bytes).await?;
+	send.finish()?;
+	Ok(())
+}
+
+/// Passes if the server closes `conn` within `within`
+async fn closed_by_server(conn: &QuinnConnection, within: Duration) -> Outcome {
+	match timeout(within, conn.closed()).await {
+		Ok(ConnectionError::ApplicationClosed(close)) => Outcome::Pass(format!("closed with code {}", close.error_code)),
+		Ok(err) => Outcome::Fail(format!("expected the server to close the connection, got: {err}")),
+		Err(_) => Outcome::Fail(format!("connection still open after {}s", within.as_secs())),
+	}
+}
+
+/// Passes if `conn` stays open and takes a heartbeat
+async fn still_open(conn: &QuinnConnection, model: &Model<side::Client>) -> Outcome {
+	if let Err(err) = model.heartbeat().await {
+		return Outcome::Fail(format!("failed to send a heartbeat: {err}"));
+	}
+	sleep(ALIVE_FOR).await;
+	match conn.close_reason() {
+		Some(err) => Outcome::Fail(format!("connection closed: {err}")),
+		None => Outcome::Pass(format!("open after {}s", ALIVE_FOR.as_secs())),
+	}
+}
+
+/// Passes if the server bounds the concurrent streams of a connection. The
+/// streams stay unused, so they don't reach the server as malformed commands.
+async fn stream_limit(conn: &QuinnConnection) -> Outcome {
+	let mut streams = Vec::new();
+	while streams.len() < FLOOD_STREAMS {
+		match timeout(ALIVE_FOR, conn.open_bi()).await {
+			Ok(Ok(stream)) => streams.push(stream),
+			Ok(Err(err)) => return Outcome::Fail(format!("opening stream {} failed: {err}", streams.len() + 1)),
+			Err(_) => {
+				conn.close(0u32.into(), b"");
+				return Outcome::Pass(format!("limited to {} concurrent streams", streams.len()));
+			}
+		}
+	}
+	conn.close(0u32.into(), b"");
+	Outcome::Fail(format!("{FLOOD_STREAMS} concurrent streams allowed"))
+}
+
+async fn echo_roundtrip(model: &Model<side::Client>, echo: &str) -> eyre::Result<Outcome> {
+	const PAYLOAD: &[u8] = b"tuic-conformance";
+
+	let (host, port) = echo.rsplit_once(':').ok_or_eyre("--echo takes HOST:PORT")?;
+	let addr = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+		Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, port.parse()?)),
+		Err(_) => Address::DomainAddress(host.to_owned(), port.parse()?),
+	};
+	let mut stream = model.connect(addr).await?;
+	stream.write_all(PAYLOAD).await?;
+	let mut buf = [0u8; PAYLOAD.len()];
+	match timeout(CLOSE_WITHIN, stream.read_exact(&mut buf)).await {
+		Ok(Ok(_)) if buf == PAYLOAD => Ok(Outcome::Pass(format!("{} bytes echoed", PAYLOAD.len()))),
+		Ok(Ok(_)) => Ok(Outcome::Fail("echo differs from what was sent".to_owned())),
+		Ok(Err(err)) => Ok(Outcome::Fail(format!("relay failed: {err}"))),
+		Err(_) => Ok(Outcome::Fail("no echo within the timeout".to_owned())),
+	}
+}
+
+/// An `Authenticate` of a protocol version before this one
+fn version_mismatch() -> Vec<u8> {
+	let mut bytes = vec![VERSION - 1, Header::TYPE_CODE_AUTHENTICATE];
+	bytes.extend_from_slice(&[0; 16 + 32]);
+	bytes
+}
+
+/// A command type no version defines
+fn unknown_command() -> Vec<u8> {
+	vec![VERSION, 0xfe]
+}
+
+/// A `Connect` to an address type no version defines
+fn malformed_address() -> Vec<u8> {
+	vec![VERSION, Header::TYPE_CODE_CONNECT, 0x7f, 0, 0, 0, 0, 0, 80]
+}
+
+/// A `Heartbeat`, only valid as a datagram
+fn heartbeat() -> Vec<u8> {
+	vec![VERSION, Header::TYPE_CODE_HEARTBEAT]
+}
+
+/// A `Packet` cut off inside its header
+fn truncated_packet() -> Vec<u8> {
+	vec![VERSION, Header::TYPE_CODE_PACKET, 0, 0, 0]
+}
+
+/// Host part of `host:port`, without the brackets of an IPv6 address
+fn host(addr: &str) -> &str {
+	let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+	host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_broken_commands_do_not_parse() {
+		for bytes in [version_mismatch(), unknown_command(), malformed_address(), truncated_packet()] {
+			assert!(Header::unmarshal(&mut bytes.as_slice()).is_err(), "{bytes:?} parsed");
+		}
+		// Well-formed, but not allowed on a bidirectional stream
+		assert!(matches!(
+			Header::unmarshal(&mut heartbeat().as_slice()),
+			Ok(Header::Heartbeat(_))
+		));
+	}
+}
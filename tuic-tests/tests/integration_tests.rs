@@ -252,6 +252,7 @@ async fn test_server_client_integration() -> eyre::Result<()> {
 			hostname: "localhost".to_string(),
 			auto_ssl: false,
 			acme_email: "admin@example.com".to_string(),
+			..Default::default()
 		},
 		data_dir: std::env::temp_dir(),
 		quic: tuic_server::config::QuicConfig::default(),
@@ -311,7 +312,14 @@ async fn test_server_client_integration() -> eyre::Result<()> {
 			socks5_udp_idle_timeout: Duration::from_secs(300),
 			tcp_forward: Vec::new(),
 			udp_forward: Vec::new(),
+			idle_exit: Duration::ZERO,
 		},
+		relays: Default::default(),
+		rules: Vec::new(),
+		budget: None,
+		ping: None,
+		stdio: None,
+		unknown_options: Vec::new(),
 		log_level: "debug".to_string(),
 	};
 
@@ -577,6 +585,7 @@ async fn test_tcp_udp_forward_integration() -> eyre::Result<()> {
 			hostname: "localhost".to_string(),
 			auto_ssl: false,
 			acme_email: "admin@example.com".to_string(),
+			..Default::default()
 		},
 		data_dir: std::env::temp_dir(),
 		quic: tuic_server::config::QuicConfig::default(),
@@ -639,8 +648,18 @@ async fn test_tcp_udp_forward_integration() -> eyre::Result<()> {
 				listen: udp_forward_listen,
 				remote: ("127.0.0.1".to_string(), udp_echo_addr.port()),
 				timeout: Duration::from_secs(10),
+				dns_cache: false,
+				dns_prefetch: false,
+				direct_resolver: None,
 			}],
+			idle_exit: Duration::ZERO,
 		},
+		relays: Default::default(),
+		rules: Vec::new(),
+		budget: None,
+		ping: None,
+		stdio: None,
+		unknown_options: Vec::new(),
 		log_level: "debug".to_string(),
 	};
 
@@ -801,6 +820,7 @@ async fn test_ipv6_server_client_integration() -> eyre::Result<()> {
 			hostname: "localhost".to_string(),
 			auto_ssl: false,
 			acme_email: "admin@example.com".to_string(),
+			..Default::default()
 		},
 		data_dir: std::env::temp_dir(),
 		restful: None,
@@ -871,6 +891,7 @@ async fn test_ipv6_server_client_integration() -> eyre::Result<()> {
 			skip_cert_verify: true,
 			proxy: None,
 			max_concurrent_streams: 1280,
+			..Default::default()
 		},
 		local: tuic_client::config::Local {
 			server: "[::1]:1081".parse().map(Some)?,
@@ -881,7 +902,14 @@ async fn test_ipv6_server_client_integration() -> eyre::Result<()> {
 			socks5_udp_idle_timeout: Duration::from_secs(300),
 			tcp_forward: Vec::new(),
 			udp_forward: Vec::new(),
+			idle_exit: Duration::ZERO,
 		},
+		relays: Default::default(),
+		rules: Vec::new(),
+		budget: None,
+		ping: None,
+		stdio: None,
+		unknown_options: Vec::new(),
 		log_level: "debug".to_string(),
 	};
 
@@ -1024,6 +1052,7 @@ async fn test_client_proxy_configuration() -> eyre::Result<()> {
 			hostname: "localhost".to_string(),
 			auto_ssl: false,
 			acme_email: "admin@example.com".to_string(),
+			..Default::default()
 		},
 		data_dir: std::env::temp_dir(),
 		udp_relay_ipv6: true,
@@ -1079,6 +1108,12 @@ async fn test_client_proxy_configuration() -> eyre::Result<()> {
 			server: "127.0.0.1:1082".parse().map(Some)?,
 			..Default::default()
 		},
+		relays: Default::default(),
+		rules: Vec::new(),
+		budget: None,
+		ping: None,
+		stdio: None,
+		unknown_options: Vec::new(),
 		log_level: "debug".to_string(),
 	};
 	let local_socks = "127.0.0.1:1082";
@@ -1156,6 +1191,7 @@ async fn test_server_port_zero() -> eyre::Result<()> {
 			hostname: "localhost".to_string(),
 			auto_ssl: false,
 			acme_email: "admin@example.com".to_string(),
+			..Default::default()
 		},
 		data_dir: std::env::temp_dir(),
 		dual_stack: false,
@@ -1180,3 +1216,126 @@ async fn test_server_port_zero() -> eyre::Result<()> {
 	guard.cancel.cancel();
 	Ok(())
 }
+
+// Test that a connection past max_session_lifetime keeps relaying its open
+// TCP stream until the stream is done
+#[tokio::test(flavor = "current_thread")]
+#[serial]
+#[tracing_test::traced_test]
+#[cfg_attr(not(any(target_arch = "x86", target_arch = "x86_64")), ignore)]
+async fn test_session_expiry_drains_open_streams() -> eyre::Result<()> {
+	use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+
+	use fast_socks5::client::{Config, Socks5Stream};
+	use tokio::{
+		io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+		net::TcpListener,
+	};
+
+	async fn echo(stream: &mut (impl AsyncRead + AsyncWrite + Unpin), data: &[u8]) -> eyre::Result<()> {
+		stream.write_all(data).await?;
+		let mut buffer = vec![0u8; data.len()];
+		timeout(Duration::from_secs(5), stream.read_exact(&mut buffer)).await??;
+		assert_eq!(buffer, data);
+		Ok(())
+	}
+
+	#[cfg(feature = "aws-lc-rs")]
+	let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+	#[cfg(feature = "ring")]
+	let _ = rustls::crypto::ring::default_provider().install_default();
+
+	let server_config = tuic_server::Config {
+		log_level: tuic_server::config::LogLevel::Debug,
+		server: "127.0.0.1:0".parse::<SocketAddr>()?,
+		users: {
+			let mut users = HashMap::new();
+			users.insert(
+				Uuid::parse_str("00000000-0000-0000-0000-000000000000")?,
+				"test_password".to_string(),
+			);
+			users
+		},
+		tls: tuic_server::config::TlsConfig {
+			self_sign: true,
+			certificate: PathBuf::from("./test_cert.pem"),
+			private_key: PathBuf::from("./test_key.pem"),
+			alpn: vec!["h3".to_string()],
+			hostname: "localhost".to_string(),
+			auto_ssl: false,
+			acme_email: "admin@example.com".to_string(),
+			..Default::default()
+		},
+		data_dir: std::env::temp_dir(),
+		dual_stack: false,
+		max_session_lifetime: Duration::from_secs(2),
+		experimental: ExperimentalConfig {
+			drop_loopback: false,
+			..Default::default()
+		},
+		..Default::default()
+	};
+	let guard = tuic_server::run(server_config).await?;
+
+	let client_config = tuic_client::Config {
+		tokio_runtime: Default::default(),
+		relay: tuic_client::config::Relay {
+			server: ("127.0.0.1".to_string(), guard.local_addr.port()),
+			uuid: Uuid::parse_str("00000000-0000-0000-0000-000000000000")?,
+			password: std::sync::Arc::from(b"test_password".to_vec().into_boxed_slice()),
+			alpn: vec![b"h3".to_vec()],
+			disable_sni: true,
+			disable_native_certs: true,
+			skip_cert_verify: true,
+			..Default::default()
+		},
+		local: tuic_client::config::Local {
+			server: "127.0.0.1:1084".parse().map(Some)?,
+			..Default::default()
+		},
+		relays: Default::default(),
+		rules: Vec::new(),
+		budget: None,
+		ping: None,
+		stdio: None,
+		unknown_options: Vec::new(),
+		log_level: "debug".to_string(),
+	};
+	let client_handle = tokio::spawn(async move {
+		match timeout(Duration::from_secs(30), tuic_client::run(client_config)).await {
+			Ok(Ok(())) => info!("[Session Expiry Test] Client completed successfully"),
+			Ok(Err(e)) => error!("[Session Expiry Test] Client error: {}", e),
+			Err(_) => error!("[Session Expiry Test] Client timeout"),
+		}
+	});
+	tokio::time::sleep(Duration::from_secs(1)).await;
+
+	// An echo server that keeps the stream open as long as the client does
+	let echo_server = TcpListener::bind("127.0.0.1:0").await?;
+	let echo_addr = echo_server.local_addr()?;
+	let echo_handle = tokio::spawn(async move {
+		if let Ok((mut socket, _)) = echo_server.accept().await {
+			let (mut reader, mut writer) = socket.split();
+			_ = tokio::io::copy(&mut reader, &mut writer).await;
+		}
+	});
+	let mut stream = Socks5Stream::connect(
+		"127.0.0.1:1084".parse::<SocketAddr>()?,
+		echo_addr.ip().to_string(),
+		echo_addr.port(),
+		Config::default(),
+	)
+	.await?;
+	echo(&mut stream, b"before expiry").await?;
+
+	// The session expires while the stream is open, which keeps relaying
+	tokio::time::sleep(Duration::from_secs(3)).await;
+	assert!(logs_contain("session lifetime of 2s reached, draining 1 stream(s)"));
+	echo(&mut stream, b"after expiry").await?;
+	drop(stream);
+
+	echo_handle.abort();
+	client_handle.abort();
+	guard.cancel.cancel();
+	Ok(())
+}